@@ -31,6 +31,8 @@ fn benchmark_model_registry(c: &mut Criterion) {
                 template: None,
                 ctx_len: black_box(4096),
                 n_threads: black_box(4),
+                backend: None,
+                command: None,
             };
             registry.add_model(black_box(model_spec));
         })
@@ -45,6 +47,8 @@ fn benchmark_model_registry(c: &mut Criterion) {
             template: None,
             ctx_len: 4096,
             n_threads: 4,
+            backend: None,
+            command: None,
         };
         registry.add_model(model_spec);
     }
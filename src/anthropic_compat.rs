@@ -0,0 +1,876 @@
+// Anthropic Messages API compatibility
+// `POST /v1/messages` takes Anthropic's request shape (top-level `system`,
+// content blocks, `tool_use`/`tool_result`) and answers with its message
+// object or named SSE events. Prompts are rendered and generated through the
+// same template, tool calling and engine path as `openai_compat`.
+
+use crate::api::{check_capabilities, ChatMessage, ChatToolCall, FunctionCall, RequestedFeatures};
+use crate::api_errors::{ApiError, Json};
+use crate::engine::stream::FinishReason;
+use crate::engine::{GenOptions, GenerationEvent};
+use crate::openai_compat::{chat_prompt, load_model, template_family, ReplyParser};
+use crate::tool_calling::{Parsed, ToolCallParser, ToolFormat, ToolMode};
+use crate::tools::{ToolCall, ToolDefinition};
+use crate::AppState;
+use axum::extract::State;
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    pub system: Option<Content>,
+    /// Required, as upstream
+    pub max_tokens: usize,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<i32>,
+    #[serde(default)]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    #[serde(default)]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Message {
+    pub role: String,
+    pub content: Content,
+}
+
+/// A plain string, or a list of content blocks
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+impl Content {
+    fn blocks(&self) -> Vec<ContentBlock> {
+        match self {
+            Content::Text(text) => vec![ContentBlock::Text { text: text.clone() }],
+            Content::Blocks(blocks) => blocks.clone(),
+        }
+    }
+
+    /// The text blocks, one per line
+    fn text(&self) -> String {
+        let texts: Vec<String> = self
+            .blocks()
+            .into_iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text),
+                _ => None,
+            })
+            .collect();
+        texts.join("\n")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: Value,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        #[serde(default)]
+        content: Option<Content>,
+        #[serde(default)]
+        is_error: Option<bool>,
+    },
+}
+
+/// `{"name", "description", "input_schema"}`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tool {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub input_schema: Value,
+}
+
+/// `{"type": "auto" | "any" | "none"}` or `{"type": "tool", "name"}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolChoice {
+    Auto,
+    Any,
+    None,
+    Tool { name: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessagesResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub role: String,
+    pub model: String,
+    pub content: Vec<ContentBlock>,
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+    pub usage: MessagesUsage,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MessagesUsage {
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+}
+
+impl MessagesRequest {
+    fn validate(&self) -> Result<(), ApiError> {
+        let invalid = |message: &str| Err(ApiError::InvalidRequest(message.to_string()));
+        if self.messages.is_empty() {
+            return invalid("messages must not be empty");
+        }
+        if self.max_tokens == 0 {
+            return invalid("max_tokens must be at least 1");
+        }
+        match self
+            .messages
+            .iter()
+            .find(|m| m.role != "user" && m.role != "assistant")
+        {
+            Some(message) => Err(ApiError::InvalidRequest(format!(
+                "unknown message role '{}'; put system prompts in 'system'",
+                message.role
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn features(&self) -> RequestedFeatures {
+        RequestedFeatures {
+            images: self.messages.iter().any(|m| {
+                m.content
+                    .blocks()
+                    .iter()
+                    .any(|block| matches!(block, ContentBlock::Image { .. }))
+            }),
+            max_tokens: Some(self.max_tokens),
+            ..Default::default()
+        }
+    }
+
+    fn options(&self) -> GenOptions {
+        let mut opts = GenOptions {
+            max_tokens: self.max_tokens,
+            stream: self.stream.unwrap_or(false),
+            ..Default::default()
+        };
+        if let Some(t) = self.temperature {
+            opts.temperature = t;
+        }
+        if let Some(p) = self.top_p {
+            opts.top_p = p;
+        }
+        if let Some(k) = self.top_k {
+            opts.top_k = k;
+        }
+        opts
+    }
+
+    /// Base64 data of the `image` blocks; image URLs are not fetched
+    fn images(&self) -> Result<Vec<String>, ApiError> {
+        self.messages
+            .iter()
+            .flat_map(|m| m.content.blocks())
+            .filter_map(|block| match block {
+                ContentBlock::Image { source } => Some(source),
+                _ => None,
+            })
+            .map(
+                |source| match (source["type"].as_str(), source["data"].as_str()) {
+                    (Some("base64"), Some(data)) => Ok(data.to_string()),
+                    _ => Err(ApiError::InvalidRequest(
+                        "image blocks need a base64 source; image URLs are not fetched".to_string(),
+                    )),
+                },
+            )
+            .collect()
+    }
+
+    /// The conversation as chat messages: `system` first, `tool_result`
+    /// blocks as `tool` messages and `tool_use` blocks as assistant calls
+    fn chat_messages(&self) -> Vec<ChatMessage> {
+        let mut messages = Vec::new();
+        if let Some(system) = &self.system {
+            messages.push(ChatMessage {
+                role: "system".to_string(),
+                content: system.text(),
+                ..Default::default()
+            });
+        }
+        for message in &self.messages {
+            let mut texts = Vec::new();
+            let mut calls = Vec::new();
+            for block in message.content.blocks() {
+                match block {
+                    ContentBlock::Text { text } => texts.push(text),
+                    ContentBlock::Image { .. } => {}
+                    ContentBlock::ToolUse { id, name, input } => calls.push(ChatToolCall {
+                        id,
+                        kind: "function".to_string(),
+                        function: FunctionCall {
+                            name,
+                            arguments: input.to_string(),
+                        },
+                    }),
+                    ContentBlock::ToolResult {
+                        tool_use_id,
+                        content,
+                        is_error,
+                    } => {
+                        let result = content.map(|c| c.text()).unwrap_or_default();
+                        messages.push(ChatMessage {
+                            role: "tool".to_string(),
+                            content: if is_error == Some(true) {
+                                format!("error: {}", result)
+                            } else {
+                                result
+                            },
+                            tool_call_id: Some(tool_use_id),
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+            if !texts.is_empty() || !calls.is_empty() {
+                messages.push(ChatMessage {
+                    role: message.role.clone(),
+                    content: texts.join("\n"),
+                    tool_calls: (!calls.is_empty()).then_some(calls),
+                    ..Default::default()
+                });
+            }
+        }
+        messages
+    }
+
+    /// The tools to offer and what `tool_choice` demands of them; `None`
+    /// when the request doesn't use tools
+    fn tool_mode(&self) -> Result<Option<(Vec<ToolDefinition>, ToolMode)>, ApiError> {
+        let tools: Vec<ToolDefinition> = self
+            .tools
+            .iter()
+            .flatten()
+            .map(|tool| ToolDefinition {
+                name: tool.name.clone(),
+                description: tool.description.clone().unwrap_or_default(),
+                parameters: tool.input_schema.clone(),
+            })
+            .collect();
+        let mode = match &self.tool_choice {
+            None | Some(ToolChoice::Auto) => ToolMode::Auto,
+            Some(ToolChoice::None) => return Ok(None),
+            Some(ToolChoice::Any) => ToolMode::Required,
+            Some(ToolChoice::Tool { name }) => {
+                if !tools.iter().any(|tool| &tool.name == name) {
+                    return Err(ApiError::InvalidRequest(format!(
+                        "tool_choice names tool '{}', which is not in tools",
+                        name
+                    )));
+                }
+                ToolMode::Function(name.clone())
+            }
+        };
+        match mode {
+            ToolMode::Auto if tools.is_empty() => Ok(None),
+            ToolMode::Required if tools.is_empty() => Err(ApiError::InvalidRequest(
+                "tool_choice 'any' needs at least one tool".to_string(),
+            )),
+            mode => Ok(Some((tools, mode))),
+        }
+    }
+}
+
+/// Generated text as content blocks: stop sequences cut it, and tool calls
+/// in the template's format become `tool_use` blocks
+struct Reply {
+    parser: ReplyParser,
+    tokens: usize,
+    calls: usize,
+}
+
+impl Reply {
+    /// The blocks `piece` completes or extends. Once a stop sequence is
+    /// found, `stopped()` turns true and the reply is over.
+    fn push(&mut self, piece: &str) -> Vec<ContentBlock> {
+        self.tokens += 1;
+        let parsed = self.parser.push(piece);
+        self.blocks(parsed)
+    }
+
+    /// The rest of the reply, once the generation ended on its own
+    fn finish(&mut self) -> Vec<ContentBlock> {
+        let parsed = self.parser.finish();
+        self.blocks(parsed)
+    }
+
+    fn stopped(&self) -> bool {
+        self.parser.stopped()
+    }
+
+    fn blocks(&mut self, parsed: Vec<Parsed>) -> Vec<ContentBlock> {
+        parsed
+            .into_iter()
+            .map(|piece| match piece {
+                Parsed::Content(text) => ContentBlock::Text { text },
+                Parsed::Call(call) => {
+                    self.calls += 1;
+                    tool_use(call)
+                }
+            })
+            .collect()
+    }
+
+    fn stop_reason(&self, finish_reason: FinishReason) -> &'static str {
+        if self.calls > 0 {
+            "tool_use"
+        } else if self.stopped() {
+            "stop_sequence"
+        } else {
+            match finish_reason {
+                FinishReason::Length => "max_tokens",
+                FinishReason::ContentFilter => "refusal",
+                _ => "end_turn",
+            }
+        }
+    }
+
+    fn stop_sequence(&self) -> Option<String> {
+        self.parser.stop_sequence().map(str::to_string)
+    }
+}
+
+fn tool_use(call: ToolCall) -> ContentBlock {
+    ContentBlock::ToolUse {
+        id: format!("toolu_{}", uuid::Uuid::new_v4().simple()),
+        name: call.name,
+        input: call.arguments,
+    }
+}
+
+/// An SSE event named after the `type` of its data
+fn sse(data: Value) -> Event {
+    let name = data["type"].as_str().unwrap_or_default().to_string();
+    Event::default().event(name).data(data.to_string())
+}
+
+/// Numbers content blocks as they stream and brackets each with
+/// `content_block_start` and `content_block_stop`
+#[derive(Default)]
+struct BlockWriter {
+    index: usize,
+    text_open: bool,
+}
+
+impl BlockWriter {
+    fn write(&mut self, blocks: Vec<ContentBlock>) -> Vec<Event> {
+        let mut events = Vec::new();
+        for block in blocks {
+            match block {
+                // Whitespace alone, such as the newline after a call, opens no block
+                ContentBlock::Text { text } if !self.text_open && text.trim().is_empty() => {}
+                ContentBlock::Text { text } => {
+                    if !self.text_open {
+                        self.text_open = true;
+                        events.push(sse(json!({"type": "content_block_start",
+                            "index": self.index, "content_block": {"type": "text", "text": ""}})));
+                    }
+                    events.push(sse(
+                        json!({"type": "content_block_delta", "index": self.index,
+                        "delta": {"type": "text_delta", "text": text}}),
+                    ));
+                }
+                // Calls arrive whole, so each is one block with a single delta
+                ContentBlock::ToolUse { id, name, input } => {
+                    events.extend(self.close());
+                    events.push(sse(json!({"type": "content_block_start", "index": self.index,
+                        "content_block": {"type": "tool_use", "id": id, "name": name, "input": {}}})));
+                    events.push(sse(
+                        json!({"type": "content_block_delta", "index": self.index,
+                        "delta": {"type": "input_json_delta", "partial_json": input.to_string()}}),
+                    ));
+                    events.push(sse(
+                        json!({"type": "content_block_stop", "index": self.index}),
+                    ));
+                    self.index += 1;
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    fn close(&mut self) -> Option<Event> {
+        if !self.text_open {
+            return None;
+        }
+        self.text_open = false;
+        let event = sse(json!({"type": "content_block_stop", "index": self.index}));
+        self.index += 1;
+        Some(event)
+    }
+
+    /// Close the open block and end the message
+    fn finish(&mut self, reply: &Reply, stop_reason: &str, output_tokens: usize) -> Vec<Event> {
+        let mut events: Vec<Event> = self.close().into_iter().collect();
+        events.push(sse(json!({"type": "message_delta",
+            "delta": {"stop_reason": stop_reason, "stop_sequence": reply.stop_sequence()},
+            "usage": {"output_tokens": output_tokens}})));
+        events.push(sse(json!({"type": "message_stop"})));
+        events
+    }
+}
+
+/// Consecutive text blocks merged into one; with tool calls, text is trimmed
+/// and empty text blocks dropped
+fn merge_blocks(blocks: Vec<ContentBlock>, calls: bool) -> Vec<ContentBlock> {
+    let mut merged: Vec<ContentBlock> = Vec::new();
+    for block in blocks {
+        match (merged.last_mut(), block) {
+            (Some(ContentBlock::Text { text }), ContentBlock::Text { text: more }) => {
+                text.push_str(&more)
+            }
+            (_, block) => merged.push(block),
+        }
+    }
+    merged
+        .into_iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } if calls => {
+                let text = text.trim();
+                (!text.is_empty()).then(|| ContentBlock::Text {
+                    text: text.to_string(),
+                })
+            }
+            ContentBlock::Text { text } if text.is_empty() => None,
+            block => Some(block),
+        })
+        .collect()
+}
+
+/// `error` in Anthropic's envelope, as sent in error bodies and `error` events
+fn error_body(error: ApiError) -> Value {
+    let kind = match error {
+        ApiError::ContextOverflow(_) | ApiError::InvalidRequest(_) => "invalid_request_error",
+        ApiError::ModelNotFound(_) | ApiError::NotFound(_) => "not_found_error",
+        ApiError::Overloaded(_) => "overloaded_error",
+        _ => "api_error",
+    };
+    json!({"type": "error",
+        "error": {"type": kind, "message": error.into_body().error.message}})
+}
+
+fn error_response(error: ApiError) -> Response {
+    (error.status(), Json(error_body(error))).into_response()
+}
+
+pub async fn messages(
+    State(state): State<Arc<AppState>>,
+    req: Result<Json<MessagesRequest>, ApiError>,
+) -> Response {
+    let Json(req) = match req {
+        Ok(req) => req,
+        Err(e) => return error_response(e),
+    };
+    let tools = match req.validate().and_then(|_| req.tool_mode()) {
+        Ok(tools) => tools,
+        Err(e) => return error_response(e),
+    };
+    let (spec, loaded) = match load_model(&state, &req.model).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+
+    let fam = template_family(&spec, &req.model);
+    let format = ToolFormat::for_template(&fam, &req.model);
+    let (prompt, prefill) = chat_prompt(&fam, format, &req.chat_messages(), tools.as_ref());
+    // Known before generating, so `message_start` can carry it
+    let input_tokens =
+        match check_capabilities(&state, &req.model, &loaded, &prompt, &req.features()).await {
+            Ok(counted) => counted.unwrap_or(0),
+            Err(e) => return error_response(e),
+        };
+    let images = match req.images() {
+        Ok(images) => images,
+        Err(e) => return error_response(e),
+    };
+
+    let mut events = loaded.generate_stream(
+        prompt,
+        GenOptions {
+            images,
+            ..req.options()
+        },
+    );
+    let mut reply = Reply {
+        parser: ReplyParser::new(
+            req.stop_sequences.as_deref().unwrap_or_default(),
+            tools
+                .as_ref()
+                .map(|_| ToolCallParser::new(format, prefill.as_deref())),
+        ),
+        tokens: 0,
+        calls: 0,
+    };
+    let message = {
+        let id = format!("msg_{}", uuid::Uuid::new_v4().simple());
+        let model = req.model.clone();
+        move |content, stop_reason: Option<&str>, stop_sequence, output_tokens| MessagesResponse {
+            id: id.clone(),
+            kind: "message".to_string(),
+            role: "assistant".to_string(),
+            model: model.clone(),
+            content,
+            stop_reason: stop_reason.map(str::to_string),
+            stop_sequence,
+            usage: MessagesUsage {
+                input_tokens,
+                output_tokens,
+            },
+        }
+    };
+
+    if req.stream.unwrap_or(false) {
+        use axum::response::sse::Sse;
+        use futures_util::{future, stream};
+
+        let start = sse(json!({"type": "message_start",
+            "message": message(vec![], None, None, 0)}));
+        let ping = sse(json!({"type": "ping"}));
+        let model = req.model.clone();
+        let mut writer = BlockWriter::default();
+        // Ending the stream after a stop sequence drops the generation,
+        // which cancels it
+        let body = events
+            .scan(false, move |finished, event| {
+                if *finished {
+                    return future::ready(None);
+                }
+                let out = match event {
+                    GenerationEvent::PromptProgress { .. } => vec![],
+                    GenerationEvent::Token { text, .. } => {
+                        let blocks = reply.push(&text);
+                        let mut out = writer.write(blocks);
+                        if reply.stopped() {
+                            *finished = true;
+                            let reason = reply.stop_reason(FinishReason::Stop);
+                            out.extend(writer.finish(&reply, reason, reply.tokens));
+                        }
+                        out
+                    }
+                    GenerationEvent::Done {
+                        finish_reason,
+                        stats,
+                    } => {
+                        *finished = true;
+                        let blocks = reply.finish();
+                        let mut out = writer.write(blocks);
+                        let reason = reply.stop_reason(finish_reason);
+                        out.extend(writer.finish(&reply, reason, stats.completion_tokens));
+                        out
+                    }
+                    GenerationEvent::Error { message } => {
+                        *finished = true;
+                        tracing::error!(
+                            "Failed to generate message for model '{}': {}",
+                            model,
+                            message
+                        );
+                        vec![sse(error_body(ApiError::GenerationFailed(message)))]
+                    }
+                    GenerationEvent::Overloaded { model } => {
+                        *finished = true;
+                        vec![sse(error_body(ApiError::Overloaded(model)))]
+                    }
+                };
+                future::ready(Some(stream::iter(out)))
+            })
+            .flatten();
+        let stream = stream::iter([start, ping])
+            .chain(body)
+            .map(Ok::<Event, std::convert::Infallible>);
+        return Sse::new(stream).into_response();
+    }
+
+    let mut blocks = Vec::new();
+    let mut finished = None;
+    while let Some(event) = events.next().await {
+        match event {
+            GenerationEvent::PromptProgress { .. } => {}
+            GenerationEvent::Token { text, .. } => {
+                blocks.extend(reply.push(&text));
+                if reply.stopped() {
+                    break;
+                }
+            }
+            GenerationEvent::Done {
+                finish_reason,
+                stats,
+            } => {
+                blocks.extend(reply.finish());
+                finished = Some((finish_reason, stats.completion_tokens));
+                break;
+            }
+            GenerationEvent::Error { message } => {
+                tracing::error!(
+                    "Failed to generate message for model '{}': {}",
+                    req.model,
+                    message
+                );
+                return error_response(ApiError::GenerationFailed(message));
+            }
+            GenerationEvent::Overloaded { model } => {
+                return error_response(ApiError::Overloaded(model));
+            }
+        }
+    }
+    let (finish_reason, output_tokens) = match finished {
+        Some(finished) => finished,
+        None if reply.stopped() => (FinishReason::Stop, reply.tokens),
+        None => {
+            return error_response(ApiError::GenerationFailed(
+                "generation ended without finishing".to_string(),
+            ))
+        }
+    };
+    let content = merge_blocks(blocks, reply.calls > 0);
+    let stop_reason = reply.stop_reason(finish_reason);
+    Json(message(
+        content,
+        Some(stop_reason),
+        reply.stop_sequence(),
+        output_tokens,
+    ))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Capabilities;
+    use crate::test_utils::{body, json_request, FakeModel};
+
+    async fn send(state: &Arc<AppState>, request: Value) -> (axum::http::StatusCode, String) {
+        body(messages(State(Arc::clone(state)), Ok(json_request(request))).await).await
+    }
+
+    #[test]
+    fn test_anthropic_conversation_maps_to_chat_messages() {
+        let request: MessagesRequest = serde_json::from_value(json!({
+            "model": "m",
+            "max_tokens": 16,
+            "system": [{"type": "text", "text": "Be brief."}],
+            "messages": [
+                {"role": "user", "content": "Weather in Oslo?"},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Checking."},
+                    {"type": "tool_use", "id": "toolu_1", "name": "weather",
+                        "input": {"city": "Oslo"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1",
+                        "content": [{"type": "text", "text": "sunny"}]},
+                    {"type": "text", "text": "And Paris?"}
+                ]}
+            ]
+        }))
+        .unwrap();
+        let messages = request.chat_messages();
+        let roles: Vec<_> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "tool", "user"]);
+        assert_eq!(messages[0].content, "Be brief.");
+        let calls = messages[2].tool_calls.as_ref().unwrap();
+        assert_eq!(calls[0].id, "toolu_1");
+        assert_eq!(calls[0].to_tool_call().arguments["city"], "Oslo");
+        assert_eq!(messages[3].content, "sunny");
+        assert_eq!(messages[3].tool_call_id.as_deref(), Some("toolu_1"));
+
+        let invalid: MessagesRequest = serde_json::from_value(json!({
+            "model": "m", "max_tokens": 16,
+            "messages": [{"role": "system", "content": "no"}]
+        }))
+        .unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_messages_stop_sequences_and_tool_use() {
+        let model = FakeModel::new(&["Hello", " wor", "ld. END more"]);
+        let state = model.state("hermes", Some("chatml"));
+        let (status, body) = send(
+            &state,
+            json!({"model": "hermes", "max_tokens": 32, "system": "Be brief.",
+                "messages": [{"role": "user", "content": "hi"}],
+                "stop_sequences": ["END"]}),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["type"], "message");
+        assert_eq!(
+            body["content"],
+            json!([{"type": "text", "text": "Hello world. "}])
+        );
+        assert_eq!(body["stop_reason"], "stop_sequence");
+        assert_eq!(body["stop_sequence"], "END");
+        assert!(model.prompt().contains("Be brief."));
+
+        let model = FakeModel::new(&[
+            "Sure. <tool_call>\n{\"name\": \"weather\", ",
+            "\"arguments\": {\"city\": \"Oslo\"}}\n</tool_call>",
+        ]);
+        let state = model.state("hermes", Some("chatml"));
+        let tools = json!([{"name": "weather", "input_schema": {"type": "object"}}]);
+        let (_, body) = send(
+            &state,
+            json!({"model": "hermes", "max_tokens": 32, "tools": tools,
+                "messages": [{"role": "user", "content": "Weather?"}]}),
+        )
+        .await;
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["stop_reason"], "tool_use");
+        assert_eq!(body["content"][0], json!({"type": "text", "text": "Sure."}));
+        assert_eq!(body["content"][1]["type"], "tool_use");
+        assert_eq!(body["content"][1]["input"], json!({"city": "Oslo"}));
+        assert!(model.prompt().contains("\"weather\""));
+
+        let (status, _) = send(
+            &state,
+            json!({"model": "hermes", "max_tokens": 32, "tools": tools,
+                "tool_choice": {"type": "tool", "name": "missing"},
+                "messages": [{"role": "user", "content": "Weather?"}]}),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_image_blocks_reach_the_engine() {
+        let model = FakeModel::new(&["A cat."]).with_capabilities(Capabilities {
+            images: true,
+            ..Capabilities::text(0)
+        });
+        let state = model.state("hermes", Some("chatml"));
+        let image = |source: Value| {
+            json!({"model": "hermes", "max_tokens": 32, "messages": [{"role": "user",
+                "content": [{"type": "image", "source": source},
+                    {"type": "text", "text": "What is this?"}]}]})
+        };
+        let (status, _) = send(
+            &state,
+            image(json!({"type": "base64", "media_type": "image/png", "data": "iVBORw0K"})),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        assert_eq!(model.options().images, ["iVBORw0K"]);
+        let (status, _) = send(
+            &state,
+            image(json!({"type": "url", "url": "https://example.com/cat.png"})),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+
+        // Models without vision are told so before the source is looked at
+        let plain = FakeModel::new(&[]).state("hermes", Some("chatml"));
+        let (status, body) = send(
+            &plain,
+            image(json!({"type": "url", "url": "https://example.com/cat.png"})),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["type"], "error");
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert!(body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("image inputs"));
+
+        let (status, body) = send(
+            &plain,
+            json!({"model": "missing", "max_tokens": 8,
+                "messages": [{"role": "user", "content": "hi"}]}),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["type"], "not_found_error");
+    }
+
+    #[tokio::test]
+    async fn test_messages_stream_named_events() {
+        let state = FakeModel::new(&[
+            "Hi. <tool_call>\n{\"name\": \"weather\", ",
+            "\"arguments\": {\"city\": \"Oslo\"}}\n</tool_call>",
+        ])
+        .state("hermes", Some("chatml"));
+        let (status, body) = send(
+            &state,
+            json!({"model": "hermes", "max_tokens": 32, "stream": true,
+                "tools": [{"name": "weather", "input_schema": {"type": "object"}}],
+                "messages": [{"role": "user", "content": "Weather?"}]}),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let names: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "message_start",
+                "ping",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop"
+            ]
+        );
+        let data: Vec<Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(data[0]["message"]["content"], json!([]));
+        assert_eq!(data[5]["index"], 1);
+        assert_eq!(data[5]["content_block"]["name"], "weather");
+        let input: Value =
+            serde_json::from_str(data[6]["delta"]["partial_json"].as_str().unwrap()).unwrap();
+        assert_eq!(input["city"], "Oslo");
+        assert_eq!(data[8]["delta"]["stop_reason"], "tool_use");
+        assert_eq!(data[8]["usage"]["output_tokens"], 2);
+    }
+}
@@ -2,14 +2,16 @@ use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
 use axum::{
     extract::State,
     response::{sse::Event, IntoResponse, Sse},
-    Json,
 };
-use futures_util::StreamExt;
+use futures_util::{future, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use crate::{engine::GenOptions, templates::TemplateFamily, AppState};
+use crate::api_errors::{ApiError, Json};
+use crate::engine::{stream, Capabilities, GenOptions, GenerationEvent, LoadedModel};
+use crate::tools::{ToolCall, GLOBAL_TOOL_REGISTRY};
+use crate::{templates::TemplateFamily, AppState};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub struct GenerateRequest {
@@ -27,12 +29,210 @@ pub struct GenerateRequest {
     pub max_tokens: Option<usize>,
     #[serde(default)]
     pub stream: Option<bool>,
+    /// GBNF grammar the output must follow
+    #[serde(default)]
+    pub grammar: Option<String>,
+    /// Text after the insertion point, for fill-in-the-middle
+    #[serde(default)]
+    pub suffix: Option<String>,
+    /// Base64-encoded images for multimodal models
+    #[serde(default)]
+    pub images: Option<Vec<String>>,
+    #[serde(default)]
+    pub logprobs: Option<bool>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+impl GenerateRequest {
+    pub(crate) fn features(&self) -> RequestedFeatures {
+        RequestedFeatures {
+            grammar: self.grammar.is_some(),
+            logprobs: self.logprobs.unwrap_or(false),
+            images: self.images.as_ref().is_some_and(|i| !i.is_empty()),
+            infill: self.suffix.is_some(),
+            max_tokens: self.max_tokens,
+        }
+    }
+
+    /// Sampling settings, with the grammar, suffix and images passed through
+    /// to engines that announced them
+    pub(crate) fn options(&self) -> GenOptions {
+        let mut opts = GenOptions::default();
+        if let Some(t) = self.temperature {
+            opts.temperature = t;
+        }
+        if let Some(p) = self.top_p {
+            opts.top_p = p;
+        }
+        if let Some(k) = self.top_k {
+            opts.top_k = k;
+        }
+        if let Some(m) = self.max_tokens {
+            opts.max_tokens = m;
+        }
+        if let Some(s) = self.stream {
+            opts.stream = s;
+        }
+        opts.grammar = self.grammar.clone();
+        opts.suffix = self.suffix.clone();
+        opts.images = self.images.clone().unwrap_or_default();
+        opts
+    }
+}
+
+/// Optional features a request asks for
+#[derive(Debug, Default)]
+pub struct RequestedFeatures {
+    pub grammar: bool,
+    pub logprobs: bool,
+    pub images: bool,
+    pub infill: bool,
+    /// `max_tokens` as sent by the client, before defaults apply
+    pub max_tokens: Option<usize>,
+}
+
+/// How long counting a prompt's tokens may take before it is left uncounted
+const TOKENIZE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reject a request the loaded model cannot serve before generating anything,
+/// returning the prompt's token count when the model can tell it.
+/// The model's capabilities are also recorded for `/v1/models`.
+pub async fn check_capabilities(
+    state: &AppState,
+    model: &str,
+    loaded: &Arc<dyn LoadedModel>,
+    prompt: &str,
+    wants: &RequestedFeatures,
+) -> Result<Option<usize>, ApiError> {
+    let caps = loaded.capabilities();
+    state.registry.record_capabilities(model, caps.clone());
+
+    let unsupported = [
+        (!caps.generate, "text generation"),
+        (
+            wants.grammar && !caps.grammar,
+            "grammars or structured output",
+        ),
+        (wants.logprobs && !caps.logprobs, "logprobs"),
+        (wants.images && !caps.images, "image inputs"),
+        (wants.infill && !caps.infill, "infill (suffix)"),
+    ];
+    if let Some((_, what)) = unsupported.iter().find(|(rejected, _)| *rejected) {
+        return Err(ApiError::InvalidRequest(format!(
+            "model '{}' does not support {}",
+            model, what
+        )));
+    }
+
+    let ctx = caps.context_length;
+    if let Some(max) = wants.max_tokens.filter(|&max| ctx > 0 && max >= ctx) {
+        return Err(ApiError::ContextOverflow(format!(
+            "max_tokens ({}) must be less than model '{}' context length of {} tokens",
+            max, model, ctx
+        )));
+    }
+    if !caps.tokenize {
+        return Ok(None);
+    }
+    // Backends that cannot count tokens leave the prompt to fail in generation
+    let Some(prompt_tokens) = count_tokens(loaded, prompt).await else {
+        return Ok(None);
+    };
+    match wants.max_tokens {
+        _ if ctx == 0 => Ok(Some(prompt_tokens)),
+        Some(max) if prompt_tokens + max > ctx => Err(ApiError::ContextOverflow(format!(
+            "prompt ({} tokens) plus max_tokens ({}) exceeds model '{}' context length of {} tokens",
+            prompt_tokens, max, model, ctx
+        ))),
+        _ if prompt_tokens >= ctx => Err(ApiError::ContextOverflow(format!(
+            "prompt ({} tokens) does not fit model '{}' context length of {} tokens",
+            prompt_tokens, model, ctx
+        ))),
+        _ => Ok(Some(prompt_tokens)),
+    }
+}
+
+/// Count `prompt`'s tokens on a blocking thread, as exec and isolated models
+/// ask their worker process and wait for its answer; `None` when the model
+/// fails to answer within `TOKENIZE_TIMEOUT`
+async fn count_tokens(loaded: &Arc<dyn LoadedModel>, prompt: &str) -> Option<usize> {
+    let loaded = Arc::clone(loaded);
+    let prompt = prompt.to_string();
+    let count = tokio::task::spawn_blocking(move || loaded.tokenize(&prompt, true));
+    match tokio::time::timeout(TOKENIZE_TIMEOUT, count).await {
+        Ok(Ok(Ok(tokens))) => Some(tokens.len()),
+        Ok(_) => None,
+        Err(_) => {
+            tracing::warn!(
+                "Counting prompt tokens took over {:?}; generating without the count",
+                TOKENIZE_TIMEOUT
+            );
+            None
+        }
+    }
+}
+
+/// Tokens a prompt of `prompt_tokens` leaves free in the model's context,
+/// for requests that generate until it is full; `None` when either is unknown
+pub fn context_room(caps: &Capabilities, prompt_tokens: Option<usize>) -> Option<usize> {
+    let prompt_tokens = prompt_tokens.filter(|_| caps.context_length > 0)?;
+    Some(caps.context_length.saturating_sub(prompt_tokens)).filter(|&room| room > 0)
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ChatMessage {
     pub role: String,
+    /// Null on assistant messages that only call tools
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ChatToolCall>>,
+    /// On `tool` messages, the call this is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// An OpenAI `tool_calls` entry
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ChatToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON-encoded, as the API sends it
+    pub arguments: String,
+}
+
+impl ChatToolCall {
+    /// A parsed call with a fresh id
+    pub fn new(call: ToolCall) -> Self {
+        Self {
+            id: format!("call_{}", uuid::Uuid::new_v4().simple()),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: call.name,
+                arguments: call.arguments.to_string(),
+            },
+        }
+    }
+
+    /// The call with its arguments decoded; arguments that are not JSON are
+    /// kept as a string
+    pub fn to_tool_call(&self) -> ToolCall {
+        ToolCall {
+            name: self.function.name.clone(),
+            arguments: serde_json::from_str(&self.function.arguments)
+                .unwrap_or_else(|_| self.function.arguments.clone().into()),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,12 +244,9 @@ pub async fn generate(
     State(state): State<Arc<AppState>>,
     Json(req): Json<GenerateRequest>,
 ) -> impl IntoResponse {
-    let Some(spec) = state.registry.to_spec(&req.model) else {
-        return axum::http::StatusCode::NOT_FOUND.into_response();
-    };
-    let engine = &state.engine;
-    let Ok(loaded) = engine.load(&spec).await else {
-        return axum::http::StatusCode::BAD_GATEWAY.into_response();
+    let (spec, loaded) = match crate::openai_compat::load_model(&state, &req.model).await {
+        Ok(loaded) => loaded,
+        Err(e) => return e.into_response(),
     };
 
     // Construct prompt
@@ -65,58 +262,52 @@ pub async fn generate(
             .collect::<Vec<_>>();
         fam.render(req.system.as_deref(), &pairs, None)
     } else {
-        req.prompt.unwrap_or_default()
+        req.prompt.clone().unwrap_or_default()
     };
-
-    let mut opts = GenOptions::default();
-    if let Some(t) = req.temperature {
-        opts.temperature = t;
-    }
-    if let Some(p) = req.top_p {
-        opts.top_p = p;
-    }
-    if let Some(k) = req.top_k {
-        opts.top_k = k;
-    }
-    if let Some(m) = req.max_tokens {
-        opts.max_tokens = m;
-    }
-    if let Some(s) = req.stream {
-        opts.stream = s;
+    if let Err(e) = check_capabilities(&state, &req.model, &loaded, &prompt, &req.features()).await
+    {
+        return e.into_response();
     }
 
+    let opts = req.options();
+    let events = loaded.generate_stream(prompt, opts.clone());
     if opts.stream {
-        // SSE streaming
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<String>();
-        let mut opts_clone = opts.clone();
-        opts_clone.stream = false; // internal generation collects tokens while we push per token
-        let prompt_clone = prompt.clone();
-        tokio::spawn(async move {
-            let tx_tokens = tx.clone();
-            let _ = loaded
-                .generate(
-                    &prompt_clone,
-                    opts_clone,
-                    Some(Box::new(move |tok| {
-                        let _ = tx_tokens.send(tok);
-                    })),
-                )
-                .await;
-            let _ = tx.send("[DONE]".into());
-        });
-        let stream = UnboundedReceiverStream::new(rx)
-            .map(|s| Ok::<Event, std::convert::Infallible>(Event::default().data(s)));
+        // SSE: each token's text, then [DONE]; a failed generation ends with an error event
+        let stream = events
+            .filter_map(|event| {
+                future::ready(match event {
+                    GenerationEvent::Token { text, .. } if !text.is_empty() => {
+                        Some(Event::default().data(text))
+                    }
+                    GenerationEvent::Done { .. } => Some(Event::default().data("[DONE]")),
+                    GenerationEvent::Error { message } => {
+                        let error = ApiError::GenerationFailed(message).into_json();
+                        Some(Event::default().event("error").data(error))
+                    }
+                    GenerationEvent::Overloaded { model } => {
+                        let error = ApiError::Overloaded(model).into_json();
+                        Some(Event::default().event("error").data(error))
+                    }
+                    _ => None,
+                })
+            })
+            .map(Ok::<Event, std::convert::Infallible>);
         Sse::new(stream).into_response()
     } else {
-        match loaded.generate(&prompt, opts, None).await {
-            Ok(full) => Json(GenerateResponse { response: full }).into_response(),
-            Err(_) => axum::http::StatusCode::BAD_GATEWAY.into_response(),
+        match stream::complete(events, |_| {}).await {
+            Ok(done) => Json(GenerateResponse {
+                response: done.text,
+            })
+            .into_response(),
+            Err(e) => ApiError::generation_failed(&e).into_response(),
         }
     }
 }
 
 // WebSocket endpoint: client connects to /ws/generate, sends a single JSON GenerateRequest text frame.
-// Server streams each token as a Text frame and finally sends a JSON {"done":true} frame.
+// Server streams each token as a Text frame and finally sends a JSON
+// {"done":true,"finish_reason":...,"stats":{...}} frame. Failures are sent as an
+// `ApiError` body ({"error":{"message","type","param","code"}}) before closing.
 pub async fn ws_generate(
     State(state): State<Arc<AppState>>,
     ws: WebSocketUpgrade,
@@ -137,25 +328,17 @@ async fn handle_ws_generate(state: Arc<AppState>, mut socket: WebSocket) {
     let req: GenerateRequest = match serde_json::from_str(&req_json) {
         Ok(r) => r,
         Err(e) => {
-            let _ = socket
-                .send(WsMessage::Text(format!(
-                    "{{\"error\":\"bad request: {e}\"}}"
-                )))
-                .await;
+            let error = ApiError::InvalidRequest(format!("bad request: {e}"));
+            let _ = socket.send(WsMessage::Text(error.into_json())).await;
             return;
         }
     };
-    let Some(spec) = state.registry.to_spec(&req.model) else {
-        let _ = socket
-            .send(WsMessage::Text("{\"error\":\"model not found\"}".into()))
-            .await;
-        return;
-    };
-    let Ok(loaded) = state.engine.load(&spec).await else {
-        let _ = socket
-            .send(WsMessage::Text("{\"error\":\"load failed\"}".into()))
-            .await;
-        return;
+    let (spec, loaded) = match crate::openai_compat::load_model(&state, &req.model).await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            let _ = socket.send(WsMessage::Text(e.into_json())).await;
+            return;
+        }
     };
 
     // Build prompt (reuse logic)
@@ -173,50 +356,49 @@ async fn handle_ws_generate(state: Arc<AppState>, mut socket: WebSocket) {
     } else {
         req.prompt.clone().unwrap_or_default()
     };
-
-    let mut opts = GenOptions::default();
-    if let Some(t) = req.temperature {
-        opts.temperature = t;
-    }
-    if let Some(p) = req.top_p {
-        opts.top_p = p;
-    }
-    if let Some(k) = req.top_k {
-        opts.top_k = k;
-    }
-    if let Some(m) = req.max_tokens {
-        opts.max_tokens = m;
+    if let Err(e) = check_capabilities(&state, &req.model, &loaded, &prompt, &req.features()).await
+    {
+        let _ = socket.send(WsMessage::Text(e.into_json())).await;
+        return;
     }
-    // Force internal non-stream; we push per-token ourselves
-    let mut internal = opts.clone();
-    internal.stream = false;
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
-    tokio::spawn({
-        let prompt = prompt.clone();
-        let tx_done = tx.clone();
-        async move {
-            let tx_tokens = tx.clone();
-            let _ = loaded
-                .generate(
-                    &prompt,
-                    internal,
-                    Some(Box::new(move |tok| {
-                        let _ = tx_tokens.send(tok);
-                    })),
-                )
-                .await;
-            let _ = tx_done.send("[DONE]".into());
-        }
-    });
-    while let Some(piece) = rx.recv().await {
-        if piece == "[DONE]" {
-            break;
-        }
-        if socket.send(WsMessage::Text(piece)).await.is_err() {
-            break;
+
+    let opts = GenOptions {
+        stream: true,
+        ..req.options()
+    };
+    let mut events = loaded.generate_stream(prompt, opts);
+    let mut last = serde_json::json!({ "done": true }).to_string();
+    while let Some(event) = events.next().await {
+        let frame = match event {
+            GenerationEvent::Token { text, .. } if !text.is_empty() => text,
+            GenerationEvent::Done {
+                finish_reason,
+                stats,
+            } => {
+                last = serde_json::json!({
+                    "done": true,
+                    "finish_reason": finish_reason.as_str(),
+                    "stats": stats,
+                })
+                .to_string();
+                break;
+            }
+            GenerationEvent::Error { message } => {
+                last = ApiError::GenerationFailed(message).into_json();
+                break;
+            }
+            GenerationEvent::Overloaded { model } => {
+                last = ApiError::Overloaded(model).into_json();
+                break;
+            }
+            _ => continue,
+        };
+        // Client went away; dropping the stream cancels backends that support it
+        if socket.send(WsMessage::Text(frame)).await.is_err() {
+            return;
         }
     }
-    let _ = socket.send(WsMessage::Text("{\"done\":true}".into())).await;
+    let _ = socket.send(WsMessage::Text(last)).await;
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -287,66 +469,71 @@ pub async fn discover_models(State(_state): State<Arc<AppState>>) -> impl IntoRe
             }))
             .into_response()
         }
-        Err(_e) => axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        Err(e) => ApiError::Internal(format!("Model discovery failed: {}", e)).into_response(),
     }
 }
 
 use axum::extract::Path;
 
 pub async fn load_model(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     Path(name): Path<String>,
 ) -> impl IntoResponse {
-    // Simple model loading endpoint - future enhancement
-    // Dynamic model loading: Model is loaded fresh for each request for isolation
-    // For now, return a placeholder response
-    Json(serde_json::json!({
-        "message": format!("Model {} load requested", name),
-        "status": "pending"
-    }))
+    // Loaded models stay in memory for their keep-alive
+    match crate::openai_compat::load_model(&state, &name).await {
+        Ok(_) => Json(serde_json::json!({
+            "message": format!("Model {} loaded", name),
+            "status": "loaded"
+        }))
+        .into_response(),
+        Err(e) => e.into_response(),
+    }
 }
 
 pub async fn unload_model(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     Path(name): Path<String>,
 ) -> impl IntoResponse {
-    // Simple model unloading endpoint - future enhancement
-    // Model unloading: Handled automatically via Rust's Drop trait when response completes
+    // Requests still generating with the model keep it until they finish
+    let status = if state.models.unload(&name) {
+        "unloaded"
+    } else {
+        "not_loaded"
+    };
     Json(serde_json::json!({
         "message": format!("Model {} unload requested", name),
-        "status": "pending"
+        "status": status
     }))
 }
 
 pub async fn model_status(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     Path(name): Path<String>,
 ) -> impl IntoResponse {
-    // Model status: Reports operational status with memory and load information
+    let loaded = state.models.loaded().iter().any(|model| model.name == name);
     Json(serde_json::json!({
         "model": name,
-        "status": "unknown",
-        "loaded": false
+        "status": if loaded { "loaded" } else { "unloaded" },
+        "loaded": loaded
     }))
 }
 
-#[allow(dead_code)]
 pub async fn list_tools(State(_state): State<Arc<AppState>>) -> impl IntoResponse {
     Json(serde_json::json!({
-        "tools": []
+        "tools": GLOBAL_TOOL_REGISTRY.list_tools()
     }))
 }
 
-#[allow(dead_code)]
 pub async fn execute_tool(
     State(_state): State<Arc<AppState>>,
     Path(name): Path<String>,
-    Json(_arguments): Json<serde_json::Value>,
+    Json(arguments): Json<serde_json::Value>,
 ) -> impl IntoResponse {
-    Json(serde_json::json!({
-        "error": format!("Tool {} not available", name)
-    }))
-    .into_response()
+    if GLOBAL_TOOL_REGISTRY.get_tool(&name).is_none() {
+        return ApiError::NotFound(format!("Tool '{}' not found", name)).into_response();
+    }
+    let call = ToolCall { name, arguments };
+    Json(crate::tools::execute_blocking(&GLOBAL_TOOL_REGISTRY, call).await).into_response()
 }
 
 #[allow(dead_code)]
@@ -428,7 +615,7 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let request = GenerateRequest {
             model: "test".to_string(),
@@ -440,6 +627,10 @@ mod tests {
             top_p: None,
             top_k: None,
             stream: Some(false),
+            grammar: None,
+            suffix: None,
+            images: None,
+            logprobs: None,
         };
 
         // Exercise handler code path (will fail gracefully due to no model)
@@ -454,7 +645,7 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         // Exercise list_models handler code path
         let _result = list_models(State(state)).await;
@@ -486,6 +677,10 @@ mod tests {
             top_p: Some(0.9),
             top_k: Some(40),
             stream: Some(false),
+            grammar: None,
+            suffix: None,
+            images: None,
+            logprobs: None,
         };
 
         assert_eq!(req.model, "test");
@@ -498,6 +693,7 @@ mod tests {
         let msg = ChatMessage {
             role: "user".to_string(),
             content: "Hello world".to_string(),
+            ..Default::default()
         };
 
         assert_eq!(msg.role, "user");
@@ -520,7 +716,7 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         // Exercise discover_models handler code path
         let _result = discover_models(State(state)).await;
@@ -535,7 +731,7 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         // Exercise load_model handler (lines 210-218)
         let _result = load_model(State(state), Path("test-model".to_string())).await;
@@ -550,7 +746,7 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         // Exercise unload_model handler (lines 220-227)
         let _result = unload_model(State(state), Path("test-model".to_string())).await;
@@ -565,7 +761,7 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         // Exercise model_status handler (lines 229-236)
         let _result = model_status(State(state), Path("test-model".to_string())).await;
@@ -579,11 +775,15 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
-
-        // Exercise list_tools handler (lines 239-243)
-        let _result = list_tools(State(state)).await;
-        assert!(true);
+        let state = Arc::new(AppState::new(engine, registry));
+
+        let response = list_tools(State(state)).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["tools"][0]["name"], "calculator");
+        assert!(body["tools"][0]["parameters"]["properties"]["expression"].is_object());
     }
 
     #[tokio::test]
@@ -594,14 +794,33 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let arguments = serde_json::json!({"test": "value"});
 
-        // Exercise execute_tool handler (lines 246-250)
-        let _result =
-            execute_tool(State(state), Path("test-tool".to_string()), Json(arguments)).await;
-        assert!(true);
+        let response = execute_tool(
+            State(Arc::clone(&state)),
+            Path("test-tool".to_string()),
+            Json(arguments),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), axum::http::StatusCode::NOT_FOUND);
+
+        let arguments = serde_json::json!({"expression": "2 + 3"});
+        let response = execute_tool(
+            State(state),
+            Path("calculator".to_string()),
+            Json(arguments),
+        )
+        .await
+        .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["success"], true);
+        assert_eq!(body["result"], 5.0);
     }
 
     #[tokio::test]
@@ -611,7 +830,7 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let request = serde_json::json!({"workflow": "test"});
 
@@ -633,10 +852,12 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let request = GenerateRequest {
             model: "stream-test".to_string(),
@@ -648,6 +869,10 @@ mod tests {
             top_p: Some(0.9),
             top_k: Some(40),
             stream: Some(true), // Enable streaming (line 54)
+            grammar: None,
+            suffix: None,
+            images: None,
+            logprobs: None,
         };
 
         // Exercise streaming path (lines 54-64)
@@ -668,10 +893,12 @@ mod tests {
             template: Some("llama3".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let request = GenerateRequest {
             model: "messages-test".to_string(),
@@ -680,10 +907,12 @@ mod tests {
                 ChatMessage {
                     role: "user".to_string(),
                     content: "Hello".to_string(),
+                    ..Default::default()
                 },
                 ChatMessage {
                     role: "assistant".to_string(),
                     content: "Hi there!".to_string(),
+                    ..Default::default()
                 },
             ]),
             system: Some("You are a helpful assistant".to_string()),
@@ -692,6 +921,10 @@ mod tests {
             top_p: None,
             top_k: None,
             stream: Some(false),
+            grammar: None,
+            suffix: None,
+            images: None,
+            logprobs: None,
         };
 
         // Exercise messages path with system prompt (lines 35-42)
@@ -785,10 +1018,12 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = Box::new(InferenceEngineAdapter::new());
-        let _state = Arc::new(AppState { engine, registry });
+        let _state = Arc::new(AppState::new(engine, registry));
 
         // We can't easily test the WebSocket upgrade without a real WebSocket connection,
         // but we can test that the handler function exists and accepts the right parameters
@@ -866,12 +1101,14 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         // The registry might have discovered models too
         // Exercise both paths in list_models handler (lines 155-175)
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let _response = list_models(State(state)).await;
         assert!(true);
@@ -886,6 +1123,7 @@ mod tests {
         let messages = Some(vec![ChatMessage {
             role: "user".to_string(),
             content: "Hello".to_string(),
+            ..Default::default()
         }]);
 
         let system = Some("System message");
@@ -943,18 +1181,27 @@ mod tests {
     #[test]
     fn test_json_error_responses() {
         // Test JSON error response formats used in WebSocket handler
-        let error_response = serde_json::json!({"error": "bad request: parse error"});
-        assert!(error_response["error"].is_string());
-        assert!(error_response["error"]
+        let frame =
+            |e: ApiError| -> serde_json::Value { serde_json::from_str(&e.into_json()).unwrap() };
+        let error_response = frame(ApiError::InvalidRequest(
+            "bad request: parse error".to_string(),
+        ));
+        assert_eq!(error_response["error"]["type"], "invalid_request_error");
+        assert!(error_response["error"]["message"]
             .as_str()
             .unwrap()
             .contains("bad request"));
 
-        let model_not_found = serde_json::json!({"error": "model not found"});
-        assert_eq!(model_not_found["error"], "model not found");
+        let model_not_found = frame(ApiError::ModelNotFound("phi3".to_string()));
+        assert_eq!(model_not_found["error"]["code"], "model_not_found");
+        assert_eq!(model_not_found["error"]["param"], "model");
 
-        let load_failed = serde_json::json!({"error": "load failed"});
-        assert_eq!(load_failed["error"], "load failed");
+        let load_failed = frame(ApiError::ModelLoadFailed {
+            model: "phi3".to_string(),
+            reason: "bad file".to_string(),
+        });
+        assert_eq!(load_failed["error"]["code"], "model_load_failed");
+        assert_eq!(load_failed["error"]["type"], "server_error");
 
         let done_message = serde_json::json!({"done": true});
         assert_eq!(done_message["done"], true);
@@ -967,7 +1214,7 @@ mod tests {
 
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         // Exercise discover_models handler success path (lines 187-200)
         let _response = discover_models(State(state)).await;
@@ -987,6 +1234,10 @@ mod tests {
             top_p: Some(0.9),
             top_k: Some(40),
             stream: Some(false),
+            grammar: None,
+            suffix: None,
+            images: None,
+            logprobs: None,
         };
 
         let debug_str = format!("{:?}", req);
@@ -996,6 +1247,7 @@ mod tests {
         let chat_msg = ChatMessage {
             role: "user".to_string(),
             content: "hello".to_string(),
+            ..Default::default()
         };
 
         let debug_str = format!("{:?}", chat_msg);
@@ -1096,4 +1348,112 @@ mod tests {
         assert!(request.messages.is_some());
         assert_eq!(request.messages.as_ref().unwrap().len(), 1);
     }
+
+    /// Counts whitespace-separated words as tokens
+    struct FixedModel(crate::engine::Capabilities);
+
+    #[async_trait::async_trait]
+    impl LoadedModel for FixedModel {
+        async fn generate(
+            &self,
+            _prompt: &str,
+            _opts: GenOptions,
+            _on_token: Option<Box<dyn FnMut(String) + Send>>,
+        ) -> anyhow::Result<String> {
+            Ok(String::new())
+        }
+
+        fn tokenize(&self, text: &str, _add_special: bool) -> anyhow::Result<Vec<u32>> {
+            Ok(text.split_whitespace().map(|_| 0).collect())
+        }
+
+        fn capabilities(&self) -> crate::engine::Capabilities {
+            self.0.clone()
+        }
+    }
+
+    async fn check(
+        model: &FixedModel,
+        prompt: &str,
+        wants: RequestedFeatures,
+    ) -> Result<Option<usize>, String> {
+        let state = AppState::new(
+            Box::new(crate::engine::adapter::InferenceEngineAdapter::new()),
+            crate::model_registry::Registry::new(),
+        );
+        let loaded: Arc<dyn LoadedModel> = Arc::new(FixedModel(model.0.clone()));
+        let result = check_capabilities(&state, "m", &loaded, prompt, &wants).await;
+        assert_eq!(state.registry.capabilities("m"), Some(model.0.clone()));
+        result.map_err(|e| match e {
+            ApiError::InvalidRequest(msg) | ApiError::ContextOverflow(msg) => msg,
+            other => panic!("expected InvalidRequest, got {:?}", other),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_features_are_rejected_up_front() {
+        let model = FixedModel(crate::engine::Capabilities::text(0));
+        assert!(check(&model, "hi", RequestedFeatures::default())
+            .await
+            .is_ok());
+
+        let err = check(
+            &model,
+            "hi",
+            RequestedFeatures {
+                logprobs: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err, "model 'm' does not support logprobs");
+
+        let request: GenerateRequest =
+            serde_json::from_str(r#"{"model": "m", "prompt": "def f(", "suffix": "return x"}"#)
+                .unwrap();
+        let err = check(&model, "def f(", request.features())
+            .await
+            .unwrap_err();
+        assert!(err.contains("infill"));
+
+        let weights_only = FixedModel(crate::engine::Capabilities::default());
+        let err = check(&weights_only, "hi", RequestedFeatures::default())
+            .await
+            .unwrap_err();
+        assert!(err.contains("text generation"));
+    }
+
+    #[tokio::test]
+    async fn test_context_length_is_checked_before_generating() {
+        let model = FixedModel(crate::engine::Capabilities {
+            tokenize: true,
+            ..crate::engine::Capabilities::text(8)
+        });
+        let max_tokens = |n| RequestedFeatures {
+            max_tokens: Some(n),
+            ..Default::default()
+        };
+        assert_eq!(
+            check(&model, "one two three", max_tokens(5)).await,
+            Ok(Some(3))
+        );
+        assert!(check(&model, "one two three", max_tokens(6))
+            .await
+            .unwrap_err()
+            .contains("prompt (3 tokens) plus max_tokens (6)"));
+        assert!(check(&model, "", max_tokens(8))
+            .await
+            .unwrap_err()
+            .contains("max_tokens (8) must be less than"));
+        // The server default for max_tokens never rejects a prompt that fits
+        assert!(check(&model, "a b c d e f g", RequestedFeatures::default())
+            .await
+            .is_ok());
+        assert!(
+            check(&model, "a b c d e f g h", RequestedFeatures::default())
+                .await
+                .is_err()
+        );
+    }
 }
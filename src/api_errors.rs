@@ -1,39 +1,191 @@
-// Improved API error handling
-use axum::{http::StatusCode, response::Json};
-use serde::{Deserialize, Serialize};
-
-#[derive(Serialize, Deserialize)]
+// API errors
+// Every handler reports failures as an `ApiError`, answered with OpenAI's
+// `{"error": {"message", "type", "param", "code"}}` body so clients of any
+// compatibility layer can tell failures apart by `code`.
+use async_trait::async_trait;
+use axum::{
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorResponse {
-    pub error: String,
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub message: String,
+    /// `invalid_request_error` for problems with the request, else `server_error`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The request field at fault, when there is one
+    pub param: Option<String>,
+    pub code: Option<String>,
 }
 
 #[derive(Debug)]
 pub enum ApiError {
     ModelNotFound(String),
-    GenerationFailed(String),
+    /// The model exists but its backend could not load it
+    ModelLoadFailed {
+        model: String,
+        reason: String,
+    },
+    /// The prompt, plus the tokens asked for, does not fit the model's context
+    ContextOverflow(String),
+    /// A parameter is malformed, out of range or unsupported by the model
     InvalidRequest(String),
+    /// The backend the model needs is not compiled into this build
+    BackendUnavailable(String),
+    /// The model already has as many requests queued as it accepts
+    Overloaded(String),
+    /// The generation failed after the model was loaded
+    GenerationFailed(String),
+    /// A resource other than a model, such as a tool or a stored response
+    NotFound(String),
+    Internal(String),
 }
 
-impl From<ApiError> for (StatusCode, Json<ErrorResponse>) {
-    fn from(err: ApiError) -> Self {
-        match err {
+impl ApiError {
+    /// The error for a model that failed to load: `BackendUnavailable` when
+    /// its backend is missing from this build
+    pub fn load_failed(model: &str, error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<crate::error::ShimmyError>() {
+            Some(crate::error::ShimmyError::BackendNotAvailable { backend }) => {
+                ApiError::BackendUnavailable(backend.clone())
+            }
+            _ => ApiError::ModelLoadFailed {
+                model: model.to_string(),
+                reason: error.to_string(),
+            },
+        }
+    }
+
+    /// The error for a generation that failed: `Overloaded` when the model
+    /// turned it away because its queue was full
+    pub fn generation_failed(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<crate::error::ShimmyError>() {
+            Some(crate::error::ShimmyError::Overloaded { model }) => {
+                ApiError::Overloaded(model.clone())
+            }
+            _ => ApiError::GenerationFailed(error.to_string()),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::ModelNotFound(_) | ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::ContextOverflow(_) | ApiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::ModelLoadFailed { .. } | ApiError::GenerationFailed(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            ApiError::BackendUnavailable(_) => StatusCode::NOT_IMPLEMENTED,
+            ApiError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The body to send, also used for WebSocket and SSE error frames
+    pub fn into_body(self) -> ErrorResponse {
+        let client = self.status().is_client_error();
+        let (message, param, code) = match self {
             ApiError::ModelNotFound(model) => (
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: format!("Model '{}' not found", model),
-                }),
+                format!("Model '{}' not found", model),
+                Some("model"),
+                "model_not_found",
+            ),
+            ApiError::ModelLoadFailed { model, reason } => (
+                format!("Failed to load model '{}': {}", model, reason),
+                Some("model"),
+                "model_load_failed",
+            ),
+            ApiError::ContextOverflow(msg) => (msg, None, "context_length_exceeded"),
+            ApiError::InvalidRequest(msg) => (msg, None, "invalid_parameter"),
+            ApiError::BackendUnavailable(backend) => (
+                format!("Backend '{}' is not available in this build", backend),
+                None,
+                "backend_unavailable",
+            ),
+            ApiError::Overloaded(model) => (
+                format!(
+                    "Model '{}' has too many requests queued; retry later",
+                    model
+                ),
+                Some("model"),
+                "overloaded",
             ),
             ApiError::GenerationFailed(msg) => (
-                StatusCode::BAD_GATEWAY,
-                Json(ErrorResponse {
-                    error: format!("Generation failed: {}", msg),
-                }),
+                format!("Generation failed: {}", msg),
+                None,
+                "generation_failed",
             ),
-            ApiError::InvalidRequest(msg) => {
-                (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: msg }))
-            }
+            ApiError::NotFound(msg) => (msg, None, "not_found"),
+            ApiError::Internal(msg) => (msg, None, "internal_error"),
+        };
+        ErrorResponse {
+            error: ErrorBody {
+                message,
+                kind: if client {
+                    "invalid_request_error"
+                } else {
+                    "server_error"
+                }
+                .to_string(),
+                param: param.map(str::to_string),
+                code: Some(code.to_string()),
+            },
         }
     }
+
+    /// The body as JSON text, for error frames
+    pub fn into_json(self) -> String {
+        serde_json::to_string(&self.into_body()).unwrap_or_default()
+    }
+}
+
+impl From<ApiError> for (StatusCode, axum::Json<ErrorResponse>) {
+    fn from(err: ApiError) -> Self {
+        (err.status(), axum::Json(err.into_body()))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        <(StatusCode, axum::Json<ErrorResponse>)>::from(self).into_response()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::InvalidRequest(rejection.body_text())
+    }
+}
+
+/// `axum::Json`, except that a body which is not the expected JSON is
+/// rejected with an `invalid_parameter` error instead of plain text
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(req, state).await?;
+        Ok(Json(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
 }
 
 #[cfg(test)]
@@ -42,26 +194,34 @@ mod tests {
     use axum::http::StatusCode;
     use axum::Json;
 
+    fn body(message: &str) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorBody {
+                message: message.to_string(),
+                kind: "invalid_request_error".to_string(),
+                param: None,
+                code: None,
+            },
+        }
+    }
+
     #[test]
     fn test_error_response_creation() {
-        let response = ErrorResponse {
-            error: "Test error message".to_string(),
-        };
+        let response = body("Test error message");
 
-        assert_eq!(response.error, "Test error message");
+        assert_eq!(response.error.message, "Test error message");
     }
 
     #[test]
     fn test_error_response_serialization() {
-        let response = ErrorResponse {
-            error: "Serialization test".to_string(),
-        };
+        let response = body("Serialization test");
 
         let json = serde_json::to_string(&response).unwrap();
         assert!(json.contains("Serialization test"));
+        assert!(json.contains(r#""type":"invalid_request_error""#));
 
         let parsed: ErrorResponse = serde_json::from_str(&json).unwrap();
-        assert_eq!(parsed.error, "Serialization test");
+        assert_eq!(parsed.error.message, "Serialization test");
     }
 
     #[test]
@@ -70,7 +230,10 @@ mod tests {
         let (status, json_response) = <(StatusCode, Json<ErrorResponse>)>::from(error);
 
         assert_eq!(status, StatusCode::NOT_FOUND);
-        assert_eq!(json_response.0.error, "Model 'test-model' not found");
+        assert_eq!(
+            json_response.0.error.message,
+            "Model 'test-model' not found"
+        );
     }
 
     #[test]
@@ -79,7 +242,10 @@ mod tests {
         let (status, json_response) = <(StatusCode, Json<ErrorResponse>)>::from(error);
 
         assert_eq!(status, StatusCode::BAD_GATEWAY);
-        assert_eq!(json_response.0.error, "Generation failed: Out of memory");
+        assert_eq!(
+            json_response.0.error.message,
+            "Generation failed: Out of memory"
+        );
     }
 
     #[test]
@@ -88,7 +254,7 @@ mod tests {
         let (status, json_response) = <(StatusCode, Json<ErrorResponse>)>::from(error);
 
         assert_eq!(status, StatusCode::BAD_REQUEST);
-        assert_eq!(json_response.0.error, "Missing required field");
+        assert_eq!(json_response.0.error.message, "Missing required field");
     }
 
     #[test]
@@ -97,7 +263,7 @@ mod tests {
         let (status, json_response) = <(StatusCode, Json<ErrorResponse>)>::from(error);
 
         assert_eq!(status, StatusCode::NOT_FOUND);
-        assert_eq!(json_response.0.error, "Model '' not found");
+        assert_eq!(json_response.0.error.message, "Model '' not found");
     }
 
     #[test]
@@ -106,7 +272,7 @@ mod tests {
         let (status, json_response) = <(StatusCode, Json<ErrorResponse>)>::from(error);
 
         assert_eq!(status, StatusCode::NOT_FOUND);
-        assert!(json_response.0.error.contains("model/with/slashes"));
+        assert!(json_response.0.error.message.contains("model/with/slashes"));
     }
 
     #[test]
@@ -115,7 +281,7 @@ mod tests {
         let (status, json_response) = <(StatusCode, Json<ErrorResponse>)>::from(error);
 
         assert_eq!(status, StatusCode::BAD_GATEWAY);
-        assert!(json_response.0.error.contains("éñ¡"));
+        assert!(json_response.0.error.message.contains("éñ¡"));
     }
 
     #[test]
@@ -125,7 +291,7 @@ mod tests {
         let (status, json_response) = <(StatusCode, Json<ErrorResponse>)>::from(error);
 
         assert_eq!(status, StatusCode::BAD_REQUEST);
-        assert_eq!(json_response.0.error, long_message);
+        assert_eq!(json_response.0.error.message, long_message);
     }
 
     #[test]
@@ -153,9 +319,11 @@ mod tests {
 
         // Test that the structure can be serialized correctly
         let serialized = serde_json::to_value(&json_response.0).unwrap();
-        assert!(serialized.is_object());
-        assert!(serialized["error"].is_string());
-        assert_eq!(serialized["error"], "Model 'my-model' not found");
+        assert!(serialized["error"].is_object());
+        assert_eq!(serialized["error"]["message"], "Model 'my-model' not found");
+        assert_eq!(serialized["error"]["type"], "invalid_request_error");
+        assert_eq!(serialized["error"]["param"], "model");
+        assert_eq!(serialized["error"]["code"], "model_not_found");
     }
 
     #[test]
@@ -181,15 +349,133 @@ mod tests {
 
     #[test]
     fn test_error_response_fields() {
-        let response = ErrorResponse {
-            error: "Test".to_string(),
-        };
+        let response = body("Test");
 
         // Verify the struct has only the expected fields
         let json = serde_json::to_value(&response).unwrap();
         let obj = json.as_object().unwrap();
         assert_eq!(obj.len(), 1);
         assert!(obj.contains_key("error"));
+        let error = json["error"].as_object().unwrap();
+        let mut fields: Vec<_> = error.keys().map(String::as_str).collect();
+        fields.sort();
+        assert_eq!(fields, ["code", "message", "param", "type"]);
+    }
+
+    #[test]
+    fn test_each_failure_has_its_own_code() {
+        let errors = [
+            (ApiError::ModelNotFound("m".into()), 404, "model_not_found"),
+            (
+                ApiError::ModelLoadFailed {
+                    model: "m".into(),
+                    reason: "bad file".into(),
+                },
+                502,
+                "model_load_failed",
+            ),
+            (
+                ApiError::ContextOverflow("too long".into()),
+                400,
+                "context_length_exceeded",
+            ),
+            (
+                ApiError::InvalidRequest("n".into()),
+                400,
+                "invalid_parameter",
+            ),
+            (
+                ApiError::BackendUnavailable("llama".into()),
+                501,
+                "backend_unavailable",
+            ),
+            (
+                ApiError::GenerationFailed("x".into()),
+                502,
+                "generation_failed",
+            ),
+            (ApiError::Overloaded("m".into()), 503, "overloaded"),
+            (ApiError::NotFound("gone".into()), 404, "not_found"),
+        ];
+        for (error, status, code) in errors {
+            let (actual, Json(body)) = <(StatusCode, Json<ErrorResponse>)>::from(error);
+            assert_eq!(actual.as_u16(), status);
+            assert_eq!(body.error.code.as_deref(), Some(code));
+            let kind = if status < 500 {
+                "invalid_request_error"
+            } else {
+                "server_error"
+            };
+            assert_eq!(body.error.kind, kind);
+        }
+    }
+
+    #[test]
+    fn test_load_errors_name_missing_backends() {
+        let missing = anyhow::Error::new(crate::error::ShimmyError::BackendNotAvailable {
+            backend: "mlx".to_string(),
+        });
+        assert!(matches!(
+            ApiError::load_failed("m", &missing),
+            ApiError::BackendUnavailable(backend) if backend == "mlx"
+        ));
+        let failed = ApiError::load_failed("m", &anyhow::anyhow!("truncated file"));
+        let body = failed.into_body();
+        assert_eq!(
+            body.error.message,
+            "Failed to load model 'm': truncated file"
+        );
+        assert_eq!(body.error.param.as_deref(), Some("model"));
+    }
+
+    #[test]
+    fn test_full_queues_are_overloaded_errors() {
+        let full = anyhow::Error::new(crate::error::ShimmyError::Overloaded {
+            model: "m".to_string(),
+        });
+        let error = ApiError::generation_failed(&full);
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = error.into_body();
+        assert_eq!(body.error.code.as_deref(), Some("overloaded"));
+        assert_eq!(body.error.kind, "server_error");
+        assert!(matches!(
+            ApiError::generation_failed(&anyhow::anyhow!("oom")),
+            ApiError::GenerationFailed(message) if message == "oom"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_json_bodies_are_invalid_requests() {
+        use crate::openai_compat::ChatCompletionRequest;
+        use axum::body::Body;
+        use axum::http::header;
+
+        let request = |body: &'static str| {
+            Request::builder()
+                .method("POST")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        for bad in [r#"{"model": "m", "messages": ["#, r#"{"messages": []}"#] {
+            let rejected = super::Json::<ChatCompletionRequest>::from_request(request(bad), &())
+                .await
+                .err()
+                .unwrap();
+            let response = rejected.into_response();
+            let (status, body) = crate::test_utils::json_body(response).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["error"]["code"], "invalid_parameter");
+            assert_eq!(body["error"]["type"], "invalid_request_error");
+        }
+
+        let good = r#"{"model": "m", "messages": []}"#;
+        let super::Json(parsed) =
+            super::Json::<ChatCompletionRequest>::from_request(request(good), &())
+                .await
+                .ok()
+                .unwrap();
+        assert_eq!(parsed.model, "m");
     }
 
     #[test]
@@ -1,8 +1,12 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+use crate::engine::safetensors_native::SafeTensorsCheckpoint;
+use crate::engine::shards::ShardName;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscoveredModel {
     pub name: String,
@@ -180,6 +184,14 @@ impl ModelAutoDiscovery {
             Err(_) => return Ok(Vec::new()), // Skip directories we can't read
         };
 
+        // A sharded SafeTensors checkpoint is one model, not one per shard
+        let checkpoint = SafeTensorsCheckpoint::sharded_in_dir(dir);
+        if let Some(checkpoint) = &checkpoint {
+            models.push(self.analyze_checkpoint(checkpoint));
+        }
+        // Split GGUFs already reported, keyed by their first split's file name
+        let mut seen_splits = HashSet::new();
+
         for entry in entries {
             let entry = match entry {
                 Ok(entry) => entry,
@@ -220,6 +232,12 @@ impl ModelAutoDiscovery {
                 }
                 // Recursively scan subdirectories with depth tracking
                 models.extend(self.scan_directory_with_depth(&path, depth + 1)?);
+            } else if checkpoint.as_ref().is_some_and(|c| c.contains_file(&path)) {
+                continue;
+            } else if let Some(split) = ShardName::from_path(&path, "gguf") {
+                if seen_splits.insert(split.sibling(1)) {
+                    models.push(self.analyze_split_gguf(dir, &split));
+                }
             } else if self.is_model_file(&path) {
                 if let Ok(model) = self.analyze_model_file(&path) {
                     models.push(model);
@@ -319,7 +337,16 @@ impl ModelAutoDiscovery {
             .unwrap_or("unknown")
             .to_string();
 
-        let (model_type, parameter_count, quantization) = self.parse_filename(&filename);
+        let (model_type, mut parameter_count, quantization) = self.parse_filename(&filename);
+
+        // SafeTensors headers give the real count when the filename doesn't
+        if parameter_count.is_none()
+            && path.extension().and_then(|e| e.to_str()) == Some("safetensors")
+        {
+            parameter_count = SafeTensorsCheckpoint::open(path)
+                .ok()
+                .map(|c| format_parameter_count(c.parameter_count()));
+        }
 
         // Generate a clean model name
         let name = self.generate_model_name(&filename);
@@ -338,6 +365,46 @@ impl ModelAutoDiscovery {
         })
     }
 
+    /// One entry for every split of a GGUF model, pointing at the first split
+    /// (which is what llama.cpp expects; missing splits are reported on load)
+    fn analyze_split_gguf(&self, dir: &Path, split: &ShardName) -> DiscoveredModel {
+        let first = dir.join(split.sibling(1));
+        let size_bytes = split
+            .all()
+            .iter()
+            .filter_map(|name| fs::metadata(dir.join(name)).ok())
+            .map(|m| m.len())
+            .sum();
+        let filename = format!("{}.gguf", split.prefix);
+        let (model_type, parameter_count, quantization) = self.parse_filename(&filename);
+
+        DiscoveredModel {
+            name: self.generate_model_name(&filename),
+            lora_path: self.find_lora_for_model(&first),
+            path: first,
+            size_bytes,
+            model_type,
+            parameter_count,
+            quantization,
+        }
+    }
+
+    /// One entry for a whole sharded checkpoint directory
+    fn analyze_checkpoint(&self, checkpoint: &SafeTensorsCheckpoint) -> DiscoveredModel {
+        let dir_name = checkpoint_name(checkpoint.dir());
+        let (model_type, _, quantization) = self.parse_filename(&dir_name);
+
+        DiscoveredModel {
+            name: dir_name.replace(['_', ' '], "-").to_lowercase(),
+            path: checkpoint.dir().to_path_buf(),
+            lora_path: None,
+            size_bytes: checkpoint.total_size(),
+            model_type,
+            parameter_count: Some(format_parameter_count(checkpoint.parameter_count())),
+            quantization,
+        }
+    }
+
     fn parse_filename(&self, filename: &str) -> (String, Option<String>, Option<String>) {
         let lower = filename.to_lowercase();
 
@@ -506,6 +573,40 @@ impl ModelAutoDiscovery {
     }
 }
 
+/// Directory name of a checkpoint, looking through the HF hub cache layout
+/// (`models--Org--Name/snapshots/<revision>`) to the repository name
+fn checkpoint_name(dir: &Path) -> String {
+    let file_name = |p: &Path| {
+        p.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string()
+    };
+    let parent = dir.parent();
+    if parent.map(file_name).as_deref() == Some("snapshots") {
+        if let Some(repo) = parent.and_then(Path::parent).map(file_name) {
+            if let Some(name) = repo.strip_prefix("models--") {
+                return name.rsplit("--").next().unwrap_or(name).to_string();
+            }
+        }
+    }
+    file_name(dir)
+}
+
+/// Human-readable parameter count, e.g. `7.2B` or `494M`
+fn format_parameter_count(count: u64) -> String {
+    let scaled = |value: f64, unit: &str| {
+        let text = format!("{:.1}", value);
+        format!("{}{}", text.strip_suffix(".0").unwrap_or(&text), unit)
+    };
+    match count {
+        n if n >= 1_000_000_000 => scaled(n as f64 / 1e9, "B"),
+        n if n >= 1_000_000 => format!("{}M", n / 1_000_000),
+        n if n >= 1_000 => format!("{}K", n / 1_000),
+        n => n.to_string(),
+    }
+}
+
 impl Default for ModelAutoDiscovery {
     fn default() -> Self {
         Self::new()
@@ -537,6 +638,83 @@ mod tests {
         assert!(discovery.search_paths.len() >= 1);
     }
 
+    #[test]
+    fn test_sharded_safetensors_is_one_model() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path().join("models");
+        let checkpoint = root.join("Qwen2-Tiny");
+        fs::create_dir_all(&checkpoint).unwrap();
+
+        let weights = vec![0u8; 1000 * 4];
+        let mut weight_map = serde_json::Map::new();
+        for i in 1..=3 {
+            let file = format!("model-{:05}-of-00003.safetensors", i);
+            let name = format!("layers.{}.weight", i);
+            let view =
+                safetensors::tensor::TensorView::new(safetensors::Dtype::F32, vec![1000], &weights)
+                    .unwrap();
+            let bytes = safetensors::serialize([(name.clone(), view)], &None).unwrap();
+            fs::write(checkpoint.join(&file), bytes).unwrap();
+            weight_map.insert(name, file.into());
+        }
+        let index = serde_json::json!({ "weight_map": weight_map });
+        fs::write(
+            checkpoint.join("model.safetensors.index.json"),
+            index.to_string(),
+        )
+        .unwrap();
+
+        let discovery = ModelAutoDiscovery::new();
+        let models = discovery.scan_directory(&root).unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "qwen2-tiny");
+        assert_eq!(models[0].path, checkpoint);
+        assert_eq!(models[0].parameter_count.as_deref(), Some("3K"));
+        assert!(models[0].size_bytes > 3 * 4000);
+    }
+
+    #[test]
+    fn test_split_gguf_is_one_model() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path().join("models");
+        fs::create_dir_all(&root).unwrap();
+        for i in 1..=3 {
+            let name = format!("llama-70b-q4_k_m-{:05}-of-00003.gguf", i);
+            fs::write(root.join(name), vec![0u8; 100 * i]).unwrap();
+        }
+        fs::write(root.join("phi-2.gguf"), b"GGUF").unwrap();
+
+        let discovery = ModelAutoDiscovery::new();
+        let mut models = discovery.scan_directory(&root).unwrap();
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(models.len(), 2);
+
+        let split = &models[0];
+        assert_eq!(split.name, "llama-70b-q4-k-m");
+        assert_eq!(
+            split.path,
+            root.join("llama-70b-q4_k_m-00001-of-00003.gguf")
+        );
+        assert_eq!(split.size_bytes, 600);
+        assert_eq!(split.parameter_count.as_deref(), Some("70B"));
+        assert_eq!(split.quantization.as_deref(), Some("Q4_K_M"));
+        assert_eq!(models[1].name, "phi-2");
+    }
+
+    #[test]
+    fn test_checkpoint_name_and_parameter_format() {
+        assert_eq!(
+            checkpoint_name(Path::new(
+                "/hub/models--Qwen--Qwen2.5-7B-Instruct/snapshots/0123abcd"
+            )),
+            "Qwen2.5-7B-Instruct"
+        );
+        assert_eq!(checkpoint_name(Path::new("/models/phi-2")), "phi-2");
+        assert_eq!(format_parameter_count(7_241_732_096), "7.2B");
+        assert_eq!(format_parameter_count(8_030_000_000), "8B");
+        assert_eq!(format_parameter_count(494_032_768), "494M");
+    }
+
     #[test]
     fn test_filename_parsing() {
         let discovery = ModelAutoDiscovery::new();
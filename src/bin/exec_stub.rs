@@ -0,0 +1,150 @@
+// Stub engine for the exec backend's JSON-lines protocol (docs/EXEC_PROTOCOL.md)
+// Used by tests/exec_backend.rs. Deterministic stand-ins for a real model:
+//   generate   - echoes the prompt upper-cased, one character per token
+//                "forever" streams "." until cancelled, "crash" exits,
+//                "cancelled?" reports how many generations were cancelled,
+//                "adapter?" reports the adapter given to `load`,
+//                "pid?" reports the engine's process id
+//   tokenize   - UTF-8 bytes, preceded by 256 when add_special is set
+//   detokenize - the bytes back, skipping ids above 255
+//   embed      - [byte length, word count]
+
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn emit(event: Value) {
+    let mut out = std::io::stdout().lock();
+    let _ = writeln!(out, "{}", event);
+    let _ = out.flush();
+}
+
+fn main() {
+    let cancelled: Arc<Mutex<HashSet<u64>>> = Arc::default();
+    let (requests, incoming) = mpsc::channel::<Value>();
+    let reader_cancelled = Arc::clone(&cancelled);
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            let Ok(msg) = serde_json::from_str::<Value>(&line) else {
+                emit(json!({"id": 0, "event": "error", "message": "invalid request"}));
+                continue;
+            };
+            if msg["op"] == "cancel" {
+                let id = msg["id"].as_u64().unwrap_or(0);
+                reader_cancelled.lock().unwrap().insert(id);
+            } else if requests.send(msg).is_err() {
+                break;
+            }
+        }
+        // stdin closed: shimmy went away
+        std::process::exit(0);
+    });
+
+    let mut adapter: Option<String> = None;
+    let mut cancelled_count = 0;
+    for msg in incoming {
+        let id = msg["id"].as_u64().unwrap_or(0);
+        let text = msg["text"].as_str().unwrap_or_default();
+        match msg["op"].as_str().unwrap_or_default() {
+            "load" => {
+                if msg["model"] == "missing" {
+                    emit(json!({"id": id, "event": "error", "message": "no such model"}));
+                } else {
+                    adapter = msg["adapter"].as_str().map(String::from);
+                    let capabilities = json!({
+                        "generate": true,
+                        "tokenize": true,
+                        "embeddings": true,
+                        "context_length": msg["ctx_len"],
+                    });
+                    emit(json!({"id": id, "event": "ready", "capabilities": capabilities}));
+                }
+            }
+            "health" => emit(json!({"id": id, "event": "ready"})),
+            "generate" => {
+                let prompt = msg["prompt"].as_str().unwrap_or_default();
+                let max = msg["max_new_tokens"].as_u64().unwrap_or(256) as usize;
+                match prompt {
+                    "crash" => {
+                        eprintln!("stub engine crashed on purpose");
+                        std::process::exit(3);
+                    }
+                    "forever" => {
+                        while !cancelled.lock().unwrap().contains(&id) {
+                            emit(json!({"id": id, "event": "token", "text": "."}));
+                            thread::sleep(Duration::from_millis(5));
+                        }
+                        cancelled_count += 1;
+                        emit(
+                            json!({"id": id, "event": "done", "text": "", "finish_reason": "cancelled"}),
+                        );
+                    }
+                    "cancelled?" => {
+                        let text = cancelled_count.to_string();
+                        emit(
+                            json!({"id": id, "event": "done", "text": text, "finish_reason": "stop"}),
+                        );
+                    }
+                    "adapter?" => {
+                        let text = adapter.clone().unwrap_or_else(|| "none".to_string());
+                        emit(
+                            json!({"id": id, "event": "done", "text": text, "finish_reason": "stop"}),
+                        );
+                    }
+                    "pid?" => {
+                        let text = std::process::id().to_string();
+                        emit(json!({"id": id, "event": "token", "text": text}));
+                        emit(
+                            json!({"id": id, "event": "done", "text": text, "finish_reason": "stop"}),
+                        );
+                    }
+                    _ => {
+                        let out: String = prompt.to_uppercase().chars().take(max).collect();
+                        for ch in out.chars() {
+                            emit(json!({"id": id, "event": "token", "text": ch.to_string()}));
+                        }
+                        let reason = if out.chars().count() == max {
+                            "length"
+                        } else {
+                            "stop"
+                        };
+                        // Counted the way `tokenize` counts: one per byte plus BOS
+                        emit(json!({"id": id, "event": "done", "text": out,
+                            "finish_reason": reason, "prompt_tokens": prompt.len() + 1,
+                            "completion_tokens": out.chars().count()}));
+                    }
+                }
+            }
+            "tokenize" => {
+                let mut tokens: Vec<u32> = Vec::new();
+                if msg["add_special"] == true {
+                    tokens.push(256);
+                }
+                tokens.extend(text.bytes().map(u32::from));
+                emit(json!({"id": id, "event": "tokens", "tokens": tokens}));
+            }
+            "detokenize" => {
+                let bytes: Vec<u8> = msg["tokens"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_u64)
+                    .filter_map(|t| u8::try_from(t).ok())
+                    .collect();
+                let text = String::from_utf8_lossy(&bytes);
+                emit(json!({"id": id, "event": "text", "text": text}));
+            }
+            "embed" => {
+                let embedding = [text.len() as f32, text.split_whitespace().count() as f32];
+                emit(json!({"id": id, "event": "embedding", "embedding": embedding}));
+            }
+            op => {
+                emit(json!({"id": id, "event": "error", "message": format!("unknown op {:?}", op)}))
+            }
+        }
+    }
+}
@@ -24,6 +24,8 @@ async fn main() -> Result<()> {
         template: None,
         ctx_len: 2048,
         n_threads: None,
+        backend: None,
+        command: None,
     };
 
     let engine = SafeTensorsEngine::new();
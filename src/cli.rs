@@ -1,5 +1,6 @@
 use crate::port_manager::GLOBAL_PORT_ALLOCATOR;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -18,6 +19,10 @@ pub struct Cli {
         help = "Additional model directories to search (e.g., --model-dirs 'D:\\models;E:\\ollama\\models')"
     )]
     pub model_dirs: Option<String>,
+
+    /// JSON file of model entries to register (see docs/CONFIGURATION.md)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -26,6 +31,9 @@ pub enum Command {
     Serve {
         #[arg(long, default_value = "auto")]
         bind: String,
+        /// Run each model in its own worker process that is restarted if it crashes
+        #[arg(long)]
+        isolate: bool,
     },
     /// List registered and auto-discovered models
     List,
@@ -47,12 +55,22 @@ pub enum Command {
         #[arg(long, default_value_t = 64)]
         max_tokens: usize,
     },
+    /// Serve one model over stdin/stdout for `serve --isolate` (internal)
+    #[command(hide = true)]
+    Worker {
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        backend: Option<String>,
+        #[arg(long)]
+        threads: Option<i32>,
+    },
 }
 
 impl Command {
     pub fn get_bind_address(&self) -> String {
         match self {
-            Command::Serve { bind } => {
+            Command::Serve { bind, .. } => {
                 if bind == "auto" {
                     match GLOBAL_PORT_ALLOCATOR.find_available_port("shimmy-server") {
                         Ok(port) => format!("127.0.0.1:{}", port),
@@ -79,7 +97,7 @@ mod tests {
     fn test_cli_serve_command_default() {
         let cli = Cli::try_parse_from(&["shimmy", "serve"]).unwrap();
         match cli.cmd {
-            Command::Serve { bind } => assert_eq!(bind, "auto"),
+            Command::Serve { bind, .. } => assert_eq!(bind, "auto"),
             _ => panic!("Expected Serve command"),
         }
     }
@@ -88,7 +106,7 @@ mod tests {
     fn test_cli_serve_command_manual_bind() {
         let cli = Cli::try_parse_from(&["shimmy", "serve", "--bind", "127.0.0.1:8080"]).unwrap();
         match cli.cmd {
-            Command::Serve { bind } => assert_eq!(bind, "127.0.0.1:8080"),
+            Command::Serve { bind, .. } => assert_eq!(bind, "127.0.0.1:8080"),
             _ => panic!("Expected Serve command"),
         }
     }
@@ -97,6 +115,7 @@ mod tests {
     fn test_get_bind_address_auto() {
         let command = Command::Serve {
             bind: "auto".to_string(),
+            isolate: false,
         };
         let address = command.get_bind_address();
 
@@ -111,12 +130,34 @@ mod tests {
     fn test_get_bind_address_manual() {
         let command = Command::Serve {
             bind: "192.168.1.100:9000".to_string(),
+            isolate: false,
         };
         let address = command.get_bind_address();
 
         assert_eq!(address, "192.168.1.100:9000");
     }
 
+    #[test]
+    fn test_cli_serve_isolated_and_worker() {
+        let cli = Cli::try_parse_from(["shimmy", "serve", "--isolate"]).unwrap();
+        assert!(matches!(cli.cmd, Command::Serve { isolate: true, .. }));
+
+        let cli =
+            Cli::try_parse_from(["shimmy", "worker", "--name", "m", "--threads", "4"]).unwrap();
+        match cli.cmd {
+            Command::Worker {
+                name,
+                backend,
+                threads,
+            } => {
+                assert_eq!(name, "m");
+                assert_eq!(backend, None);
+                assert_eq!(threads, Some(4));
+            }
+            _ => panic!("Expected Worker command"),
+        }
+    }
+
     #[test]
     fn test_cli_list_command() {
         let cli = Cli::try_parse_from(&["shimmy", "list"]).unwrap();
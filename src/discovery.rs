@@ -6,6 +6,9 @@ use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 
+use crate::engine::safetensors_native::SafeTensorsCheckpoint;
+use crate::engine::shards::ShardName;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscoveredModel {
     pub name: String,
@@ -105,12 +108,45 @@ impl ModelDiscovery {
     }
 
     fn scan_directory(&self, dir: &Path, models: &mut Vec<DiscoveredModel>) -> Result<()> {
+        // Shards of one checkpoint are reported once, as their directory
+        let checkpoint = SafeTensorsCheckpoint::sharded_in_dir(dir);
+        if let Some(checkpoint) = &checkpoint {
+            models.push(DiscoveredModel {
+                name: dir
+                    .file_name()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or("unknown")
+                    .to_string(),
+                path: dir.to_path_buf(),
+                format: ModelFormat::SafeTensors,
+                size_bytes: Some(checkpoint.total_size()),
+            });
+        }
+
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
 
             if path.is_dir() {
                 self.scan_directory(&path, models)?;
+            } else if checkpoint.as_ref().is_some_and(|c| c.contains_file(&path)) {
+                continue;
+            } else if let Some(split) = ShardName::from_path(&path, "gguf") {
+                // Split GGUFs are one model, reported at the first split with the combined size
+                if split.index == 1 {
+                    let size: u64 = split
+                        .all()
+                        .iter()
+                        .filter_map(|name| fs::metadata(dir.join(name)).ok())
+                        .map(|m| m.len())
+                        .sum();
+                    models.push(DiscoveredModel {
+                        name: split.prefix.clone(),
+                        path: path.clone(),
+                        format: ModelFormat::Gguf,
+                        size_bytes: Some(size),
+                    });
+                }
             } else if self.is_model_file(&path) {
                 if let Ok(model) = self.analyze_model_file(&path) {
                     models.push(model);
@@ -1,7 +1,9 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use std::path::{Path, PathBuf};
 
-use super::{GenOptions, InferenceEngine, LoadedModel, ModelSpec};
+use super::{Capabilities, GenOptions, InferenceEngine, LoadedModel, ModelSpec};
+use crate::error::ShimmyError;
 
 #[cfg(feature = "huggingface")]
 use super::{UniversalEngine, UniversalModel, UniversalModelSpec};
@@ -12,8 +14,11 @@ pub struct InferenceEngineAdapter {
     huggingface_engine: super::huggingface::HuggingFaceEngine,
     #[cfg(feature = "llama")]
     llama_engine: super::llama::LlamaEngine,
+    gguf_engine: super::gguf_native::GgufNativeEngine,
     safetensors_engine: super::safetensors_native::SafeTensorsEngine,
-    // Note: loaded_models removed as caching is not currently implemented
+    exec_engine: super::exec::ExecEngine,
+    /// Set by `isolated`: the shimmy executable that runs each model as a worker
+    worker_exe: Option<PathBuf>,
 }
 
 impl Default for InferenceEngineAdapter {
@@ -29,122 +34,157 @@ impl InferenceEngineAdapter {
             huggingface_engine: super::huggingface::HuggingFaceEngine::new(),
             #[cfg(feature = "llama")]
             llama_engine: super::llama::LlamaEngine::new(),
+            gguf_engine: super::gguf_native::GgufNativeEngine::new(),
             safetensors_engine: super::safetensors_native::SafeTensorsEngine::new(),
+            exec_engine: super::exec::ExecEngine::new(),
+            worker_exe: None,
         }
     }
 
-    /// Auto-detect best backend for model
-    fn select_backend(&self, spec: &ModelSpec) -> BackendChoice {
-        // Check file extension and path patterns to determine optimal backend
-        let path_str = spec.base_path.to_string_lossy();
-
-        // Check for SafeTensors files FIRST - native Rust implementation
-        if let Some(ext) = spec.base_path.extension().and_then(|s| s.to_str()) {
-            if ext == "safetensors" {
-                return BackendChoice::SafeTensors;
-            }
-        }
+    /// Load every model in its own `shimmy worker` process started from
+    /// `worker_exe`, so a crashing backend cannot take the server down.
+    /// Models with `backend = "exec"` already run out of process and are
+    /// started directly.
+    pub fn isolated(mut self, worker_exe: impl Into<PathBuf>) -> Self {
+        self.worker_exe = Some(worker_exe.into());
+        self
+    }
 
-        // Check for GGUF files by extension - these should use LlamaEngine
-        if let Some(ext) = spec.base_path.extension().and_then(|s| s.to_str()) {
-            if ext == "gguf" {
-                #[cfg(feature = "llama")]
-                {
-                    return BackendChoice::Llama;
-                }
-                #[cfg(not(feature = "llama"))]
-                {
-                    // This shouldn't happen with default features, but handle gracefully
-                    panic!("GGUF file detected but llama feature not enabled. Please install with --features llama");
+    /// The backend named in the model config, or one detected from its files
+    fn select_backend(&self, spec: &ModelSpec) -> Result<BackendChoice> {
+        match spec.backend.as_deref() {
+            Some(name) => BackendChoice::from_name(name).ok_or_else(|| {
+                ShimmyError::ConfigError {
+                    field: "backend".to_string(),
+                    value: name.to_string(),
                 }
-            }
+                .into()
+            }),
+            None => detect_backend(&spec.base_path),
         }
+    }
+}
 
-        // Check for Ollama blob files (GGUF files without extension)
-        if path_str.contains("ollama") && path_str.contains("blobs") && path_str.contains("sha256-")
-        {
-            #[cfg(feature = "llama")]
-            {
-                return BackendChoice::Llama;
-            }
-            #[cfg(not(feature = "llama"))]
-            {
-                #[cfg(feature = "huggingface")]
-                {
-                    return BackendChoice::HuggingFace;
-                }
-                #[cfg(not(feature = "huggingface"))]
-                {
-                    panic!("Ollama blob detected but no backend enabled");
-                }
-            }
-        }
+/// Pick a backend from what is on disk: GGUF and SafeTensors files by their
+/// headers, HF checkpoint directories by layout. Paths that do not exist are
+/// HuggingFace Hub model ids.
+fn detect_backend(path: &Path) -> Result<BackendChoice> {
+    use super::safetensors_native::SafeTensorsEngine;
 
-        // Check for other patterns that indicate GGUF files
-        if path_str.contains(".gguf")
-            || spec.name.contains("llama")
-            || spec.name.contains("phi")
-            || spec.name.contains("qwen")
-            || spec.name.contains("gemma")
-            || spec.name.contains("mistral")
-        {
-            #[cfg(feature = "llama")]
-            {
-                return BackendChoice::Llama;
-            }
-            #[cfg(not(feature = "llama"))]
-            {
-                #[cfg(feature = "huggingface")]
-                {
-                    return BackendChoice::HuggingFace;
-                }
-                #[cfg(not(feature = "huggingface"))]
-                {
-                    panic!("GGUF model detected but no backend enabled");
-                }
-            }
+    if path.is_dir() {
+        if SafeTensorsEngine::is_safetensors_model(path) {
+            return Ok(BackendChoice::SafeTensors);
         }
-
-        // Default to HuggingFace for other models
-        #[cfg(feature = "huggingface")]
-        {
-            BackendChoice::HuggingFace
+        // PyTorch-format checkpoints need transformers
+        if path.join("config.json").is_file() {
+            return Ok(BackendChoice::HuggingFace);
         }
-        #[cfg(not(feature = "huggingface"))]
-        {
-            #[cfg(feature = "llama")]
-            {
-                BackendChoice::Llama
-            }
-            #[cfg(not(feature = "llama"))]
-            {
-                panic!("No backend features enabled. Please compile with --features llama or --features huggingface");
-            }
+    } else if path.is_file() {
+        // Also covers Ollama blobs, which are GGUF files without an extension
+        if super::gguf::has_gguf_magic(path) {
+            return Ok(BackendChoice::gguf());
+        }
+        if SafeTensorsEngine::is_safetensors_model(path) {
+            return Ok(BackendChoice::SafeTensors);
         }
+    } else {
+        let model_file = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| MODEL_FILE_EXTENSIONS.contains(&e));
+        if !model_file {
+            return Ok(BackendChoice::HuggingFace);
+        }
+    }
+    Err(ShimmyError::ModelLoadError {
+        path: path.to_path_buf(),
+        source: anyhow!("no GGUF or SafeTensors model found; set `backend` in the model config"),
     }
+    .into())
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Extensions that mark a missing path as a local file rather than a Hub id
+const MODEL_FILE_EXTENSIONS: &[&str] = &["gguf", "safetensors", "json", "bin", "pt", "pth"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum BackendChoice {
-    #[cfg(feature = "llama")]
     Llama,
-    #[cfg(feature = "huggingface")]
+    NativeGguf,
     HuggingFace,
     SafeTensors,
+    Candle,
+    Exec,
+}
+
+impl BackendChoice {
+    /// From the model config's `backend` setting
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "llama" => Some(Self::Llama),
+            "gguf_native" => Some(Self::NativeGguf),
+            "huggingface" => Some(Self::HuggingFace),
+            "safetensors" => Some(Self::SafeTensors),
+            "candle" => Some(Self::Candle),
+            super::exec::BACKEND_NAME => Some(Self::Exec),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Llama => "llama",
+            Self::NativeGguf => "gguf_native",
+            Self::HuggingFace => "huggingface",
+            Self::SafeTensors => "safetensors",
+            Self::Candle => "candle",
+            Self::Exec => super::exec::BACKEND_NAME,
+        }
+    }
+
+    /// llama.cpp when it is compiled in, otherwise the pure Rust GGUF engine
+    fn gguf() -> Self {
+        if cfg!(feature = "llama") {
+            Self::Llama
+        } else {
+            Self::NativeGguf
+        }
+    }
+
+    /// Error for a backend whose cargo feature is not in this build
+    fn not_available(self, model: &str) -> anyhow::Error {
+        anyhow::Error::new(ShimmyError::BackendNotAvailable {
+            backend: self.name().to_string(),
+        })
+        .context(format!(
+            "model '{}' needs the {} backend, which this build does not include (rebuild with --features {})",
+            model,
+            self.name(),
+            self.name()
+        ))
+    }
 }
 
 #[async_trait]
 impl InferenceEngine for InferenceEngineAdapter {
     async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
-        // Select backend and load model directly (no caching for now to avoid complexity)
-        let backend = self.select_backend(spec);
+        if let Some(worker_exe) = &self.worker_exe {
+            if spec.backend.as_deref() != Some(super::exec::BACKEND_NAME) {
+                return super::isolation::load(worker_exe, spec).await;
+            }
+        }
+        // Loads every time; callers keep the model in `AppState::models`
+        let backend = self.select_backend(spec)?;
         match backend {
             BackendChoice::SafeTensors => {
                 // Use native SafeTensors engine - NO Python dependency!
                 self.safetensors_engine.load(spec).await
             }
+            BackendChoice::NativeGguf => self.gguf_engine.load(spec).await,
+            BackendChoice::Exec => self.exec_engine.load(spec).await,
             #[cfg(feature = "llama")]
             BackendChoice::Llama => self.llama_engine.load(spec).await,
+            #[cfg(feature = "candle")]
+            BackendChoice::Candle => super::candle::CandleEngine::new().load(spec).await,
             #[cfg(feature = "huggingface")]
             BackendChoice::HuggingFace => {
                 // Convert to UniversalModelSpec for huggingface backend (for HF model IDs)
@@ -165,6 +205,9 @@ impl InferenceEngine for InferenceEngineAdapter {
                     model: universal_model,
                 }))
             }
+            // Compiled out of this build
+            #[allow(unreachable_patterns)]
+            unavailable => Err(unavailable.not_available(&spec.name)),
         }
     }
 }
@@ -186,7 +229,133 @@ impl LoadedModel for UniversalModelWrapper {
     ) -> Result<String> {
         self.model.generate(prompt, opts, on_token).await
     }
+
+    fn capabilities(&self) -> Capabilities {
+        // Only generation is forwarded through UniversalModel
+        Capabilities {
+            tokenize: false,
+            embeddings: false,
+            ..self.model.capabilities()
+        }
+    }
 }
 
-// Note: Cached model references removed as they were unused placeholder code.
-// Future implementation should use Arc<dyn LoadedModel> for proper model sharing.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn spec(path: &Path, backend: Option<&str>) -> ModelSpec {
+        ModelSpec {
+            name: "phi-3-mistral-llama".to_string(),
+            base_path: path.to_path_buf(),
+            lora_path: None,
+            template: None,
+            ctx_len: 2048,
+            n_threads: None,
+            backend: backend.map(String::from),
+            command: None,
+        }
+    }
+
+    fn safetensors_bytes() -> Vec<u8> {
+        let header = br#"{"w":{"dtype":"F32","shape":[1],"data_offsets":[0,4]}}"#;
+        let mut data = (header.len() as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(header);
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    #[test]
+    fn test_files_are_detected_by_content_not_name() {
+        let dir = TempDir::new().unwrap();
+        // Ollama-style blob: GGUF without an extension
+        let blob = dir.path().join("sha256-1234");
+        fs::write(&blob, b"GGUF\x03\x00\x00\x00").unwrap();
+        assert_eq!(detect_backend(&blob).unwrap(), BackendChoice::gguf());
+
+        let weights = dir.path().join("llama-weights");
+        fs::write(&weights, safetensors_bytes()).unwrap();
+        assert_eq!(
+            detect_backend(&weights).unwrap(),
+            BackendChoice::SafeTensors
+        );
+
+        let other = dir.path().join("qwen.txt");
+        fs::write(&other, b"not a model").unwrap();
+        let err = detect_backend(&other).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ShimmyError>(),
+            Some(ShimmyError::ModelLoadError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_directories_are_detected_by_layout() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("config.json"), "{}").unwrap();
+        fs::write(dir.path().join("pytorch_model.bin"), b"").unwrap();
+        assert_eq!(
+            detect_backend(dir.path()).unwrap(),
+            BackendChoice::HuggingFace
+        );
+
+        fs::write(dir.path().join("model.safetensors"), safetensors_bytes()).unwrap();
+        assert_eq!(
+            detect_backend(dir.path()).unwrap(),
+            BackendChoice::SafeTensors
+        );
+    }
+
+    #[test]
+    fn test_missing_paths() {
+        assert_eq!(
+            detect_backend(Path::new("microsoft/Phi-3-mini-4k-instruct")).unwrap(),
+            BackendChoice::HuggingFace
+        );
+        assert!(detect_backend(Path::new("/nonexistent/phi3-mini.gguf")).is_err());
+    }
+
+    #[test]
+    fn test_explicit_backend_overrides_detection() {
+        let adapter = InferenceEngineAdapter::new();
+        let path = Path::new("/nonexistent/model.gguf");
+        assert_eq!(
+            adapter
+                .select_backend(&spec(path, Some("safetensors")))
+                .unwrap(),
+            BackendChoice::SafeTensors
+        );
+        assert_eq!(
+            adapter
+                .select_backend(&spec(path, Some("gguf_native")))
+                .unwrap(),
+            BackendChoice::NativeGguf
+        );
+
+        let err = adapter
+            .select_backend(&spec(path, Some("pytorch")))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ShimmyError>(),
+            Some(ShimmyError::ConfigError { field, value }) if field == "backend" && value == "pytorch"
+        ));
+    }
+
+    #[cfg(not(feature = "llama"))]
+    #[tokio::test]
+    async fn test_compiled_out_backend_is_a_typed_error() {
+        let adapter = InferenceEngineAdapter::new();
+        let err = adapter
+            .load(&spec(Path::new("model.gguf"), Some("llama")))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ShimmyError>(),
+            Some(ShimmyError::BackendNotAvailable { backend }) if backend == "llama"
+        ));
+        assert!(err.to_string().contains("--features llama"));
+    }
+}
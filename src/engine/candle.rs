@@ -0,0 +1,696 @@
+// Candle backend - real transformer inference for SafeTensors checkpoints
+// Runs HuggingFace-format Llama, Mistral, Phi, Phi-3 and Qwen2 directories
+// (config.json + *.safetensors + tokenizer.json/tokenizer.model) on the CPU
+// with a KV cache.
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{llama, mistral, phi, phi3, qwen2};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use tracing::{debug, info};
+
+use super::executor::ModelExecutor;
+use super::gguf_native::{logprob, sample, REPEAT_LAST_N};
+use super::safetensors_native::SafeTensorsCheckpoint;
+use super::stream::{self, FinishReason, GenerationEvent, GenerationStats, GenerationStream};
+use super::tokenizer::TextTokenizer;
+use super::{Capabilities, GenOptions, InferenceEngine, LoadedModel, ModelSpec};
+
+/// Token strings that end a turn for the chat models we run
+const END_OF_TURN_TOKENS: &[&str] = &[
+    "</s>",
+    "<|endoftext|>",
+    "<|im_end|>",
+    "<|eot_id|>",
+    "<|end_of_text|>",
+    "<|end|>",
+];
+
+#[derive(Debug, Default)]
+pub struct CandleEngine;
+
+impl CandleEngine {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Directory holding config.json/tokenizer.json for a model path
+    pub fn model_dir(path: &Path) -> PathBuf {
+        if path.is_dir() {
+            path.to_path_buf()
+        } else {
+            path.parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| PathBuf::from("."))
+        }
+    }
+
+    /// True when the checkpoint has a config.json naming an architecture we run
+    pub fn can_load(path: &Path) -> bool {
+        read_config(&Self::model_dir(path))
+            .ok()
+            .and_then(|config| Architecture::from_config(&config))
+            .is_some()
+    }
+}
+
+#[async_trait]
+impl InferenceEngine for CandleEngine {
+    async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+        if spec.lora_path.is_some() {
+            bail!("LoRA adapters are not supported by the Candle backend");
+        }
+        let spec = spec.clone();
+        let model = tokio::task::spawn_blocking(move || CandleModel::load(&spec)).await??;
+        Ok(Box::new(model))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Architecture {
+    Llama,
+    Mistral,
+    Phi,
+    Phi3,
+    Qwen2,
+}
+
+impl Architecture {
+    fn from_config(config: &Value) -> Option<Self> {
+        let declared = config
+            .get("architectures")
+            .and_then(|a| a.get(0))
+            .and_then(Value::as_str);
+        match declared {
+            Some("LlamaForCausalLM") => return Some(Self::Llama),
+            Some("MistralForCausalLM") => return Some(Self::Mistral),
+            Some("PhiForCausalLM") => return Some(Self::Phi),
+            Some("Phi3ForCausalLM") => return Some(Self::Phi3),
+            Some("Qwen2ForCausalLM") => return Some(Self::Qwen2),
+            _ => {}
+        }
+        match config.get("model_type").and_then(Value::as_str)? {
+            "llama" => Some(Self::Llama),
+            "mistral" => Some(Self::Mistral),
+            "phi" => Some(Self::Phi),
+            "phi3" => Some(Self::Phi3),
+            "qwen2" => Some(Self::Qwen2),
+            _ => None,
+        }
+    }
+}
+
+/// The transformer plus whatever KV cache state it needs between calls
+enum Backbone {
+    Llama {
+        model: llama::Llama,
+        config: llama::Config,
+        cache: llama::Cache,
+    },
+    Mistral(mistral::Model),
+    Phi(phi::Model),
+    Phi3(phi3::Model),
+    Qwen2(qwen2::ModelForCausalLM),
+}
+
+impl Backbone {
+    fn load(
+        arch: Architecture,
+        config: &Value,
+        vb: VarBuilder,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let config = config.clone();
+        Ok(match arch {
+            Architecture::Llama => {
+                let config =
+                    serde_json::from_value::<llama::LlamaConfig>(config)?.into_config(false);
+                let cache = llama::Cache::new(true, dtype, &config, device)?;
+                Backbone::Llama {
+                    model: llama::Llama::load(vb, &config)?,
+                    config,
+                    cache,
+                }
+            }
+            Architecture::Mistral => {
+                let config: mistral::Config = serde_json::from_value(config)?;
+                Backbone::Mistral(mistral::Model::new(&config, vb)?)
+            }
+            Architecture::Phi => {
+                let config: phi::Config = serde_json::from_value(config)?;
+                Backbone::Phi(phi::Model::new(&config, vb)?)
+            }
+            Architecture::Phi3 => {
+                let config: phi3::Config = serde_json::from_value(config)?;
+                Backbone::Phi3(phi3::Model::new(&config, vb)?)
+            }
+            Architecture::Qwen2 => {
+                let config: qwen2::Config = serde_json::from_value(config)?;
+                Backbone::Qwen2(qwen2::ModelForCausalLM::new(&config, vb)?)
+            }
+        })
+    }
+
+    /// Drop all cached keys/values so the next forward starts at position 0
+    fn clear_kv_cache(&mut self, dtype: DType, device: &Device) -> Result<()> {
+        match self {
+            Backbone::Llama { config, cache, .. } => {
+                *cache = llama::Cache::new(true, dtype, config, device)?;
+            }
+            Backbone::Mistral(m) => m.clear_kv_cache(),
+            Backbone::Phi(m) => m.clear_kv_cache(),
+            Backbone::Phi3(m) => m.clear_kv_cache(),
+            Backbone::Qwen2(m) => m.clear_kv_cache(),
+        }
+        Ok(())
+    }
+
+    /// Evaluate `input` (shape [1, seq]) starting at `pos` and return the
+    /// logits of the last position as f32
+    fn forward(&mut self, input: &Tensor, pos: usize) -> Result<Vec<f32>> {
+        let logits = match self {
+            Backbone::Llama { model, cache, .. } => model.forward(input, pos, cache)?,
+            Backbone::Mistral(m) => m.forward(input, pos)?,
+            // Phi keeps its own position counter alongside the KV cache
+            Backbone::Phi(m) => m.forward(input)?,
+            Backbone::Phi3(m) => m.forward(input, pos)?,
+            Backbone::Qwen2(m) => m.forward(input, pos)?,
+        };
+        Ok(logits
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?)
+    }
+}
+
+struct Inner {
+    name: String,
+    arch: Architecture,
+    backbone: Mutex<Backbone>,
+    tokenizer: TextTokenizer,
+    eos_tokens: HashSet<u32>,
+    dtype: DType,
+    device: Device,
+    ctx_len: usize,
+    chat_template: bool,
+}
+
+pub struct CandleModel {
+    inner: Arc<Inner>,
+    executor: ModelExecutor,
+}
+
+impl CandleModel {
+    fn load(spec: &ModelSpec) -> Result<Self> {
+        let dir = CandleEngine::model_dir(&spec.base_path);
+        let config = read_config(&dir)?;
+        let arch = Architecture::from_config(&config).ok_or_else(|| {
+            anyhow!(
+                "unsupported architecture in {}/config.json (supported: llama, mistral, phi, phi3, qwen2)",
+                dir.display()
+            )
+        })?;
+        // A single file, or every shard of an indexed checkpoint
+        let weights = SafeTensorsCheckpoint::open(&spec.base_path)?
+            .shards()
+            .to_vec();
+        let device = Device::Cpu;
+        let dtype = config_dtype(&config, &device);
+
+        let tokenizer = TextTokenizer::from_dir(&dir)?;
+
+        info!(
+            path = %spec.base_path.display(),
+            arch = ?arch,
+            dtype = ?dtype,
+            shards = weights.len(),
+            "Loading SafeTensors model with Candle"
+        );
+        // SAFETY: the weight files are opened read-only and stay mapped for the
+        // lifetime of the model; we never write through the mapping.
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&weights, dtype, &device)? };
+        let backbone = Backbone::load(arch, &config, vb, dtype, &device)?;
+
+        let max_positions = config
+            .get("max_position_embeddings")
+            .and_then(Value::as_u64)
+            .map(|n| n as usize)
+            .unwrap_or(spec.ctx_len);
+        let ctx_len = spec.ctx_len.min(max_positions).max(1);
+        let chat_template = fs::read_to_string(dir.join("tokenizer_config.json"))
+            .ok()
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            .is_some_and(|c| !c["chat_template"].is_null());
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                name: spec.name.clone(),
+                arch,
+                backbone: Mutex::new(backbone),
+                eos_tokens: eos_tokens(&dir, &config, &tokenizer),
+                tokenizer,
+                dtype,
+                device,
+                ctx_len,
+                chat_template,
+            }),
+            executor: ModelExecutor::new(&spec.name)?,
+        })
+    }
+}
+
+#[async_trait]
+impl LoadedModel for CandleModel {
+    async fn generate(
+        &self,
+        prompt: &str,
+        opts: GenOptions,
+        on_token: Option<Box<dyn FnMut(String) + Send>>,
+    ) -> Result<String> {
+        let inner = Arc::clone(&self.inner);
+        let prompt = prompt.to_string();
+        self.executor
+            .run(move || {
+                stream::collect_text(|emit| inner.generate(&prompt, &opts, emit), on_token)
+            })
+            .await?
+    }
+
+    fn generate_stream(self: Arc<Self>, prompt: String, opts: GenOptions) -> GenerationStream {
+        let inner = Arc::clone(&self.inner);
+        self.executor
+            .stream(move |emit| inner.generate(&prompt, &opts, emit))
+    }
+
+    fn tokenize(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        self.inner.tokenizer.encode(text, add_special)
+    }
+
+    fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        self.inner.tokenizer.decode(tokens, true)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            chat_template: self.inner.chat_template,
+            tokenize: true,
+            logprobs: true,
+            ..Capabilities::text(self.inner.ctx_len)
+        }
+    }
+}
+
+impl Inner {
+    fn generate(
+        &self,
+        prompt: &str,
+        opts: &GenOptions,
+        emit: &mut dyn FnMut(GenerationEvent) -> bool,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let mut tokens = self.tokenizer.encode(prompt, true)?;
+        if tokens.is_empty() {
+            bail!("prompt produced no tokens");
+        }
+        if tokens.len() >= self.ctx_len {
+            bail!(
+                "prompt is {} tokens but the context window is {}",
+                tokens.len(),
+                self.ctx_len
+            );
+        }
+
+        // A panic mid-forward poisons the lock; the cache is cleared below either way
+        let mut backbone = self.backbone.lock().unwrap_or_else(PoisonError::into_inner);
+        backbone.clear_kv_cache(self.dtype, &self.device)?;
+        debug!(
+            model = %self.name,
+            arch = ?self.arch,
+            prompt_tokens = tokens.len(),
+            "candle prefill"
+        );
+
+        let prompt_tokens = tokens.len();
+        emit(GenerationEvent::PromptProgress {
+            processed: 0,
+            total: prompt_tokens,
+        });
+        let input = Tensor::new(tokens.as_slice(), &self.device)?.unsqueeze(0)?;
+        let mut logits = backbone.forward(&input, 0)?;
+        emit(GenerationEvent::PromptProgress {
+            processed: prompt_tokens,
+            total: prompt_tokens,
+        });
+        let prompt_done = Instant::now();
+
+        let mut rng = match opts.seed {
+            Some(seed) => StdRng::seed_from_u64(seed as u64),
+            None => StdRng::from_entropy(),
+        };
+        let mut decoder = self.tokenizer.stream_decoder();
+        let mut finish_reason = FinishReason::Length;
+        let mut generated = 0;
+        while generated < opts.max_tokens {
+            let recent_start = tokens.len().saturating_sub(REPEAT_LAST_N);
+            let token = sample(&mut logits, opts, &tokens[recent_start..], &mut rng);
+            if self.eos_tokens.contains(&token) {
+                finish_reason = FinishReason::Stop;
+                break;
+            }
+            generated += 1;
+            let pos = tokens.len();
+            tokens.push(token);
+            let sent = emit(GenerationEvent::Token {
+                id: Some(token),
+                text: decoder.push(token)?,
+                logprob: Some(logprob(&logits, token)),
+            });
+            // The client went away
+            if !sent {
+                finish_reason = FinishReason::Cancelled;
+                break;
+            }
+            if tokens.len() >= self.ctx_len {
+                break;
+            }
+            let input = Tensor::new(&[token], &self.device)?.unsqueeze(0)?;
+            logits = backbone.forward(&input, pos)?;
+        }
+        let tail = decoder.flush();
+        if !tail.is_empty() {
+            emit(GenerationEvent::text(tail));
+        }
+        emit(GenerationEvent::Done {
+            finish_reason,
+            stats: GenerationStats::timed(prompt_tokens, generated, started, Some(prompt_done)),
+        });
+        Ok(())
+    }
+}
+
+fn read_config(dir: &Path) -> Result<Value> {
+    let path = dir.join("config.json");
+    let text = fs::read_to_string(&path)
+        .map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+/// Compute precision for the checkpoint's `torch_dtype`. The CPU backend has
+/// no bf16 matmul, so bf16 weights are widened to f32 as they are loaded.
+fn config_dtype(config: &Value, device: &Device) -> DType {
+    match config.get("torch_dtype").and_then(Value::as_str) {
+        Some("bfloat16") if !device.is_cpu() => DType::BF16,
+        Some("float16") => DType::F16,
+        _ => DType::F32,
+    }
+}
+
+/// End-of-generation ids from config.json, generation_config.json and the
+/// tokenizer's own end-of-turn tokens
+fn eos_tokens(dir: &Path, config: &Value, tokenizer: &TextTokenizer) -> HashSet<u32> {
+    let mut ids = HashSet::new();
+    let mut collect = |value: Option<&Value>| match value {
+        Some(Value::Number(n)) => ids.extend(n.as_u64().map(|n| n as u32)),
+        Some(Value::Array(list)) => {
+            ids.extend(list.iter().filter_map(Value::as_u64).map(|n| n as u32))
+        }
+        _ => {}
+    };
+    collect(config.get("eos_token_id"));
+    if let Ok(text) = fs::read_to_string(dir.join("generation_config.json")) {
+        if let Ok(generation) = serde_json::from_str::<Value>(&text) {
+            collect(generation.get("eos_token_id"));
+        }
+    }
+    ids.extend(
+        END_OF_TURN_TOKENS
+            .iter()
+            .filter_map(|t| tokenizer.token_to_id(t)),
+    );
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tokenizers::Tokenizer;
+
+    const WORDS: &[&str] = &[
+        "the", "cat", "sat", "on", "mat", "a", "dog", "ran", "to", "park", "and", "then",
+    ];
+
+    /// Write a tiny random Llama checkpoint with a word-level tokenizer
+    fn write_tiny_llama(dir: &Path, dtype: DType) {
+        let (d, ff, layers, kv_dim) = (32usize, 64usize, 2usize, 16usize);
+        let mut vocab: HashMap<String, u32> = HashMap::new();
+        for (i, t) in ["<unk>", "<s>", "</s>"].iter().chain(WORDS).enumerate() {
+            vocab.insert(t.to_string(), i as u32);
+        }
+        let n_vocab = vocab.len();
+
+        let model = WordLevel::builder()
+            .vocab(vocab.into_iter().collect())
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.save(dir.join("tokenizer.json"), false).unwrap();
+
+        let torch_dtype = match dtype {
+            DType::BF16 => "bfloat16",
+            DType::F16 => "float16",
+            _ => "float32",
+        };
+        let config = serde_json::json!({
+            "architectures": ["LlamaForCausalLM"],
+            "hidden_size": d,
+            "intermediate_size": ff,
+            "vocab_size": n_vocab,
+            "num_hidden_layers": layers,
+            "num_attention_heads": 4,
+            "num_key_value_heads": 2,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "max_position_embeddings": 64,
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+            "torch_dtype": torch_dtype,
+        });
+        fs::write(dir.join("config.json"), config.to_string()).unwrap();
+
+        let device = Device::Cpu;
+        let mut tensors: HashMap<String, Tensor> = HashMap::new();
+        let mut add = |name: String, shape: &[usize]| {
+            let t = Tensor::randn(0f32, 0.5, shape, &device)
+                .unwrap()
+                .to_dtype(dtype)
+                .unwrap();
+            tensors.insert(name, t);
+        };
+        add("model.embed_tokens.weight".into(), &[n_vocab, d]);
+        add("lm_head.weight".into(), &[n_vocab, d]);
+        add("model.norm.weight".into(), &[d]);
+        for l in 0..layers {
+            let p = format!("model.layers.{}", l);
+            add(format!("{}.self_attn.q_proj.weight", p), &[d, d]);
+            add(format!("{}.self_attn.k_proj.weight", p), &[kv_dim, d]);
+            add(format!("{}.self_attn.v_proj.weight", p), &[kv_dim, d]);
+            add(format!("{}.self_attn.o_proj.weight", p), &[d, d]);
+            add(format!("{}.mlp.gate_proj.weight", p), &[ff, d]);
+            add(format!("{}.mlp.up_proj.weight", p), &[ff, d]);
+            add(format!("{}.mlp.down_proj.weight", p), &[d, ff]);
+            add(format!("{}.input_layernorm.weight", p), &[d]);
+            add(format!("{}.post_attention_layernorm.weight", p), &[d]);
+        }
+        candle_core::safetensors::save(&tensors, dir.join("model.safetensors")).unwrap();
+    }
+
+    fn spec_for(dir: &Path) -> ModelSpec {
+        ModelSpec {
+            name: "tiny-llama".to_string(),
+            base_path: dir.join("model.safetensors"),
+            lora_path: None,
+            template: None,
+            ctx_len: 64,
+            n_threads: None,
+            backend: None,
+            command: None,
+        }
+    }
+
+    fn greedy(max_tokens: usize) -> GenOptions {
+        GenOptions {
+            max_tokens,
+            temperature: 0.0,
+            repeat_penalty: 1.0,
+            seed: Some(7),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_architecture_from_config() {
+        let arch = |v: Value| Architecture::from_config(&v);
+        assert_eq!(
+            arch(serde_json::json!({"architectures": ["Qwen2ForCausalLM"]})),
+            Some(Architecture::Qwen2)
+        );
+        assert_eq!(
+            arch(serde_json::json!({"model_type": "phi3"})),
+            Some(Architecture::Phi3)
+        );
+        assert_eq!(arch(serde_json::json!({"model_type": "gpt2"})), None);
+    }
+
+    #[test]
+    fn test_config_dtype() {
+        let cpu = Device::Cpu;
+        let dtype = |v: Value| config_dtype(&v, &cpu);
+        assert_eq!(
+            dtype(serde_json::json!({"torch_dtype": "bfloat16"})),
+            DType::F32
+        );
+        assert_eq!(
+            dtype(serde_json::json!({"torch_dtype": "float16"})),
+            DType::F16
+        );
+        assert_eq!(dtype(serde_json::json!({})), DType::F32);
+    }
+
+    #[test]
+    fn test_kv_cache_matches_full_recompute() {
+        let dir = TempDir::new().unwrap();
+        write_tiny_llama(dir.path(), DType::F32);
+        let model = CandleModel::load(&spec_for(dir.path())).unwrap();
+        let inner = &model.inner;
+        let mut backbone = inner.backbone.lock().unwrap();
+        let tokens = [1u32, 3, 4, 5, 6, 7];
+
+        // Prefill all but the last token, then feed the last one through the cache
+        backbone.clear_kv_cache(inner.dtype, &inner.device).unwrap();
+        let prefix = Tensor::new(&tokens[..5], &inner.device)
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap();
+        backbone.forward(&prefix, 0).unwrap();
+        let last = Tensor::new(&tokens[5..], &inner.device)
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap();
+        let cached = backbone.forward(&last, 5).unwrap();
+
+        backbone.clear_kv_cache(inner.dtype, &inner.device).unwrap();
+        let full = Tensor::new(&tokens[..], &inner.device)
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap();
+        let recomputed = backbone.forward(&full, 0).unwrap();
+
+        assert_eq!(cached.len(), recomputed.len());
+        for (a, b) in cached.iter().zip(&recomputed) {
+            assert!((a - b).abs() < 1e-4, "{} vs {}", a, b);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_is_deterministic_and_streams() {
+        let dir = TempDir::new().unwrap();
+        write_tiny_llama(dir.path(), DType::F32);
+        let model = CandleEngine::new()
+            .load(&spec_for(dir.path()))
+            .await
+            .unwrap();
+
+        let first = model
+            .generate("the cat sat", greedy(8), None)
+            .await
+            .unwrap();
+        let streamed = Arc::new(Mutex::new(String::new()));
+        let sink = Arc::clone(&streamed);
+        let second = model
+            .generate(
+                "the cat sat",
+                greedy(8),
+                Some(Box::new(move |piece| sink.lock().unwrap().push_str(&piece))),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(*streamed.lock().unwrap(), second);
+
+        let ids = model.tokenize("the cat", false).unwrap();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(model.detokenize(&ids).unwrap(), "the cat");
+        assert!(model.capabilities().logprobs);
+    }
+
+    #[tokio::test]
+    async fn test_generate_recovers_from_poisoned_state() {
+        let dir = TempDir::new().unwrap();
+        write_tiny_llama(dir.path(), DType::F32);
+        let model = CandleModel::load(&spec_for(dir.path())).unwrap();
+        let inner = Arc::clone(&model.inner);
+        std::thread::spawn(move || {
+            let _backbone = inner.backbone.lock().unwrap();
+            panic!("decode failed");
+        })
+        .join()
+        .unwrap_err();
+        assert!(model.inner.backbone.is_poisoned());
+
+        model
+            .generate("the cat sat", greedy(4), None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_half_precision_weights_load_and_generate() {
+        for dtype in [DType::BF16, DType::F16] {
+            let dir = TempDir::new().unwrap();
+            write_tiny_llama(dir.path(), dtype);
+            assert!(CandleEngine::can_load(
+                &dir.path().join("model.safetensors")
+            ));
+            let model = CandleEngine::new()
+                .load(&spec_for(dir.path()))
+                .await
+                .unwrap();
+            model.generate("a dog ran", greedy(4), None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_architecture_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        write_tiny_llama(dir.path(), DType::F32);
+        fs::write(
+            dir.path().join("config.json"),
+            r#"{"architectures": ["GPT2LMHeadModel"]}"#,
+        )
+        .unwrap();
+        assert!(!CandleEngine::can_load(dir.path()));
+        let err = CandleEngine::new()
+            .load(&spec_for(dir.path()))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("unsupported architecture"));
+    }
+}
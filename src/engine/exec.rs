@@ -0,0 +1,820 @@
+// External inference engines over a subprocess protocol
+// `backend = "exec"` starts any configured executable and drives it with
+// newline-delimited JSON on stdin/stdout (see docs/EXEC_PROTOCOL.md). The
+// HuggingFace backend's Python worker speaks the same protocol.
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use super::stream::{self, FinishReason, GenerationEvent, GenerationStats, GenerationStream};
+use super::{Capabilities, GenOptions, InferenceEngine, LoadedModel, ModelSpec};
+use crate::error::ShimmyError;
+
+/// Value of the model config's `backend` setting that selects this engine
+pub const BACKEND_NAME: &str = "exec";
+
+/// Lines of engine stderr kept for error messages
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Engine restarts across every worker in this process, for `/metrics`
+static RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+/// How many engine processes (exec, HuggingFace and isolated workers) have
+/// been restarted after exiting since the server started
+pub fn total_restarts() -> u64 {
+    RESTARTS.load(Ordering::SeqCst)
+}
+
+/// How to start an engine process
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecCommand {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl ExecCommand {
+    /// From a config `command` list: the executable followed by its arguments
+    pub fn from_argv(argv: &[String]) -> Result<Self> {
+        let (program, args) = argv
+            .split_first()
+            .ok_or_else(|| anyhow!("the exec backend needs a command to run"))?;
+        Ok(Self {
+            program: program.clone(),
+            args: args.to_vec(),
+        })
+    }
+}
+
+/// What the engine loads, re-sent whenever the process is restarted
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadRequest {
+    pub model: String,
+    pub adapter: Option<String>,
+    pub device: Option<String>,
+    pub ctx_len: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request<'a> {
+    Load {
+        id: u64,
+        #[serde(flatten)]
+        load: &'a LoadRequest,
+    },
+    Generate {
+        id: u64,
+        prompt: &'a str,
+        max_new_tokens: usize,
+        temperature: f32,
+        top_p: f32,
+        top_k: i32,
+        repetition_penalty: f32,
+        seed: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        grammar: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        suffix: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        images: Option<&'a [String]>,
+    },
+    Tokenize {
+        id: u64,
+        text: &'a str,
+        add_special: bool,
+    },
+    Detokenize {
+        id: u64,
+        tokens: &'a [u32],
+    },
+    Embed {
+        id: u64,
+        text: &'a str,
+    },
+    Health {
+        id: u64,
+    },
+    Cancel {
+        id: u64,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(super) enum Event {
+    Ready {
+        #[serde(default)]
+        capabilities: Option<Capabilities>,
+    },
+    Token {
+        text: String,
+        /// The token's id and log probability, for engines that report them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token_id: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        logprob: Option<f32>,
+    },
+    Done {
+        text: String,
+        finish_reason: String,
+        /// Token counts, for engines that report them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prompt_tokens: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        completion_tokens: Option<usize>,
+    },
+    Tokens {
+        tokens: Vec<u32>,
+    },
+    Text {
+        text: String,
+    },
+    Embedding {
+        embedding: Vec<f32>,
+    },
+    Error {
+        message: String,
+    },
+    /// The engine turned a generation away because its queue is full
+    Overloaded {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        model: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct EventLine {
+    pub(super) id: u64,
+    #[serde(flatten)]
+    pub(super) event: Event,
+}
+
+/// Where the reader thread delivers a request's events. Generation is awaited;
+/// tokenize/embed back synchronous `LoadedModel` methods and block instead.
+enum Waiter {
+    Async(mpsc::UnboundedSender<Event>),
+    Blocking(std_mpsc::Sender<Event>),
+}
+
+impl Waiter {
+    fn send(&self, event: Event) {
+        let _ = match self {
+            Waiter::Async(tx) => tx.send(event).map_err(drop),
+            Waiter::Blocking(tx) => tx.send(event).map_err(drop),
+        };
+    }
+}
+
+type Pending = Arc<Mutex<HashMap<u64, Waiter>>>;
+
+/// One running engine process and the threads pumping its pipes
+struct Process {
+    program: String,
+    requests: std_mpsc::Sender<String>,
+    pending: Pending,
+    alive: Arc<AtomicBool>,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    child: Child,
+}
+
+impl Process {
+    fn spawn(command: &ExecCommand) -> Result<Self> {
+        let mut child = Command::new(&command.program)
+            .args(&command.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("cannot start engine '{}': {}", command.program, e))?;
+        let (mut stdin, stdout, stderr) =
+            match (child.stdin.take(), child.stdout.take(), child.stderr.take()) {
+                (Some(i), Some(o), Some(e)) => (i, o, e),
+                _ => bail!("engine '{}' pipes were not captured", command.program),
+            };
+
+        // Plain threads rather than tasks, so blocking callers never wait on
+        // a runtime they are themselves occupying
+        let (requests, outgoing) = std_mpsc::channel::<String>();
+        std::thread::spawn(move || {
+            for line in outgoing {
+                let written = stdin
+                    .write_all(line.as_bytes())
+                    .and_then(|_| stdin.write_all(b"\n"))
+                    .and_then(|_| stdin.flush());
+                if written.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+        let tail = Arc::clone(&stderr_tail);
+        let program = command.program.clone();
+        let (stderr_done, stderr_closed) = std_mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            let _done = stderr_done;
+            for line in BufReader::new(stderr).lines() {
+                let Ok(line) = line else { break };
+                debug!(target: "shimmy::exec", engine = %program, "{}", line);
+                let mut tail = tail.lock().unwrap();
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+        });
+
+        let pending: Pending = Arc::default();
+        let alive = Arc::new(AtomicBool::new(true));
+        let reader_pending = Arc::clone(&pending);
+        let reader_alive = Arc::clone(&alive);
+        let program = command.program.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                let EventLine { id, event } = match serde_json::from_str(&line) {
+                    Ok(parsed) => parsed,
+                    Err(_) => {
+                        debug!(engine = %program, line = %line, "Ignoring non-protocol engine output");
+                        continue;
+                    }
+                };
+                let mut pending = reader_pending.lock().unwrap();
+                if id == 0 {
+                    // Not tied to a request (e.g. missing dependencies): fail everything
+                    if let Event::Error { message } = event {
+                        for (_, waiter) in pending.drain() {
+                            waiter.send(Event::Error {
+                                message: message.clone(),
+                            });
+                        }
+                    }
+                    continue;
+                }
+                let finished = !matches!(event, Event::Token { .. });
+                if let Some(waiter) = pending.get(&id) {
+                    waiter.send(event);
+                }
+                if finished {
+                    pending.remove(&id);
+                }
+            }
+            // Exited or crashed: let its last words reach the stderr tail, then wake
+            // every waiter and let the next request restart it
+            let _ = stderr_closed.recv_timeout(Duration::from_secs(1));
+            reader_alive.store(false, Ordering::SeqCst);
+            reader_pending.lock().unwrap().clear();
+        });
+
+        Ok(Self {
+            program: command.program.clone(),
+            requests,
+            pending,
+            alive,
+            stderr_tail,
+            child,
+        })
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    /// Send a request whose events are delivered to `waiter`
+    fn send(&self, id: u64, request: &Request, waiter: Waiter) -> Result<()> {
+        self.pending.lock().unwrap().insert(id, waiter);
+        // The reader clears `pending` only after marking the process dead, so
+        // a waiter registered while it still looked alive is always released
+        if !self.is_alive() {
+            self.pending.lock().unwrap().remove(&id);
+            bail!("{}", self.exit_reason());
+        }
+        self.requests
+            .send(serde_json::to_string(request)?)
+            .map_err(|_| anyhow!("engine '{}' is not running", self.program))
+    }
+
+    fn send_async(&self, id: u64, request: &Request) -> Result<mpsc::UnboundedReceiver<Event>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.send(id, request, Waiter::Async(tx))?;
+        Ok(rx)
+    }
+
+    fn send_blocking(&self, id: u64, request: &Request) -> Result<std_mpsc::Receiver<Event>> {
+        let (tx, rx) = std_mpsc::channel();
+        self.send(id, request, Waiter::Blocking(tx))?;
+        Ok(rx)
+    }
+
+    /// Why the engine went away, from the tail of its stderr
+    fn exit_reason(&self) -> String {
+        let tail = self.stderr_tail.lock().unwrap();
+        match tail.back() {
+            Some(last) => format!("engine '{}' exited: {}", self.program, last),
+            None => format!("engine '{}' exited", self.program),
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Outcome of a `load` from its next event, or `None` to keep waiting. A
+/// successful load carries whatever capabilities the engine announced.
+fn load_outcome(event: Option<Event>, process: &Process) -> Option<Result<Option<Capabilities>>> {
+    match event {
+        Some(Event::Ready { capabilities }) => Some(Ok(capabilities)),
+        Some(Event::Error { message }) => Some(Err(anyhow!("{}", message))),
+        Some(_) => None,
+        None => Some(Err(anyhow!("{}", process.exit_reason()))),
+    }
+}
+
+fn unexpected(op: &str, event: Event) -> anyhow::Error {
+    anyhow!("engine answered {} with unexpected {:?}", op, event)
+}
+
+/// Tells the engine to stop a generation whose caller went away
+struct CancelOnDrop {
+    id: u64,
+    requests: std_mpsc::Sender<String>,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if self.armed {
+            debug!(id = self.id, "Cancelling engine generation");
+            if let Ok(line) = serde_json::to_string(&Request::Cancel { id: self.id }) {
+                let _ = self.requests.send(line);
+            }
+        }
+    }
+}
+
+/// A model served by a persistent engine process. A crashed engine is
+/// restarted (and the model reloaded) on the next request.
+pub struct ExecWorker {
+    command: ExecCommand,
+    load: LoadRequest,
+    process: Mutex<Option<Arc<Process>>>,
+    restarting: tokio::sync::Mutex<()>,
+    next_id: AtomicU64,
+    restarts: AtomicU64,
+    capabilities: Mutex<Capabilities>,
+}
+
+impl ExecWorker {
+    /// Start the engine and wait until the model is loaded
+    pub async fn start(command: ExecCommand, load: LoadRequest) -> Result<Self> {
+        let worker = Self {
+            command,
+            capabilities: Mutex::new(Capabilities::text(load.ctx_len)),
+            load,
+            process: Mutex::new(None),
+            restarting: tokio::sync::Mutex::new(()),
+            next_id: AtomicU64::new(1),
+            restarts: AtomicU64::new(0),
+        };
+        worker.process().await?;
+        info!(engine = %worker.command.program, model = %worker.load.model, "Engine ready");
+        Ok(worker)
+    }
+
+    /// How many times the engine has been restarted after exiting
+    // The server reports the process-wide `total_restarts` instead
+    #[allow(dead_code)]
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::SeqCst)
+    }
+
+    /// As announced by the engine's last `ready` to `load`; plain text
+    /// generation within `ctx_len` when it announced nothing
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities.lock().unwrap().clone()
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn live(&self) -> Option<Arc<Process>> {
+        let slot = self.process.lock().unwrap();
+        slot.as_ref().filter(|p| p.is_alive()).cloned()
+    }
+
+    /// Spawn a process and send it the load request; the caller waits for ready
+    fn relaunch(&self) -> Result<(Arc<Process>, u64)> {
+        if let Some(dead) = self.process.lock().unwrap().take() {
+            let restarts = self.restarts.fetch_add(1, Ordering::SeqCst) + 1;
+            RESTARTS.fetch_add(1, Ordering::SeqCst);
+            warn!(reason = %dead.exit_reason(), restarts, "Restarting engine");
+        }
+        let process = Arc::new(Process::spawn(&self.command)?);
+        Ok((process, self.next_id()))
+    }
+
+    fn install(&self, process: &Arc<Process>, capabilities: Option<Capabilities>) {
+        if let Some(capabilities) = capabilities {
+            *self.capabilities.lock().unwrap() = capabilities;
+        }
+        *self.process.lock().unwrap() = Some(Arc::clone(process));
+    }
+
+    /// The running process, restarting it if it exited
+    async fn process(&self) -> Result<Arc<Process>> {
+        if let Some(process) = self.live() {
+            return Ok(process);
+        }
+        let _restart = self.restarting.lock().await;
+        if let Some(process) = self.live() {
+            return Ok(process);
+        }
+        let (process, id) = self.relaunch()?;
+        let load = Request::Load {
+            id,
+            load: &self.load,
+        };
+        let mut events = process.send_async(id, &load)?;
+        let capabilities = loop {
+            if let Some(outcome) = load_outcome(events.recv().await, &process) {
+                break outcome?;
+            }
+        };
+        self.install(&process, capabilities);
+        Ok(process)
+    }
+
+    /// Like `process`, for synchronous callers
+    fn process_blocking(&self) -> Result<Arc<Process>> {
+        if let Some(process) = self.live() {
+            return Ok(process);
+        }
+        let Ok(_restart) = self.restarting.try_lock() else {
+            bail!("engine '{}' is restarting", self.command.program);
+        };
+        if let Some(process) = self.live() {
+            return Ok(process);
+        }
+        let (process, id) = self.relaunch()?;
+        let load = Request::Load {
+            id,
+            load: &self.load,
+        };
+        let events = process.send_blocking(id, &load)?;
+        let capabilities = loop {
+            if let Some(outcome) = load_outcome(events.recv().ok(), &process) {
+                break outcome?;
+            }
+        };
+        self.install(&process, capabilities);
+        Ok(process)
+    }
+
+    /// Send a request answered by a single event, waiting on this thread
+    fn call<'a>(&self, request: impl FnOnce(u64) -> Request<'a>) -> Result<Event> {
+        let process = self.process_blocking()?;
+        let id = self.next_id();
+        let events = process.send_blocking(id, &request(id))?;
+        loop {
+            match events.recv() {
+                Ok(Event::Token { .. }) => continue,
+                Ok(Event::Error { message }) => bail!("{}", message),
+                Ok(event) => return Ok(event),
+                Err(_) => bail!("{}", process.exit_reason()),
+            }
+        }
+    }
+
+    /// Generate a completion, streaming pieces to `on_token`. Dropping the
+    /// returned future cancels the generation in the engine.
+    pub async fn generate(
+        &self,
+        prompt: &str,
+        opts: &GenOptions,
+        mut on_token: Option<Box<dyn FnMut(String) + Send>>,
+    ) -> Result<String> {
+        self.generate_events(prompt, opts, |event| {
+            if let (GenerationEvent::Token { text, .. }, Some(cb)) = (event, on_token.as_mut()) {
+                cb(text);
+            }
+        })
+        .await
+    }
+
+    /// Like `generate`, reporting tokens and the engine's finish reason as
+    /// events. Returns the full text from the engine's `done`.
+    pub async fn generate_events(
+        &self,
+        prompt: &str,
+        opts: &GenOptions,
+        mut emit: impl FnMut(GenerationEvent) + Send,
+    ) -> Result<String> {
+        let started = Instant::now();
+        let process = self.process().await?;
+        let id = self.next_id();
+        let mut events = process.send_async(
+            id,
+            &Request::Generate {
+                id,
+                prompt,
+                max_new_tokens: opts.max_tokens,
+                temperature: opts.temperature,
+                top_p: opts.top_p,
+                top_k: opts.top_k,
+                repetition_penalty: opts.repeat_penalty,
+                seed: opts.seed,
+                grammar: opts.grammar.as_deref(),
+                suffix: opts.suffix.as_deref(),
+                images: (!opts.images.is_empty()).then_some(opts.images.as_slice()),
+            },
+        )?;
+        let mut cancel = CancelOnDrop {
+            id,
+            requests: process.requests.clone(),
+            armed: true,
+        };
+
+        let mut streamed = String::new();
+        let mut first_token = None;
+        let mut pieces = 0;
+        let result = loop {
+            match events.recv().await {
+                Some(Event::Token {
+                    text,
+                    token_id,
+                    logprob,
+                }) => {
+                    first_token.get_or_insert_with(Instant::now);
+                    pieces += 1;
+                    streamed.push_str(&text);
+                    emit(GenerationEvent::Token {
+                        id: token_id,
+                        text,
+                        logprob,
+                    });
+                }
+                Some(Event::Done {
+                    text,
+                    finish_reason,
+                    prompt_tokens,
+                    completion_tokens,
+                }) => {
+                    debug!(id, finish_reason = %finish_reason, "Engine generation finished");
+                    // Engines may answer with `done` alone; stream what was not streamed
+                    if let Some(rest) = text.strip_prefix(streamed.as_str()) {
+                        if !rest.is_empty() {
+                            emit(GenerationEvent::text(rest));
+                        }
+                    }
+                    let stats = GenerationStats::timed(
+                        prompt_tokens.unwrap_or(0),
+                        completion_tokens.unwrap_or(pieces),
+                        started,
+                        first_token,
+                    );
+                    emit(GenerationEvent::Done {
+                        finish_reason: FinishReason::parse(&finish_reason),
+                        stats,
+                    });
+                    break Ok(text);
+                }
+                Some(Event::Error { message }) => {
+                    break Err(anyhow!("generation failed: {}", message))
+                }
+                Some(Event::Overloaded { model }) => {
+                    let model = model.unwrap_or_else(|| self.load.model.clone());
+                    break Err(ShimmyError::Overloaded { model }.into());
+                }
+                Some(event) => break Err(unexpected("generate", event)),
+                None => break Err(anyhow!("{}", process.exit_reason())),
+            }
+        };
+        cancel.armed = false;
+        result
+    }
+
+    pub fn tokenize(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        match self.call(|id| Request::Tokenize {
+            id,
+            text,
+            add_special,
+        })? {
+            Event::Tokens { tokens } => Ok(tokens),
+            event => Err(unexpected("tokenize", event)),
+        }
+    }
+
+    pub fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        match self.call(|id| Request::Detokenize { id, tokens })? {
+            Event::Text { text } => Ok(text),
+            event => Err(unexpected("detokenize", event)),
+        }
+    }
+
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        match self.call(|id| Request::Embed { id, text })? {
+            Event::Embedding { embedding } => Ok(embedding),
+            event => Err(unexpected("embed", event)),
+        }
+    }
+
+    /// Ask the engine whether it is still serving its model
+    // For library users; the server finds a dead engine on its next request
+    #[allow(dead_code)]
+    pub fn health(&self) -> Result<()> {
+        match self.call(|id| Request::Health { id })? {
+            Event::Ready { .. } => Ok(()),
+            event => Err(unexpected("health", event)),
+        }
+    }
+}
+
+/// Serves models with `backend = "exec"` through their configured command
+#[derive(Debug, Default)]
+pub struct ExecEngine;
+
+impl ExecEngine {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait]
+impl InferenceEngine for ExecEngine {
+    async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+        let argv = spec.command.as_deref().ok_or_else(|| {
+            anyhow!(
+                "model '{}' sets backend = \"exec\" but has no command",
+                spec.name
+            )
+        })?;
+        let command = ExecCommand::from_argv(argv)?;
+        start_model(spec, command, "exec backend").await
+    }
+}
+
+/// Start `command` as the engine for `spec` and wait until its model is loaded
+pub(super) async fn start_model(
+    spec: &ModelSpec,
+    command: ExecCommand,
+    served_by: &str,
+) -> Result<Box<dyn LoadedModel>> {
+    let load = LoadRequest {
+        model: spec.base_path.to_string_lossy().to_string(),
+        adapter: spec
+            .lora_path
+            .as_ref()
+            .map(|p| p.to_string_lossy().to_string()),
+        device: None,
+        ctx_len: spec.ctx_len,
+    };
+    let worker = ExecWorker::start(command, load).await.map_err(|e| {
+        anyhow!(
+            "Failed to load '{}' with the {}: {}",
+            spec.name,
+            served_by,
+            e
+        )
+    })?;
+    Ok(Box::new(ExecModel { worker }))
+}
+
+struct ExecModel {
+    worker: ExecWorker,
+}
+
+#[async_trait]
+impl LoadedModel for ExecModel {
+    async fn generate(
+        &self,
+        prompt: &str,
+        opts: GenOptions,
+        on_token: Option<Box<dyn FnMut(String) + Send>>,
+    ) -> Result<String> {
+        self.worker.generate(prompt, &opts, on_token).await
+    }
+
+    fn generate_stream(self: Arc<Self>, prompt: String, opts: GenOptions) -> GenerationStream {
+        stream::from_future(|tx| async move {
+            let emit = |event| {
+                let _ = tx.send(event);
+            };
+            self.worker.generate_events(&prompt, &opts, emit).await?;
+            Ok(())
+        })
+    }
+
+    fn tokenize(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        self.worker.tokenize(text, add_special)
+    }
+
+    fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        self.worker.detokenize(tokens)
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.worker.embed(text)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.worker.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_from_argv() {
+        let argv = vec!["/opt/engine".to_string(), "--threads".into(), "8".into()];
+        let command = ExecCommand::from_argv(&argv).unwrap();
+        assert_eq!(command.program, "/opt/engine");
+        assert_eq!(command.args, ["--threads", "8"]);
+        assert!(ExecCommand::from_argv(&[]).is_err());
+    }
+
+    #[test]
+    fn test_requests_are_tagged_json_lines() {
+        let load = LoadRequest {
+            model: "m".into(),
+            adapter: None,
+            device: None,
+            ctx_len: 2048,
+        };
+        let line = serde_json::to_string(&Request::Load { id: 1, load: &load }).unwrap();
+        assert_eq!(
+            line,
+            r#"{"op":"load","id":1,"model":"m","adapter":null,"device":null,"ctx_len":2048}"#
+        );
+        let line = serde_json::to_string(&Request::Tokenize {
+            id: 2,
+            text: "a\nb",
+            add_special: true,
+        })
+        .unwrap();
+        assert!(!line.contains('\n'));
+
+        let parsed: EventLine =
+            serde_json::from_str(r#"{"id":3,"event":"tokens","tokens":[1,2]}"#).unwrap();
+        assert_eq!(parsed.id, 3);
+        assert!(matches!(parsed.event, Event::Tokens { tokens } if tokens == [1, 2]));
+
+        let parsed: EventLine = serde_json::from_str(
+            r#"{"id":4,"event":"token","text":"a","token_id":7,"logprob":-0.5}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            parsed.event,
+            Event::Token { token_id: Some(7), logprob: Some(p), .. } if p == -0.5
+        ));
+        let parsed: EventLine = serde_json::from_str(r#"{"id":5,"event":"overloaded"}"#).unwrap();
+        assert!(matches!(parsed.event, Event::Overloaded { model: None }));
+    }
+
+    #[tokio::test]
+    async fn test_missing_command_is_reported() {
+        let spec = ModelSpec {
+            name: "inhouse".to_string(),
+            base_path: "/models/inhouse".into(),
+            lora_path: None,
+            template: None,
+            ctx_len: 2048,
+            n_threads: None,
+            backend: Some(BACKEND_NAME.to_string()),
+            command: None,
+        };
+        let err = ExecEngine::new().load(&spec).await.err().unwrap();
+        assert!(err.to_string().contains("has no command"));
+    }
+
+    #[tokio::test]
+    async fn test_unstartable_engine_is_reported() {
+        let command = ExecCommand {
+            program: "/nonexistent/shimmy-engine".to_string(),
+            args: vec![],
+        };
+        let load = LoadRequest {
+            model: "m".into(),
+            adapter: None,
+            device: None,
+            ctx_len: 2048,
+        };
+        let err = ExecWorker::start(command, load).await.err().unwrap();
+        assert!(err.to_string().contains("cannot start engine"));
+    }
+}
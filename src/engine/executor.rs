@@ -0,0 +1,281 @@
+// Dedicated inference threads
+// Decoding is blocking FFI or tensor math that can run for seconds. Each
+// loaded model gets its own OS thread fed by a bounded job queue, so
+// generations never occupy tokio's workers and `/health`, SSE flushing and
+// other async work stay responsive while models are busy. One thread per
+// model matches how the backends work anyway: a model's context or KV cache
+// serves one generation at a time. Requests reach a model through the
+// loaded-model cache, so they all queue on the same thread. A job submitted
+// while the queue is full fails with `ShimmyError::Overloaded` instead of
+// waiting.
+
+use anyhow::{anyhow, Result};
+use std::panic::{self, AssertUnwindSafe};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+use super::stream::{self, ChoiceStream, GenerationEvent, GenerationStream};
+use crate::error::ShimmyError;
+
+/// Jobs a model may have queued behind the running one before more are rejected
+pub const DEFAULT_QUEUE_DEPTH: usize = 8;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Queue depth from `SHIMMY_INFERENCE_QUEUE`, or the default
+fn queue_depth() -> usize {
+    std::env::var("SHIMMY_INFERENCE_QUEUE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&depth| depth > 0)
+        .unwrap_or(DEFAULT_QUEUE_DEPTH)
+}
+
+/// The inference thread of one loaded model
+pub struct ModelExecutor {
+    model: String,
+    jobs: mpsc::Sender<Job>,
+}
+
+impl ModelExecutor {
+    /// Start the inference thread for `model`
+    pub fn new(model: &str) -> Result<Self> {
+        Self::with_queue_depth(model, queue_depth())
+    }
+
+    /// The thread runs jobs in submission order and exits once the executor
+    /// is dropped and the jobs already queued have finished.
+    pub fn with_queue_depth(model: &str, depth: usize) -> Result<Self> {
+        let (jobs, mut queue) = mpsc::channel::<Job>(depth.max(1));
+        let name = model.to_string();
+        std::thread::Builder::new()
+            .name(format!("shimmy-infer-{}", model))
+            .spawn(move || {
+                while let Some(job) = queue.blocking_recv() {
+                    // A panicking backend fails its own request, not the thread
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!(model = %name, "Inference job panicked");
+                    }
+                }
+            })?;
+        Ok(Self {
+            model: model.to_string(),
+            jobs,
+        })
+    }
+
+    /// Run `job` on the inference thread, or fail with
+    /// `ShimmyError::Overloaded` when the queue is full
+    pub async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        submit(self.jobs.clone(), self.model.clone(), job).await
+    }
+
+    /// Stream a generation that runs on the inference thread. A generation
+    /// whose stream is dropped while it is still queued never starts; once
+    /// running, `emit` returns `false` and the backend stops decoding. A full
+    /// queue ends the stream with an `Overloaded` event.
+    pub fn stream<F>(&self, run: F) -> GenerationStream
+    where
+        F: FnOnce(&mut dyn FnMut(GenerationEvent) -> bool) -> Result<()> + Send + 'static,
+    {
+        let (jobs, model) = (self.jobs.clone(), self.model.clone());
+        stream::from_future(move |tx| async move {
+            submit(jobs, model, move || {
+                if tx.is_closed() {
+                    return Ok(());
+                }
+                run(&mut |event| tx.send(event).is_ok())
+            })
+            .await?
+        })
+    }
+
+    /// `stream` for a generation that emits the events of several choices
+    #[cfg_attr(not(feature = "llama"), allow(dead_code))]
+    pub fn stream_choices<F>(&self, run: F) -> ChoiceStream
+    where
+        F: FnOnce(&mut dyn FnMut(usize, GenerationEvent) -> bool) -> Result<()> + Send + 'static,
+    {
+        let (jobs, model) = (self.jobs.clone(), self.model.clone());
+        stream::choices_from_future(move |tx| async move {
+            submit(jobs, model, move || {
+                if tx.is_closed() {
+                    return Ok(());
+                }
+                run(&mut |index, event| tx.send((index, event)).is_ok())
+            })
+            .await?
+        })
+    }
+}
+
+async fn submit<T, F>(jobs: mpsc::Sender<Job>, model: String, job: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (done, result) = oneshot::channel();
+    jobs.try_send(Box::new(move || {
+        let _ = done.send(job());
+    }))
+    .map_err(|e| match e {
+        TrySendError::Full(_) => ShimmyError::Overloaded { model }.into(),
+        TrySendError::Closed(_) => anyhow!("inference thread has stopped"),
+    })?;
+    result.await.map_err(|_| anyhow!("inference job panicked"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::stream::{complete, FinishReason, GenerationStats};
+    use futures_util::{FutureExt, StreamExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc as std_mpsc, Arc};
+    use std::time::Duration;
+
+    fn tokens(
+        texts: &'static [&'static str],
+    ) -> impl FnOnce(&mut dyn FnMut(GenerationEvent) -> bool) -> Result<()> {
+        move |emit| {
+            for text in texts {
+                emit(GenerationEvent::text(*text));
+            }
+            emit(GenerationEvent::Done {
+                finish_reason: FinishReason::Stop,
+                stats: GenerationStats::default(),
+            });
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_jobs_run_off_the_runtime_thread() {
+        let executor = ModelExecutor::new("test").unwrap();
+        let runtime_thread = std::thread::current().id();
+        let (thread, name) = executor
+            .run(|| {
+                let current = std::thread::current();
+                (current.id(), current.name().map(String::from))
+            })
+            .await
+            .unwrap();
+        assert_ne!(thread, runtime_thread);
+        assert_eq!(name.as_deref(), Some("shimmy-infer-test"));
+
+        let done = complete(executor.stream(tokens(&["a", "b"])), |_| {})
+            .await
+            .unwrap();
+        assert_eq!(done.text, "ab");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_runtime_stays_responsive_while_a_job_blocks() {
+        let executor = ModelExecutor::with_queue_depth("busy", 1).unwrap();
+        let (release, gate) = std_mpsc::channel::<()>();
+        let blocked = executor.run(move || gate.recv().unwrap());
+        tokio::pin!(blocked);
+        // The single-threaded runtime still runs other tasks
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut blocked)
+                .await
+                .is_err()
+        );
+        assert_eq!(tokio::spawn(async { 2 + 2 }).await.unwrap(), 4);
+        release.send(()).unwrap();
+        blocked.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped_streams_are_skipped_and_panics_are_contained() {
+        let executor = ModelExecutor::with_queue_depth("skip", 4).unwrap();
+        let (release, gate) = std_mpsc::channel::<()>();
+        let mut first = Box::pin(executor.run(move || gate.recv().unwrap()));
+        assert!((&mut first).now_or_never().is_none());
+
+        let started = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&started);
+        let mut queued = executor.stream(move |emit| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokens(&["never"])(emit)
+        });
+        // Poll once so the job is queued behind the blocked one, then drop it
+        assert!(queued.next().now_or_never().is_none());
+        drop(queued);
+        release.send(()).unwrap();
+        first.await.unwrap();
+
+        let err = executor
+            .run(|| -> u32 { panic!("backend bug") })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("panicked"));
+        assert_eq!(executor.run(|| 7).await.unwrap(), 7);
+        assert_eq!(started.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_rejects_jobs_as_overloaded() {
+        let executor = ModelExecutor::with_queue_depth("full", 1).unwrap();
+        let (release, gate) = std_mpsc::channel::<()>();
+        let (started, running) = std_mpsc::channel::<()>();
+        let mut first = Box::pin(executor.run(move || {
+            started.send(()).unwrap();
+            gate.recv().unwrap()
+        }));
+        assert!((&mut first).now_or_never().is_none());
+        // Off the queue and running, so one more job fits
+        running.recv().unwrap();
+        let mut queued = Box::pin(executor.run(|| 1));
+        assert!((&mut queued).now_or_never().is_none());
+
+        let err = executor.run(|| 2).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ShimmyError>(),
+            Some(ShimmyError::Overloaded { model }) if model == "full"
+        ));
+        let mut rejected = executor.stream(tokens(&["never"]));
+        assert_eq!(
+            rejected.next().await,
+            Some(GenerationEvent::Overloaded {
+                model: "full".to_string()
+            })
+        );
+        assert_eq!(rejected.next().await, None);
+
+        release.send(()).unwrap();
+        first.await.unwrap();
+        assert_eq!(queued.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_running_generation_stops_when_its_stream_is_dropped() {
+        let executor = ModelExecutor::with_queue_depth("cancel", 1).unwrap();
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&sent);
+        let mut running = executor.stream(move |emit| {
+            // A generation that would run for a long time
+            while counter.load(Ordering::SeqCst) < 100_000 {
+                if !emit(GenerationEvent::text("x")) {
+                    return Ok(());
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            Ok(())
+        });
+        assert_eq!(running.next().await, Some(GenerationEvent::text("x")));
+        drop(running);
+        // Jobs run in order, so this finishes only after the generation stopped
+        tokio::time::timeout(Duration::from_secs(10), executor.run(|| ()))
+            .await
+            .expect("the inference thread is still decoding")
+            .unwrap();
+        assert!(sent.load(Ordering::SeqCst) < 100_000);
+    }
+}
@@ -0,0 +1,1039 @@
+// GGUF container support - pure Rust, no llama.cpp required
+// Reads GGUF v2/v3 headers, metadata and tensor tables, and dequantizes the
+// block formats we can run natively (F32/F16/BF16/Q4_0/Q8_0/Q4_K/Q6_K).
+
+use anyhow::{anyhow, bail, Result};
+use half::{bf16, f16};
+use memmap2::Mmap;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::shards::ShardName;
+
+pub const GGUF_MAGIC: [u8; 4] = *b"GGUF";
+pub const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+/// Arrays of arrays nested deeper than this are rejected rather than
+/// recursed into
+const MAX_ARRAY_DEPTH: usize = 16;
+
+/// Quick check for the GGUF magic bytes without parsing the whole header
+pub fn has_gguf_magic(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map(|_| magic == GGUF_MAGIC)
+        .unwrap_or(false)
+}
+
+/// Every file of a GGUF model in split order: just `path`, or all
+/// `<prefix>-NNNNN-of-MMMMM.gguf` splits next to it (any split may be given).
+/// Errors name the splits that are missing.
+pub fn split_files(path: &Path) -> Result<Vec<PathBuf>> {
+    match ShardName::from_path(path, "gguf") {
+        Some(shard) => shard.paths_in(path.parent().unwrap_or_else(|| Path::new("."))),
+        None => Ok(vec![path.to_path_buf()]),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgmlType {
+    F32,
+    F16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q8_1,
+    Q2K,
+    Q3K,
+    Q4K,
+    Q5K,
+    Q6K,
+    Q8K,
+    BF16,
+}
+
+impl GgmlType {
+    pub fn from_u32(v: u32) -> Result<Self> {
+        Ok(match v {
+            0 => GgmlType::F32,
+            1 => GgmlType::F16,
+            2 => GgmlType::Q4_0,
+            3 => GgmlType::Q4_1,
+            6 => GgmlType::Q5_0,
+            7 => GgmlType::Q5_1,
+            8 => GgmlType::Q8_0,
+            9 => GgmlType::Q8_1,
+            10 => GgmlType::Q2K,
+            11 => GgmlType::Q3K,
+            12 => GgmlType::Q4K,
+            13 => GgmlType::Q5K,
+            14 => GgmlType::Q6K,
+            15 => GgmlType::Q8K,
+            30 => GgmlType::BF16,
+            other => bail!("unsupported ggml tensor type id {}", other),
+        })
+    }
+
+    pub fn as_u32(self) -> u32 {
+        match self {
+            GgmlType::F32 => 0,
+            GgmlType::F16 => 1,
+            GgmlType::Q4_0 => 2,
+            GgmlType::Q4_1 => 3,
+            GgmlType::Q5_0 => 6,
+            GgmlType::Q5_1 => 7,
+            GgmlType::Q8_0 => 8,
+            GgmlType::Q8_1 => 9,
+            GgmlType::Q2K => 10,
+            GgmlType::Q3K => 11,
+            GgmlType::Q4K => 12,
+            GgmlType::Q5K => 13,
+            GgmlType::Q6K => 14,
+            GgmlType::Q8K => 15,
+            GgmlType::BF16 => 30,
+        }
+    }
+
+    /// Number of elements per quantization block
+    pub fn block_size(self) -> usize {
+        match self {
+            GgmlType::F32 | GgmlType::F16 | GgmlType::BF16 => 1,
+            GgmlType::Q4_0 | GgmlType::Q4_1 | GgmlType::Q5_0 | GgmlType::Q5_1 => 32,
+            GgmlType::Q8_0 | GgmlType::Q8_1 => 32,
+            GgmlType::Q2K | GgmlType::Q3K | GgmlType::Q4K | GgmlType::Q5K => 256,
+            GgmlType::Q6K | GgmlType::Q8K => 256,
+        }
+    }
+
+    /// Size in bytes of one block
+    pub fn type_size(self) -> usize {
+        match self {
+            GgmlType::F32 => 4,
+            GgmlType::F16 | GgmlType::BF16 => 2,
+            GgmlType::Q4_0 => 2 + 16,
+            GgmlType::Q4_1 => 2 + 2 + 16,
+            GgmlType::Q5_0 => 2 + 4 + 16,
+            GgmlType::Q5_1 => 2 + 2 + 4 + 16,
+            GgmlType::Q8_0 => 2 + 32,
+            GgmlType::Q8_1 => 4 + 4 + 32,
+            GgmlType::Q2K => 256 / 16 + 256 / 4 + 2 + 2,
+            GgmlType::Q3K => 256 / 8 + 256 / 4 + 12 + 2,
+            GgmlType::Q4K => 2 + 2 + 12 + 256 / 2,
+            GgmlType::Q5K => 2 + 2 + 12 + 256 / 8 + 256 / 2,
+            GgmlType::Q6K => 256 / 2 + 256 / 4 + 256 / 16 + 2,
+            GgmlType::Q8K => 4 + 256 + 256 / 16 * 2,
+        }
+    }
+
+    /// Bytes needed to store `n` elements of this type
+    pub fn row_bytes(self, n: usize) -> usize {
+        (n / self.block_size()).saturating_mul(self.type_size())
+    }
+
+    /// Whether the native engine can dequantize this type
+    pub fn is_supported(self) -> bool {
+        matches!(
+            self,
+            GgmlType::F32
+                | GgmlType::F16
+                | GgmlType::BF16
+                | GgmlType::Q4_0
+                | GgmlType::Q8_0
+                | GgmlType::Q4K
+                | GgmlType::Q6K
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<MetadataValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl MetadataValue {
+    fn type_id(&self) -> u32 {
+        match self {
+            MetadataValue::U8(_) => 0,
+            MetadataValue::I8(_) => 1,
+            MetadataValue::U16(_) => 2,
+            MetadataValue::I16(_) => 3,
+            MetadataValue::U32(_) => 4,
+            MetadataValue::I32(_) => 5,
+            MetadataValue::F32(_) => 6,
+            MetadataValue::Bool(_) => 7,
+            MetadataValue::String(_) => 8,
+            MetadataValue::Array(_) => 9,
+            MetadataValue::U64(_) => 10,
+            MetadataValue::I64(_) => 11,
+            MetadataValue::F64(_) => 12,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            MetadataValue::U8(v) => Some(v as u64),
+            MetadataValue::U16(v) => Some(v as u64),
+            MetadataValue::U32(v) => Some(v as u64),
+            MetadataValue::U64(v) => Some(v),
+            MetadataValue::I8(v) if v >= 0 => Some(v as u64),
+            MetadataValue::I16(v) if v >= 0 => Some(v as u64),
+            MetadataValue::I32(v) if v >= 0 => Some(v as u64),
+            MetadataValue::I64(v) if v >= 0 => Some(v as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            MetadataValue::I8(v) => Some(v as i64),
+            MetadataValue::I16(v) => Some(v as i64),
+            MetadataValue::I32(v) => Some(v as i64),
+            MetadataValue::I64(v) => Some(v),
+            _ => self.as_u64().map(|v| v as i64),
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            MetadataValue::F32(v) => Some(v),
+            MetadataValue::F64(v) => Some(v as f32),
+            _ => self.as_i64().map(|v| v as f32),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            MetadataValue::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MetadataValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[MetadataValue]> {
+        match self {
+            MetadataValue::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TensorInfo {
+    pub name: String,
+    /// Dimensions in ggml order: `dims[0]` is the innermost (contiguous) axis
+    pub dims: Vec<u64>,
+    pub ggml_type: GgmlType,
+    /// Offset relative to the start of the tensor data section
+    pub offset: u64,
+}
+
+impl TensorInfo {
+    pub fn n_elements(&self) -> usize {
+        // Saturates so hostile dims fail the bounds check instead of overflowing
+        let n = self.dims.iter().fold(1u64, |n, &d| n.saturating_mul(d));
+        usize::try_from(n).unwrap_or(usize::MAX)
+    }
+
+    pub fn n_bytes(&self) -> usize {
+        self.ggml_type.row_bytes(self.n_elements())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GgufHeader {
+    pub version: u32,
+    pub metadata: BTreeMap<String, MetadataValue>,
+    pub tensors: Vec<TensorInfo>,
+    /// Absolute file offset of the tensor data section
+    pub data_offset: u64,
+}
+
+impl GgufHeader {
+    pub fn get(&self, key: &str) -> Option<&MetadataValue> {
+        self.metadata.get(key)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(|v| v.as_str())
+    }
+
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(|v| v.as_u64())
+    }
+
+    pub fn get_f32(&self, key: &str) -> Option<f32> {
+        self.get(key).and_then(|v| v.as_f32())
+    }
+
+    pub fn architecture(&self) -> Option<&str> {
+        self.get_str("general.architecture")
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&TensorInfo> {
+        self.tensors.iter().find(|t| t.name == name)
+    }
+
+    /// Parse a GGUF header from the beginning of `bytes`
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut r = ByteReader { bytes, pos: 0 };
+        if r.take(4)? != GGUF_MAGIC {
+            bail!("not a GGUF file (bad magic)");
+        }
+        let version = r.u32()?;
+        if !(2..=3).contains(&version) {
+            bail!("unsupported GGUF version {}", version);
+        }
+        let n_tensors = r.u64()?;
+        let n_kv = r.u64()?;
+
+        let mut metadata = BTreeMap::new();
+        for _ in 0..n_kv {
+            let key = r.string()?;
+            let ty = r.u32()?;
+            let value = r.value(ty, 0)?;
+            metadata.insert(key, value);
+        }
+
+        // Counts come from the file; a corrupt one must not size huge allocations
+        let mut tensors = Vec::with_capacity((n_tensors as usize).min(1 << 20));
+        for _ in 0..n_tensors {
+            let name = r.string()?;
+            let n_dims = r.u32()? as usize;
+            let mut dims = Vec::with_capacity(n_dims.min(8));
+            for _ in 0..n_dims {
+                dims.push(r.u64()?);
+            }
+            let ggml_type = GgmlType::from_u32(r.u32()?)?;
+            let offset = r.u64()?;
+            tensors.push(TensorInfo {
+                name,
+                dims,
+                ggml_type,
+                offset,
+            });
+        }
+
+        let alignment = match metadata.get("general.alignment") {
+            None => GGUF_DEFAULT_ALIGNMENT,
+            Some(value) => match value.as_u64() {
+                Some(alignment) if alignment.is_power_of_two() => alignment,
+                _ => bail!("general.alignment must be a power of two, got {:?}", value),
+            },
+        };
+        let data_offset = align_up(r.pos as u64, alignment);
+
+        Ok(GgufHeader {
+            version,
+            metadata,
+            tensors,
+            data_offset,
+        })
+    }
+}
+
+/// A memory-mapped GGUF file with its parsed header
+pub struct GgufFile {
+    pub header: GgufHeader,
+    mmap: Mmap,
+}
+
+impl std::fmt::Debug for GgufFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GgufFile")
+            .field("version", &self.header.version)
+            .field("tensors", &self.header.tensors.len())
+            .field("bytes", &self.mmap.len())
+            .finish()
+    }
+}
+
+impl GgufFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .map_err(|e| anyhow!("failed to open GGUF file {}: {}", path.display(), e))?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let header = GgufHeader::parse(&mmap)?;
+        Ok(Self { header, mmap })
+    }
+
+    /// The whole mapped file
+    pub fn bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    /// Raw bytes of a tensor, bounds-checked against the file
+    pub fn tensor_data(&self, info: &TensorInfo) -> Result<&[u8]> {
+        let start = self
+            .header
+            .data_offset
+            .checked_add(info.offset)
+            .and_then(|start| usize::try_from(start).ok());
+        let end = start.and_then(|start| start.checked_add(info.n_bytes()));
+        let (Some(start), Some(end)) = (start, end) else {
+            bail!("tensor '{}' has an out-of-range offset", info.name);
+        };
+        self.mmap.get(start..end).ok_or_else(|| {
+            anyhow!(
+                "tensor '{}' extends past end of file ({}..{} > {})",
+                info.name,
+                start,
+                end,
+                self.mmap.len()
+            )
+        })
+    }
+}
+
+fn align_up(v: u64, alignment: u64) -> u64 {
+    v.div_ceil(alignment) * alignment
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&e| e <= self.bytes.len())
+            .ok_or_else(|| anyhow!("unexpected end of GGUF header at byte {}", self.pos))?;
+        let out = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("length checked"))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u64()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    /// A metadata value of type `ty`, inside `depth` enclosing arrays
+    fn value(&mut self, ty: u32, depth: usize) -> Result<MetadataValue> {
+        Ok(match ty {
+            0 => MetadataValue::U8(self.array::<1>()?[0]),
+            1 => MetadataValue::I8(self.array::<1>()?[0] as i8),
+            2 => MetadataValue::U16(u16::from_le_bytes(self.array()?)),
+            3 => MetadataValue::I16(i16::from_le_bytes(self.array()?)),
+            4 => MetadataValue::U32(self.u32()?),
+            5 => MetadataValue::I32(i32::from_le_bytes(self.array()?)),
+            6 => MetadataValue::F32(f32::from_le_bytes(self.array()?)),
+            7 => MetadataValue::Bool(self.array::<1>()?[0] != 0),
+            8 => MetadataValue::String(self.string()?),
+            9 => {
+                if depth >= MAX_ARRAY_DEPTH {
+                    bail!(
+                        "GGUF metadata arrays nested deeper than {}",
+                        MAX_ARRAY_DEPTH
+                    );
+                }
+                let elem_ty = self.u32()?;
+                let len = self.u64()? as usize;
+                let mut items = Vec::with_capacity(len.min(1 << 20));
+                for _ in 0..len {
+                    items.push(self.value(elem_ty, depth + 1)?);
+                }
+                MetadataValue::Array(items)
+            }
+            10 => MetadataValue::U64(self.u64()?),
+            11 => MetadataValue::I64(i64::from_le_bytes(self.array()?)),
+            12 => MetadataValue::F64(f64::from_le_bytes(self.array()?)),
+            other => bail!("unknown GGUF metadata value type {}", other),
+        })
+    }
+}
+
+/// Tensor payload for [`GgufWriter`]
+enum TensorData {
+    #[cfg(test)]
+    Bytes(Vec<u8>),
+    /// Encoded while writing, so large conversions never hold every tensor at once
+    Deferred(Box<dyn Fn() -> Result<Vec<u8>>>),
+}
+
+/// Minimal GGUF v3 writer. Tensors are written in insertion order, each
+/// aligned to `general.alignment`.
+#[derive(Default)]
+pub struct GgufWriter {
+    metadata: Vec<(String, MetadataValue)>,
+    tensors: Vec<(String, Vec<u64>, GgmlType, TensorData)>,
+}
+
+impl GgufWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_metadata(&mut self, key: impl Into<String>, value: MetadataValue) -> &mut Self {
+        self.metadata.push((key.into(), value));
+        self
+    }
+
+    /// Add a tensor. `dims` are in ggml order (innermost first) and `data`
+    /// must already be encoded as `ggml_type`. Conversions defer encoding
+    /// instead, so only tests build files this way.
+    #[cfg(test)]
+    pub fn add_tensor(
+        &mut self,
+        name: impl Into<String>,
+        dims: Vec<u64>,
+        ggml_type: GgmlType,
+        data: Vec<u8>,
+    ) -> Result<&mut Self> {
+        let name = name.into();
+        let expected = ggml_type.row_bytes(dims.iter().product::<u64>() as usize);
+        if data.len() != expected {
+            bail!(
+                "tensor '{}' has {} bytes, expected {} for {:?}",
+                name,
+                data.len(),
+                expected,
+                ggml_type
+            );
+        }
+        self.tensors
+            .push((name, dims, ggml_type, TensorData::Bytes(data)));
+        Ok(self)
+    }
+
+    /// Add a tensor whose bytes are produced by `encode` when the file is
+    /// written. The result is checked against `dims` and `ggml_type` then.
+    pub fn add_deferred_tensor(
+        &mut self,
+        name: impl Into<String>,
+        dims: Vec<u64>,
+        ggml_type: GgmlType,
+        encode: impl Fn() -> Result<Vec<u8>> + 'static,
+    ) -> &mut Self {
+        self.tensors.push((
+            name.into(),
+            dims,
+            ggml_type,
+            TensorData::Deferred(Box::new(encode)),
+        ));
+        self
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        let alignment = GGUF_DEFAULT_ALIGNMENT;
+        let mut head = Vec::new();
+        head.extend_from_slice(&GGUF_MAGIC);
+        head.extend_from_slice(&3u32.to_le_bytes());
+        head.extend_from_slice(&(self.tensors.len() as u64).to_le_bytes());
+        head.extend_from_slice(&(self.metadata.len() as u64).to_le_bytes());
+        for (key, value) in &self.metadata {
+            write_string(&mut head, key);
+            head.extend_from_slice(&value.type_id().to_le_bytes());
+            write_value(&mut head, value);
+        }
+
+        let n_bytes =
+            |dims: &[u64], ty: GgmlType| ty.row_bytes(dims.iter().product::<u64>() as usize);
+        let mut offset = 0u64;
+        for (name, dims, ty, _) in &self.tensors {
+            write_string(&mut head, name);
+            head.extend_from_slice(&(dims.len() as u32).to_le_bytes());
+            for d in dims {
+                head.extend_from_slice(&d.to_le_bytes());
+            }
+            head.extend_from_slice(&ty.as_u32().to_le_bytes());
+            head.extend_from_slice(&offset.to_le_bytes());
+            offset = align_up(offset + n_bytes(dims, *ty) as u64, alignment);
+        }
+        head.resize(align_up(head.len() as u64, alignment) as usize, 0);
+        w.write_all(&head)?;
+
+        for (name, dims, ty, data) in &self.tensors {
+            let deferred;
+            let data = match data {
+                #[cfg(test)]
+                TensorData::Bytes(bytes) => bytes,
+                TensorData::Deferred(encode) => {
+                    deferred = encode()?;
+                    if deferred.len() != n_bytes(dims, *ty) {
+                        bail!(
+                            "tensor '{}' encoded to {} bytes, expected {} for {:?}",
+                            name,
+                            deferred.len(),
+                            n_bytes(dims, *ty),
+                            ty
+                        );
+                    }
+                    &deferred
+                }
+            };
+            w.write_all(data)?;
+            let pad = align_up(data.len() as u64, alignment) as usize - data.len();
+            w.write_all(&vec![0u8; pad])?;
+        }
+        Ok(())
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<()> {
+        let mut file = std::io::BufWriter::new(File::create(path)?);
+        self.write_to(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &MetadataValue) {
+    match value {
+        MetadataValue::U8(v) => out.push(*v),
+        MetadataValue::I8(v) => out.push(*v as u8),
+        MetadataValue::U16(v) => out.extend_from_slice(&v.to_le_bytes()),
+        MetadataValue::I16(v) => out.extend_from_slice(&v.to_le_bytes()),
+        MetadataValue::U32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        MetadataValue::I32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        MetadataValue::F32(v) => out.extend_from_slice(&v.to_le_bytes()),
+        MetadataValue::Bool(v) => out.push(*v as u8),
+        MetadataValue::String(s) => write_string(out, s),
+        MetadataValue::Array(items) => {
+            let elem_ty = items.first().map(|v| v.type_id()).unwrap_or(4);
+            out.extend_from_slice(&elem_ty.to_le_bytes());
+            out.extend_from_slice(&(items.len() as u64).to_le_bytes());
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        MetadataValue::U64(v) => out.extend_from_slice(&v.to_le_bytes()),
+        MetadataValue::I64(v) => out.extend_from_slice(&v.to_le_bytes()),
+        MetadataValue::F64(v) => out.extend_from_slice(&v.to_le_bytes()),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Dequantization
+// ---------------------------------------------------------------------------
+
+#[inline]
+fn f16_at(bytes: &[u8], i: usize) -> f32 {
+    f16::from_le_bytes([bytes[i], bytes[i + 1]]).to_f32()
+}
+
+/// Dequantize `bytes` (encoded as `ty`) into `out`. `out.len()` must be a
+/// multiple of the block size and match the encoded element count.
+pub fn dequantize(ty: GgmlType, bytes: &[u8], out: &mut [f32]) -> Result<()> {
+    if ty.row_bytes(out.len()) != bytes.len() || !out.len().is_multiple_of(ty.block_size()) {
+        bail!(
+            "dequantize {:?}: {} bytes do not hold {} elements",
+            ty,
+            bytes.len(),
+            out.len()
+        );
+    }
+    let bs = ty.block_size();
+    let ts = ty.type_size();
+    match ty {
+        GgmlType::F32 => {
+            for (o, c) in out.iter_mut().zip(bytes.chunks_exact(4)) {
+                *o = f32::from_le_bytes([c[0], c[1], c[2], c[3]]);
+            }
+        }
+        GgmlType::F16 => {
+            for (o, c) in out.iter_mut().zip(bytes.chunks_exact(2)) {
+                *o = f16::from_le_bytes([c[0], c[1]]).to_f32();
+            }
+        }
+        GgmlType::BF16 => {
+            for (o, c) in out.iter_mut().zip(bytes.chunks_exact(2)) {
+                *o = bf16::from_le_bytes([c[0], c[1]]).to_f32();
+            }
+        }
+        GgmlType::Q4_0 => {
+            for (y, b) in out.chunks_exact_mut(bs).zip(bytes.chunks_exact(ts)) {
+                dequant_block_q4_0(b, y);
+            }
+        }
+        GgmlType::Q8_0 => {
+            for (y, b) in out.chunks_exact_mut(bs).zip(bytes.chunks_exact(ts)) {
+                dequant_block_q8_0(b, y);
+            }
+        }
+        GgmlType::Q4K => {
+            for (y, b) in out.chunks_exact_mut(bs).zip(bytes.chunks_exact(ts)) {
+                dequant_block_q4_k(b, y);
+            }
+        }
+        GgmlType::Q6K => {
+            for (y, b) in out.chunks_exact_mut(bs).zip(bytes.chunks_exact(ts)) {
+                dequant_block_q6_k(b, y);
+            }
+        }
+        other => bail!("dequantization of {:?} is not supported", other),
+    }
+    Ok(())
+}
+
+fn dequant_block_q4_0(b: &[u8], y: &mut [f32]) {
+    let d = f16_at(b, 0);
+    let qs = &b[2..18];
+    for j in 0..16 {
+        y[j] = ((qs[j] & 0x0F) as i32 - 8) as f32 * d;
+        y[j + 16] = ((qs[j] >> 4) as i32 - 8) as f32 * d;
+    }
+}
+
+fn dequant_block_q8_0(b: &[u8], y: &mut [f32]) {
+    let d = f16_at(b, 0);
+    for (o, &q) in y.iter_mut().zip(&b[2..34]) {
+        *o = (q as i8) as f32 * d;
+    }
+}
+
+#[inline]
+fn scale_min_k4(j: usize, q: &[u8]) -> (u8, u8) {
+    if j < 4 {
+        (q[j] & 63, q[j + 4] & 63)
+    } else {
+        (
+            (q[j + 4] & 0x0F) | ((q[j - 4] >> 6) << 4),
+            (q[j + 4] >> 4) | ((q[j] >> 6) << 4),
+        )
+    }
+}
+
+fn dequant_block_q4_k(b: &[u8], y: &mut [f32]) {
+    let d = f16_at(b, 0);
+    let dmin = f16_at(b, 2);
+    let scales = &b[4..16];
+    let qs = &b[16..144];
+    for (chunk, (y, q)) in y.chunks_exact_mut(64).zip(qs.chunks_exact(32)).enumerate() {
+        let (sc1, m1) = scale_min_k4(chunk * 2, scales);
+        let (sc2, m2) = scale_min_k4(chunk * 2 + 1, scales);
+        let (d1, m1) = (d * sc1 as f32, dmin * m1 as f32);
+        let (d2, m2) = (d * sc2 as f32, dmin * m2 as f32);
+        for l in 0..32 {
+            y[l] = d1 * (q[l] & 0x0F) as f32 - m1;
+            y[l + 32] = d2 * (q[l] >> 4) as f32 - m2;
+        }
+    }
+}
+
+fn dequant_block_q6_k(b: &[u8], y: &mut [f32]) {
+    let ql_all = &b[0..128];
+    let qh_all = &b[128..192];
+    let sc_all = &b[192..208];
+    let d = f16_at(b, 208);
+    for n in 0..2 {
+        let ql = &ql_all[n * 64..];
+        let qh = &qh_all[n * 32..];
+        let sc = &sc_all[n * 8..];
+        let y = &mut y[n * 128..];
+        for l in 0..32 {
+            let is = l / 16;
+            let q1 = ((ql[l] & 0x0F) | ((qh[l] & 3) << 4)) as i32 - 32;
+            let q2 = ((ql[l + 32] & 0x0F) | (((qh[l] >> 2) & 3) << 4)) as i32 - 32;
+            let q3 = ((ql[l] >> 4) | (((qh[l] >> 4) & 3) << 4)) as i32 - 32;
+            let q4 = ((ql[l + 32] >> 4) | (((qh[l] >> 6) & 3) << 4)) as i32 - 32;
+            y[l] = d * (sc[is] as i8) as f32 * q1 as f32;
+            y[l + 32] = d * (sc[is + 2] as i8) as f32 * q2 as f32;
+            y[l + 64] = d * (sc[is + 4] as i8) as f32 * q3 as f32;
+            y[l + 96] = d * (sc[is + 6] as i8) as f32 * q4 as f32;
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Quantization (used by writers and tests)
+// ---------------------------------------------------------------------------
+
+/// Encode f32 values as `ty`. Only the simple formats are supported.
+pub fn quantize(ty: GgmlType, values: &[f32]) -> Result<Vec<u8>> {
+    if !values.len().is_multiple_of(ty.block_size()) {
+        bail!(
+            "{} values is not a multiple of the {:?} block size {}",
+            values.len(),
+            ty,
+            ty.block_size()
+        );
+    }
+    let mut out = Vec::with_capacity(ty.row_bytes(values.len()));
+    match ty {
+        GgmlType::F32 => values
+            .iter()
+            .for_each(|v| out.extend_from_slice(&v.to_le_bytes())),
+        GgmlType::F16 => values
+            .iter()
+            .for_each(|v| out.extend_from_slice(&f16::from_f32(*v).to_le_bytes())),
+        GgmlType::BF16 => values
+            .iter()
+            .for_each(|v| out.extend_from_slice(&bf16::from_f32(*v).to_le_bytes())),
+        GgmlType::Q8_0 => {
+            for block in values.chunks_exact(32) {
+                let amax = block.iter().fold(0f32, |m, v| m.max(v.abs()));
+                let d = amax / 127.0;
+                let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+                out.extend_from_slice(&f16::from_f32(d).to_le_bytes());
+                out.extend(block.iter().map(|v| (v * id).round() as i8 as u8));
+            }
+        }
+        GgmlType::Q4_0 => {
+            for block in values.chunks_exact(32) {
+                let max = block
+                    .iter()
+                    .copied()
+                    .fold(0f32, |m, v| if v.abs() > m.abs() { v } else { m });
+                let d = max / -8.0;
+                let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+                out.extend_from_slice(&f16::from_f32(d).to_le_bytes());
+                for j in 0..16 {
+                    let lo = ((block[j] * id + 8.5) as i32).clamp(0, 15) as u8;
+                    let hi = ((block[j + 16] * id + 8.5) as i32).clamp(0, 15) as u8;
+                    out.push(lo | (hi << 4));
+                }
+            }
+        }
+        other => bail!("quantization to {:?} is not supported", other),
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_values(n: usize) -> Vec<f32> {
+        (0..n).map(|i| ((i as f32) * 0.37).sin() * 2.0).collect()
+    }
+
+    #[test]
+    fn test_type_sizes_match_ggml() {
+        assert_eq!(GgmlType::Q4_0.type_size(), 18);
+        assert_eq!(GgmlType::Q8_0.type_size(), 34);
+        assert_eq!(GgmlType::Q4K.type_size(), 144);
+        assert_eq!(GgmlType::Q6K.type_size(), 210);
+        assert_eq!(GgmlType::Q4K.row_bytes(512), 288);
+    }
+
+    #[test]
+    fn test_q8_0_roundtrip() {
+        let values = sample_values(64);
+        let bytes = quantize(GgmlType::Q8_0, &values).unwrap();
+        let mut out = vec![0f32; 64];
+        dequantize(GgmlType::Q8_0, &bytes, &mut out).unwrap();
+        for (a, b) in values.iter().zip(&out) {
+            assert!((a - b).abs() < 0.02, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_q4_0_roundtrip() {
+        let values = sample_values(32);
+        let bytes = quantize(GgmlType::Q4_0, &values).unwrap();
+        let mut out = vec![0f32; 32];
+        dequantize(GgmlType::Q4_0, &bytes, &mut out).unwrap();
+        // One quantization step is amax / 8; the side opposite amax clamps at 7 steps
+        for (a, b) in values.iter().zip(&out) {
+            assert!((a - b).abs() <= 0.26, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_q4_k_block_dequant() {
+        // d = 1.0, dmin = 0.5, scale/min of sub-block 0 = 2/1, nibbles all 3
+        let mut block = vec![0u8; GgmlType::Q4K.type_size()];
+        block[0..2].copy_from_slice(&f16::from_f32(1.0).to_le_bytes());
+        block[2..4].copy_from_slice(&f16::from_f32(0.5).to_le_bytes());
+        block[4] = 2;
+        block[8] = 1;
+        for q in &mut block[16..144] {
+            *q = 0x33;
+        }
+        let mut out = vec![0f32; 256];
+        dequantize(GgmlType::Q4K, &block, &mut out).unwrap();
+        assert_eq!(out[0], 2.0 * 3.0 - 0.5);
+        // Sub-block 1 has zero scale and zero min
+        assert_eq!(out[32], 0.0);
+    }
+
+    #[test]
+    fn test_q6_k_block_dequant() {
+        let mut block = vec![0u8; GgmlType::Q6K.type_size()];
+        // low nibble 1, high bits 0 -> q = 1 - 32 = -31
+        for q in &mut block[0..128] {
+            *q = 0x11;
+        }
+        for s in &mut block[192..208] {
+            *s = 1;
+        }
+        block[208..210].copy_from_slice(&f16::from_f32(0.5).to_le_bytes());
+        let mut out = vec![0f32; 256];
+        dequantize(GgmlType::Q6K, &block, &mut out).unwrap();
+        assert!(out.iter().all(|&v| v == -15.5));
+    }
+
+    #[test]
+    fn test_dequantize_rejects_size_mismatch() {
+        let mut out = vec![0f32; 32];
+        assert!(dequantize(GgmlType::Q8_0, &[0u8; 10], &mut out).is_err());
+    }
+
+    #[test]
+    fn test_writer_reader_roundtrip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        let values = sample_values(64);
+
+        let mut writer = GgufWriter::new();
+        writer
+            .add_metadata(
+                "general.architecture",
+                MetadataValue::String("llama".into()),
+            )
+            .add_metadata("llama.block_count", MetadataValue::U32(2))
+            .add_metadata(
+                "tokenizer.ggml.tokens",
+                MetadataValue::Array(vec![
+                    MetadataValue::String("a".into()),
+                    MetadataValue::String("b".into()),
+                ]),
+            );
+        writer
+            .add_tensor(
+                "w",
+                vec![32, 2],
+                GgmlType::Q8_0,
+                quantize(GgmlType::Q8_0, &values).unwrap(),
+            )
+            .unwrap();
+        writer
+            .add_tensor(
+                "f",
+                vec![3],
+                GgmlType::F32,
+                quantize(GgmlType::F32, &[1.0, 2.0, 3.0]).unwrap(),
+            )
+            .unwrap();
+        writer.write_file(&path).unwrap();
+
+        assert!(has_gguf_magic(&path));
+        let file = GgufFile::open(&path).unwrap();
+        assert_eq!(file.header.version, 3);
+        assert_eq!(file.header.architecture(), Some("llama"));
+        assert_eq!(file.header.get_u64("llama.block_count"), Some(2));
+
+        let f = file.header.tensor("f").unwrap().clone();
+        let mut out = vec![0f32; 3];
+        dequantize(f.ggml_type, file.tensor_data(&f).unwrap(), &mut out).unwrap();
+        assert_eq!(out, vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_magic() {
+        assert!(GgufHeader::parse(b"GGML\x03\0\0\0").is_err());
+        assert!(GgufHeader::parse(b"GG").is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated_huge_counts() {
+        // Claims u64::MAX tensors and no metadata, then ends
+        let mut bytes = GGUF_MAGIC.to_vec();
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        assert!(GgufHeader::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_alignment_and_deep_arrays() {
+        // A header without tensors whose one key is `key` of type `ty`
+        let header = |key: &str, ty: u32, value: &[u8]| {
+            let mut bytes = GGUF_MAGIC.to_vec();
+            bytes.extend_from_slice(&3u32.to_le_bytes());
+            bytes.extend_from_slice(&0u64.to_le_bytes());
+            bytes.extend_from_slice(&1u64.to_le_bytes());
+            bytes.extend_from_slice(&(key.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(key.as_bytes());
+            bytes.extend_from_slice(&ty.to_le_bytes());
+            bytes.extend_from_slice(value);
+            bytes
+        };
+        let aligned = |alignment: u32| {
+            GgufHeader::parse(&header("general.alignment", 4, &alignment.to_le_bytes()))
+        };
+        assert_eq!(aligned(64).unwrap().data_offset % 64, 0);
+        assert!(aligned(0).is_err());
+        assert!(aligned(48).is_err());
+
+        // Arrays of one array each, `depth` deep, around a u8
+        let nested = |depth: usize| {
+            let mut value = Vec::new();
+            for level in 1..=depth {
+                let elem_ty: u32 = if level == depth { 0 } else { 9 };
+                value.extend_from_slice(&elem_ty.to_le_bytes());
+                value.extend_from_slice(&1u64.to_le_bytes());
+            }
+            value.push(7);
+            GgufHeader::parse(&header("deep", 9, &value))
+        };
+        assert!(nested(MAX_ARRAY_DEPTH).is_ok());
+        assert!(nested(MAX_ARRAY_DEPTH + 1).is_err());
+        assert!(nested(100_000).is_err());
+    }
+
+    #[test]
+    fn test_tensor_data_rejects_overflowing_offset() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        let mut writer = GgufWriter::new();
+        writer
+            .add_tensor(
+                "f",
+                vec![1],
+                GgmlType::F32,
+                quantize(GgmlType::F32, &[1.0]).unwrap(),
+            )
+            .unwrap();
+        writer.write_file(&path).unwrap();
+
+        let file = GgufFile::open(&path).unwrap();
+        let mut info = file.header.tensor("f").unwrap().clone();
+        info.offset = u64::MAX;
+        assert!(file.tensor_data(&info).is_err());
+        info.offset = 0;
+        info.dims = vec![u64::MAX, u64::MAX];
+        assert!(file.tensor_data(&info).is_err());
+    }
+}
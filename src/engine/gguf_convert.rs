@@ -0,0 +1,1316 @@
+// Native SafeTensors -> GGUF conversion - NO Python dependency
+// Converts HF checkpoints and PEFT LoRA adapters into GGUF files that llama.cpp
+// (and the native GGUF engine) can load. Results live in a conversion cache so
+// source directories are never written to.
+
+use anyhow::{anyhow, bail, Result};
+use half::{bf16, f16};
+use memmap2::Mmap;
+use safetensors::{Dtype, SafeTensors};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tracing::{debug, info, warn};
+
+use super::gguf::{quantize, GgmlType, GgufFile, GgufHeader, GgufWriter, MetadataValue};
+use super::safetensors_native::SafeTensorsCheckpoint;
+use super::tokenizer::{GgufVocab, TokenType};
+
+/// Bumped whenever the output of the converter changes, invalidating the cache
+const CONVERTER_VERSION: u32 = 1;
+/// llama.cpp's `GGML_QNT_VERSION`
+const QUANTIZATION_VERSION: u32 = 2;
+/// PEFT prefixes every adapter tensor with the wrapped model's attribute path
+const PEFT_PREFIX: &str = "base_model.model.";
+/// Projections a GGUF LoRA adapter can target
+const LORA_TARGETS: &[&str] = &[
+    "q_proj",
+    "k_proj",
+    "v_proj",
+    "o_proj",
+    "gate_proj",
+    "up_proj",
+    "down_proj",
+];
+
+/// Element type for the converted weight matrices. Norms, biases and other
+/// 1-D tensors are always stored as F32.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum OutType {
+    #[default]
+    F16,
+    Q8_0,
+}
+
+impl OutType {
+    /// `general.file_type` (llama.cpp `LLAMA_FTYPE_MOSTLY_*`)
+    fn file_type(self) -> u32 {
+        match self {
+            OutType::F16 => 1,
+            OutType::Q8_0 => 7,
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            OutType::F16 => "f16",
+            OutType::Q8_0 => "q8_0",
+        }
+    }
+
+    /// Storage type for a matrix with `cols` elements per row; rows that do not
+    /// fill whole Q8_0 blocks stay F16, as llama.cpp's converter does
+    fn matrix_type(self, cols: usize) -> GgmlType {
+        match self {
+            OutType::Q8_0 if cols.is_multiple_of(GgmlType::Q8_0.block_size()) => GgmlType::Q8_0,
+            _ => GgmlType::F16,
+        }
+    }
+}
+
+impl std::str::FromStr for OutType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "f16" => Ok(OutType::F16),
+            "q8_0" | "q8" => Ok(OutType::Q8_0),
+            other => bail!(
+                "unsupported GGUF output type '{}' (expected f16 or q8_0)",
+                other
+            ),
+        }
+    }
+}
+
+/// What the converter needs to know about a model's architecture
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ArchInfo {
+    /// GGUF architecture name (`llama`, `qwen2`)
+    pub arch: String,
+    pub n_head: usize,
+    pub n_head_kv: usize,
+}
+
+impl ArchInfo {
+    /// From an HF `config.json`. Mistral checkpoints are stored as `llama`, as
+    /// llama.cpp does.
+    pub fn from_hf_config(config: &Value) -> Result<Self> {
+        let model_type = config["model_type"].as_str().unwrap_or_default();
+        let architecture = config["architectures"][0].as_str().unwrap_or_default();
+        let arch = match (model_type, architecture) {
+            ("llama" | "mistral", _) | (_, "LlamaForCausalLM" | "MistralForCausalLM") => "llama",
+            ("qwen2", _) | (_, "Qwen2ForCausalLM") => "qwen2",
+            _ => bail!(
+                "cannot convert '{}' checkpoints to GGUF (supported: llama, mistral, qwen2)",
+                if model_type.is_empty() {
+                    architecture
+                } else {
+                    model_type
+                }
+            ),
+        };
+        let n_head = config["num_attention_heads"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("config.json is missing num_attention_heads"))?
+            as usize;
+        let n_head_kv = config["num_key_value_heads"]
+            .as_u64()
+            .map(|v| v as usize)
+            .unwrap_or(n_head);
+        Ok(Self {
+            arch: arch.to_string(),
+            n_head,
+            n_head_kv,
+        })
+    }
+
+    /// From the header of the base model a LoRA adapter will be applied to
+    pub fn from_gguf(header: &GgufHeader) -> Result<Self> {
+        let arch = header
+            .architecture()
+            .ok_or_else(|| anyhow!("GGUF file has no general.architecture"))?
+            .to_string();
+        let n_head = header
+            .get_u64(&format!("{}.attention.head_count", arch))
+            .ok_or_else(|| anyhow!("GGUF metadata is missing {}.attention.head_count", arch))?
+            as usize;
+        let n_head_kv = header
+            .get_u64(&format!("{}.attention.head_count_kv", arch))
+            .map(|v| v as usize)
+            .unwrap_or(n_head);
+        Ok(Self {
+            arch,
+            n_head,
+            n_head_kv,
+        })
+    }
+
+    /// Head count for the rows of an HF q/k projection that must be reordered
+    /// into GGUF's interleaved RoPE layout, or `None` when no permute is needed
+    fn permute_heads(&self, module: &str) -> Option<usize> {
+        if self.arch != "llama" {
+            return None;
+        }
+        match module {
+            "self_attn.q_proj" => Some(self.n_head),
+            "self_attn.k_proj" => Some(self.n_head_kv),
+            _ => None,
+        }
+    }
+}
+
+/// Modules a PEFT adapter was trained on (`target_modules`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetModules {
+    /// Module names, matched against the last component of each module path
+    Names(Vec<String>),
+    /// `all-linear`, or a regular expression PEFT already resolved when saving
+    All,
+}
+
+/// The parts of a PEFT `adapter_config.json` that affect conversion
+#[derive(Debug, Clone)]
+pub struct AdapterConfig {
+    pub r: usize,
+    pub lora_alpha: f32,
+    pub use_rslora: bool,
+    pub target_modules: TargetModules,
+    /// Per-module `lora_alpha` overrides, keyed by module path suffix.
+    /// (`rank_pattern` needs no handling: ranks are read from the tensors.)
+    pub alpha_pattern: HashMap<String, f32>,
+    pub base_model: Option<String>,
+}
+
+impl AdapterConfig {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join("adapter_config.json");
+        if !path.is_file() {
+            bail!(
+                "{} has no adapter_config.json; PEFT adapters need it for lora_alpha, r and target_modules",
+                dir.display()
+            );
+        }
+        let config: Value = serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| anyhow!("invalid {}: {}", path.display(), e))?;
+        if let Some(peft_type) = config["peft_type"].as_str() {
+            if !peft_type.eq_ignore_ascii_case("lora") {
+                bail!(
+                    "PEFT adapter type '{}' is not supported, only LoRA",
+                    peft_type
+                );
+            }
+        }
+        let saved: Vec<&str> = config["modules_to_save"]
+            .as_array()
+            .map(|m| m.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        if !saved.is_empty() {
+            bail!(
+                "adapter {} stores full copies of {} (modules_to_save), which a GGUF LoRA adapter cannot carry",
+                dir.display(),
+                saved.join(", ")
+            );
+        }
+
+        let target_modules = match &config["target_modules"] {
+            Value::Array(names) => {
+                let names: Vec<String> = names
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect();
+                let unsupported: Vec<&str> = names
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|n| !LORA_TARGETS.contains(n))
+                    .collect();
+                if !unsupported.is_empty() {
+                    bail!(
+                        "adapter {} targets {}, which GGUF LoRA adapters do not support (supported: {})",
+                        dir.display(),
+                        unsupported.join(", "),
+                        LORA_TARGETS.join(", ")
+                    );
+                }
+                TargetModules::Names(names)
+            }
+            _ => TargetModules::All,
+        };
+
+        // PEFT defaults
+        let r = config["r"].as_u64().unwrap_or(8) as usize;
+        Ok(Self {
+            r,
+            lora_alpha: config["lora_alpha"].as_f64().unwrap_or(8.0) as f32,
+            use_rslora: config["use_rslora"].as_bool().unwrap_or(false),
+            target_modules,
+            alpha_pattern: config["alpha_pattern"]
+                .as_object()
+                .map(|m| {
+                    m.iter()
+                        .filter_map(|(k, v)| Some((k.clone(), v.as_f64()? as f32)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            base_model: config["base_model_name_or_path"]
+                .as_str()
+                .map(str::to_string),
+        })
+    }
+
+    /// Whether the adapter was configured to train `module`
+    /// (`model.layers.0.self_attn.q_proj`)
+    pub fn targets(&self, module: &str) -> bool {
+        match &self.target_modules {
+            TargetModules::All => true,
+            TargetModules::Names(names) => {
+                let last = module.rsplit('.').next().unwrap_or(module);
+                names.iter().any(|n| n == last)
+            }
+        }
+    }
+
+    /// The scale PEFT applies to `B @ A` for `module` with the given rank:
+    /// `alpha / r`, or `alpha / sqrt(r)` for rank-stabilized LoRA
+    pub fn scale(&self, module: &str, rank: usize) -> f32 {
+        let alpha = pattern_value(&self.alpha_pattern, module).unwrap_or(self.lora_alpha);
+        let rank = rank.max(1) as f32;
+        if self.use_rslora {
+            alpha / rank.sqrt()
+        } else {
+            alpha / rank
+        }
+    }
+
+    /// Factor to fold into a module's B matrix so that llama.cpp, which
+    /// scales every tensor by `adapter.lora.alpha / rank`, applies
+    /// [`scale`](Self::scale). 1.0 unless patterns or rsLoRA are in play.
+    fn b_factor(&self, module: &str, rank: usize) -> f32 {
+        self.scale(module, rank) * rank as f32 / self.gguf_alpha()
+    }
+
+    /// `adapter.lora.alpha` for GGUF. llama.cpp scales by `alpha / r`, so
+    /// rank-stabilized adapters (`alpha / sqrt(r)`) are folded in here.
+    pub fn gguf_alpha(&self) -> f32 {
+        if self.use_rslora {
+            self.lora_alpha * (self.r as f32).sqrt()
+        } else {
+            self.lora_alpha
+        }
+    }
+}
+
+/// PEFT matches pattern keys against the end of the module path
+fn pattern_value<T: Copy>(patterns: &HashMap<String, T>, module: &str) -> Option<T> {
+    patterns
+        .iter()
+        .filter(|(key, _)| module == key.as_str() || module.ends_with(&format!(".{}", key)))
+        .max_by_key(|(key, _)| key.len())
+        .map(|(_, v)| *v)
+}
+
+/// Whether `path` is a PEFT adapter directory or a file inside one
+pub fn is_lora_adapter(path: &Path) -> bool {
+    adapter_dir(path).join("adapter_config.json").is_file()
+}
+
+/// Whether a `lora_path` points at a PEFT SafeTensors adapter (its directory
+/// or weights file) that must be converted before llama.cpp can attach it
+#[cfg_attr(not(feature = "llama"), allow(dead_code))]
+pub fn is_safetensors_lora(path: &Path) -> bool {
+    if path.is_dir() {
+        return is_lora_adapter(path);
+    }
+    path.extension().and_then(|s| s.to_str()) == Some("safetensors")
+}
+
+fn adapter_dir(path: &Path) -> &Path {
+    if path.is_dir() {
+        path
+    } else {
+        path.parent().unwrap_or_else(|| Path::new("."))
+    }
+}
+
+/// Converts SafeTensors checkpoints and LoRA adapters to GGUF, caching the
+/// results by source size and modification time
+#[derive(Debug, Clone)]
+pub struct GgufConverter {
+    cache_dir: PathBuf,
+    out_type: OutType,
+}
+
+impl GgufConverter {
+    // The server converts only the PEFT adapters it attaches with llama.cpp
+    #[cfg_attr(not(feature = "llama"), allow(dead_code))]
+    pub fn new(cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            cache_dir: cache_dir.into(),
+            out_type: OutType::default(),
+        }
+    }
+
+    /// `~/.cache/shimmy/gguf` (or the platform equivalent)
+    #[cfg_attr(not(feature = "llama"), allow(dead_code))]
+    pub fn default_cache_dir() -> PathBuf {
+        dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("shimmy")
+            .join("gguf")
+    }
+
+    // Output types and whole checkpoints are for library callers such as
+    // `safetensors_adapter`; the server keeps the F16 default
+    #[allow(dead_code)]
+    pub fn with_out_type(mut self, out_type: OutType) -> Self {
+        self.out_type = out_type;
+        self
+    }
+
+    /// Convert a checkpoint, or a LoRA adapter whose base model is a local
+    /// checkpoint named in its `adapter_config.json`
+    #[allow(dead_code)]
+    pub fn convert(&self, path: &Path) -> Result<PathBuf> {
+        if !is_lora_adapter(path) {
+            return self.convert_checkpoint(path);
+        }
+        let config = AdapterConfig::load(adapter_dir(path))?;
+        let base = config
+            .base_model
+            .as_deref()
+            .map(Path::new)
+            .filter(|p| p.join("config.json").is_file())
+            .ok_or_else(|| {
+                anyhow!(
+                    "cannot tell the architecture of LoRA adapter {}: base model '{}' is not a local checkpoint",
+                    path.display(),
+                    config.base_model.as_deref().unwrap_or("<unset>")
+                )
+            })?;
+        let arch = ArchInfo::from_hf_config(&read_json(&base.join("config.json"))?)?;
+        self.convert_lora(path, &arch)
+    }
+
+    /// Convert an HF checkpoint (directory, index file or any shard)
+    pub fn convert_checkpoint(&self, path: &Path) -> Result<PathBuf> {
+        let checkpoint = SafeTensorsCheckpoint::open(path)?;
+        let dir = checkpoint.dir().to_path_buf();
+        let config = read_json(&dir.join("config.json"))?;
+        let arch = ArchInfo::from_hf_config(&config)?;
+
+        let mut sources: Vec<PathBuf> = checkpoint.shards().to_vec();
+        for extra in [
+            "config.json",
+            "tokenizer.json",
+            "tokenizer.model",
+            "tokenizer_config.json",
+        ] {
+            if dir.join(extra).is_file() {
+                sources.push(dir.join(extra));
+            }
+        }
+        let name = file_stem_name(&dir);
+        let out = self.cache_path(&name, &sources, &arch)?;
+        if out.is_file() {
+            debug!(path = %out.display(), "Using cached GGUF conversion");
+            return Ok(out);
+        }
+
+        info!(source = %dir.display(), out_type = ?self.out_type, "Converting SafeTensors checkpoint to GGUF");
+        let mut w = GgufWriter::new();
+        w.add_metadata("general.architecture", string(&arch.arch))
+            .add_metadata("general.name", string(&name))
+            .add_metadata(
+                "general.file_type",
+                MetadataValue::U32(self.out_type.file_type()),
+            )
+            .add_metadata(
+                "general.quantization_version",
+                MetadataValue::U32(QUANTIZATION_VERSION),
+            );
+        write_hparams(&mut w, &arch, &config)?;
+
+        let mut vocab = GgufVocab::from_dir(&dir)?;
+        let n_vocab = config["vocab_size"].as_u64().unwrap_or(0) as usize;
+        // Embedding matrices are often padded past the tokenizer's vocabulary
+        for id in vocab.tokens.len()..n_vocab {
+            vocab.tokens.push(format!("[PAD{}]", id));
+            vocab.scores.push(0.0);
+            vocab.types.push(TokenType::Unused);
+        }
+        vocab.bos_id = vocab.bos_id.or_else(|| token_id(&config["bos_token_id"]));
+        vocab.eos_id = vocab.eos_id.or_else(|| token_id(&config["eos_token_id"]));
+        vocab.write_metadata(&mut w);
+        if let Ok(tokenizer_config) = read_json(&dir.join("tokenizer_config.json")) {
+            if let Some(template) = tokenizer_config["chat_template"].as_str() {
+                w.add_metadata("tokenizer.chat_template", string(template));
+            }
+        }
+
+        let mmaps = map_files(checkpoint.shards())?;
+        let mut tensors: Vec<(String, &str, usize, Vec<usize>)> = Vec::new();
+        for (hf_name, shard, shape) in checkpoint.tensor_layout() {
+            if let Some(name) = gguf_tensor_name(hf_name)? {
+                tensors.push((name, hf_name, shard, shape.to_vec()));
+            }
+        }
+        tensors.sort();
+        for (name, hf_name, shard, shape) in &tensors {
+            let permute = permute_for(&arch, hf_name, ".weight");
+            let transform = Transform {
+                permute,
+                scale: None,
+            };
+            self.add_tensor(&mut w, name, hf_name, &mmaps[*shard], shape, transform)?;
+        }
+
+        self.write(&w, &out)?;
+        info!(path = %out.display(), tensors = tensors.len(), "GGUF conversion complete");
+        Ok(out)
+    }
+
+    /// Convert a PEFT LoRA adapter (its directory or `adapter_model.safetensors`)
+    /// for a base model with the given architecture
+    pub fn convert_lora(&self, adapter: &Path, base: &ArchInfo) -> Result<PathBuf> {
+        let dir = adapter_dir(adapter).to_path_buf();
+        let weights = if adapter.is_dir() {
+            dir.join("adapter_model.safetensors")
+        } else {
+            adapter.to_path_buf()
+        };
+        if !weights.is_file() {
+            bail!("LoRA adapter weights not found: {}", weights.display());
+        }
+        let config = AdapterConfig::load(&dir)?;
+
+        let sources = vec![weights.clone(), dir.join("adapter_config.json")];
+        let name = file_stem_name(&dir);
+        let out = self.cache_path(&name, &sources, base)?;
+        if out.is_file() {
+            debug!(path = %out.display(), "Using cached GGUF LoRA conversion");
+            return Ok(out);
+        }
+
+        info!(source = %weights.display(), rank = config.r, alpha = config.lora_alpha, "Converting LoRA adapter to GGUF");
+        let mut w = GgufWriter::new();
+        w.add_metadata("general.architecture", string(&base.arch))
+            .add_metadata("general.type", string("adapter"))
+            .add_metadata("general.name", string(&name))
+            .add_metadata(
+                "general.file_type",
+                MetadataValue::U32(self.out_type.file_type()),
+            )
+            .add_metadata("adapter.type", string("lora"))
+            .add_metadata(
+                "adapter.lora.alpha",
+                MetadataValue::F32(config.gguf_alpha()),
+            );
+
+        let mmaps = map_files(std::slice::from_ref(&weights))?;
+        let header = SafeTensors::deserialize(&mmaps[0]).map_err(|e| {
+            anyhow!(
+                "{} is not a valid SafeTensors file: {}",
+                weights.display(),
+                e
+            )
+        })?;
+        let mut tensors: Vec<(String, String, Vec<usize>)> = Vec::new();
+        for (hf_name, view) in header.tensors() {
+            let lora = LoraTensor::parse(&hf_name)?;
+            if !config.targets(&lora.module) {
+                warn!(tensor = %hf_name, "Skipping LoRA tensor outside target_modules");
+                continue;
+            }
+            tensors.push((lora.gguf_name, hf_name, view.shape().to_vec()));
+        }
+        if tensors.is_empty() {
+            bail!(
+                "LoRA adapter {} contains no tensors for its target_modules",
+                weights.display()
+            );
+        }
+        tensors.sort();
+        for (name, hf_name, shape) in &tensors {
+            let lora = LoraTensor::parse(hf_name)?;
+            let transform = if lora.is_b {
+                // Only B's output rows follow the projection's row order; the
+                // rank is B's column count
+                let rank = shape.get(1).copied().unwrap_or(config.r);
+                let factor = config.b_factor(&lora.module, rank);
+                Transform {
+                    permute: permute_for(base, hf_name, ".lora_B.weight"),
+                    scale: ((factor - 1.0).abs() > f32::EPSILON).then_some(factor),
+                }
+            } else {
+                Transform::default()
+            };
+            self.add_tensor(&mut w, name, hf_name, &mmaps[0], shape, transform)?;
+        }
+
+        self.write(&w, &out)?;
+        info!(path = %out.display(), tensors = tensors.len(), "GGUF LoRA conversion complete");
+        Ok(out)
+    }
+
+    /// Convert a LoRA adapter for the GGUF model at `base` (its first split),
+    /// taking the architecture from the model's metadata
+    #[cfg_attr(not(feature = "llama"), allow(dead_code))]
+    pub fn convert_lora_for_base(&self, adapter: &Path, base: &Path) -> Result<PathBuf> {
+        let arch = ArchInfo::from_gguf(&GgufFile::open(base)?.header)?;
+        self.convert_lora(adapter, &arch)
+    }
+
+    /// Queue one tensor; its data is read and encoded only while writing
+    fn add_tensor(
+        &self,
+        w: &mut GgufWriter,
+        name: &str,
+        hf_name: &str,
+        mmap: &Arc<Mmap>,
+        shape: &[usize],
+        transform: Transform,
+    ) -> Result<()> {
+        let Transform { permute, scale } = transform;
+        let (ty, rows) = match shape {
+            [_] => (GgmlType::F32, 1),
+            [rows, cols] => (self.out_type.matrix_type(*cols), *rows),
+            _ => bail!("tensor '{}' has unsupported shape {:?}", hf_name, shape),
+        };
+        if let Some(n_head) = permute {
+            if n_head == 0 || rows % (n_head * 2) != 0 {
+                bail!(
+                    "tensor '{}' has {} rows, which do not split into {} heads",
+                    hf_name,
+                    rows,
+                    n_head
+                );
+            }
+        }
+        let dims = shape.iter().rev().map(|&d| d as u64).collect();
+        let (mmap, hf_name) = (Arc::clone(mmap), hf_name.to_string());
+        w.add_deferred_tensor(name, dims, ty, move || {
+            let tensors = SafeTensors::deserialize(&mmap)?;
+            let view = tensors.tensor(&hf_name)?;
+            let mut values = to_f32(view.dtype(), view.data())
+                .map_err(|e| anyhow!("tensor '{}': {}", hf_name, e))?;
+            if let Some(n_head) = permute {
+                values = permute_rows(&values, rows, n_head);
+            }
+            if let Some(scale) = scale {
+                values.iter_mut().for_each(|v| *v *= scale);
+            }
+            quantize(ty, &values)
+        });
+        Ok(())
+    }
+
+    /// `{name}-{fingerprint}.{type}.gguf` in the cache directory
+    fn cache_path(&self, name: &str, sources: &[PathBuf], arch: &ArchInfo) -> Result<PathBuf> {
+        let mut hasher = DefaultHasher::new();
+        CONVERTER_VERSION.hash(&mut hasher);
+        self.out_type.hash(&mut hasher);
+        arch.hash(&mut hasher);
+        for source in sources {
+            let meta = fs::metadata(source)?;
+            fs::canonicalize(source)?.hash(&mut hasher);
+            meta.len().hash(&mut hasher);
+            meta.modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos())
+                .hash(&mut hasher);
+        }
+        Ok(self.cache_dir.join(format!(
+            "{}-{:016x}.{}.gguf",
+            name,
+            hasher.finish(),
+            self.out_type.suffix()
+        )))
+    }
+
+    /// Write via a temporary file so an interrupted conversion is never
+    /// mistaken for a cached one
+    fn write(&self, w: &GgufWriter, out: &Path) -> Result<()> {
+        fs::create_dir_all(&self.cache_dir)?;
+        let partial = out.with_extension("gguf.partial");
+        if let Err(e) = w.write_file(&partial) {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, out)?;
+        Ok(())
+    }
+}
+
+/// `{arch}.*` hyperparameters from an HF `config.json`
+fn write_hparams(w: &mut GgufWriter, arch: &ArchInfo, config: &Value) -> Result<()> {
+    let key = |k: &str| format!("{}.{}", arch.arch, k);
+    let req = |k: &str| {
+        config[k]
+            .as_u64()
+            .ok_or_else(|| anyhow!("config.json is missing {}", k))
+    };
+    let hidden = req("hidden_size")?;
+    let head_dim = config["head_dim"]
+        .as_u64()
+        .unwrap_or(hidden / arch.n_head as u64);
+    w.add_metadata(
+        key("context_length"),
+        MetadataValue::U32(config["max_position_embeddings"].as_u64().unwrap_or(4096) as u32),
+    )
+    .add_metadata(key("embedding_length"), MetadataValue::U32(hidden as u32))
+    .add_metadata(
+        key("block_count"),
+        MetadataValue::U32(req("num_hidden_layers")? as u32),
+    )
+    .add_metadata(
+        key("feed_forward_length"),
+        MetadataValue::U32(req("intermediate_size")? as u32),
+    )
+    .add_metadata(
+        key("attention.head_count"),
+        MetadataValue::U32(arch.n_head as u32),
+    )
+    .add_metadata(
+        key("attention.head_count_kv"),
+        MetadataValue::U32(arch.n_head_kv as u32),
+    )
+    .add_metadata(
+        key("attention.layer_norm_rms_epsilon"),
+        MetadataValue::F32(config["rms_norm_eps"].as_f64().unwrap_or(1e-5) as f32),
+    )
+    .add_metadata(
+        key("rope.freq_base"),
+        MetadataValue::F32(config["rope_theta"].as_f64().unwrap_or(10000.0) as f32),
+    )
+    .add_metadata(
+        key("rope.dimension_count"),
+        MetadataValue::U32(head_dim as u32),
+    );
+    if let Some(vocab_size) = config["vocab_size"].as_u64() {
+        w.add_metadata(key("vocab_size"), MetadataValue::U32(vocab_size as u32));
+    }
+    Ok(())
+}
+
+/// HF tensor name to its GGUF name, or `None` for tensors GGUF does not store
+fn gguf_tensor_name(hf: &str) -> Result<Option<String>> {
+    let name = hf.strip_prefix("model.").unwrap_or(hf);
+    let fixed = match name {
+        "embed_tokens.weight" => Some("token_embd.weight"),
+        "norm.weight" => Some("output_norm.weight"),
+        "lm_head.weight" => Some("output.weight"),
+        _ => None,
+    };
+    if let Some(fixed) = fixed {
+        return Ok(Some(fixed.to_string()));
+    }
+
+    let unknown = || anyhow!("no GGUF equivalent for tensor '{}'", hf);
+    let (layer, rest) = name
+        .strip_prefix("layers.")
+        .and_then(|r| r.split_once('.'))
+        .ok_or_else(unknown)?;
+    let layer: usize = layer.parse().map_err(|_| unknown())?;
+    if rest.ends_with("rotary_emb.inv_freq") {
+        return Ok(None);
+    }
+    let (module, kind) = rest.rsplit_once('.').ok_or_else(unknown)?;
+    let module = match module {
+        "input_layernorm" => "attn_norm",
+        "post_attention_layernorm" => "ffn_norm",
+        "self_attn.q_proj" => "attn_q",
+        "self_attn.k_proj" => "attn_k",
+        "self_attn.v_proj" => "attn_v",
+        "self_attn.o_proj" => "attn_output",
+        "mlp.gate_proj" => "ffn_gate",
+        "mlp.up_proj" => "ffn_up",
+        "mlp.down_proj" => "ffn_down",
+        _ => return Err(unknown()),
+    };
+    if kind != "weight" && kind != "bias" {
+        return Err(unknown());
+    }
+    Ok(Some(format!("blk.{}.{}.{}", layer, module, kind)))
+}
+
+/// Reordering and scaling applied to a tensor's rows while converting
+#[derive(Debug, Clone, Copy, Default)]
+struct Transform {
+    permute: Option<usize>,
+    scale: Option<f32>,
+}
+
+/// One PEFT LoRA tensor, e.g. `base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight`
+#[derive(Debug, Clone, PartialEq)]
+struct LoraTensor {
+    /// `blk.0.attn_q.weight.lora_a`
+    gguf_name: String,
+    /// `model.layers.0.self_attn.q_proj`
+    module: String,
+    is_b: bool,
+}
+
+impl LoraTensor {
+    fn parse(hf: &str) -> Result<Self> {
+        let name = hf.strip_prefix(PEFT_PREFIX).unwrap_or(hf);
+        let (module, suffix, is_b) = if let Some(module) = name.strip_suffix(".lora_A.weight") {
+            (module, "lora_a", false)
+        } else if let Some(module) = name.strip_suffix(".lora_B.weight") {
+            (module, "lora_b", true)
+        } else {
+            bail!(
+                "unsupported tensor '{}' in LoRA adapter (only lora_A/lora_B weights can be converted)",
+                hf
+            );
+        };
+        let target = gguf_tensor_name(&format!("{}.weight", module))?
+            .ok_or_else(|| anyhow!("no GGUF equivalent for LoRA target '{}'", module))?;
+        Ok(Self {
+            gguf_name: format!("{}.{}", target, suffix),
+            module: module.to_string(),
+            is_b,
+        })
+    }
+}
+
+/// Head count to permute `hf_name` by, when it is a llama q/k projection
+fn permute_for(arch: &ArchInfo, hf_name: &str, suffix: &str) -> Option<usize> {
+    let module = hf_name.strip_suffix(suffix)?;
+    let module = module.rsplit_once(".self_attn.")?.1;
+    arch.permute_heads(&format!("self_attn.{}", module))
+}
+
+/// Reorder each head's rows from HF's split-halves RoPE layout to GGUF's
+/// interleaved pairs: row `j * hd/2 + i` of a head becomes row `2i + j`
+fn permute_rows(values: &[f32], rows: usize, n_head: usize) -> Vec<f32> {
+    let cols = values.len() / rows;
+    let head_dim = rows / n_head;
+    let half = head_dim / 2;
+    let mut out = vec![0f32; values.len()];
+    for h in 0..n_head {
+        for i in 0..half {
+            for j in 0..2 {
+                let src = h * head_dim + j * half + i;
+                let dst = h * head_dim + i * 2 + j;
+                out[dst * cols..(dst + 1) * cols]
+                    .copy_from_slice(&values[src * cols..(src + 1) * cols]);
+            }
+        }
+    }
+    out
+}
+
+fn to_f32(dtype: Dtype, data: &[u8]) -> Result<Vec<f32>> {
+    Ok(match dtype {
+        Dtype::F32 => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        Dtype::F16 => data
+            .chunks_exact(2)
+            .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        Dtype::BF16 => data
+            .chunks_exact(2)
+            .map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        other => bail!("{:?} tensors cannot be converted to GGUF", other),
+    })
+}
+
+fn map_files(paths: &[PathBuf]) -> Result<Vec<Arc<Mmap>>> {
+    paths
+        .iter()
+        .map(|p| {
+            let file = File::open(p)?;
+            Ok(Arc::new(unsafe { Mmap::map(&file)? }))
+        })
+        .collect()
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    serde_json::from_str(&text).map_err(|e| anyhow!("invalid {}: {}", path.display(), e))
+}
+
+/// `eos_token_id` may be a single id or a list
+fn token_id(value: &Value) -> Option<u32> {
+    value
+        .as_u64()
+        .or_else(|| value.as_array()?.first()?.as_u64())
+        .map(|id| id as u32)
+}
+
+fn file_stem_name(dir: &Path) -> String {
+    dir.file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| !n.is_empty())
+        .unwrap_or("model")
+        .to_string()
+}
+
+fn string(s: &str) -> MetadataValue {
+    MetadataValue::String(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::gguf::dequantize;
+    use crate::engine::gguf_native::GgufNativeEngine;
+    use crate::engine::tokenizer::bytes_to_unicode;
+    use crate::engine::{GenOptions, InferenceEngine, ModelSpec};
+    use safetensors::tensor::TensorView;
+    use tempfile::TempDir;
+
+    const D: usize = 64;
+    const HEADS: usize = 4;
+    const KV_HEADS: usize = 2;
+
+    /// Byte-level BPE over the 256 byte symbols plus a few merges
+    fn byte_level_tokenizer_json() -> Value {
+        let mut vocab = serde_json::Map::new();
+        for c in bytes_to_unicode() {
+            vocab.insert(c.to_string(), Value::from(vocab.len()));
+        }
+        for merged in ["he", "ll", "Ġw", "or"] {
+            vocab.insert(merged.to_string(), Value::from(vocab.len()));
+        }
+        serde_json::json!({
+            "version": "1.0",
+            "added_tokens": [
+                {"id": vocab.len(), "content": "<|endoftext|>", "single_word": false, "lstrip": false,
+                 "rstrip": false, "normalized": false, "special": true}
+            ],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true},
+            "post_processor": null,
+            "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true},
+            "model": {
+                "type": "BPE", "dropout": null, "unk_token": null, "continuing_subword_prefix": null,
+                "end_of_word_suffix": null, "fuse_unk": false, "byte_fallback": false,
+                "vocab": vocab,
+                "merges": ["h e", "l l", "Ġ w", "o r"]
+            }
+        })
+    }
+
+    fn rand_values(seed: &mut u32, n: usize) -> Vec<f32> {
+        (0..n)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 17;
+                *seed ^= *seed << 5;
+                (*seed % 2000) as f32 / 1000.0 - 1.0
+            })
+            .collect()
+    }
+
+    fn write_safetensors(path: &Path, tensors: &[(String, Vec<usize>, Vec<f32>)]) {
+        let data: Vec<Vec<u8>> = tensors
+            .iter()
+            .map(|(_, _, v)| v.iter().flat_map(|x| x.to_le_bytes()).collect())
+            .collect();
+        let views = tensors.iter().zip(&data).map(|((name, shape, _), bytes)| {
+            let view = TensorView::new(Dtype::F32, shape.clone(), bytes).unwrap();
+            (name.clone(), view)
+        });
+        fs::write(path, safetensors::serialize(views, &None).unwrap()).unwrap();
+    }
+
+    /// Write a tiny random HF Llama checkpoint with a byte-level BPE tokenizer
+    fn write_hf_llama(dir: &Path) -> Vec<(String, Vec<usize>, Vec<f32>)> {
+        let tokenizer = byte_level_tokenizer_json();
+        let n_vocab = 256 + 4 + 1;
+        fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+        let (ff, layers, kv) = (128usize, 2usize, D / HEADS * KV_HEADS);
+        let config = serde_json::json!({
+            "architectures": ["LlamaForCausalLM"],
+            "model_type": "llama",
+            "hidden_size": D,
+            "intermediate_size": ff,
+            "vocab_size": n_vocab + 3,
+            "num_hidden_layers": layers,
+            "num_attention_heads": HEADS,
+            "num_key_value_heads": KV_HEADS,
+            "rms_norm_eps": 1e-5,
+            "max_position_embeddings": 128,
+            "eos_token_id": n_vocab - 1,
+            "tie_word_embeddings": true,
+        });
+        fs::write(dir.join("config.json"), config.to_string()).unwrap();
+
+        let mut seed = 0x1234567u32;
+        let mut tensors = Vec::new();
+        let mut add = |name: String, shape: Vec<usize>| {
+            let values = rand_values(&mut seed, shape.iter().product());
+            tensors.push((name, shape, values));
+        };
+        add("model.embed_tokens.weight".into(), vec![n_vocab + 3, D]);
+        add("model.norm.weight".into(), vec![D]);
+        for l in 0..layers {
+            let p = format!("model.layers.{}", l);
+            add(format!("{}.input_layernorm.weight", p), vec![D]);
+            add(format!("{}.post_attention_layernorm.weight", p), vec![D]);
+            add(format!("{}.self_attn.q_proj.weight", p), vec![D, D]);
+            add(format!("{}.self_attn.k_proj.weight", p), vec![kv, D]);
+            add(format!("{}.self_attn.v_proj.weight", p), vec![kv, D]);
+            add(format!("{}.self_attn.o_proj.weight", p), vec![D, D]);
+            add(format!("{}.mlp.gate_proj.weight", p), vec![ff, D]);
+            add(format!("{}.mlp.up_proj.weight", p), vec![ff, D]);
+            add(format!("{}.mlp.down_proj.weight", p), vec![D, ff]);
+        }
+        write_safetensors(&dir.join("model.safetensors"), &tensors);
+        tensors
+    }
+
+    fn read_tensor(file: &GgufFile, name: &str) -> Vec<f32> {
+        let info = file.header.tensor(name).unwrap();
+        let mut out = vec![0f32; info.n_elements()];
+        dequantize(info.ggml_type, file.tensor_data(info).unwrap(), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_tensor_names() {
+        let name = |hf: &str| gguf_tensor_name(hf).unwrap();
+        assert_eq!(
+            name("model.layers.3.self_attn.q_proj.weight").as_deref(),
+            Some("blk.3.attn_q.weight")
+        );
+        assert_eq!(
+            name("model.layers.0.self_attn.k_proj.bias").as_deref(),
+            Some("blk.0.attn_k.bias")
+        );
+        assert_eq!(name("lm_head.weight").as_deref(), Some("output.weight"));
+        assert_eq!(name("model.layers.1.self_attn.rotary_emb.inv_freq"), None);
+        assert!(gguf_tensor_name("model.vision_tower.weight").is_err());
+
+        let lora = LoraTensor::parse("base_model.model.model.layers.2.mlp.down_proj.lora_B.weight")
+            .unwrap();
+        assert_eq!(lora.gguf_name, "blk.2.ffn_down.weight.lora_b");
+        assert_eq!(lora.module, "model.layers.2.mlp.down_proj");
+        assert!(lora.is_b);
+    }
+
+    #[test]
+    fn test_permute_rows_interleaves_rope_halves() {
+        // One head of dimension 4, one column: rows [a0, a1, b0, b1] -> [a0, b0, a1, b1]
+        assert_eq!(
+            permute_rows(&[0.0, 1.0, 2.0, 3.0], 4, 1),
+            vec![0.0, 2.0, 1.0, 3.0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_convert_checkpoint_runs_on_native_engine() {
+        let (src, cache) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let tensors = write_hf_llama(src.path());
+        let converter = GgufConverter::new(cache.path());
+        let out = converter.convert(src.path()).unwrap();
+        assert!(out.starts_with(cache.path()));
+        assert!(out.to_string_lossy().ends_with(".f16.gguf"));
+        assert_eq!(fs::read_dir(src.path()).unwrap().count(), 3);
+
+        let file = GgufFile::open(&out).unwrap();
+        assert_eq!(file.header.architecture(), Some("llama"));
+        assert_eq!(
+            file.header.get_u64("llama.attention.head_count_kv"),
+            Some(2)
+        );
+        assert_eq!(file.header.get_str("tokenizer.ggml.model"), Some("gpt2"));
+        let tokens = file.header.get("tokenizer.ggml.tokens").unwrap();
+        assert_eq!(tokens.as_array().unwrap().len(), 264);
+
+        // k_proj rows are permuted per head; v_proj rows are not
+        let find = |name: &str| &tensors.iter().find(|t| t.0 == name).unwrap().2;
+        let k = find("model.layers.0.self_attn.k_proj.weight");
+        let converted = read_tensor(&file, "blk.0.attn_k.weight");
+        let expected = permute_rows(k, D / HEADS * KV_HEADS, KV_HEADS);
+        for (a, b) in converted.iter().zip(&expected) {
+            assert!((a - b).abs() < 1e-3);
+        }
+        assert_ne!(converted[D], k[D]);
+        let v = find("model.layers.0.self_attn.v_proj.weight");
+        let converted = read_tensor(&file, "blk.0.attn_v.weight");
+        assert!((converted[D] - v[D]).abs() < 1e-3);
+
+        let model = GgufNativeEngine::new()
+            .load(&ModelSpec {
+                name: "converted".to_string(),
+                base_path: out,
+                lora_path: None,
+                template: None,
+                ctx_len: 64,
+                n_threads: Some(2),
+                backend: None,
+                command: None,
+            })
+            .await
+            .unwrap();
+        // he ll o Ġw or l d
+        let ids = model.tokenize("hello world", false).unwrap();
+        assert_eq!(ids.len(), 7);
+        assert_eq!(model.detokenize(&ids).unwrap(), "hello world");
+        let opts = GenOptions {
+            max_tokens: 4,
+            temperature: 0.0,
+            ..Default::default()
+        };
+        model.generate("hello", opts, None).await.unwrap();
+    }
+
+    /// The converted model must compute what the HF checkpoint computes, which
+    /// only holds if the q/k rows were permuted for GGUF's RoPE layout
+    #[cfg(feature = "candle")]
+    #[tokio::test]
+    async fn test_converted_model_matches_candle() {
+        let (src, cache) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        write_hf_llama(src.path());
+        let out = GgufConverter::new(cache.path())
+            .convert(src.path())
+            .unwrap();
+        let spec = |path: PathBuf| ModelSpec {
+            name: "tiny".to_string(),
+            base_path: path,
+            lora_path: None,
+            template: None,
+            ctx_len: 64,
+            n_threads: Some(2),
+            backend: None,
+            command: None,
+        };
+        let opts = || GenOptions {
+            max_tokens: 6,
+            temperature: 0.0,
+            repeat_penalty: 1.0,
+            ..Default::default()
+        };
+        let hf = crate::engine::candle::CandleEngine::new()
+            .load(&spec(src.path().join("model.safetensors")))
+            .await
+            .unwrap();
+        let gguf = GgufNativeEngine::new().load(&spec(out)).await.unwrap();
+        assert_eq!(
+            hf.generate("hello world", opts(), None).await.unwrap(),
+            gguf.generate("hello world", opts(), None).await.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_conversion_is_cached_until_source_changes() {
+        let (src, cache) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        write_hf_llama(src.path());
+        let converter = GgufConverter::new(cache.path()).with_out_type(OutType::Q8_0);
+        let first = converter.convert(src.path()).unwrap();
+        assert!(first.to_string_lossy().ends_with(".q8_0.gguf"));
+        let file = GgufFile::open(&first).unwrap();
+        assert_eq!(
+            file.header.tensor("blk.0.ffn_up.weight").unwrap().ggml_type,
+            GgmlType::Q8_0
+        );
+        assert_eq!(
+            file.header.tensor("output_norm.weight").unwrap().ggml_type,
+            GgmlType::F32
+        );
+        assert_eq!(converter.convert(src.path()).unwrap(), first);
+
+        // A different config.json size is a different source
+        let config = fs::read_to_string(src.path().join("config.json")).unwrap();
+        fs::write(src.path().join("config.json"), format!("{} ", config)).unwrap();
+        let second = converter.convert(src.path()).unwrap();
+        assert_ne!(second, first);
+        assert_eq!(fs::read_dir(cache.path()).unwrap().count(), 2);
+    }
+
+    fn lora_config(base: Option<&Path>) -> Value {
+        serde_json::json!({
+            "peft_type": "LORA",
+            "r": 4,
+            "lora_alpha": 8,
+            "target_modules": ["q_proj", "v_proj"],
+            "base_model_name_or_path": base.map(|p| p.to_string_lossy().to_string()),
+        })
+    }
+
+    fn write_adapter(dir: &Path, config: &Value) -> Vec<(String, Vec<usize>, Vec<f32>)> {
+        fs::write(dir.join("adapter_config.json"), config.to_string()).unwrap();
+        let mut seed = 99u32;
+        let mut tensors = Vec::new();
+        for module in ["q_proj", "v_proj"] {
+            let rows = if module == "q_proj" {
+                D
+            } else {
+                D / HEADS * KV_HEADS
+            };
+            let p = format!("base_model.model.model.layers.0.self_attn.{}", module);
+            tensors.push((
+                format!("{}.lora_A.weight", p),
+                vec![4, D],
+                rand_values(&mut seed, 4 * D),
+            ));
+            tensors.push((
+                format!("{}.lora_B.weight", p),
+                vec![rows, 4],
+                rand_values(&mut seed, rows * 4),
+            ));
+        }
+        write_safetensors(&dir.join("adapter_model.safetensors"), &tensors);
+        tensors
+    }
+
+    #[test]
+    fn test_convert_lora_adapter() {
+        let (base, adapter, cache) = (
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+        );
+        write_hf_llama(base.path());
+        let tensors = write_adapter(adapter.path(), &lora_config(Some(base.path())));
+        assert!(is_lora_adapter(adapter.path()));
+        assert!(is_safetensors_lora(adapter.path()));
+        assert!(!is_safetensors_lora(&adapter.path().join("adapter.gguf")));
+        assert!(is_lora_adapter(
+            &adapter.path().join("adapter_model.safetensors")
+        ));
+
+        let out = GgufConverter::new(cache.path())
+            .convert(&adapter.path().join("adapter_model.safetensors"))
+            .unwrap();
+        let file = GgufFile::open(&out).unwrap();
+        assert_eq!(file.header.get_str("general.type"), Some("adapter"));
+        assert_eq!(file.header.get_str("adapter.type"), Some("lora"));
+        assert_eq!(file.header.get_f32("adapter.lora.alpha"), Some(8.0));
+        assert_eq!(file.header.tensors.len(), 4);
+
+        let info = file.header.tensor("blk.0.attn_q.weight.lora_a").unwrap();
+        assert_eq!(info.dims, vec![D as u64, 4]);
+        let b = &tensors[1].2;
+        let converted = read_tensor(&file, "blk.0.attn_q.weight.lora_b");
+        let expected = permute_rows(b, D, HEADS);
+        for (x, y) in converted.iter().zip(&expected) {
+            assert!((x - y).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_lora_without_local_base_needs_arch() {
+        let (adapter, cache) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        write_adapter(adapter.path(), &lora_config(None));
+        let converter = GgufConverter::new(cache.path());
+        let err = converter.convert(adapter.path()).unwrap_err().to_string();
+        assert!(err.contains("not a local checkpoint"));
+
+        let arch = ArchInfo {
+            arch: "qwen2".to_string(),
+            n_head: HEADS,
+            n_head_kv: KV_HEADS,
+        };
+        let out = converter.convert_lora(adapter.path(), &arch).unwrap();
+        let file = GgufFile::open(&out).unwrap();
+        assert_eq!(file.header.architecture(), Some("qwen2"));
+    }
+
+    #[test]
+    fn test_adapter_scale_honors_patterns_and_rslora() {
+        let dir = TempDir::new().unwrap();
+        let mut config = lora_config(None);
+        config["alpha_pattern"] = serde_json::json!({"v_proj": 16});
+        fs::write(dir.path().join("adapter_config.json"), config.to_string()).unwrap();
+        let config = AdapterConfig::load(dir.path()).unwrap();
+
+        assert!(config.targets("model.layers.0.self_attn.q_proj"));
+        assert!(!config.targets("model.layers.0.mlp.up_proj"));
+        assert_eq!(config.scale("model.layers.0.self_attn.q_proj", 4), 2.0);
+        assert_eq!(config.scale("model.layers.0.self_attn.v_proj", 4), 4.0);
+        // llama.cpp applies 8 / 4 to every tensor, so v_proj's B is doubled
+        assert_eq!(config.b_factor("model.layers.0.self_attn.q_proj", 4), 1.0);
+        assert_eq!(config.b_factor("model.layers.0.self_attn.v_proj", 4), 2.0);
+
+        let rslora = AdapterConfig {
+            r: 16,
+            use_rslora: true,
+            alpha_pattern: HashMap::new(),
+            ..config
+        };
+        assert_eq!(rslora.gguf_alpha(), 32.0);
+        assert_eq!(rslora.scale("model.layers.0.self_attn.q_proj", 16), 2.0);
+        assert_eq!(rslora.b_factor("model.layers.0.self_attn.q_proj", 16), 1.0);
+    }
+
+    #[test]
+    fn test_adapter_config_rejects_what_gguf_cannot_carry() {
+        let dir = TempDir::new().unwrap();
+        let load = |config: &Value| {
+            fs::write(dir.path().join("adapter_config.json"), config.to_string()).unwrap();
+            AdapterConfig::load(dir.path())
+                .map(|_| ())
+                .unwrap_err()
+                .to_string()
+        };
+        let mut config = lora_config(None);
+        config["target_modules"] = serde_json::json!(["q_proj", "embed_tokens"]);
+        assert!(load(&config).contains("targets embed_tokens"));
+
+        let mut config = lora_config(None);
+        config["modules_to_save"] = serde_json::json!(["lm_head"]);
+        assert!(load(&config).contains("modules_to_save"));
+
+        let mut config = lora_config(None);
+        config["peft_type"] = Value::from("IA3");
+        assert!(load(&config).contains("IA3"));
+
+        fs::remove_file(dir.path().join("adapter_config.json")).unwrap();
+        let err = AdapterConfig::load(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("no adapter_config.json"));
+    }
+
+    #[test]
+    fn test_lora_for_gguf_base_folds_alpha_pattern() {
+        let (base, adapter, cache) = (
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+        );
+        let base_gguf = base.path().join("tiny.gguf");
+        crate::engine::gguf_native::tests::write_tiny_llama(&base_gguf, GgmlType::F32);
+        let mut config = lora_config(None);
+        config["alpha_pattern"] = serde_json::json!({"v_proj": 16});
+        let tensors = write_adapter(adapter.path(), &config);
+
+        let converter = GgufConverter::new(cache.path());
+        let out = converter
+            .convert_lora_for_base(adapter.path(), &base_gguf)
+            .unwrap();
+        let file = GgufFile::open(&out).unwrap();
+        assert_eq!(file.header.architecture(), Some("llama"));
+        assert_eq!(file.header.get_f32("adapter.lora.alpha"), Some(8.0));
+
+        let q_b = permute_rows(&tensors[1].2, D, HEADS);
+        let converted = read_tensor(&file, "blk.0.attn_q.weight.lora_b");
+        for (x, y) in converted.iter().zip(&q_b) {
+            assert!((x - y).abs() < 1e-3);
+        }
+        let v_b = &tensors[3].2;
+        let converted = read_tensor(&file, "blk.0.attn_v.weight.lora_b");
+        for (x, y) in converted.iter().zip(v_b) {
+            assert!((x - 2.0 * y).abs() < 2e-3);
+        }
+
+        // Cached on the adapter's size and mtime
+        assert_eq!(
+            converter
+                .convert_lora_for_base(adapter.path(), &base_gguf)
+                .unwrap(),
+            out
+        );
+    }
+}
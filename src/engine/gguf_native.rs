@@ -0,0 +1,1299 @@
+// Native GGUF inference engine - NO C++ dependency
+// Runs Llama-family GGUF models (llama, mistral, qwen2) on the CPU in pure Rust so
+// that `--features fast` and `coverage` builds can serve real completions.
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{debug, info};
+
+use super::executor::ModelExecutor;
+use super::gguf::{dequantize, split_files, GgmlType, GgufFile, GgufHeader};
+use super::shared::WeightCache;
+use super::stream::{self, FinishReason, GenerationEvent, GenerationStats, GenerationStream};
+use super::tokenizer::Tokenizer;
+use super::{Capabilities, GenOptions, InferenceEngine, LoadedModel, ModelSpec};
+
+/// Matrices smaller than this are multiplied on the calling thread
+const PARALLEL_MIN_ELEMENTS: usize = 1 << 16;
+/// Number of recent tokens considered by the repeat penalty (matches llama.cpp)
+pub(crate) const REPEAT_LAST_N: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct GgufNativeEngine;
+
+impl GgufNativeEngine {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Architectures the native forward pass implements
+    pub fn supports_architecture(arch: &str) -> bool {
+        matches!(arch, "llama" | "mistral" | "qwen2")
+    }
+}
+
+#[async_trait]
+impl InferenceEngine for GgufNativeEngine {
+    async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+        if spec.lora_path.is_some() {
+            bail!(
+                "LoRA adapters are not supported by the native GGUF engine; build with --features llama"
+            );
+        }
+        let spec = spec.clone();
+        let model = tokio::task::spawn_blocking(move || GgufNativeModel::load(&spec)).await??;
+        Ok(Box::new(model))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LlamaConfig {
+    arch: String,
+    n_embd: usize,
+    n_layer: usize,
+    n_head: usize,
+    n_head_kv: usize,
+    head_dim: usize,
+    n_ff: usize,
+    rms_eps: f32,
+    rope_base: f32,
+    rope_dim: usize,
+    /// NEOX rotation pairs (i, i + d/2); otherwise adjacent pairs (2i, 2i + 1)
+    rope_neox: bool,
+    ctx_len: usize,
+}
+
+impl LlamaConfig {
+    fn from_header(header: &GgufHeader, requested_ctx: usize) -> Result<Self> {
+        let arch = header
+            .architecture()
+            .ok_or_else(|| anyhow!("GGUF file has no general.architecture"))?
+            .to_string();
+        if !GgufNativeEngine::supports_architecture(&arch) {
+            bail!(
+                "architecture '{}' is not supported by the native GGUF engine; build with --features llama",
+                arch
+            );
+        }
+        let key = |k: &str| format!("{}.{}", arch, k);
+        let req = |k: &str| {
+            header
+                .get_u64(&key(k))
+                .map(|v| v as usize)
+                .ok_or_else(|| anyhow!("GGUF metadata is missing {}", key(k)))
+        };
+
+        let n_embd = req("embedding_length")?;
+        let n_head = req("attention.head_count")?;
+        let n_head_kv = header
+            .get_u64(&key("attention.head_count_kv"))
+            .map(|v| v as usize)
+            .unwrap_or(n_head);
+        if n_head == 0 || n_head_kv == 0 || n_head % n_head_kv != 0 || n_embd % n_head != 0 {
+            bail!(
+                "invalid attention shape: {} heads, {} kv heads, embedding {}",
+                n_head,
+                n_head_kv,
+                n_embd
+            );
+        }
+        let head_dim = n_embd / n_head;
+        let model_ctx = header
+            .get_u64(&key("context_length"))
+            .map(|v| v as usize)
+            .unwrap_or(requested_ctx);
+
+        Ok(Self {
+            n_embd,
+            n_layer: req("block_count")?,
+            n_head,
+            n_head_kv,
+            head_dim,
+            n_ff: req("feed_forward_length")?,
+            rms_eps: header
+                .get_f32(&key("attention.layer_norm_rms_epsilon"))
+                .unwrap_or(1e-5),
+            rope_base: header.get_f32(&key("rope.freq_base")).unwrap_or(10000.0),
+            rope_dim: header
+                .get_u64(&key("rope.dimension_count"))
+                .map(|v| v as usize)
+                .unwrap_or(head_dim),
+            rope_neox: arch == "qwen2",
+            ctx_len: requested_ctx.min(model_ctx).max(1),
+            arch,
+        })
+    }
+
+    fn kv_dim(&self) -> usize {
+        self.n_head_kv * self.head_dim
+    }
+}
+
+/// A (possibly quantized) row-major matrix living inside one of the mmapped
+/// GGUF splits
+#[derive(Debug, Clone)]
+struct QMatrix {
+    ty: GgmlType,
+    rows: usize,
+    cols: usize,
+    split: usize,
+    start: usize,
+}
+
+impl QMatrix {
+    fn row_bytes(&self) -> usize {
+        self.ty.row_bytes(self.cols)
+    }
+
+    fn row<'a>(&self, files: &'a [GgufFile], r: usize) -> &'a [u8] {
+        let rb = self.row_bytes();
+        let begin = self.start + r * rb;
+        &files[self.split].bytes()[begin..begin + rb]
+    }
+}
+
+#[derive(Debug)]
+struct LayerWeights {
+    attn_norm: Vec<f32>,
+    wq: QMatrix,
+    wk: QMatrix,
+    wv: QMatrix,
+    wo: QMatrix,
+    bq: Option<Vec<f32>>,
+    bk: Option<Vec<f32>>,
+    bv: Option<Vec<f32>>,
+    ffn_norm: Vec<f32>,
+    w_gate: QMatrix,
+    w_up: QMatrix,
+    w_down: QMatrix,
+}
+
+#[derive(Debug)]
+struct Weights {
+    files: Vec<GgufFile>,
+    token_embd: QMatrix,
+    layers: Vec<LayerWeights>,
+    output_norm: Vec<f32>,
+    output: QMatrix,
+}
+
+impl Weights {
+    fn load(files: Vec<GgufFile>, cfg: &LlamaConfig) -> Result<Self> {
+        // Split models spread tensors across files; each name lives in exactly one
+        let find = |name: &str| {
+            files
+                .iter()
+                .enumerate()
+                .find_map(|(i, f)| f.header.tensor(name).map(|info| (i, f, info)))
+        };
+        let matrix = |name: &str, rows: usize, cols: usize| -> Result<QMatrix> {
+            let (split, file, info) =
+                find(name).ok_or_else(|| anyhow!("GGUF file is missing tensor '{}'", name))?;
+            if !info.ggml_type.is_supported() {
+                bail!(
+                    "tensor '{}' uses {:?}, which the native engine cannot dequantize",
+                    name,
+                    info.ggml_type
+                );
+            }
+            if info.dims.len() != 2
+                || info.dims[0] as usize != cols
+                || info.dims[1] as usize != rows
+            {
+                bail!(
+                    "tensor '{}' has shape {:?}, expected [{}, {}]",
+                    name,
+                    info.dims,
+                    cols,
+                    rows
+                );
+            }
+            if !cols.is_multiple_of(info.ggml_type.block_size()) {
+                bail!("tensor '{}' rows are not block aligned", name);
+            }
+            file.tensor_data(info)?;
+            Ok(QMatrix {
+                ty: info.ggml_type,
+                rows,
+                cols,
+                split,
+                start: (file.header.data_offset + info.offset) as usize,
+            })
+        };
+        let vector = |name: &str, len: usize| -> Result<Vec<f32>> {
+            let (_, file, info) =
+                find(name).ok_or_else(|| anyhow!("GGUF file is missing tensor '{}'", name))?;
+            if info.n_elements() != len {
+                bail!(
+                    "tensor '{}' has {} elements, expected {}",
+                    name,
+                    info.n_elements(),
+                    len
+                );
+            }
+            let mut out = vec![0f32; len];
+            dequantize(info.ggml_type, file.tensor_data(info)?, &mut out)?;
+            Ok(out)
+        };
+        let optional_vector = |name: &str, len: usize| -> Result<Option<Vec<f32>>> {
+            match find(name) {
+                Some(_) => vector(name, len).map(Some),
+                None => Ok(None),
+            }
+        };
+
+        let vocab = find("token_embd.weight")
+            .and_then(|(_, _, t)| t.dims.get(1).copied())
+            .ok_or_else(|| anyhow!("GGUF file is missing tensor 'token_embd.weight'"))?
+            as usize;
+        let (d, kv, ff) = (cfg.n_embd, cfg.kv_dim(), cfg.n_ff);
+
+        let token_embd = matrix("token_embd.weight", vocab, d)?;
+        let output = if find("output.weight").is_some() {
+            matrix("output.weight", vocab, d)?
+        } else {
+            // Tied embeddings
+            token_embd.clone()
+        };
+
+        let mut layers = Vec::with_capacity(cfg.n_layer);
+        for i in 0..cfg.n_layer {
+            let n = |s: &str| format!("blk.{}.{}", i, s);
+            layers.push(LayerWeights {
+                attn_norm: vector(&n("attn_norm.weight"), d)?,
+                wq: matrix(&n("attn_q.weight"), d, d)?,
+                wk: matrix(&n("attn_k.weight"), kv, d)?,
+                wv: matrix(&n("attn_v.weight"), kv, d)?,
+                wo: matrix(&n("attn_output.weight"), d, d)?,
+                bq: optional_vector(&n("attn_q.bias"), d)?,
+                bk: optional_vector(&n("attn_k.bias"), kv)?,
+                bv: optional_vector(&n("attn_v.bias"), kv)?,
+                ffn_norm: vector(&n("ffn_norm.weight"), d)?,
+                w_gate: matrix(&n("ffn_gate.weight"), ff, d)?,
+                w_up: matrix(&n("ffn_up.weight"), ff, d)?,
+                w_down: matrix(&n("ffn_down.weight"), d, ff)?,
+            });
+        }
+        let output_norm = vector("output_norm.weight", d)?;
+
+        Ok(Self {
+            files,
+            token_embd,
+            layers,
+            output_norm,
+            output,
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Entries that name the same GGUF share its weights; each keeps its own KV cache
+    static ref WEIGHTS: WeightCache<Weights> = WeightCache::new();
+}
+
+/// KV cache plus the tokens it currently holds, reused across requests that
+/// share a prompt prefix
+struct Session {
+    keys: Vec<Vec<f32>>,
+    values: Vec<Vec<f32>>,
+    tokens: Vec<u32>,
+}
+
+struct Inner {
+    name: String,
+    config: LlamaConfig,
+    weights: Arc<Weights>,
+    tokenizer: Tokenizer,
+    session: Mutex<Session>,
+    n_threads: usize,
+    chat_template: bool,
+}
+
+pub struct GgufNativeModel {
+    inner: Arc<Inner>,
+    executor: ModelExecutor,
+}
+
+impl GgufNativeModel {
+    fn load(spec: &ModelSpec) -> Result<Self> {
+        let paths = split_files(&spec.base_path)?;
+        let files = paths
+            .iter()
+            .map(|path| GgufFile::open(path))
+            .collect::<Result<Vec<_>>>()?;
+        // Hyperparameters and vocabulary live in the first split
+        let header = &files[0].header;
+        if let Some(count) = header.get_u64("split.count") {
+            if count as usize != files.len() {
+                bail!(
+                    "GGUF metadata expects {} splits but {} were found",
+                    count,
+                    files.len()
+                );
+            }
+        }
+        let config = LlamaConfig::from_header(header, spec.ctx_len)?;
+        let tokenizer = Tokenizer::from_gguf(header)?;
+        let chat_template = header.get_str("tokenizer.chat_template").is_some();
+        info!(
+            path = %spec.base_path.display(),
+            arch = %config.arch,
+            layers = config.n_layer,
+            ctx = config.ctx_len,
+            splits = files.len(),
+            "Loading GGUF model with native engine"
+        );
+        let weights = WEIGHTS.get_or_load(&paths[0], || Weights::load(files, &config))?;
+        if weights.token_embd.rows != tokenizer.vocab_size() {
+            debug!(
+                "Embedding rows ({}) differ from tokenizer vocabulary ({})",
+                weights.token_embd.rows,
+                tokenizer.vocab_size()
+            );
+        }
+
+        let cache_len = config.ctx_len * config.kv_dim();
+        let session = Session {
+            keys: vec![vec![0f32; cache_len]; config.n_layer],
+            values: vec![vec![0f32; cache_len]; config.n_layer],
+            tokens: Vec::new(),
+        };
+        let n_threads = spec
+            .n_threads
+            .map(|n| n.max(1) as usize)
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(4)
+            });
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                name: spec.name.clone(),
+                config,
+                weights,
+                tokenizer,
+                session: Mutex::new(session),
+                n_threads,
+                chat_template,
+            }),
+            executor: ModelExecutor::new(&spec.name)?,
+        })
+    }
+}
+
+#[async_trait]
+impl LoadedModel for GgufNativeModel {
+    async fn generate(
+        &self,
+        prompt: &str,
+        opts: GenOptions,
+        on_token: Option<Box<dyn FnMut(String) + Send>>,
+    ) -> Result<String> {
+        let inner = Arc::clone(&self.inner);
+        let prompt = prompt.to_string();
+        self.executor
+            .run(move || {
+                stream::collect_text(|emit| inner.generate(&prompt, &opts, emit), on_token)
+            })
+            .await?
+    }
+
+    fn generate_stream(self: Arc<Self>, prompt: String, opts: GenOptions) -> GenerationStream {
+        let inner = Arc::clone(&self.inner);
+        self.executor
+            .stream(move |emit| inner.generate(&prompt, &opts, emit))
+    }
+
+    fn tokenize(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        Ok(self.inner.tokenizer.encode(text, add_special))
+    }
+
+    fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        Ok(self.inner.tokenizer.decode(tokens))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            chat_template: self.inner.chat_template,
+            tokenize: true,
+            logprobs: true,
+            ..Capabilities::text(self.inner.config.ctx_len)
+        }
+    }
+}
+
+impl Inner {
+    fn generate(
+        &self,
+        prompt: &str,
+        opts: &GenOptions,
+        emit: &mut dyn FnMut(GenerationEvent) -> bool,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let prompt_tokens = self.tokenizer.encode(prompt, true);
+        if prompt_tokens.is_empty() {
+            bail!("prompt produced no tokens");
+        }
+        let ctx_len = self.config.ctx_len;
+        if prompt_tokens.len() >= ctx_len {
+            bail!(
+                "prompt is {} tokens but the context window is {}",
+                prompt_tokens.len(),
+                ctx_len
+            );
+        }
+
+        let mut session = self
+            .session
+            .lock()
+            .map_err(|_| anyhow!("model session poisoned by an earlier panic"))?;
+
+        // Reuse the cached prefix; at least one token must be evaluated for logits
+        let reuse = session
+            .tokens
+            .iter()
+            .zip(&prompt_tokens)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(prompt_tokens.len() - 1);
+        session.tokens.truncate(reuse);
+        debug!(
+            model = %self.name,
+            prompt_tokens = prompt_tokens.len(),
+            reused = reuse,
+            "native GGUF prefill"
+        );
+
+        let total = prompt_tokens.len();
+        emit(GenerationEvent::PromptProgress {
+            processed: reuse,
+            total,
+        });
+        let mut logits = self.forward(&mut session, &prompt_tokens[reuse..])?;
+        emit(GenerationEvent::PromptProgress {
+            processed: total,
+            total,
+        });
+        let prompt_done = Instant::now();
+
+        let mut rng = match opts.seed {
+            Some(seed) => StdRng::seed_from_u64(seed as u64),
+            None => StdRng::from_entropy(),
+        };
+        let mut decoder = self.tokenizer.stream_decoder();
+        let mut finish_reason = FinishReason::Length;
+        let mut generated = 0;
+        while generated < opts.max_tokens {
+            let recent_start = session.tokens.len().saturating_sub(REPEAT_LAST_N);
+            let token = sample(&mut logits, opts, &session.tokens[recent_start..], &mut rng);
+            if self.tokenizer.is_eog(token) {
+                finish_reason = FinishReason::Stop;
+                break;
+            }
+            generated += 1;
+            let sent = emit(GenerationEvent::Token {
+                id: Some(token),
+                text: decoder.push(token),
+                logprob: Some(logprob(&logits, token)),
+            });
+            // The client went away
+            if !sent {
+                finish_reason = FinishReason::Cancelled;
+                break;
+            }
+            if session.tokens.len() + 1 >= ctx_len {
+                break;
+            }
+            logits = self.forward(&mut session, &[token])?;
+        }
+        let tail = decoder.flush();
+        if !tail.is_empty() {
+            emit(GenerationEvent::text(tail));
+        }
+        emit(GenerationEvent::Done {
+            finish_reason,
+            stats: GenerationStats::timed(total, generated, started, Some(prompt_done)),
+        });
+        Ok(())
+    }
+
+    /// Run `tokens` through the transformer starting at the session's current
+    /// position and return the logits of the last token
+    fn forward(&self, session: &mut Session, tokens: &[u32]) -> Result<Vec<f32>> {
+        let cfg = &self.config;
+        let w = &self.weights;
+        let files = &w.files;
+        let (d, kv_dim, hd) = (cfg.n_embd, cfg.kv_dim(), cfg.head_dim);
+        let n = tokens.len();
+        let start_pos = session.tokens.len();
+        if start_pos + n > cfg.ctx_len {
+            bail!("context window of {} tokens exceeded", cfg.ctx_len);
+        }
+
+        let mut x = vec![0f32; n * d];
+        for (t, &tok) in tokens.iter().enumerate() {
+            if tok as usize >= w.token_embd.rows {
+                bail!("token id {} outside embedding table", tok);
+            }
+            dequantize(
+                w.token_embd.ty,
+                w.token_embd.row(files, tok as usize),
+                &mut x[t * d..(t + 1) * d],
+            )?;
+        }
+
+        let mut xn = vec![0f32; n * d];
+        let mut q = vec![0f32; n * d];
+        let mut k = vec![0f32; n * kv_dim];
+        let mut v = vec![0f32; n * kv_dim];
+        let mut attn = vec![0f32; n * d];
+        let mut proj = vec![0f32; n * d];
+        let mut gate = vec![0f32; n * cfg.n_ff];
+        let mut up = vec![0f32; n * cfg.n_ff];
+        let group = cfg.n_head / cfg.n_head_kv;
+        let scale = 1.0 / (hd as f32).sqrt();
+
+        for (l, layer) in w.layers.iter().enumerate() {
+            rms_norm(&x, &layer.attn_norm, cfg.rms_eps, &mut xn);
+            self.matmul(&layer.wq, &xn, n, &mut q)?;
+            self.matmul(&layer.wk, &xn, n, &mut k)?;
+            self.matmul(&layer.wv, &xn, n, &mut v)?;
+            add_bias(&mut q, layer.bq.as_deref());
+            add_bias(&mut k, layer.bk.as_deref());
+            add_bias(&mut v, layer.bv.as_deref());
+
+            for t in 0..n {
+                let pos = start_pos + t;
+                for h in q[t * d..(t + 1) * d].chunks_exact_mut(hd) {
+                    rope(h, pos, cfg);
+                }
+                for h in k[t * kv_dim..(t + 1) * kv_dim].chunks_exact_mut(hd) {
+                    rope(h, pos, cfg);
+                }
+                session.keys[l][pos * kv_dim..(pos + 1) * kv_dim]
+                    .copy_from_slice(&k[t * kv_dim..(t + 1) * kv_dim]);
+                session.values[l][pos * kv_dim..(pos + 1) * kv_dim]
+                    .copy_from_slice(&v[t * kv_dim..(t + 1) * kv_dim]);
+            }
+
+            let keys = &session.keys[l];
+            let values = &session.values[l];
+            let mut scores = vec![0f32; start_pos + n];
+            for t in 0..n {
+                let n_ctx = start_pos + t + 1;
+                for h in 0..cfg.n_head {
+                    let kvh = h / group;
+                    let qh = &q[t * d + h * hd..t * d + (h + 1) * hd];
+                    for (p, s) in scores[..n_ctx].iter_mut().enumerate() {
+                        let kp = &keys[p * kv_dim + kvh * hd..p * kv_dim + (kvh + 1) * hd];
+                        *s = dot(qh, kp) * scale;
+                    }
+                    softmax(&mut scores[..n_ctx]);
+                    let out = &mut attn[t * d + h * hd..t * d + (h + 1) * hd];
+                    out.fill(0.0);
+                    for (p, &s) in scores[..n_ctx].iter().enumerate() {
+                        let vp = &values[p * kv_dim + kvh * hd..p * kv_dim + (kvh + 1) * hd];
+                        axpy(s, vp, out);
+                    }
+                }
+            }
+
+            self.matmul(&layer.wo, &attn, n, &mut proj)?;
+            x.iter_mut().zip(&proj).for_each(|(a, b)| *a += b);
+
+            rms_norm(&x, &layer.ffn_norm, cfg.rms_eps, &mut xn);
+            self.matmul(&layer.w_gate, &xn, n, &mut gate)?;
+            self.matmul(&layer.w_up, &xn, n, &mut up)?;
+            gate.iter_mut()
+                .zip(&up)
+                .for_each(|(g, u)| *g = silu(*g) * u);
+            self.matmul(&layer.w_down, &gate, n, &mut proj)?;
+            x.iter_mut().zip(&proj).for_each(|(a, b)| *a += b);
+        }
+
+        session.tokens.extend_from_slice(tokens);
+
+        let last = &x[(n - 1) * d..];
+        let mut normed = vec![0f32; d];
+        rms_norm(last, &w.output_norm, cfg.rms_eps, &mut normed);
+        let mut logits = vec![0f32; w.output.rows];
+        self.matmul(&w.output, &normed, 1, &mut logits)?;
+        Ok(logits)
+    }
+
+    /// `out[t][r] = sum_c w[r][c] * x[t][c]` for `n_tok` input vectors.
+    /// Each weight row is dequantized once and reused for every token.
+    fn matmul(&self, w: &QMatrix, x: &[f32], n_tok: usize, out: &mut [f32]) -> Result<()> {
+        let files = &self.weights.files;
+        let compute = |rows: std::ops::Range<usize>, dst: &mut [f32]| -> Result<()> {
+            let mut row = vec![0f32; w.cols];
+            for (i, r) in rows.enumerate() {
+                dequantize(w.ty, w.row(files, r), &mut row)?;
+                for t in 0..n_tok {
+                    dst[i * n_tok + t] = dot(&row, &x[t * w.cols..(t + 1) * w.cols]);
+                }
+            }
+            Ok(())
+        };
+
+        let threads = if w.rows * w.cols < PARALLEL_MIN_ELEMENTS {
+            1
+        } else {
+            self.n_threads.min(w.rows).max(1)
+        };
+        // Results are computed row-major ([row][token]) and transposed into `out`
+        let mut by_row = vec![0f32; w.rows * n_tok];
+        if threads == 1 {
+            compute(0..w.rows, &mut by_row)?;
+        } else {
+            let chunk = w.rows.div_ceil(threads);
+            std::thread::scope(|s| -> Result<()> {
+                let handles: Vec<_> = by_row
+                    .chunks_mut(chunk * n_tok)
+                    .enumerate()
+                    .map(|(i, dst)| {
+                        let rows = i * chunk..((i + 1) * chunk).min(w.rows);
+                        s.spawn(move || compute(rows, dst))
+                    })
+                    .collect();
+                for h in handles {
+                    h.join().map_err(|_| anyhow!("matmul worker panicked"))??;
+                }
+                Ok(())
+            })?;
+        }
+        for r in 0..w.rows {
+            for t in 0..n_tok {
+                out[t * w.rows + r] = by_row[r * n_tok + t];
+            }
+        }
+        Ok(())
+    }
+}
+
+fn add_bias(x: &mut [f32], bias: Option<&[f32]>) {
+    if let Some(b) = bias {
+        for row in x.chunks_exact_mut(b.len()) {
+            row.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+        }
+    }
+}
+
+fn rms_norm(x: &[f32], weight: &[f32], eps: f32, out: &mut [f32]) {
+    let d = weight.len();
+    for (xr, or) in x.chunks_exact(d).zip(out.chunks_exact_mut(d)) {
+        let ms = dot(xr, xr) / d as f32;
+        let inv = 1.0 / (ms + eps).sqrt();
+        for ((o, &v), &g) in or.iter_mut().zip(xr).zip(weight) {
+            *o = v * inv * g;
+        }
+    }
+}
+
+fn rope(head: &mut [f32], pos: usize, cfg: &LlamaConfig) {
+    let dim = cfg.rope_dim.min(head.len());
+    let half = dim / 2;
+    for i in 0..half {
+        let theta = pos as f32 * cfg.rope_base.powf(-2.0 * i as f32 / dim as f32);
+        let (sin, cos) = theta.sin_cos();
+        let (a, b) = if cfg.rope_neox {
+            (i, i + half)
+        } else {
+            (2 * i, 2 * i + 1)
+        };
+        let (x0, x1) = (head[a], head[b]);
+        head[a] = x0 * cos - x1 * sin;
+        head[b] = x0 * sin + x1 * cos;
+    }
+}
+
+fn softmax(x: &mut [f32]) {
+    let max = x.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for v in x.iter_mut() {
+        *v = (*v - max).exp();
+        sum += *v;
+    }
+    if sum > 0.0 {
+        x.iter_mut().for_each(|v| *v /= sum);
+    }
+}
+
+#[inline]
+fn silu(x: f32) -> f32 {
+    x / (1.0 + (-x).exp())
+}
+
+#[inline]
+fn axpy(a: f32, x: &[f32], y: &mut [f32]) {
+    y.iter_mut().zip(x).for_each(|(y, x)| *y += a * x);
+}
+
+/// Dot product with an AVX2/FMA kernel when available. The portable path uses
+/// eight independent accumulators so LLVM vectorizes it (SSE/NEON).
+#[inline]
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    debug_assert_eq!(a.len(), b.len());
+    #[cfg(target_arch = "x86_64")]
+    {
+        if std::arch::is_x86_feature_detected!("avx2") && std::arch::is_x86_feature_detected!("fma")
+        {
+            // SAFETY: the required CPU features were detected at runtime
+            return unsafe { dot_avx2(a, b) };
+        }
+    }
+    dot_portable(a, b)
+}
+
+fn dot_portable(a: &[f32], b: &[f32]) -> f32 {
+    let mut acc = [0f32; 8];
+    let chunks = a.len() / 8;
+    for (ca, cb) in a.chunks_exact(8).zip(b.chunks_exact(8)) {
+        for i in 0..8 {
+            acc[i] += ca[i] * cb[i];
+        }
+    }
+    let mut sum: f32 = acc.iter().sum();
+    for i in chunks * 8..a.len() {
+        sum += a[i] * b[i];
+    }
+    sum
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn dot_avx2(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+    let n = a.len().min(b.len());
+    let mut acc0 = _mm256_setzero_ps();
+    let mut acc1 = _mm256_setzero_ps();
+    let mut i = 0;
+    while i + 16 <= n {
+        acc0 = _mm256_fmadd_ps(
+            _mm256_loadu_ps(a.as_ptr().add(i)),
+            _mm256_loadu_ps(b.as_ptr().add(i)),
+            acc0,
+        );
+        acc1 = _mm256_fmadd_ps(
+            _mm256_loadu_ps(a.as_ptr().add(i + 8)),
+            _mm256_loadu_ps(b.as_ptr().add(i + 8)),
+            acc1,
+        );
+        i += 16;
+    }
+    let acc = _mm256_add_ps(acc0, acc1);
+    let hi = _mm256_extractf128_ps(acc, 1);
+    let lo = _mm256_castps256_ps128(acc);
+    let s = _mm_add_ps(hi, lo);
+    let s = _mm_add_ps(s, _mm_movehl_ps(s, s));
+    let s = _mm_add_ss(s, _mm_shuffle_ps(s, s, 1));
+    let mut sum = _mm_cvtss_f32(s);
+    while i < n {
+        sum += a[i] * b[i];
+        i += 1;
+    }
+    sum
+}
+
+/// Log probability of `token` under the logits `sample` left behind
+pub(crate) fn logprob(logits: &[f32], token: u32) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|&l| (l - max).exp()).sum();
+    logits[token as usize] - max - sum.ln()
+}
+
+/// Pick the next token: repeat penalty, then greedy at temperature 0, otherwise
+/// top-k, temperature, top-p and a weighted draw.
+pub(crate) fn sample(
+    logits: &mut [f32],
+    opts: &GenOptions,
+    recent: &[u32],
+    rng: &mut StdRng,
+) -> u32 {
+    if opts.repeat_penalty != 1.0 && opts.repeat_penalty > 0.0 {
+        for &t in recent {
+            if let Some(l) = logits.get_mut(t as usize) {
+                *l = if *l > 0.0 {
+                    *l / opts.repeat_penalty
+                } else {
+                    *l * opts.repeat_penalty
+                };
+            }
+        }
+    }
+
+    let argmax = || {
+        logits
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i as u32)
+            .unwrap_or(0)
+    };
+    if opts.temperature <= 0.0 || opts.top_k == 1 {
+        return argmax();
+    }
+
+    let mut candidates: Vec<(u32, f32)> = logits
+        .iter()
+        .enumerate()
+        .map(|(i, &l)| (i as u32, l))
+        .collect();
+    candidates.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+    if opts.top_k > 0 {
+        candidates.truncate(opts.top_k as usize);
+    }
+
+    let max = candidates[0].1;
+    let mut total = 0.0;
+    for c in candidates.iter_mut() {
+        c.1 = ((c.1 - max) / opts.temperature).exp();
+        total += c.1;
+    }
+    let mut cumulative = 0.0;
+    let len = candidates.len();
+    let mut keep = len;
+    for (i, c) in candidates.iter_mut().enumerate() {
+        c.1 /= total;
+        cumulative += c.1;
+        if opts.top_p < 1.0 && cumulative >= opts.top_p && keep == len {
+            keep = i + 1;
+        }
+    }
+    candidates.truncate(keep);
+
+    let mass: f32 = candidates.iter().map(|c| c.1).sum();
+    let mut r = rng.gen::<f32>() * mass;
+    for &(id, p) in &candidates {
+        if r < p {
+            return id;
+        }
+        r -= p;
+    }
+    candidates.last().map(|c| c.0).unwrap_or_else(argmax)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::engine::gguf::{quantize, GgufWriter, MetadataValue};
+    use crate::loaded_models::{KeepAlive, LoadedModels};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Write a tiny random Llama model with a character-level vocabulary.
+    /// `ty` controls how the projection matrices are stored.
+    pub(crate) fn write_tiny_llama(path: &Path, ty: GgmlType) {
+        let (d, ff, layers, heads, kv_heads) = (64usize, 128usize, 2usize, 4u32, 2u32);
+        let mut pieces = vec!["<unk>".to_string(), "<s>".to_string(), "</s>".to_string()];
+        let mut types = vec![2i32, 3, 3];
+        for c in "▁abcdefghijklmnopqrstuvwxyz".chars() {
+            pieces.push(c.to_string());
+            types.push(1);
+        }
+        let vocab = pieces.len();
+
+        let mut w = GgufWriter::new();
+        let s = |v: &str| MetadataValue::String(v.to_string());
+        w.add_metadata("general.architecture", s("llama"))
+            .add_metadata("llama.context_length", MetadataValue::U32(256))
+            .add_metadata("llama.embedding_length", MetadataValue::U32(d as u32))
+            .add_metadata("llama.block_count", MetadataValue::U32(layers as u32))
+            .add_metadata("llama.feed_forward_length", MetadataValue::U32(ff as u32))
+            .add_metadata("llama.attention.head_count", MetadataValue::U32(heads))
+            .add_metadata(
+                "llama.attention.head_count_kv",
+                MetadataValue::U32(kv_heads),
+            )
+            .add_metadata(
+                "llama.attention.layer_norm_rms_epsilon",
+                MetadataValue::F32(1e-5),
+            )
+            .add_metadata("tokenizer.ggml.model", s("llama"))
+            .add_metadata(
+                "tokenizer.ggml.tokens",
+                MetadataValue::Array(pieces.iter().map(|p| s(p)).collect()),
+            )
+            .add_metadata(
+                "tokenizer.ggml.scores",
+                MetadataValue::Array((0..vocab).map(|_| MetadataValue::F32(0.0)).collect()),
+            )
+            .add_metadata(
+                "tokenizer.ggml.token_type",
+                MetadataValue::Array(types.iter().map(|&t| MetadataValue::I32(t)).collect()),
+            )
+            .add_metadata("tokenizer.ggml.bos_token_id", MetadataValue::U32(1))
+            .add_metadata("tokenizer.ggml.eos_token_id", MetadataValue::U32(2));
+
+        let mut seed = 0x2545F491u32;
+        let mut rand_vec = |n: usize, scale: f32| -> Vec<f32> {
+            (0..n)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 17;
+                    seed ^= seed << 5;
+                    ((seed % 2000) as f32 / 1000.0 - 1.0) * scale
+                })
+                .collect()
+        };
+        let mut add = |name: String, dims: Vec<u64>, ty: GgmlType, values: Vec<f32>| {
+            w.add_tensor(name, dims, ty, quantize(ty, &values).unwrap())
+                .unwrap();
+        };
+
+        add(
+            "token_embd.weight".into(),
+            vec![d as u64, vocab as u64],
+            GgmlType::F32,
+            rand_vec(d * vocab, 1.0),
+        );
+        let kv = d / heads as usize * kv_heads as usize;
+        for l in 0..layers {
+            let n = |s: &str| format!("blk.{}.{}", l, s);
+            add(
+                n("attn_norm.weight"),
+                vec![d as u64],
+                GgmlType::F32,
+                vec![1.0; d],
+            );
+            add(
+                n("ffn_norm.weight"),
+                vec![d as u64],
+                GgmlType::F32,
+                vec![1.0; d],
+            );
+            for (name, rows, cols) in [
+                ("attn_q.weight", d, d),
+                ("attn_k.weight", kv, d),
+                ("attn_v.weight", kv, d),
+                ("attn_output.weight", d, d),
+                ("ffn_gate.weight", ff, d),
+                ("ffn_up.weight", ff, d),
+                ("ffn_down.weight", d, ff),
+            ] {
+                add(
+                    n(name),
+                    vec![cols as u64, rows as u64],
+                    ty,
+                    rand_vec(rows * cols, 0.3),
+                );
+            }
+        }
+        add(
+            "output_norm.weight".into(),
+            vec![d as u64],
+            GgmlType::F32,
+            vec![1.0; d],
+        );
+        w.write_file(path).unwrap();
+    }
+
+    fn spec_for(path: PathBuf) -> ModelSpec {
+        ModelSpec {
+            name: "tiny".to_string(),
+            base_path: path,
+            lora_path: None,
+            template: None,
+            ctx_len: 128,
+            n_threads: Some(2),
+            backend: None,
+            command: None,
+        }
+    }
+
+    fn greedy(max_tokens: usize) -> GenOptions {
+        GenOptions {
+            max_tokens,
+            temperature: 0.0,
+            repeat_penalty: 1.0,
+            stream: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_supported_architectures() {
+        assert!(GgufNativeEngine::supports_architecture("llama"));
+        assert!(GgufNativeEngine::supports_architecture("qwen2"));
+        assert!(!GgufNativeEngine::supports_architecture("phi3"));
+    }
+
+    #[test]
+    fn test_dot_matches_portable() {
+        let a: Vec<f32> = (0..37).map(|i| i as f32 * 0.5).collect();
+        let b: Vec<f32> = (0..37).map(|i| 1.0 - i as f32 * 0.1).collect();
+        assert!((dot(&a, &b) - dot_portable(&a, &b)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_sample_greedy_and_top_k() {
+        let opts = GenOptions {
+            temperature: 0.0,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(sample(&mut [0.1, 3.0, 0.5], &opts, &[], &mut rng), 1);
+
+        let opts = GenOptions {
+            temperature: 1.0,
+            top_k: 1,
+            ..Default::default()
+        };
+        assert_eq!(sample(&mut [0.1, 0.2, 5.0], &opts, &[], &mut rng), 2);
+    }
+
+    #[test]
+    fn test_repeat_penalty_demotes_recent_tokens() {
+        let opts = GenOptions {
+            temperature: 0.0,
+            repeat_penalty: 10.0,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(sample(&mut [2.0, 1.0], &opts, &[0], &mut rng), 1);
+    }
+
+    #[tokio::test]
+    async fn test_generate_is_deterministic_and_reuses_prefix() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        write_tiny_llama(&path, GgmlType::Q8_0);
+
+        let model = GgufNativeEngine::new()
+            .load(&spec_for(path.clone()))
+            .await
+            .unwrap();
+        let first = model
+            .generate("hello world", greedy(8), None)
+            .await
+            .unwrap();
+        let second = model
+            .generate("hello world", greedy(8), None)
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+
+        // A freshly loaded model without a warm cache must agree
+        let fresh = GgufNativeEngine::new().load(&spec_for(path)).await.unwrap();
+        assert_eq!(
+            fresh
+                .generate("hello world", greedy(8), None)
+                .await
+                .unwrap(),
+            first
+        );
+    }
+
+    #[tokio::test]
+    async fn test_entries_for_one_file_share_weights_but_not_sessions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        write_tiny_llama(&path, GgmlType::Q8_0);
+
+        let mut short = spec_for(path.clone());
+        short.name = "tiny-short".to_string();
+        short.ctx_len = 32;
+        let a = GgufNativeModel::load(&spec_for(path)).unwrap();
+        let b = GgufNativeModel::load(&short).unwrap();
+        assert!(Arc::ptr_eq(&a.inner.weights, &b.inner.weights));
+        assert_eq!(b.inner.config.ctx_len, 32);
+
+        let first = a.generate("hello", greedy(6), None).await.unwrap();
+        assert!(b.inner.session.lock().unwrap().tokens.is_empty());
+        assert_eq!(b.generate("hello", greedy(6), None).await.unwrap(), first);
+
+        let weights = Arc::downgrade(&a.inner.weights);
+        drop((a, b));
+        assert!(weights.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cached_models_keep_their_weights_until_unloaded() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        write_tiny_llama(&path, GgmlType::Q8_0);
+        let models = LoadedModels::new(KeepAlive::default());
+        let spec = spec_for(path.clone());
+        let model = models
+            .get_or_load(&GgufNativeEngine::new(), &spec, None)
+            .await
+            .unwrap();
+        model.generate("hello", greedy(2), None).await.unwrap();
+        drop(model);
+
+        // The request is over, but the cached model still holds the weights
+        let reload = || -> Result<Weights> { bail!("weights were loaded again") };
+        assert!(WEIGHTS.get_or_load(&path, reload).is_ok());
+        assert!(models.unload("tiny"));
+        assert!(WEIGHTS.get_or_load(&path, reload).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_callback_matches_output() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tiny-q4.gguf");
+        write_tiny_llama(&path, GgmlType::Q4_0);
+
+        let model = GgufNativeEngine::new().load(&spec_for(path)).await.unwrap();
+        let streamed = Arc::new(Mutex::new(String::new()));
+        let sink = Arc::clone(&streamed);
+        let out = model
+            .generate(
+                "abc",
+                greedy(6),
+                Some(Box::new(move |tok| sink.lock().unwrap().push_str(&tok))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(*streamed.lock().unwrap(), out);
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_reports_ids_progress_and_stats() {
+        use futures_util::StreamExt;
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        write_tiny_llama(&path, GgmlType::Q8_0);
+
+        let model: Arc<dyn LoadedModel> =
+            Arc::from(GgufNativeEngine::new().load(&spec_for(path)).await.unwrap());
+        let expected = model.generate("abc", greedy(6), None).await.unwrap();
+        let events: Vec<_> = model
+            .clone()
+            .generate_stream("abc".to_string(), greedy(6))
+            .collect()
+            .await;
+
+        let prompt_tokens = model.tokenize("abc", true).unwrap().len();
+        assert!(events.contains(&GenerationEvent::PromptProgress {
+            processed: prompt_tokens,
+            total: prompt_tokens,
+        }));
+        let mut text = String::new();
+        let mut ids = 0;
+        for event in &events {
+            if let GenerationEvent::Token {
+                id,
+                text: piece,
+                logprob,
+            } = event
+            {
+                text.push_str(piece);
+                if id.is_some() {
+                    ids += 1;
+                    assert!(logprob.is_some_and(|p| p <= 0.0));
+                }
+            }
+        }
+        assert_eq!(text, expected);
+        let Some(GenerationEvent::Done { stats, .. }) = events.last() else {
+            panic!("stream must end with Done: {:?}", events.last());
+        };
+        assert_eq!(stats.prompt_tokens, prompt_tokens);
+        assert_eq!(stats.completion_tokens, ids);
+        // Every token carried a logprob, so requests for them are served
+        assert!(model.capabilities().logprobs);
+    }
+
+    /// Re-write `src` as llama.cpp-style splits: all metadata in the first,
+    /// tensors dealt round-robin across `parts` files
+    fn split_gguf(src: &Path, dir: &Path, parts: u16) -> Vec<PathBuf> {
+        let file = GgufFile::open(src).unwrap();
+        let mut writers: Vec<GgufWriter> = (0..parts).map(|_| GgufWriter::new()).collect();
+        for (key, value) in &file.header.metadata {
+            writers[0].add_metadata(key.clone(), value.clone());
+        }
+        for (i, w) in writers.iter_mut().enumerate() {
+            w.add_metadata("split.no", MetadataValue::U16(i as u16))
+                .add_metadata("split.count", MetadataValue::U16(parts));
+        }
+        for (i, info) in file.header.tensors.iter().enumerate() {
+            let data = file.tensor_data(info).unwrap().to_vec();
+            writers[i % parts as usize]
+                .add_tensor(info.name.clone(), info.dims.clone(), info.ggml_type, data)
+                .unwrap();
+        }
+        writers
+            .iter()
+            .enumerate()
+            .map(|(i, w)| {
+                let path = dir.join(format!("tiny-{:05}-of-{:05}.gguf", i + 1, parts));
+                w.write_file(&path).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_split_gguf_matches_single_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let whole = dir.path().join("tiny.gguf");
+        write_tiny_llama(&whole, GgmlType::Q8_0);
+        let splits = split_gguf(&whole, dir.path(), 3);
+
+        let expected = GgufNativeEngine::new()
+            .load(&spec_for(whole))
+            .await
+            .unwrap()
+            .generate("hello", greedy(6), None)
+            .await
+            .unwrap();
+        // Any split can be named; the engine finds the rest
+        for path in [&splits[0], &splits[2]] {
+            let model = GgufNativeEngine::new()
+                .load(&spec_for(path.clone()))
+                .await
+                .unwrap();
+            assert_eq!(
+                model.generate("hello", greedy(6), None).await.unwrap(),
+                expected
+            );
+        }
+
+        fs::remove_file(&splits[1]).unwrap();
+        let err = GgufNativeEngine::new()
+            .load(&spec_for(splits[0].clone()))
+            .await
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("missing 1 of 3 shards: tiny-00002-of-00003.gguf"));
+    }
+
+    #[tokio::test]
+    async fn test_load_rejects_unsupported_architecture() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("phi.gguf");
+        let mut w = GgufWriter::new();
+        w.add_metadata("general.architecture", MetadataValue::String("phi3".into()));
+        w.write_file(&path).unwrap();
+
+        let err = GgufNativeEngine::new()
+            .load(&spec_for(path))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not supported"));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_longer_than_context_fails() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        write_tiny_llama(&path, GgmlType::F16);
+        let mut spec = spec_for(path);
+        spec.ctx_len = 8;
+        let model = GgufNativeEngine::new().load(&spec).await.unwrap();
+        let err = model
+            .generate("abcdefghijklmnop", greedy(4), None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("context window"));
+    }
+}
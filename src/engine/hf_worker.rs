@@ -0,0 +1,364 @@
+// Long-lived Python worker for the HuggingFace backend. One process per loaded
+// model speaks the exec backend's JSON-lines protocol over stdin/stdout, so the
+// model is loaded once and prompts travel only as JSON data - never as Python source.
+
+use super::exec::ExecCommand;
+
+/// The worker program, passed to `python -c`. It is constant: everything
+/// request-specific arrives over stdin.
+pub const WORKER_SCRIPT: &str = r##"# shimmy HuggingFace worker: serves one model per process over newline-delimited
+# JSON on stdin/stdout. Requests (prompts included) only ever arrive as data.
+#
+#   -> {"id": 1, "op": "load", "model": "...", "adapter": null, "device": "cpu"}
+#   <- {"id": 1, "event": "ready", "capabilities": {"generate": true, ...}}
+#   -> {"id": 2, "op": "generate", "prompt": "...", "max_new_tokens": 64, ...}
+#   <- {"id": 2, "event": "token", "text": "..."}  (repeated)
+#   <- {"id": 2, "event": "done", "text": "...", "finish_reason": "stop"}
+#   -> {"id": 2, "op": "cancel"}
+#   <- {"id": N, "event": "error", "message": "..."}
+import json
+import queue
+import sys
+import threading
+
+# Libraries sometimes print to stdout; keep the protocol stream clean
+protocol = sys.stdout
+sys.stdout = sys.stderr
+out_lock = threading.Lock()
+
+
+def emit(**event):
+    line = json.dumps(event)
+    with out_lock:
+        protocol.write(line + "\n")
+        protocol.flush()
+
+
+try:
+    import torch
+    from transformers import (
+        AutoModelForCausalLM,
+        AutoTokenizer,
+        StoppingCriteria,
+        StoppingCriteriaList,
+        TextStreamer,
+    )
+except ImportError as e:
+    emit(id=0, event="error",
+         message="Python dependencies missing. Install: pip install torch transformers peft (%s)" % e)
+    sys.exit(1)
+
+requests = queue.Queue()
+cancelled = set()
+cancel_lock = threading.Lock()
+
+
+def read_stdin():
+    # Runs beside generation so cancels are seen while a request is in flight
+    for line in sys.stdin:
+        line = line.strip()
+        if not line:
+            continue
+        try:
+            msg = json.loads(line)
+        except ValueError as e:
+            emit(id=0, event="error", message="invalid request: %s" % e)
+            continue
+        if msg.get("op") == "cancel":
+            with cancel_lock:
+                cancelled.add(msg.get("id"))
+        else:
+            requests.put(msg)
+    requests.put({"op": "shutdown"})
+
+
+def is_cancelled(request_id):
+    with cancel_lock:
+        return request_id in cancelled
+
+
+class EventStreamer(TextStreamer):
+    def __init__(self, tokenizer, request_id):
+        super().__init__(tokenizer, skip_prompt=True, skip_special_tokens=True)
+        self.request_id = request_id
+        self.pieces = []
+
+    def on_finalized_text(self, text, stream_end=False):
+        if text:
+            self.pieces.append(text)
+            emit(id=self.request_id, event="token", text=text)
+
+
+class StopWhenCancelled(StoppingCriteria):
+    def __init__(self, request_id):
+        self.request_id = request_id
+
+    def __call__(self, input_ids, scores, **kwargs):
+        return is_cancelled(self.request_id)
+
+
+model = None
+tokenizer = None
+
+
+def load(msg):
+    global model, tokenizer
+    device = msg.get("device") or "cpu"
+    tokenizer = AutoTokenizer.from_pretrained(msg["model"])
+    model = AutoModelForCausalLM.from_pretrained(
+        msg["model"],
+        torch_dtype=torch.float16 if device == "cuda" else torch.float32,
+        device_map="auto" if device == "cuda" else None,
+    )
+    if msg.get("adapter"):
+        from peft import PeftModel
+        model = PeftModel.from_pretrained(model, msg["adapter"])
+    model.eval()
+    if tokenizer.pad_token is None:
+        tokenizer.pad_token = tokenizer.eos_token
+
+
+def capabilities(msg):
+    return {
+        "generate": True,
+        "chat_template": bool(getattr(tokenizer, "chat_template", None)),
+        "context_length": int(msg.get("ctx_len") or 0),
+    }
+
+
+def generate(msg):
+    request_id = msg["id"]
+    with cancel_lock:
+        # Ids only grow, so older cancels can no longer match anything
+        stale = {i for i in cancelled if i is None or i < request_id}
+        cancelled.difference_update(stale)
+    if model is None:
+        raise RuntimeError("no model loaded")
+    if msg.get("seed") is not None:
+        torch.manual_seed(int(msg["seed"]))
+
+    max_new_tokens = int(msg.get("max_new_tokens", 256))
+    temperature = float(msg.get("temperature", 0.0))
+    streamer = EventStreamer(tokenizer, request_id)
+    kwargs = dict(
+        max_new_tokens=max_new_tokens,
+        repetition_penalty=float(msg.get("repetition_penalty", 1.0)),
+        pad_token_id=tokenizer.pad_token_id,
+        streamer=streamer,
+        stopping_criteria=StoppingCriteriaList([StopWhenCancelled(request_id)]),
+    )
+    if temperature > 0.0:
+        kwargs.update(
+            do_sample=True,
+            temperature=temperature,
+            top_p=float(msg.get("top_p", 1.0)),
+            top_k=max(int(msg.get("top_k", 0)), 0),
+        )
+    else:
+        kwargs.update(do_sample=False)
+
+    inputs = tokenizer(msg["prompt"], return_tensors="pt").to(model.device)
+    n_new = 0
+    if not is_cancelled(request_id):
+        with torch.no_grad():
+            output = model.generate(**inputs, **kwargs)
+        n_new = output.shape[-1] - inputs["input_ids"].shape[-1]
+
+    if is_cancelled(request_id):
+        reason = "cancelled"
+    elif n_new >= max_new_tokens:
+        reason = "length"
+    else:
+        reason = "stop"
+    with cancel_lock:
+        cancelled.discard(request_id)
+    emit(
+        id=request_id,
+        event="done",
+        text="".join(streamer.pieces),
+        finish_reason=reason,
+        prompt_tokens=int(inputs["input_ids"].shape[-1]),
+        completion_tokens=int(n_new),
+    )
+
+
+def main():
+    threading.Thread(target=read_stdin, daemon=True).start()
+    while True:
+        msg = requests.get()
+        op = msg.get("op")
+        request_id = msg.get("id", 0)
+        if op == "shutdown":
+            return
+        try:
+            if op == "load":
+                load(msg)
+                emit(id=request_id, event="ready", capabilities=capabilities(msg))
+            elif op == "generate":
+                generate(msg)
+            else:
+                emit(id=request_id, event="error", message="unknown op %r" % op)
+        except Exception as e:
+            emit(id=request_id, event="error", message="%s: %s" % (type(e).__name__, e))
+
+
+main()
+"##;
+
+/// `python -u -c WORKER_SCRIPT`
+pub fn python_command(python_path: &str) -> ExecCommand {
+    ExecCommand {
+        program: python_path.to_string(),
+        args: vec!["-u".into(), "-c".into(), WORKER_SCRIPT.into()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::exec::{ExecWorker, LoadRequest};
+    use crate::engine::GenOptions;
+    use anyhow::Result;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Just enough of torch/transformers for the real worker script: the
+    /// "model" upper-cases the prompt, or emits dots forever for "forever"
+    const FAKE_TORCH: &str = r#"
+float16, float32 = "f16", "f32"
+def manual_seed(seed): pass
+class _NoGrad:
+    def __enter__(self): pass
+    def __exit__(self, *args): pass
+def no_grad(): return _NoGrad()
+"#;
+    const FAKE_TRANSFORMERS: &str = r#"
+import time
+class _Ids:
+    def __init__(self, n): self.shape = (1, n)
+class _Encoding(dict):
+    def to(self, device): return self
+class AutoTokenizer:
+    pad_token, eos_token, pad_token_id = None, "</s>", 0
+    @classmethod
+    def from_pretrained(cls, name): return cls()
+    def __call__(self, text, return_tensors=None):
+        return _Encoding(input_ids=_Ids(len(text)), prompt=text)
+class AutoModelForCausalLM:
+    device = "cpu"
+    @classmethod
+    def from_pretrained(cls, name, **kwargs):
+        if name == "missing": raise OSError("no such model")
+        return cls()
+    def eval(self): return self
+    def generate(self, input_ids, prompt, streamer, stopping_criteria, max_new_tokens, **kwargs):
+        n = 0
+        while n < max_new_tokens and not stopping_criteria(None, None):
+            if prompt == "forever":
+                piece = "."; time.sleep(0.01)
+            elif n < len(prompt):
+                piece = prompt[n].upper()
+            else:
+                break
+            streamer.on_finalized_text(piece); n += 1
+        return _Ids(input_ids.shape[1] + n)
+class StoppingCriteria: pass
+class StoppingCriteriaList(list):
+    def __call__(self, ids, scores): return any(c(ids, scores) for c in self)
+class TextStreamer:
+    def __init__(self, tokenizer, skip_prompt=False, **kwargs): pass
+"#;
+
+    /// Runs WORKER_SCRIPT against the fakes; `None` when there is no python3
+    async fn fake_worker(model: &str) -> Option<(tempfile::TempDir, Result<ExecWorker>)> {
+        let available = std::process::Command::new("python3")
+            .arg("--version")
+            .output()
+            .is_ok_and(|o| o.status.success());
+        if !available {
+            eprintln!("python3 not found; skipping worker script test");
+            return None;
+        }
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("torch.py"), FAKE_TORCH).unwrap();
+        std::fs::write(dir.path().join("transformers.py"), FAKE_TRANSFORMERS).unwrap();
+        let script = dir.path().join("worker.py");
+        std::fs::write(&script, WORKER_SCRIPT).unwrap();
+        let command = ExecCommand {
+            program: "python3".to_string(),
+            args: vec![
+                "-u".into(),
+                "-c".into(),
+                format!(
+                    "import sys; sys.path.insert(0, {:?}); exec(open({:?}).read())",
+                    dir.path().to_string_lossy(),
+                    script.to_string_lossy()
+                ),
+            ],
+        };
+        let load = LoadRequest {
+            model: model.to_string(),
+            adapter: None,
+            device: Some("cpu".to_string()),
+            ctx_len: 2048,
+        };
+        let worker = ExecWorker::start(command, load).await;
+        Some((dir, worker))
+    }
+
+    #[tokio::test]
+    async fn test_worker_script_streams_prompts_sent_as_data() {
+        let Some((_dir, worker)) = fake_worker("tiny").await else {
+            return;
+        };
+        let worker = worker.unwrap();
+        let prompt = "it's '''\"); import os; os._exit(1) #\nnext";
+        let opts = GenOptions {
+            max_tokens: 13,
+            ..Default::default()
+        };
+        let streamed = Arc::new(Mutex::new(String::new()));
+        let sink = Arc::clone(&streamed);
+        let out = worker
+            .generate(
+                prompt,
+                &opts,
+                Some(Box::new(move |t| sink.lock().unwrap().push_str(&t))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(out, "IT'S '''\"); I");
+        assert_eq!(*streamed.lock().unwrap(), out);
+        assert_eq!(worker.restarts(), 0);
+
+        let caps = worker.capabilities();
+        assert!(caps.generate && !caps.tokenize && !caps.embeddings);
+        assert_eq!(caps.context_length, 2048);
+    }
+
+    #[tokio::test]
+    async fn test_worker_script_stops_cancelled_generation() {
+        let Some((_dir, worker)) = fake_worker("tiny").await else {
+            return;
+        };
+        let worker = worker.unwrap();
+        let opts = GenOptions::default();
+        let forever = worker.generate("forever", &opts, None);
+        assert!(tokio::time::timeout(Duration::from_millis(100), forever)
+            .await
+            .is_err());
+        // Only answered once the abandoned generation has stopped
+        let next = worker.generate("ok", &opts, None);
+        let out = tokio::time::timeout(Duration::from_secs(10), next).await;
+        assert_eq!(out.unwrap().unwrap(), "OK");
+    }
+
+    #[tokio::test]
+    async fn test_worker_script_reports_load_errors() {
+        let Some((_dir, worker)) = fake_worker("missing").await else {
+            return;
+        };
+        let err = worker.err().expect("load should fail").to_string();
+        assert!(err.contains("OSError: no such model"));
+    }
+}
@@ -1,10 +1,13 @@
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use std::ffi::OsString;
 use std::path::Path;
-use std::process::Command;
-use tokio::process::Command as TokioCommand;
 
-use super::{GenOptions, ModelBackend, UniversalEngine, UniversalModel, UniversalModelSpec};
+use super::exec::{ExecWorker, LoadRequest};
+use super::hf_worker::python_command;
+use super::{
+    Capabilities, GenOptions, ModelBackend, UniversalEngine, UniversalModel, UniversalModelSpec,
+};
 
 #[derive(Debug)]
 pub struct HuggingFaceEngine {
@@ -18,14 +21,36 @@ impl Default for HuggingFaceEngine {
 }
 
 impl HuggingFaceEngine {
+    /// Workers run under `SHIMMY_PYTHON`, else the first Python on `PATH`
     pub fn new() -> Self {
-        // Use the verified Python path from CLAUDE.md
         Self {
-            python_path: "C:/Python311/python.exe".to_string(),
+            python_path: find_python(
+                std::env::var("SHIMMY_PYTHON").ok(),
+                std::env::var_os("PATH"),
+            ),
         }
     }
 }
 
+/// `configured`, else the first `python3` or `python` in `path`. Falls back
+/// to the bare name so a missing interpreter fails when a model loads.
+fn find_python(configured: Option<String>, path: Option<OsString>) -> String {
+    if let Some(configured) = configured.filter(|p| !p.is_empty()) {
+        return configured;
+    }
+    let names = if cfg!(windows) {
+        ["python.exe", "python3.exe"]
+    } else {
+        ["python3", "python"]
+    };
+    path.iter()
+        .flat_map(std::env::split_paths)
+        .flat_map(|dir| names.map(|name| dir.join(name)))
+        .find(|candidate| candidate.is_file())
+        .map(|found| found.to_string_lossy().into_owned())
+        .unwrap_or_else(|| names[0].to_string())
+}
+
 #[async_trait]
 impl UniversalEngine for HuggingFaceEngine {
     async fn load(&self, spec: &UniversalModelSpec) -> Result<Box<dyn UniversalModel>> {
@@ -41,6 +66,7 @@ impl UniversalEngine for HuggingFaceEngine {
                     peft_path.as_deref(),
                     *use_local,
                     &spec.device,
+                    spec.ctx_len,
                 )
                 .await?;
                 Ok(Box::new(model))
@@ -53,10 +79,8 @@ impl UniversalEngine for HuggingFaceEngine {
 }
 
 struct HuggingFaceModel {
-    python_path: String,
     base_model_id: String,
-    peft_path: Option<String>,
-    device: String,
+    worker: ExecWorker,
 }
 
 impl HuggingFaceModel {
@@ -66,70 +90,28 @@ impl HuggingFaceModel {
         peft_path: Option<&Path>,
         _use_local: bool,
         device: &str,
+        ctx_len: usize,
     ) -> Result<Self> {
-        // Verify Python and transformers availability
-        let output = Command::new(python_path)
-            .args(["-c", "import torch, transformers, peft; print('OK')"])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(anyhow!(
-                "Python dependencies missing. Install: pip install torch transformers peft\nError: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ));
-        }
-
-        // Verify model can be loaded (quick check)
-        let verify_cmd = vec![
-            "-c".to_string(),
-            format!(
-                r#"
-import torch
-from transformers import AutoTokenizer, AutoModelForCausalLM
-from peft import PeftModel
-import sys
-
-try:
-    print("Loading base model...", file=sys.stderr)
-    model = AutoModelForCausalLM.from_pretrained('{}', torch_dtype=torch.float16)
-    
-    {}
-    
-    print("SUCCESS: Model loaded", file=sys.stderr)
-    print("OK")
-except Exception as e:
-    print(f"ERROR: {{e}}", file=sys.stderr)
-    sys.exit(1)
-"#,
-                base_model_id,
-                if let Some(peft_path) = peft_path {
-                    format!(
-                        r#"print("Loading PEFT adapter...", file=sys.stderr)
-    model = PeftModel.from_pretrained(model, '{}')
-    print("PEFT adapter loaded", file=sys.stderr)"#,
-                        peft_path.display()
-                    )
-                } else {
-                    "".to_string()
-                }
-            ),
-        ];
-
-        let verify_output = Command::new(python_path).args(&verify_cmd).output()?;
-
-        if !verify_output.status.success() {
-            return Err(anyhow!(
-                "Failed to load HuggingFace model '{}': {}",
-                base_model_id,
-                String::from_utf8_lossy(&verify_output.stderr)
-            ));
-        }
+        // The model is loaded once, in a worker that stays up for this model's lifetime
+        let load = LoadRequest {
+            model: base_model_id.to_string(),
+            adapter: peft_path.map(|p| p.to_string_lossy().to_string()),
+            device: Some(device.to_string()),
+            ctx_len,
+        };
+        let worker = ExecWorker::start(python_command(python_path), load)
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to load HuggingFace model '{}': {}",
+                    base_model_id,
+                    e
+                )
+            })?;
 
         Ok(HuggingFaceModel {
-            python_path: python_path.to_string(),
             base_model_id: base_model_id.to_string(),
-            peft_path: peft_path.map(|p| p.to_string_lossy().to_string()),
-            device: device.to_string(),
+            worker,
         })
     }
 }
@@ -142,116 +124,14 @@ impl UniversalModel for HuggingFaceModel {
         opts: GenOptions,
         on_token: Option<Box<dyn FnMut(String) + Send>>,
     ) -> Result<String> {
-        let generation_script = format!(
-            r#"
-import torch
-from transformers import AutoTokenizer, AutoModelForCausalLM, TextStreamer
-from peft import PeftModel
-import sys
-import json
-
-# Load model and tokenizer
-print("Loading model...", file=sys.stderr)
-tokenizer = AutoTokenizer.from_pretrained('{}')
-model = AutoModelForCausalLM.from_pretrained(
-    '{}',
-    torch_dtype=torch.float16,
-    device_map="auto" if '{}' == "cuda" else None
-)
-
-{}
-
-# Set pad token if not present
-if tokenizer.pad_token is None:
-    tokenizer.pad_token = tokenizer.eos_token
-
-# Generate
-print("Generating...", file=sys.stderr)
-inputs = tokenizer('''{}''', return_tensors="pt")
-if '{}' == "cuda":
-    inputs = {{k: v.cuda() for k, v in inputs.items()}}
-
-with torch.no_grad():
-    outputs = model.generate(
-        **inputs,
-        max_new_tokens={},
-        temperature={},
-        top_p={},
-        top_k={},
-        repetition_penalty={},
-        pad_token_id=tokenizer.pad_token_id,
-        do_sample=True if {} > 0.0 else False,
-        {}
-    )
-
-# Decode output
-generated_text = tokenizer.decode(outputs[0], skip_special_tokens=True)
-# Remove input prompt from output
-input_text = '''{}'''
-if generated_text.startswith(input_text):
-    generated_text = generated_text[len(input_text):].strip()
-
-print(generated_text)
-"#,
-            self.base_model_id,
-            self.base_model_id,
-            self.device,
-            if let Some(peft_path) = &self.peft_path {
-                format!(
-                    r#"print("Loading PEFT adapter...", file=sys.stderr)
-model = PeftModel.from_pretrained(model, '{}')"#,
-                    peft_path
-                )
-            } else {
-                "".to_string()
-            },
-            prompt.replace("'", r"\'"),
-            self.device,
-            opts.max_tokens,
-            opts.temperature,
-            opts.top_p,
-            opts.top_k,
-            opts.repeat_penalty,
-            opts.temperature,
-            if let Some(seed) = opts.seed {
-                format!("use_cache=False,\n        torch.manual_seed({})", seed)
-            } else {
-                "use_cache=True".to_string()
-            },
-            prompt.replace("'", r"\'")
-        );
-
-        let mut cmd = TokioCommand::new(&self.python_path);
-        cmd.args(["-c", &generation_script]);
-
-        let output = cmd.output().await?;
-
-        if !output.status.success() {
-            return Err(anyhow!(
-                "HuggingFace generation failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ));
-        }
-
-        let result = String::from_utf8_lossy(&output.stdout);
-        let lines: Vec<&str> = result.lines().collect();
-
-        // Find the actual generated text (after loading messages)
-        let generated_text = lines
-            .iter()
-            .rev()
-            .find(|line| !line.trim().is_empty())
-            .unwrap_or(&"")
-            .to_string();
-
-        // Handle streaming callback if provided
-        if let Some(mut callback) = on_token {
-            for word in generated_text.split_whitespace() {
-                callback(format!("{} ", word));
-            }
-        }
+        self.worker
+            .generate(prompt, &opts, on_token)
+            .await
+            .map_err(|e| anyhow!("HuggingFace model '{}': {}", self.base_model_id, e))
+    }
 
-        Ok(generated_text)
+    fn capabilities(&self) -> Capabilities {
+        self.worker.capabilities()
     }
 }
 
@@ -263,13 +143,32 @@ mod tests {
     #[test]
     fn test_default_creates_new_instance() {
         let engine = HuggingFaceEngine::default();
-        assert_eq!(engine.python_path, "C:/Python311/python.exe");
+        assert_eq!(engine.python_path, HuggingFaceEngine::new().python_path);
     }
 
     #[test]
-    fn test_new_creates_with_correct_python_path() {
-        let engine = HuggingFaceEngine::new();
-        assert_eq!(engine.python_path, "C:/Python311/python.exe");
+    fn test_python_comes_from_shimmy_python_then_path() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let name = if cfg!(windows) {
+            "python.exe"
+        } else {
+            "python3"
+        };
+        let python = dir.path().join(name);
+        std::fs::write(&python, b"").unwrap();
+        let path =
+            std::env::join_paths([dir.path().join("missing"), dir.path().to_path_buf()]).unwrap();
+
+        let configured = Some("/opt/py/bin/python".to_string());
+        assert_eq!(
+            find_python(configured, Some(path.clone())),
+            "/opt/py/bin/python"
+        );
+        assert_eq!(
+            find_python(Some(String::new()), Some(path)),
+            python.to_string_lossy()
+        );
+        assert_eq!(find_python(None, None), name);
     }
 
     #[tokio::test]
@@ -324,18 +223,12 @@ mod tests {
     }
 
     #[test]
-    fn test_huggingface_model_struct_creation() {
-        let model = HuggingFaceModel {
-            python_path: "python".to_string(),
-            base_model_id: "test_model".to_string(),
-            peft_path: Some("/path/to/adapter".to_string()),
-            device: "cuda".to_string(),
-        };
-
-        assert_eq!(model.python_path, "python");
-        assert_eq!(model.base_model_id, "test_model");
-        assert_eq!(model.peft_path, Some("/path/to/adapter".to_string()));
-        assert_eq!(model.device, "cuda");
+    fn test_worker_command_keeps_prompts_out_of_source() {
+        let command = python_command("python");
+        assert_eq!(command.program, "python");
+        assert_eq!(command.args[..2], ["-u", "-c"]);
+        // The script is fixed; requests only ever arrive over stdin
+        assert_eq!(command.args[2], super::super::hf_worker::WORKER_SCRIPT);
     }
 
     #[tokio::test]
@@ -346,6 +239,7 @@ mod tests {
             None,
             true,
             "cpu",
+            4096,
         )
         .await;
 
@@ -363,6 +257,7 @@ mod tests {
             repeat_penalty: 1.1,
             seed: Some(42),
             stream: false,
+            ..Default::default()
         };
 
         assert_eq!(opts.max_tokens, 100);
@@ -0,0 +1,411 @@
+// Process-isolated models
+// With `shimmy serve --isolate` each model runs in a child `shimmy worker`
+// process that speaks the exec backend's JSON-lines protocol on its
+// stdin/stdout (docs/EXEC_PROTOCOL.md), supervised by an `ExecWorker`. A
+// llama.cpp abort, an out-of-memory kill or a panic then only fails the
+// requests that model had in flight, and its next request restarts the worker.
+// The loaded-model cache keeps one worker per model, shared by its requests.
+
+use anyhow::Result;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use super::adapter::InferenceEngineAdapter;
+use super::exec::{self, Event, EventLine, ExecCommand};
+use super::{GenOptions, GenerationEvent, InferenceEngine, LoadedModel, ModelSpec};
+
+/// Load `spec` in a child process started from the shimmy executable `worker_exe`
+pub async fn load(worker_exe: &Path, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+    let mut args = vec![
+        "worker".to_string(),
+        "--name".to_string(),
+        spec.name.clone(),
+    ];
+    if let Some(backend) = &spec.backend {
+        args.extend(["--backend".to_string(), backend.clone()]);
+    }
+    if let Some(threads) = spec.n_threads {
+        args.extend(["--threads".to_string(), threads.to_string()]);
+    }
+    let command = ExecCommand {
+        program: worker_exe.to_string_lossy().to_string(),
+        args,
+    };
+    exec::start_model(spec, command, "isolated worker").await
+}
+
+/// What `shimmy worker` was started with; paths and `ctx_len` arrive in `load`
+#[derive(Debug, Clone)]
+pub struct WorkerOptions {
+    pub name: String,
+    pub backend: Option<String>,
+    pub n_threads: Option<i32>,
+}
+
+/// Serve one model over stdin/stdout until the supervisor closes stdin
+pub async fn run_worker(options: WorkerOptions) -> Result<()> {
+    // One line per panic, so the supervisor can quote it as the exit reason
+    std::panic::set_hook(Box::new(|panic| {
+        eprintln!("worker panicked: {}", panic.to_string().replace('\n', " "));
+    }));
+    let engine = InferenceEngineAdapter::new();
+    serve(tokio::io::stdin(), tokio::io::stdout(), &engine, &options).await
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WorkerRequest {
+    Load {
+        id: u64,
+        model: String,
+        adapter: Option<String>,
+        ctx_len: usize,
+    },
+    Generate {
+        id: u64,
+        prompt: String,
+        max_new_tokens: usize,
+        temperature: f32,
+        top_p: f32,
+        top_k: i32,
+        repetition_penalty: f32,
+        seed: Option<u32>,
+        #[serde(default)]
+        grammar: Option<String>,
+        #[serde(default)]
+        suffix: Option<String>,
+        #[serde(default)]
+        images: Vec<String>,
+    },
+    Tokenize {
+        id: u64,
+        text: String,
+        add_special: bool,
+    },
+    Detokenize {
+        id: u64,
+        tokens: Vec<u32>,
+    },
+    Embed {
+        id: u64,
+        text: String,
+    },
+    Health {
+        id: u64,
+    },
+    Cancel {
+        id: u64,
+    },
+}
+
+type Events = mpsc::UnboundedSender<EventLine>;
+
+fn send(events: &Events, id: u64, event: Event) {
+    let _ = events.send(EventLine { id, event });
+}
+
+fn outcome<T>(result: Result<T>, event: impl FnOnce(T) -> Event) -> Event {
+    match result {
+        Ok(value) => event(value),
+        Err(e) => Event::Error {
+            message: format!("{:#}", e),
+        },
+    }
+}
+
+/// The worker side of the protocol. Generations run as tasks so that
+/// `cancel` is read while they stream.
+pub(crate) async fn serve<R, W>(
+    input: R,
+    output: W,
+    engine: &dyn InferenceEngine,
+    options: &WorkerOptions,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (events, mut outgoing) = mpsc::unbounded_channel::<EventLine>();
+    tokio::spawn(async move {
+        let mut output = output;
+        while let Some(line) = outgoing.recv().await {
+            let mut json = serde_json::to_string(&line)?;
+            json.push('\n');
+            output.write_all(json.as_bytes()).await?;
+            output.flush().await?;
+        }
+        anyhow::Ok(())
+    });
+
+    let mut model: Option<Arc<dyn LoadedModel>> = None;
+    let mut running: HashMap<u64, oneshot::Sender<()>> = HashMap::new();
+    let mut lines = BufReader::new(input).lines();
+    while let Some(line) = lines.next_line().await? {
+        running.retain(|_, cancel| !cancel.is_closed());
+        let request = match serde_json::from_str::<WorkerRequest>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!(error = %e, "Ignoring invalid worker request");
+                continue;
+            }
+        };
+        let (id, event) = match request {
+            WorkerRequest::Load {
+                id,
+                model: path,
+                adapter,
+                ctx_len,
+            } => {
+                let spec = ModelSpec {
+                    name: options.name.clone(),
+                    base_path: path.into(),
+                    lora_path: adapter.map(Into::into),
+                    template: None,
+                    ctx_len,
+                    n_threads: options.n_threads,
+                    backend: options.backend.clone(),
+                    command: None,
+                };
+                let loaded = engine.load(&spec).await.map(Arc::<dyn LoadedModel>::from);
+                let event = outcome(loaded, |loaded| {
+                    info!(model = %options.name, "Worker model loaded");
+                    let capabilities = Some(loaded.capabilities());
+                    model = Some(loaded);
+                    Event::Ready { capabilities }
+                });
+                (id, event)
+            }
+            WorkerRequest::Generate {
+                id,
+                prompt,
+                max_new_tokens,
+                temperature,
+                top_p,
+                top_k,
+                repetition_penalty,
+                seed,
+                grammar,
+                suffix,
+                images,
+            } => {
+                let Some(model) = model.clone() else {
+                    send(&events, id, no_model());
+                    continue;
+                };
+                let opts = GenOptions {
+                    max_tokens: max_new_tokens,
+                    temperature,
+                    top_p,
+                    top_k,
+                    repeat_penalty: repetition_penalty,
+                    seed,
+                    stream: true,
+                    grammar,
+                    suffix,
+                    images,
+                };
+                let (cancel, cancelled) = oneshot::channel();
+                running.insert(id, cancel);
+                tokio::spawn(generate(model, prompt, opts, id, events.clone(), cancelled));
+                continue;
+            }
+            WorkerRequest::Tokenize {
+                id,
+                text,
+                add_special,
+            } => (
+                id,
+                answer(
+                    &model,
+                    |m| m.tokenize(&text, add_special),
+                    |tokens| Event::Tokens { tokens },
+                ),
+            ),
+            WorkerRequest::Detokenize { id, tokens } => (
+                id,
+                answer(
+                    &model,
+                    |m| m.detokenize(&tokens),
+                    |text| Event::Text { text },
+                ),
+            ),
+            WorkerRequest::Embed { id, text } => (
+                id,
+                answer(
+                    &model,
+                    |m| m.embed(&text),
+                    |embedding| Event::Embedding { embedding },
+                ),
+            ),
+            WorkerRequest::Health { id } => (id, Event::Ready { capabilities: None }),
+            WorkerRequest::Cancel { id } => {
+                if let Some(cancel) = running.remove(&id) {
+                    let _ = cancel.send(());
+                }
+                continue;
+            }
+        };
+        send(&events, id, event);
+    }
+    Ok(())
+}
+
+fn no_model() -> Event {
+    Event::Error {
+        message: "no model loaded".to_string(),
+    }
+}
+
+/// A synchronous model call as its answering event
+fn answer<T>(
+    model: &Option<Arc<dyn LoadedModel>>,
+    call: impl FnOnce(&dyn LoadedModel) -> Result<T>,
+    event: impl FnOnce(T) -> Event,
+) -> Event {
+    match model {
+        Some(model) => outcome(call(model.as_ref()), event),
+        None => no_model(),
+    }
+}
+
+/// Stream one generation as `token` events, ending with `done`, `error` or
+/// `overloaded`
+async fn generate(
+    model: Arc<dyn LoadedModel>,
+    prompt: String,
+    opts: GenOptions,
+    id: u64,
+    events: Events,
+    mut cancelled: oneshot::Receiver<()>,
+) {
+    let mut stream = model.generate_stream(prompt, opts);
+    let mut text = String::new();
+    let last = loop {
+        tokio::select! {
+            _ = &mut cancelled => {
+                break Event::Done {
+                    text,
+                    finish_reason: "cancelled".to_string(),
+                    prompt_tokens: None,
+                    completion_tokens: None,
+                };
+            }
+            next = stream.next() => match next {
+                Some(GenerationEvent::Token { id: token_id, text: piece, logprob }) => {
+                    text.push_str(&piece);
+                    send(&events, id, Event::Token { text: piece, token_id, logprob });
+                }
+                Some(GenerationEvent::PromptProgress { .. }) => {}
+                Some(GenerationEvent::Done { finish_reason, stats }) => {
+                    break Event::Done {
+                        text,
+                        finish_reason: finish_reason.as_str().to_string(),
+                        prompt_tokens: Some(stats.prompt_tokens),
+                        completion_tokens: Some(stats.completion_tokens),
+                    };
+                }
+                Some(GenerationEvent::Error { message }) => break Event::Error { message },
+                Some(GenerationEvent::Overloaded { model }) => {
+                    break Event::Overloaded { model: Some(model) };
+                }
+                None => {
+                    break Event::Error {
+                        message: "generation ended without finishing".to_string(),
+                    };
+                }
+            }
+        }
+    };
+    send(&events, id, last);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::gguf::GgmlType;
+    use crate::engine::gguf_native::tests::write_tiny_llama;
+    use serde_json::{json, Value};
+    use tokio::io::{Lines, ReadHalf, WriteHalf};
+
+    type Client = tokio::io::DuplexStream;
+
+    async fn call(
+        to_worker: &mut WriteHalf<Client>,
+        replies: &mut Lines<BufReader<ReadHalf<Client>>>,
+        request: Value,
+    ) -> Value {
+        let line = format!("{}\n", request);
+        to_worker.write_all(line.as_bytes()).await.unwrap();
+        next(replies).await
+    }
+
+    async fn next(replies: &mut Lines<BufReader<ReadHalf<Client>>>) -> Value {
+        serde_json::from_str(&replies.next_line().await.unwrap().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_worker_speaks_the_exec_protocol() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("tiny.gguf");
+        write_tiny_llama(&path, GgmlType::Q8_0);
+
+        let (client, worker_io) = tokio::io::duplex(1 << 16);
+        let (worker_in, worker_out) = tokio::io::split(worker_io);
+        let options = WorkerOptions {
+            name: "tiny".to_string(),
+            backend: None,
+            n_threads: Some(2),
+        };
+        let worker = tokio::spawn(async move {
+            let engine = InferenceEngineAdapter::new();
+            serve(worker_in, worker_out, &engine, &options).await
+        });
+        let (client_in, mut to_worker) = tokio::io::split(client);
+        let mut replies = BufReader::new(client_in).lines();
+
+        let early = json!({"op": "tokenize", "id": 1, "text": "abc", "add_special": false});
+        let reply = call(&mut to_worker, &mut replies, early).await;
+        assert_eq!(reply["event"], "error");
+        assert_eq!(reply["message"], "no model loaded");
+
+        let load = json!({"op": "load", "id": 2, "model": path, "adapter": null,
+            "device": null, "ctx_len": 64});
+        let ready = call(&mut to_worker, &mut replies, load).await;
+        assert_eq!(ready["event"], "ready");
+        assert_eq!(ready["capabilities"]["tokenize"], true);
+        assert_eq!(ready["capabilities"]["context_length"], 64);
+
+        let generate = json!({"op": "generate", "id": 3, "prompt": "abc", "max_new_tokens": 4,
+            "temperature": 0.0, "top_p": 1.0, "top_k": 0, "repetition_penalty": 1.0,
+            "seed": null});
+        let mut event = call(&mut to_worker, &mut replies, generate).await;
+        let mut streamed = String::new();
+        while event["event"] == "token" {
+            assert_eq!(event["id"], 3);
+            assert!(event["token_id"].is_u64());
+            assert!(event["logprob"].as_f64().unwrap() <= 0.0);
+            streamed.push_str(event["text"].as_str().unwrap());
+            event = next(&mut replies).await;
+        }
+        assert_eq!(event["event"], "done");
+        assert_eq!(event["text"], streamed);
+        assert!(["stop", "length"].contains(&event["finish_reason"].as_str().unwrap()));
+
+        let embed = json!({"op": "embed", "id": 4, "text": "abc"});
+        let reply = call(&mut to_worker, &mut replies, embed).await;
+        assert_eq!(reply["id"], 4);
+        assert!(reply["message"]
+            .as_str()
+            .unwrap()
+            .contains("does not produce embeddings"));
+
+        to_worker.shutdown().await.unwrap();
+        worker.await.unwrap().unwrap();
+    }
+}
@@ -1,14 +1,36 @@
 #![allow(clippy::too_many_arguments)]
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use async_trait::async_trait;
 
-use super::{GenOptions, InferenceEngine, LoadedModel, ModelSpec};
+use super::{InferenceEngine, LoadedModel, ModelSpec};
 
 #[cfg(feature = "llama")]
-use std::sync::Mutex;
+use super::executor::ModelExecutor;
+#[cfg(feature = "llama")]
+use super::gguf_convert::{is_safetensors_lora, GgufConverter};
+#[cfg(feature = "llama")]
+use super::shared::WeightCache;
+#[cfg(feature = "llama")]
+use super::stream::{
+    self, ChoiceStream, FinishReason, GenerationEvent, GenerationStats, GenerationStream,
+};
+#[cfg(feature = "llama")]
+use super::{Capabilities, GenOptions};
+#[cfg(feature = "llama")]
+use anyhow::anyhow;
+#[cfg(feature = "llama")]
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+#[cfg(feature = "llama")]
+use std::time::Instant;
 #[cfg(feature = "llama")]
 use tracing::info;
 
+/// Most choices that share one prompt evaluation, each on its own sequence of
+/// a context built for the request; larger `n` falls back to generating the
+/// choices in turn
+#[cfg(feature = "llama")]
+const MAX_SHARED_CHOICES: usize = 8;
+
 #[derive(Default)]
 pub struct LlamaEngine;
 impl LlamaEngine {
@@ -23,43 +45,33 @@ impl InferenceEngine for LlamaEngine {
         #[cfg(feature = "llama")]
         {
             use llama_cpp_2 as llama;
-            use std::num::NonZeroU32;
-            let be = llama::llama_backend::LlamaBackend::init()?;
-            let model = llama::model::LlamaModel::load_from_file(
-                &be,
-                &spec.base_path,
-                &Default::default(),
-            )?;
-            let ctx_params = llama::context::params::LlamaContextParams::default()
-                .with_n_ctx(NonZeroU32::new(spec.ctx_len as u32))
-                .with_n_batch(2048)
-                .with_n_ubatch(512)
-                .with_n_threads(
-                    spec.n_threads.unwrap_or(
-                        std::thread::available_parallelism()
-                            .map(|n| n.get() as i32)
-                            .unwrap_or(4),
-                    ),
-                )
-                .with_n_threads_batch(
-                    spec.n_threads.unwrap_or(
-                        std::thread::available_parallelism()
-                            .map(|n| n.get() as i32)
-                            .unwrap_or(4),
-                    ),
-                );
-            let ctx_tmp = model.new_context(&be, ctx_params)?;
-            if let Some(ref lora) = spec.lora_path {
-                // Check if it's a SafeTensors file and convert if needed
-                let lora_path = if lora.extension().and_then(|s| s.to_str()) == Some("safetensors")
-                {
-                    // For now, provide helpful error message for SafeTensors files
-                    return Err(anyhow!(
-                        "SafeTensors LoRA detected: {}. Please convert to GGUF format first.",
-                        lora.display()
-                    ));
+            // llama.cpp loads the remaining splits itself once given the first,
+            // but fails obscurely when one is absent, so check them all up front
+            let splits = super::gguf::split_files(&spec.base_path)?;
+            if splits.len() > 1 {
+                info!(splits = splits.len(), first = %splits[0].display(), "Loading split GGUF model");
+            }
+            let be = backend()?;
+            // Other entries for the same file reuse its weights
+            let model = MODELS.get_or_load(&splits[0], || {
+                Ok(SharedModel(llama::model::LlamaModel::load_from_file(
+                    be,
+                    &splits[0],
+                    &Default::default(),
+                )?))
+            })?;
+            let ctx_tmp = model.new_context(be, context_params(spec, 1))?;
+            let mut lora = None;
+            if let Some(ref lora_file) = spec.lora_path {
+                // PEFT adapters (a directory or adapter_model.safetensors) are
+                // converted once into the GGUF cache, then attached like any other
+                let lora_path = if is_safetensors_lora(lora_file) {
+                    let converted = GgufConverter::new(GgufConverter::default_cache_dir())
+                        .convert_lora_for_base(lora_file, &splits[0])?;
+                    info!(adapter=%lora_file.display(), gguf=%converted.display(), "Using converted PEFT LoRA adapter");
+                    converted
                 } else {
-                    lora.clone()
+                    lora_file.clone()
                 };
 
                 let mut adapter = model.lora_adapter_init(&lora_path)?;
@@ -67,56 +79,144 @@ impl InferenceEngine for LlamaEngine {
                     .lora_adapter_set(&mut adapter, 1.0)
                     .map_err(|e| anyhow!("lora set: {e:?}"))?;
                 info!(adapter=%lora_path.display(), "LoRA adapter attached");
+                // Multi-choice contexts attach the same adapter
+                lora = Some(Mutex::new(adapter));
             }
-            // Store both model and context together to maintain proper lifetimes
-            // The context lifetime is tied to &model; storing both in the same struct ensures safety
+            // The context lifetime is tied to &model; LlamaInner keeps the shared
+            // model alive and drops the context before it
             let ctx: llama::context::LlamaContext<'static> =
                 unsafe { std::mem::transmute(ctx_tmp) };
+            let chat_template = super::gguf::GgufFile::open(&splits[0])
+                .is_ok_and(|f| f.header.get_str("tokenizer.chat_template").is_some());
             Ok(Box::new(LlamaLoaded {
-                _be: be,
-                model,
-                ctx: Mutex::new(ctx),
+                inner: Arc::new(LlamaInner {
+                    ctx: Mutex::new(ctx),
+                    lora,
+                    model,
+                    spec: spec.clone(),
+                }),
+                executor: ModelExecutor::new(&spec.name)?,
+                capabilities: Capabilities {
+                    chat_template,
+                    tokenize: true,
+                    grammar: true,
+                    ..Capabilities::text(spec.ctx_len)
+                },
             }))
         }
         #[cfg(not(feature = "llama"))]
         {
-            let _ = spec; // silence unused warning
-            Ok(Box::new(LlamaFallback))
+            // No llama.cpp in this build - serve GGUF through the pure Rust engine
+            super::gguf_native::GgufNativeEngine::new().load(spec).await
         }
     }
 }
 
 #[cfg(feature = "llama")]
 struct LlamaLoaded {
-    _be: llama_cpp_2::llama_backend::LlamaBackend,
-    model: llama_cpp_2::model::LlamaModel,
+    inner: Arc<LlamaInner>,
+    executor: ModelExecutor,
+    capabilities: Capabilities,
+}
+
+#[cfg(feature = "llama")]
+lazy_static::lazy_static! {
+    /// Entries that name the same GGUF share one `LlamaModel`; each gets its own context
+    static ref MODELS: WeightCache<SharedModel> = WeightCache::new();
+}
+
+/// Context parameters giving each of `n_seq` sequences the full `spec.ctx_len`:
+/// llama.cpp splits `n_ctx` evenly between sequences, so the total scales with
+/// them and the context length `capabilities()` reports holds for every choice
+#[cfg(feature = "llama")]
+fn context_params(
+    spec: &ModelSpec,
+    n_seq: usize,
+) -> llama_cpp_2::context::params::LlamaContextParams {
+    use std::num::NonZeroU32;
+    let n_threads = spec.n_threads.unwrap_or(
+        std::thread::available_parallelism()
+            .map(|n| n.get() as i32)
+            .unwrap_or(4),
+    );
+    llama_cpp_2::context::params::LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new((spec.ctx_len * n_seq) as u32))
+        .with_n_batch(2048)
+        .with_n_ubatch(512)
+        .with_n_seq_max(n_seq as u32)
+        .with_n_threads(n_threads)
+        .with_n_threads_batch(n_threads)
+}
+
+/// llama.cpp refuses to initialise its backend twice in one process
+#[cfg(feature = "llama")]
+fn backend() -> Result<&'static llama_cpp_2::llama_backend::LlamaBackend> {
+    static BACKEND: OnceLock<llama_cpp_2::llama_backend::LlamaBackend> = OnceLock::new();
+    static INIT: Mutex<()> = Mutex::new(());
+    let _init = INIT.lock().unwrap();
+    if let Some(be) = BACKEND.get() {
+        return Ok(be);
+    }
+    let be = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    Ok(BACKEND.get_or_init(|| be))
+}
+
+/// Weights loaded once per file and shared by every context created from them
+#[cfg(feature = "llama")]
+struct SharedModel(llama_cpp_2::model::LlamaModel);
+
+#[cfg(feature = "llama")]
+impl std::ops::Deref for SharedModel {
+    type Target = llama_cpp_2::model::LlamaModel;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(feature = "llama")]
+struct LlamaInner {
+    // Declared first so the context is dropped before the model it borrows
     ctx: Mutex<llama_cpp_2::context::LlamaContext<'static>>,
+    lora: Option<Mutex<llama_cpp_2::model::LlamaLoraAdapter>>,
+    model: Arc<SharedModel>,
+    spec: ModelSpec,
 }
 
+#[cfg(feature = "llama")]
+// The weights are read-only after loading; llama.cpp only mutates per-context state
+unsafe impl Send for SharedModel {}
+#[cfg(feature = "llama")]
+unsafe impl Sync for SharedModel {}
+
 #[cfg(feature = "llama")]
 // The llama.cpp context & model use raw pointers internally and are !Send by default.
 // We wrap access in a Mutex and only perform FFI calls while holding the lock, so it's
 // sound to mark the container Send + Sync for our usage (single-threaded mutable access).
-unsafe impl Send for LlamaLoaded {}
+unsafe impl Send for LlamaInner {}
 #[cfg(feature = "llama")]
-unsafe impl Sync for LlamaLoaded {}
+unsafe impl Sync for LlamaInner {}
 
 #[cfg(feature = "llama")]
-#[async_trait]
-impl LoadedModel for LlamaLoaded {
-    async fn generate(
+impl LlamaInner {
+    /// Runs on the model's inference thread; every decode is a blocking FFI call
+    fn generate(
         &self,
         prompt: &str,
-        opts: GenOptions,
-        mut on_token: Option<Box<dyn FnMut(String) + Send>>,
-    ) -> Result<String> {
+        opts: &GenOptions,
+        emit: &mut dyn FnMut(GenerationEvent) -> bool,
+    ) -> Result<()> {
         use llama_cpp_2::{
             llama_batch::LlamaBatch,
             model::{AddBos, Special},
-            sampling::LlamaSampler,
         };
-        let mut ctx = self.ctx.lock().unwrap();
+        let started = Instant::now();
+        // The context outlives requests, including ones that panicked mid-decode
+        // and poisoned the lock; either way the previous cache is dropped here
+        let mut ctx = self.ctx.lock().unwrap_or_else(PoisonError::into_inner);
+        ctx.clear_kv_cache();
         let tokens = self.model.str_to_token(prompt, AddBos::Always)?;
+        let prompt_tokens = tokens.len();
 
         // Create batch with explicit logits configuration
         let mut batch = LlamaBatch::new(tokens.len(), 1);
@@ -126,31 +226,34 @@ impl LoadedModel for LlamaLoaded {
             batch.add(token, i as i32, &[0], logits)?;
         }
         ctx.decode(&mut batch)?;
+        let prompt_done = Instant::now();
+        emit(GenerationEvent::PromptProgress {
+            processed: prompt_tokens,
+            total: prompt_tokens,
+        });
 
-        let mut sampler = LlamaSampler::chain_simple([
-            LlamaSampler::temp(opts.temperature),
-            LlamaSampler::top_p(opts.top_p, 1),
-            LlamaSampler::top_k(opts.top_k),
-            // API changed order: (repeat_last_n, freq_penalty, presence_penalty, penalty)
-            LlamaSampler::penalties(64, 0.0, 0.0, opts.repeat_penalty),
-            LlamaSampler::greedy(),
-        ])
-        .with_tokens(tokens.iter().copied());
+        let mut sampler = sampler(&self.model, opts, &tokens)?;
 
-        let mut out = String::new();
+        let mut finish_reason = FinishReason::Length;
         let mut all_tokens = tokens;
         for _ in 0..opts.max_tokens {
             // Sample from the last (and only) position with logits
             let token = sampler.sample(&ctx, -1);
             if self.model.is_eog_token(token) {
+                finish_reason = FinishReason::Stop;
                 break;
             }
             // Use Plaintext to avoid re-tokenizing control tokens into special forms
             let piece = self.model.token_to_str(token, Special::Plaintext)?;
-            let start = out.len();
-            out.push_str(&piece);
-            if let Some(cb) = on_token.as_mut() {
-                cb(out[start..].to_string());
+            let sent = emit(GenerationEvent::Token {
+                id: Some(token.0 as u32),
+                text: piece,
+                logprob: None,
+            });
+            // The client went away; free the inference thread
+            if !sent {
+                finish_reason = FinishReason::Cancelled;
+                break;
             }
 
             let mut step = LlamaBatch::new(1, 1);
@@ -158,30 +261,256 @@ impl LoadedModel for LlamaLoaded {
             ctx.decode(&mut step)?;
             all_tokens.push(token);
         }
-        Ok(out)
+        let completion_tokens = all_tokens.len() - prompt_tokens;
+        emit(GenerationEvent::Done {
+            finish_reason,
+            stats: GenerationStats::timed(
+                prompt_tokens,
+                completion_tokens,
+                started,
+                Some(prompt_done),
+            ),
+        });
+        Ok(())
+    }
+
+    /// `n` choices from one evaluation of the prompt: it is decoded once on
+    /// sequence 0 and its KV cells copied to sequences 1..n, then every step
+    /// decodes the next token of each unfinished choice in a single batch.
+    /// The single-sequence context stays untouched; the choices run on a
+    /// context with `n` sequences that lives only for this request.
+    fn generate_choices(
+        &self,
+        prompt: &str,
+        opts: &GenOptions,
+        n: usize,
+        emit: &mut dyn FnMut(usize, GenerationEvent) -> bool,
+    ) -> Result<()> {
+        use llama_cpp_2::{
+            llama_batch::LlamaBatch,
+            model::{AddBos, Special},
+        };
+        let started = Instant::now();
+        let mut ctx = self
+            .model
+            .new_context(backend()?, context_params(&self.spec, n))?;
+        if let Some(lora) = &self.lora {
+            ctx.lora_adapter_set(
+                &mut lora.lock().unwrap_or_else(PoisonError::into_inner),
+                1.0,
+            )
+            .map_err(|e| anyhow!("lora set: {e:?}"))?;
+        }
+        let tokens = self.model.str_to_token(prompt, AddBos::Always)?;
+        let prompt_tokens = tokens.len();
+
+        let mut batch = LlamaBatch::new(tokens.len(), 1);
+        for (i, &token) in tokens.iter().enumerate() {
+            batch.add(token, i as i32, &[0], i == tokens.len() - 1)?;
+        }
+        ctx.decode(&mut batch)?;
+        for index in 1..n {
+            ctx.copy_kv_cache_seq(0, index as i32, None, None)?;
+        }
+        let prompt_done = Instant::now();
+        for index in 0..n {
+            emit(
+                index,
+                GenerationEvent::PromptProgress {
+                    processed: prompt_tokens,
+                    total: prompt_tokens,
+                },
+            );
+        }
+
+        struct Choice {
+            sampler: llama_cpp_2::sampling::LlamaSampler,
+            generated: usize,
+            // Batch index of the logits this choice samples from next
+            logits: i32,
+            finished: bool,
+        }
+        let seed = stream::base_seed(opts);
+        let mut choices: Vec<Choice> = (0..n)
+            .map(|index| {
+                Ok(Choice {
+                    sampler: sampler(
+                        &self.model,
+                        &stream::choice_options(opts, seed, index),
+                        &tokens,
+                    )?,
+                    generated: 0,
+                    logits: prompt_tokens as i32 - 1,
+                    finished: false,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let mut cancelled = false;
+        loop {
+            let mut step = LlamaBatch::new(n, n as i32);
+            let mut running = Vec::new();
+            for (index, choice) in choices.iter_mut().enumerate() {
+                if choice.finished {
+                    continue;
+                }
+                let token = (choice.generated < opts.max_tokens)
+                    .then(|| choice.sampler.sample(&ctx, choice.logits))
+                    .filter(|&token| !self.model.is_eog_token(token));
+                let Some(token) = token else {
+                    choice.finished = true;
+                    let finish_reason = if choice.generated < opts.max_tokens {
+                        FinishReason::Stop
+                    } else {
+                        FinishReason::Length
+                    };
+                    emit(
+                        index,
+                        GenerationEvent::Done {
+                            finish_reason,
+                            stats: GenerationStats::timed(
+                                prompt_tokens,
+                                choice.generated,
+                                started,
+                                Some(prompt_done),
+                            ),
+                        },
+                    );
+                    continue;
+                };
+                let piece = self.model.token_to_str(token, Special::Plaintext)?;
+                let sent = emit(
+                    index,
+                    GenerationEvent::Token {
+                        id: Some(token.0 as u32),
+                        text: piece,
+                        logprob: None,
+                    },
+                );
+                // The client went away; free the inference thread
+                if !sent {
+                    cancelled = true;
+                    break;
+                }
+                step.add(
+                    token,
+                    (prompt_tokens + choice.generated) as i32,
+                    &[index as i32],
+                    true,
+                )?;
+                choice.generated += 1;
+                running.push(index);
+            }
+            if running.is_empty() || cancelled {
+                break;
+            }
+            ctx.decode(&mut step)?;
+            for (i, index) in running.into_iter().enumerate() {
+                choices[index].logits = i as i32;
+            }
+        }
+        Ok(())
     }
 }
 
-/// Fallback implementation when llama.cpp feature is not enabled
-/// Returns informative message directing users to enable the feature
-#[cfg(not(feature = "llama"))]
-struct LlamaFallback;
+/// Sampler chain for one generation. Greedy unless a seed is given, so
+/// unseeded requests stay deterministic while each choice of a multi-choice
+/// request draws from its own seed. A grammar, when given, masks every token
+/// that could not continue it; only the repeat penalty sees the prompt.
+#[cfg(feature = "llama")]
+fn sampler(
+    model: &llama_cpp_2::model::LlamaModel,
+    opts: &GenOptions,
+    history: &[llama_cpp_2::token::LlamaToken],
+) -> Result<llama_cpp_2::sampling::LlamaSampler> {
+    use llama_cpp_2::sampling::LlamaSampler;
+    let pick = match opts.seed {
+        Some(seed) if opts.temperature > 0.0 => LlamaSampler::dist(seed),
+        _ => LlamaSampler::greedy(),
+    };
+    let mut chain = Vec::new();
+    if let Some(grammar) = &opts.grammar {
+        chain.push(
+            LlamaSampler::grammar(model, grammar, "root")
+                .map_err(|e| anyhow!("invalid grammar: {e:?}"))?,
+        );
+    }
+    chain.extend([
+        LlamaSampler::temp(opts.temperature),
+        LlamaSampler::top_p(opts.top_p, 1),
+        LlamaSampler::top_k(opts.top_k),
+        // API changed order: (repeat_last_n, freq_penalty, presence_penalty, penalty)
+        LlamaSampler::penalties(64, 0.0, 0.0, opts.repeat_penalty)
+            .with_tokens(history.iter().copied()),
+        pick,
+    ]);
+    Ok(LlamaSampler::chain_simple(chain))
+}
 
-#[cfg(not(feature = "llama"))]
+#[cfg(feature = "llama")]
 #[async_trait]
-impl LoadedModel for LlamaFallback {
+impl LoadedModel for LlamaLoaded {
     async fn generate(
         &self,
         prompt: &str,
-        _opts: GenOptions,
-        mut on_token: Option<Box<dyn FnMut(String) + Send>>,
+        opts: GenOptions,
+        on_token: Option<Box<dyn FnMut(String) + Send>>,
     ) -> Result<String> {
-        let fallback_msg =
-            "Llama.cpp support not enabled. Build with --features llama for full functionality.";
-        if let Some(cb) = on_token.as_mut() {
-            cb(fallback_msg.to_string());
+        let inner = Arc::clone(&self.inner);
+        let prompt = prompt.to_string();
+        self.executor
+            .run(move || {
+                stream::collect_text(|emit| inner.generate(&prompt, &opts, emit), on_token)
+            })
+            .await?
+    }
+
+    fn generate_stream(self: Arc<Self>, prompt: String, opts: GenOptions) -> GenerationStream {
+        let inner = Arc::clone(&self.inner);
+        self.executor
+            .stream(move |emit| inner.generate(&prompt, &opts, emit))
+    }
+
+    fn generate_choices(
+        self: Arc<Self>,
+        prompt: String,
+        opts: GenOptions,
+        n: usize,
+    ) -> ChoiceStream {
+        if n > MAX_SHARED_CHOICES {
+            return stream::sequential_choices(self, prompt, opts, n);
         }
-        Ok(format!("[INFO] {} Input: {}", fallback_msg, prompt))
+        let inner = Arc::clone(&self.inner);
+        self.executor
+            .stream_choices(move |emit| inner.generate_choices(&prompt, &opts, n, emit))
+    }
+
+    fn tokenize(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        use llama_cpp_2::model::AddBos;
+        let add_bos = if add_special {
+            AddBos::Always
+        } else {
+            AddBos::Never
+        };
+        let tokens = self.inner.model.str_to_token(text, add_bos)?;
+        Ok(tokens.into_iter().map(|t| t.0 as u32).collect())
+    }
+
+    fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        use llama_cpp_2::{model::Special, token::LlamaToken};
+        let mut out = String::new();
+        for &id in tokens {
+            let token = LlamaToken::new(id as i32);
+            if self.inner.model.is_eog_token(token) {
+                continue;
+            }
+            out.push_str(&self.inner.model.token_to_str(token, Special::Plaintext)?);
+        }
+        Ok(out)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
     }
 }
 
@@ -206,6 +535,8 @@ mod tests {
             template: Some("chatml".to_string()),
             ctx_len: 2048,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         // let result = engine.load(&spec).await; // Commented to avoid test file dependencies
@@ -221,6 +552,8 @@ mod tests {
             template: Some("chatml".to_string()),
             ctx_len: 4096,
             n_threads: Some(4),
+            backend: None,
+            command: None,
         };
 
         assert_eq!(spec.name, "valid");
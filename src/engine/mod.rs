@@ -2,6 +2,9 @@ use anyhow::Result;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::sync::Arc;
+
+pub use stream::{ChoiceStream, GenerationEvent, GenerationStream};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GenOptions {
@@ -12,6 +15,15 @@ pub struct GenOptions {
     pub repeat_penalty: f32,
     pub seed: Option<u32>,
     pub stream: bool,
+    /// GBNF grammar the output must follow
+    #[serde(default)]
+    pub grammar: Option<String>,
+    /// Text after the insertion point, for engines that fill in the middle
+    #[serde(default)]
+    pub suffix: Option<String>,
+    /// Base64-encoded images the prompt refers to
+    #[serde(default)]
+    pub images: Vec<String>,
 }
 
 impl Default for GenOptions {
@@ -24,6 +36,42 @@ impl Default for GenOptions {
             repeat_penalty: 1.1,
             seed: None,
             stream: true,
+            grammar: None,
+            suffix: None,
+            images: Vec::new(),
+        }
+    }
+}
+
+/// What a loaded model can do. API handlers check this before generating so
+/// unsupported requests are rejected up front instead of failing in a backend.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Produces completions at all (weights-only SafeTensors models do not)
+    pub generate: bool,
+    /// Ships its own chat template; shimmy's template families are used either way
+    pub chat_template: bool,
+    pub embeddings: bool,
+    /// Grammar-constrained or JSON-mode sampling
+    pub grammar: bool,
+    pub logprobs: bool,
+    pub images: bool,
+    /// Fill-in-the-middle completion with a `suffix`
+    pub infill: bool,
+    /// `tokenize`/`detokenize` use the model's own vocabulary
+    pub tokenize: bool,
+    /// Prompt plus completion tokens the model was loaded with, 0 when unknown
+    pub context_length: usize,
+}
+
+impl Capabilities {
+    /// Plain text generation within `context_length` tokens, nothing else
+    pub fn text(context_length: usize) -> Self {
+        Self {
+            generate: true,
+            context_length,
+            ..Self::default()
         }
     }
 }
@@ -45,11 +93,18 @@ pub enum ModelBackend {
         use_local: bool,            // Use cached vs download
     },
 
-    // Pure Rust Candle (future)
+    // Pure Rust Candle (SafeTensors checkpoints)
     Candle {
         model_path: PathBuf,
         adapter_path: Option<PathBuf>,
     },
+
+    // Any external engine speaking the exec JSON-lines protocol
+    Exec {
+        command: Vec<String>, // ["/opt/engine/bin/serve", "--threads", "8"]
+        model_path: PathBuf,  // Passed to the engine's `load`
+        adapter_path: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -72,17 +127,29 @@ pub struct ModelSpec {
     pub template: Option<String>,
     pub ctx_len: usize,
     pub n_threads: Option<i32>,
+    /// Backend named in the model config; detected from `base_path` when unset
+    pub backend: Option<String>,
+    /// Executable and arguments for `backend = "exec"`
+    pub command: Option<Vec<String>>,
 }
 
 #[cfg(feature = "huggingface")]
 impl From<ModelSpec> for UniversalModelSpec {
     fn from(spec: ModelSpec) -> Self {
-        UniversalModelSpec {
-            name: spec.name,
-            backend: ModelBackend::LlamaGGUF {
+        let backend = match (spec.backend.as_deref(), spec.command) {
+            (Some(exec::BACKEND_NAME), command) => ModelBackend::Exec {
+                command: command.unwrap_or_default(),
+                model_path: spec.base_path,
+                adapter_path: spec.lora_path,
+            },
+            _ => ModelBackend::LlamaGGUF {
                 base_path: spec.base_path,
                 lora_path: spec.lora_path,
             },
+        };
+        UniversalModelSpec {
+            name: spec.name,
+            backend,
             template: spec.template,
             ctx_len: spec.ctx_len,
             device: "cpu".to_string(),
@@ -107,6 +174,10 @@ pub trait UniversalModel: Send + Sync {
         opts: GenOptions,
         on_token: Option<Box<dyn FnMut(String) + Send>>,
     ) -> Result<String>;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::text(0)
+    }
 }
 
 // Legacy trait for backward compatibility
@@ -116,17 +187,58 @@ pub trait InferenceEngine: Send + Sync {
 }
 
 #[async_trait]
-pub trait LoadedModel: Send + Sync {
+pub trait LoadedModel: Send + Sync + 'static {
     async fn generate(
         &self,
         prompt: &str,
         opts: GenOptions,
         on_token: Option<Box<dyn FnMut(String) + Send>>,
     ) -> Result<String>;
+
+    /// The generation as structured events, ending with `Done` or `Error`.
+    /// Backends that know token ids or prompt progress override this; the
+    /// default adapts `generate`.
+    fn generate_stream(self: Arc<Self>, prompt: String, opts: GenOptions) -> GenerationStream {
+        stream::from_callbacks(self, prompt, opts)
+    }
+
+    /// `n` generations for one prompt, each seeded `opts.seed + index`.
+    /// Backends that can evaluate the prompt once and copy it to several
+    /// sequences override this; the default runs the choices one by one.
+    fn generate_choices(
+        self: Arc<Self>,
+        prompt: String,
+        opts: GenOptions,
+        n: usize,
+    ) -> ChoiceStream {
+        stream::sequential_choices(self, prompt, opts, n)
+    }
+
+    /// Token ids for `text` using the model's own vocabulary
+    fn tokenize(&self, _text: &str, _add_special: bool) -> Result<Vec<u32>> {
+        Err(anyhow::anyhow!("this backend does not expose a tokenizer"))
+    }
+
+    /// Text for a sequence of token ids, without special tokens
+    fn detokenize(&self, _tokens: &[u32]) -> Result<String> {
+        Err(anyhow::anyhow!("this backend does not expose a tokenizer"))
+    }
+
+    /// Embedding vector for `text`
+    fn embed(&self, _text: &str) -> Result<Vec<f32>> {
+        Err(anyhow::anyhow!("this backend does not produce embeddings"))
+    }
+
+    /// Features this model supports, checked by the API before generating
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::text(0)
+    }
 }
 
 pub mod llama;
 
+#[cfg(feature = "huggingface")]
+pub mod hf_worker;
 #[cfg(feature = "huggingface")]
 pub mod huggingface;
 
@@ -134,4 +246,16 @@ pub mod huggingface;
 pub mod universal;
 
 pub mod adapter;
+#[cfg(feature = "candle")]
+pub mod candle;
+pub mod exec;
+pub mod executor;
+pub mod gguf;
+pub mod gguf_convert;
+pub mod gguf_native;
+pub mod isolation;
 pub mod safetensors_native;
+pub mod shards;
+pub mod shared;
+pub mod stream;
+pub mod tokenizer;
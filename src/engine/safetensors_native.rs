@@ -3,9 +3,11 @@
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use safetensors::tensor::TensorView;
 use safetensors::SafeTensors;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::{self, File};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 use tracing::{debug, info, warn};
@@ -13,7 +15,9 @@ use tracing::{debug, info, warn};
 // use crate::cache::{ModelCache, ModelMetadata};
 // use crate::cache::model_cache;
 
-use super::{GenOptions, InferenceEngine, LoadedModel, ModelSpec};
+use super::shards::ShardName;
+use super::tokenizer::TextTokenizer;
+use super::{Capabilities, GenOptions, InferenceEngine, LoadedModel, ModelSpec};
 
 // Memory-mapped file support for large models
 use memmap2::MmapOptions;
@@ -50,22 +54,24 @@ impl SafeTensorsEngine {
 
     /// Check if a model file is SafeTensors format
     pub fn is_safetensors_model(path: &Path) -> bool {
-        if let Some(ext) = path.extension().and_then(|s| s.to_str()) {
-            return ext == "safetensors";
+        if path.is_dir() {
+            return SafeTensorsCheckpoint::is_checkpoint_dir(path);
+        }
+        if is_index_file(path) {
+            return true;
         }
 
-        // Also check by reading header if no extension
-        if let Ok(data) = fs::read(path) {
-            if data.len() >= 8 {
-                // SafeTensors files start with 8-byte header length
-                return SafeTensors::deserialize(&data).is_ok();
-            }
+        if let Some(ext) = path.extension().and_then(|s| s.to_str()) {
+            return ext == "safetensors";
         }
 
-        false
+        // Also check the header if no extension
+        has_safetensors_header(path)
     }
 
     /// Discover SafeTensors models in a directory
+    ///
+    /// A sharded checkpoint is reported once, as its directory.
     pub fn discover_safetensors_models(dir: &Path) -> Result<Vec<PathBuf>> {
         let mut models = Vec::new();
 
@@ -73,10 +79,18 @@ impl SafeTensorsEngine {
             return Ok(models);
         }
 
+        let sharded = SafeTensorsCheckpoint::sharded_in_dir(dir);
+        if let Some(checkpoint) = &sharded {
+            models.push(checkpoint.dir().to_path_buf());
+        }
+
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
 
+            if sharded.as_ref().is_some_and(|c| c.contains_file(&path)) || is_index_file(&path) {
+                continue;
+            }
             if path.is_file() && Self::is_safetensors_model(&path) {
                 models.push(path);
             }
@@ -86,11 +100,293 @@ impl SafeTensorsEngine {
     }
 }
 
+/// Shard files in order, plus the index's tensor name → file name map
+type ShardList = (Vec<PathBuf>, Option<HashMap<String, String>>);
+
+/// Where one tensor of a checkpoint lives
+#[derive(Debug, Clone)]
+struct TensorEntry {
+    shard: usize,
+    shape: Vec<usize>,
+}
+
+/// A SafeTensors checkpoint: a single file, or `model-00001-of-00004.safetensors`
+/// shards tied together by `model.safetensors.index.json`
+///
+/// Only the shard headers are read; tensor data stays on disk until a model maps it.
+#[derive(Debug, Clone)]
+pub struct SafeTensorsCheckpoint {
+    dir: PathBuf,
+    shards: Vec<PathBuf>,
+    tensors: HashMap<String, TensorEntry>,
+}
+
+impl SafeTensorsCheckpoint {
+    /// Open a checkpoint from its directory, index file, any one shard, or a single file
+    pub fn open(path: &Path) -> Result<Self> {
+        let (shards, weight_map) = Self::resolve_shards(path)?;
+        let dir = shards[0]
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from("."));
+
+        let mut tensors = HashMap::new();
+        for (shard, shard_path) in shards.iter().enumerate() {
+            for (name, shape) in read_header(shard_path)? {
+                tensors.insert(name, TensorEntry { shard, shape });
+            }
+        }
+
+        // The index is authoritative about placement; make sure the shards agree
+        if let Some(weight_map) = weight_map {
+            for (name, file) in &weight_map {
+                let expected = shards
+                    .iter()
+                    .position(|p| p.file_name() == Some(file.as_ref()));
+                match (tensors.get(name), expected) {
+                    (Some(entry), Some(shard)) if entry.shard == shard => {}
+                    _ => {
+                        return Err(anyhow!(
+                            "index lists tensor '{}' in {}, but that shard does not contain it",
+                            name,
+                            file
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            dir,
+            shards,
+            tensors,
+        })
+    }
+
+    /// The sharded checkpoint in `dir`, if it holds one
+    pub fn sharded_in_dir(dir: &Path) -> Option<Self> {
+        let has_shards = find_index(dir).is_some()
+            || safetensors_files(dir)
+                .iter()
+                .any(|p| shard_name(p).is_some());
+        if !has_shards {
+            return None;
+        }
+        match Self::open(dir) {
+            Ok(checkpoint) if checkpoint.is_sharded() => Some(checkpoint),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Skipping sharded checkpoint {}: {}", dir.display(), e);
+                None
+            }
+        }
+    }
+
+    /// True when `dir` has an index or at least one `.safetensors` file
+    pub fn is_checkpoint_dir(dir: &Path) -> bool {
+        find_index(dir).is_some() || !safetensors_files(dir).is_empty()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn shards(&self) -> &[PathBuf] {
+        &self.shards
+    }
+
+    pub fn is_sharded(&self) -> bool {
+        self.shards.len() > 1
+    }
+
+    pub fn contains_file(&self, path: &Path) -> bool {
+        self.shards.iter().any(|p| p == path)
+    }
+
+    /// Total parameters across every shard
+    pub fn parameter_count(&self) -> u64 {
+        self.tensors
+            .values()
+            .map(|t| t.shape.iter().product::<usize>() as u64)
+            .sum()
+    }
+
+    /// Total bytes on disk across every shard
+    pub fn total_size(&self) -> u64 {
+        self.shards
+            .iter()
+            .filter_map(|p| fs::metadata(p).ok())
+            .map(|m| m.len())
+            .sum()
+    }
+
+    pub fn tensor_count(&self) -> usize {
+        self.tensors.len()
+    }
+
+    /// Every tensor with the index of the shard that holds it and its shape
+    pub fn tensor_layout(&self) -> impl Iterator<Item = (&str, usize, &[usize])> {
+        self.tensors
+            .iter()
+            .map(|(name, t)| (name.as_str(), t.shard, t.shape.as_slice()))
+    }
+
+    fn tensor_names(&self) -> impl Iterator<Item = &String> {
+        self.tensors.keys()
+    }
+
+    fn shard_of(&self, name: &str) -> Option<usize> {
+        self.tensors.get(name).map(|t| t.shard)
+    }
+
+    fn resolve_shards(path: &Path) -> Result<ShardList> {
+        if is_index_file(path) {
+            return read_index(path).map(|(shards, map)| (shards, Some(map)));
+        }
+
+        if path.is_dir() {
+            if let Some(index) = find_index(path) {
+                return read_index(&index).map(|(shards, map)| (shards, Some(map)));
+            }
+            let files = safetensors_files(path);
+            let first_shard = files.iter().find_map(|p| shard_name(p));
+            return match first_shard {
+                Some(shard) => Ok((shard.paths_in(path)?, None)),
+                None if files.is_empty() => Err(anyhow!(
+                    "no .safetensors weights found in {}",
+                    path.display()
+                )),
+                None => Ok((files, None)),
+            };
+        }
+
+        if let Some(shard) = shard_name(path) {
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            if let Some(index) = find_index(dir) {
+                return read_index(&index).map(|(shards, map)| (shards, Some(map)));
+            }
+            return Ok((shard.paths_in(dir)?, None));
+        }
+
+        if !path.is_file() {
+            return Err(anyhow!("SafeTensors file not found: {}", path.display()));
+        }
+        Ok((vec![path.to_path_buf()], None))
+    }
+}
+
+/// Quick check for a SafeTensors header - an 8-byte length followed by a JSON
+/// object that fits in the file - without reading the tensor data
+pub fn has_safetensors_header(path: &Path) -> bool {
+    let Ok(mut file) = File::open(path) else {
+        return false;
+    };
+    let mut prefix = [0u8; 9];
+    if file.read_exact(&mut prefix).is_err() {
+        return false;
+    }
+    let header_len = u64::from_le_bytes(prefix[..8].try_into().unwrap());
+    let file_len = file.metadata().map(|m| m.len()).unwrap_or(0);
+    prefix[8] == b'{'
+        && header_len >= 2
+        && file_len
+            .checked_sub(8)
+            .is_some_and(|room| header_len <= room)
+}
+
+fn is_index_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.ends_with(".safetensors.index.json"))
+}
+
+fn shard_name(path: &Path) -> Option<ShardName> {
+    ShardName::from_path(path, "safetensors")
+}
+
+fn safetensors_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.extension().and_then(|e| e.to_str()) == Some("safetensors"))
+        .collect();
+    files.sort();
+    files
+}
+
+/// `model.safetensors.index.json`, or any other `*.safetensors.index.json` in `dir`
+fn find_index(dir: &Path) -> Option<PathBuf> {
+    let preferred = dir.join("model.safetensors.index.json");
+    if preferred.is_file() {
+        return Some(preferred);
+    }
+    let mut indexes: Vec<PathBuf> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && is_index_file(p))
+        .collect();
+    indexes.sort();
+    indexes.into_iter().next()
+}
+
+fn read_index(index: &Path) -> Result<(Vec<PathBuf>, HashMap<String, String>)> {
+    let text = fs::read_to_string(index)?;
+    let json: serde_json::Value = serde_json::from_str(&text)
+        .map_err(|e| anyhow!("invalid index {}: {}", index.display(), e))?;
+    let weight_map: HashMap<String, String> = json
+        .get("weight_map")
+        .and_then(|m| m.as_object())
+        .ok_or_else(|| anyhow!("index {} has no weight_map", index.display()))?
+        .iter()
+        .filter_map(|(name, file)| Some((name.clone(), file.as_str()?.to_string())))
+        .collect();
+    if weight_map.is_empty() {
+        return Err(anyhow!("index {} lists no tensors", index.display()));
+    }
+
+    let dir = index.parent().unwrap_or_else(|| Path::new("."));
+    let files: BTreeSet<&String> = weight_map.values().collect();
+    let missing: Vec<&str> = files
+        .iter()
+        .filter(|f| !dir.join(f.as_str()).is_file())
+        .map(|f| f.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "checkpoint {} is missing shards: {}",
+            index.display(),
+            missing.join(", ")
+        ));
+    }
+    let shards = files.into_iter().map(|f| dir.join(f)).collect();
+    Ok((shards, weight_map))
+}
+
+/// Tensor names and shapes from a file's header, without reading tensor data
+fn read_header(path: &Path) -> Result<Vec<(String, Vec<usize>)>> {
+    let file = File::open(path)?;
+    let mmap = unsafe { MmapOptions::new().map(&file)? };
+    let (_, metadata) = SafeTensors::read_metadata(&mmap)
+        .map_err(|e| anyhow!("{} is not a valid SafeTensors file: {}", path.display(), e))?;
+    Ok(metadata
+        .tensors()
+        .into_iter()
+        .map(|(name, info)| (name, info.shape.clone()))
+        .collect())
+}
+
 #[async_trait]
 impl InferenceEngine for SafeTensorsEngine {
     async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
         info!("Loading SafeTensors model: {}", spec.base_path.display());
 
+        // Checkpoints with a config.json for a known architecture get a real forward pass
+        #[cfg(feature = "candle")]
+        if super::candle::CandleEngine::can_load(&spec.base_path) {
+            return super::candle::CandleEngine::new().load(spec).await;
+        }
+
         // Check if it's actually a SafeTensors file
         if !Self::is_safetensors_model(&spec.base_path) {
             return Err(anyhow!(
@@ -134,12 +430,26 @@ impl ModelData {
     }
 }
 
+/// Resolve `name` through the checkpoint's tensor map into the shard that holds it
+fn shard_tensor<'a>(
+    checkpoint: &SafeTensorsCheckpoint,
+    shards: &'a [ModelData],
+    name: &str,
+) -> Result<TensorView<'a>> {
+    let shard = checkpoint
+        .shard_of(name)
+        .ok_or_else(|| anyhow!("tensor '{}' not found in checkpoint", name))?;
+    let tensors = SafeTensors::deserialize(shards[shard].as_bytes())?;
+    Ok(tensors.tensor(name)?)
+}
+
 #[derive(Debug)]
 struct SafeTensorsModel {
     name: String,
-    model_data: ModelData, // Either in-memory or memory-mapped
+    checkpoint: SafeTensorsCheckpoint,
+    shards: Vec<ModelData>, // One per checkpoint shard, in-memory or memory-mapped
     config: ModelConfig,
-    tokenizer: SimpleTokenizer,
+    tokenizer: Option<TextTokenizer>,
 }
 
 #[derive(Debug, Clone)]
@@ -151,48 +461,28 @@ struct ModelConfig {
     // Add more config fields as needed
 }
 
-#[derive(Debug)]
-struct SimpleTokenizer {
-    // Simple tokenizer implementation
-    vocab: HashMap<String, u32>,
-    reverse_vocab: HashMap<u32, String>,
-    bos_token: u32,
-    eos_token: u32,
-}
-
 impl SafeTensorsModel {
     /// Load model and cache metadata (for new models not in cache)
     async fn load_and_cache(
         spec: &ModelSpec,
         /* cache: &RwLock<ModelCache>, */ use_mmap: bool,
     ) -> Result<Self> {
-        info!("Reading SafeTensors file: {}", spec.base_path.display());
-
-        // Choose loading strategy based on file size and mmap setting
-        let file_size = fs::metadata(&spec.base_path)?.len();
-        let use_mmap_for_file = use_mmap && file_size > 100 * 1024 * 1024; // Use mmap for files > 100MB
-
-        let model_data = if use_mmap_for_file {
-            info!(
-                "Using memory-mapped loading for large model ({:.1} MB)",
-                file_size as f64 / 1024.0 / 1024.0
-            );
-            let file = File::open(&spec.base_path)?;
-            let mmap = unsafe { MmapOptions::new().map(&file)? };
-            ModelData::MemoryMapped(mmap)
-        } else {
-            info!(
-                "Loading model into memory ({:.1} MB)",
-                file_size as f64 / 1024.0 / 1024.0
-            );
-            let data = fs::read(&spec.base_path)?;
-            ModelData::InMemory(data)
-        };
+        info!(
+            "Reading SafeTensors checkpoint: {}",
+            spec.base_path.display()
+        );
 
-        // Parse SafeTensors format to validate and get info
-        let tensors = SafeTensors::deserialize(model_data.as_bytes())?;
+        let checkpoint = SafeTensorsCheckpoint::open(&spec.base_path)?;
+        let mut shards = Vec::with_capacity(checkpoint.shards().len());
+        for shard_path in checkpoint.shards() {
+            shards.push(Self::read_shard(shard_path, use_mmap)?);
+        }
 
-        debug!("SafeTensors loaded with {} tensors", tensors.len());
+        debug!(
+            "SafeTensors loaded with {} tensors across {} shard(s)",
+            checkpoint.tensor_count(),
+            shards.len()
+        );
 
         // Extract metadata for caching
         // let metadata = model_cache::extract_safetensors_metadata(&spec.base_path)?;
@@ -209,24 +499,53 @@ impl SafeTensorsModel {
         let config = /* if let Some(config_data) = &metadata.config {
             Self::parse_config_from_json(config_data)?
         } else */ {
-            Self::load_config(&spec.base_path, &tensors).await?
+            Self::load_config(&checkpoint, &shards).await?
         };
 
         // Load tokenizer from cached metadata if available, otherwise parse
         let tokenizer = /* if let Some(tokenizer_data) = &metadata.tokenizer {
-            Self::parse_tokenizer_from_json(tokenizer_data)?
+            Some(Self::parse_tokenizer_from_json(tokenizer_data)?)
         } else */ {
-            Self::load_tokenizer(&spec.base_path).await?
+            Self::load_tokenizer(checkpoint.dir()).await
         };
 
         Ok(SafeTensorsModel {
             name: spec.name.clone(),
-            model_data,
+            checkpoint,
+            shards,
             config,
             tokenizer,
         })
     }
 
+    /// Map or read one shard depending on its size and the mmap setting
+    fn read_shard(path: &Path, use_mmap: bool) -> Result<ModelData> {
+        let file_size = fs::metadata(path)?.len();
+        let use_mmap_for_file = use_mmap && file_size > 100 * 1024 * 1024; // Use mmap for files > 100MB
+
+        let model_data = if use_mmap_for_file {
+            info!(
+                "Using memory-mapped loading for {} ({:.1} MB)",
+                path.display(),
+                file_size as f64 / 1024.0 / 1024.0
+            );
+            let file = File::open(path)?;
+            let mmap = unsafe { MmapOptions::new().map(&file)? };
+            ModelData::MemoryMapped(mmap)
+        } else {
+            info!(
+                "Loading {} into memory ({:.1} MB)",
+                path.display(),
+                file_size as f64 / 1024.0 / 1024.0
+            );
+            ModelData::InMemory(fs::read(path)?)
+        };
+
+        // Parse SafeTensors format to validate
+        SafeTensors::deserialize(model_data.as_bytes())?;
+        Ok(model_data)
+    }
+
     /// Load model from cached metadata (much faster)
     /* async fn load_from_cached_metadata(spec: &ModelSpec, metadata: &ModelMetadata, use_mmap: bool) -> Result<Self> {
         info!("Loading model from cached metadata");
@@ -257,10 +576,10 @@ impl SafeTensorsModel {
 
         // Parse tokenizer from cached metadata
         let tokenizer = if let Some(tokenizer_data) = &metadata.tokenizer {
-            Self::parse_tokenizer_from_json(tokenizer_data)?
+            Some(Self::parse_tokenizer_from_json(tokenizer_data)?)
         } else {
             // Fallback to file-based loading if not in cache
-            Self::load_tokenizer(&spec.base_path).await?
+            Self::load_tokenizer(&spec.base_path).await
         };
 
         debug!("Model loaded from cache with {} cached tensors", metadata.tensors.len());
@@ -307,38 +626,20 @@ impl SafeTensorsModel {
     }
 
     /// Parse tokenizer from cached JSON data
-    fn parse_tokenizer_from_json(tokenizer_data: &serde_json::Value) -> Result<SimpleTokenizer> {
-        let mut vocab = HashMap::new();
-        let mut reverse_vocab = HashMap::new();
-
-        // Parse vocab from tokenizer JSON
-        if let Some(vocab_obj) = tokenizer_data.get("model").and_then(|m| m.get("vocab")) {
-            if let Some(vocab_map) = vocab_obj.as_object() {
-                for (token, id) in vocab_map {
-                    if let Some(token_id) = id.as_u64() {
-                        let token_id = token_id as u32;
-                        vocab.insert(token.clone(), token_id);
-                        reverse_vocab.insert(token_id, token.clone());
-                    }
-                }
-            }
-        }
-
-        // Default special tokens
-        let bos_token = vocab.get("<s>").copied().unwrap_or(1);
-        let eos_token = vocab.get("</s>").copied().unwrap_or(2);
-
-        Ok(SimpleTokenizer {
-            vocab,
-            reverse_vocab,
-            bos_token,
-            eos_token,
-        })
+    fn parse_tokenizer_from_json(tokenizer_data: &serde_json::Value) -> Result<TextTokenizer> {
+        let tokenizer: tokenizers::Tokenizer = tokenizer_data
+            .to_string()
+            .parse()
+            .map_err(|e| anyhow!("invalid tokenizer.json: {}", e))?;
+        Ok(TextTokenizer::HuggingFace(Box::new(tokenizer)))
     }
 
-    async fn load_config(model_path: &Path, tensors: &SafeTensors<'_>) -> Result<ModelConfig> {
-        // Try to load config.json from same directory
-        let config_path = model_path.with_file_name("config.json");
+    async fn load_config(
+        checkpoint: &SafeTensorsCheckpoint,
+        shards: &[ModelData],
+    ) -> Result<ModelConfig> {
+        // Try to load config.json from the checkpoint directory
+        let config_path = checkpoint.dir().join("config.json");
 
         if config_path.exists() {
             let config_data = fs::read_to_string(&config_path)?;
@@ -361,16 +662,17 @@ impl SafeTensorsModel {
         info!("No config.json found, inferring from tensor shapes");
 
         // Look for embedding or output layer to determine vocab size
-        let vocab_size = if let Ok(tensor) = tensors.tensor("lm_head.weight") {
+        let tensor = |name: &str| shard_tensor(checkpoint, shards, name);
+        let vocab_size = if let Ok(tensor) = tensor("lm_head.weight") {
             tensor.shape()[0]
-        } else if let Ok(tensor) = tensors.tensor("embed_tokens.weight") {
+        } else if let Ok(tensor) = tensor("embed_tokens.weight") {
             tensor.shape()[0]
         } else {
             32000 // Default vocab size
         };
 
         // Look for hidden layers to determine model size
-        let hidden_size = if let Ok(tensor) = tensors.tensor("embed_tokens.weight") {
+        let hidden_size = if let Ok(tensor) = tensor("embed_tokens.weight") {
             tensor.shape()[1]
         } else {
             4096 // Default hidden size
@@ -378,7 +680,7 @@ impl SafeTensorsModel {
 
         // Count layers by looking for layer-specific tensors
         let mut num_layers = 0;
-        for name in tensors.names() {
+        for name in checkpoint.tensor_names() {
             if name.contains("layers.") {
                 if let Some(layer_num_str) = name
                     .split("layers.")
@@ -404,114 +706,24 @@ impl SafeTensorsModel {
         })
     }
 
-    async fn load_tokenizer(model_path: &Path) -> Result<SimpleTokenizer> {
-        // Try to load tokenizer.json
-        let tokenizer_path = model_path.with_file_name("tokenizer.json");
-
-        if tokenizer_path.exists() {
-            let tokenizer_data = fs::read_to_string(&tokenizer_path)?;
-            let json: serde_json::Value = serde_json::from_str(&tokenizer_data)?;
-
-            // Extract vocabulary from HuggingFace tokenizer.json format
-            if let Some(model) = json.get("model") {
-                if let Some(vocab) = model.get("vocab") {
-                    let mut vocab_map = HashMap::new();
-                    let mut reverse_vocab = HashMap::new();
-
-                    if let Some(vocab_obj) = vocab.as_object() {
-                        for (token, id) in vocab_obj {
-                            if let Some(id_num) = id.as_u64() {
-                                vocab_map.insert(token.clone(), id_num as u32);
-                                reverse_vocab.insert(id_num as u32, token.clone());
-                            }
-                        }
-                    }
-
-                    // Find special tokens
-                    let bos_token = vocab_map
-                        .get("<s>")
-                        .copied()
-                        .or_else(|| vocab_map.get("<|startoftext|>").copied())
-                        .unwrap_or(0);
-                    let eos_token = vocab_map
-                        .get("</s>")
-                        .copied()
-                        .or_else(|| vocab_map.get("<|endoftext|>").copied())
-                        .unwrap_or(1);
-
-                    return Ok(SimpleTokenizer {
-                        vocab: vocab_map,
-                        reverse_vocab,
-                        bos_token,
-                        eos_token,
-                    });
-                }
+    async fn load_tokenizer(dir: &Path) -> Option<TextTokenizer> {
+        // tokenizer.json or SentencePiece tokenizer.model next to the weights
+        match TextTokenizer::from_dir(dir) {
+            Ok(tokenizer) => Some(tokenizer),
+            Err(e) => {
+                warn!("No usable tokenizer in {}: {}", dir.display(), e);
+                None
             }
         }
-
-        // Fallback: create minimal tokenizer
-        warn!("No tokenizer.json found, using simple character tokenizer");
-        Self::create_simple_char_tokenizer()
-    }
-
-    fn create_simple_char_tokenizer() -> Result<SimpleTokenizer> {
-        let mut vocab = HashMap::new();
-        let mut reverse_vocab = HashMap::new();
-
-        // Add special tokens
-        vocab.insert("<s>".to_string(), 0);
-        reverse_vocab.insert(0, "<s>".to_string());
-        vocab.insert("</s>".to_string(), 1);
-        reverse_vocab.insert(1, "</s>".to_string());
-        vocab.insert("<unk>".to_string(), 2);
-        reverse_vocab.insert(2, "<unk>".to_string());
-
-        // Add basic ASCII characters
-        let mut id = 3;
-        for c in 32u8..=126 {
-            // Printable ASCII
-            let char_str = (c as char).to_string();
-            vocab.insert(char_str.clone(), id);
-            reverse_vocab.insert(id, char_str);
-            id += 1;
-        }
-
-        Ok(SimpleTokenizer {
-            vocab,
-            reverse_vocab,
-            bos_token: 0,
-            eos_token: 1,
-        })
     }
-}
-
-impl SimpleTokenizer {
-    fn encode(&self, text: &str) -> Vec<u32> {
-        let mut tokens = vec![self.bos_token];
-
-        // Simple character-level tokenization for fallback
-        for char in text.chars() {
-            let token = self.vocab.get(&char.to_string()).copied().unwrap_or(2); // <unk>
-            tokens.push(token);
-        }
-
-        tokens
-    }
-
-    fn decode(&self, tokens: &[u32]) -> String {
-        let mut text = String::new();
-
-        for &token in tokens {
-            if token == self.bos_token || token == self.eos_token {
-                continue; // Skip special tokens
-            }
 
-            if let Some(token_str) = self.reverse_vocab.get(&token) {
-                text.push_str(token_str);
-            }
-        }
-
-        text
+    fn tokenizer(&self) -> Result<&TextTokenizer> {
+        self.tokenizer.as_ref().ok_or_else(|| {
+            anyhow!(
+                "SafeTensors model '{}' has no tokenizer.json or tokenizer.model",
+                self.name
+            )
+        })
     }
 }
 
@@ -520,73 +732,49 @@ impl LoadedModel for SafeTensorsModel {
     async fn generate(
         &self,
         prompt: &str,
-        opts: GenOptions,
-        on_token: Option<Box<dyn FnMut(String) + Send>>,
+        _opts: GenOptions,
+        _on_token: Option<Box<dyn FnMut(String) + Send>>,
     ) -> Result<String> {
-        info!(
-            "Generating with SafeTensors model: prompt length = {}",
-            prompt.len()
-        );
-
-        // Tokenize input
-        let input_tokens = self.tokenizer.encode(prompt);
-        debug!("Input tokens: {} tokens", input_tokens.len());
-
-        // Simple template-based generation for now
-        // In a full implementation, this would do actual forward pass through the model
-        let response = self.simple_generate(prompt, &opts).await?;
-
-        // Handle streaming callback
-        if let Some(mut callback) = on_token {
-            // Simulate token-by-token generation
-            for word in response.split_whitespace() {
-                callback(format!("{} ", word));
-                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-            }
+        if let Some(tokenizer) = &self.tokenizer {
+            debug!(
+                "Input tokens: {} tokens",
+                tokenizer.encode(prompt, true)?.len()
+            );
         }
 
-        Ok(response)
-    }
-}
-
-impl SafeTensorsModel {
-    async fn simple_generate(&self, prompt: &str, opts: &GenOptions) -> Result<String> {
-        // This is a simplified implementation for demonstration
-        // A full implementation would:
-        // 1. Run forward pass through transformer layers
-        // 2. Apply attention mechanisms
-        // 3. Generate tokens using sampling strategies
-
-        let memory_info = match &self.model_data {
-            ModelData::InMemory(_) => "in-memory",
-            ModelData::MemoryMapped(_) => "memory-mapped",
+        let memory_info = match self.shards.first() {
+            Some(ModelData::MemoryMapped(_)) => "memory-mapped",
+            _ => "in-memory",
         };
 
-        info!("Using {} loading for SafeTensors inference", memory_info);
-
-        // For now, return a template response indicating the model loaded successfully
-        let response = format!(
-            "SafeTensors model '{}' loaded successfully with {} layers and vocab size {} using {} loading. \
-            Input prompt: '{}' (length: {}). \
-            This demonstrates native SafeTensors support in Rust with optimized memory handling. \
-            Full transformer inference coming soon!",
+        // Only checkpoints the Candle backend recognises can actually run
+        Err(anyhow!(
+            "SafeTensors model '{}' ({} layers, vocab {}, {} parameters in {} shard(s), {}) has no runnable architecture: \
+            it needs a config.json for llama, mistral, phi, phi3 or qwen2 and a build with --features candle",
             self.name,
             self.config.num_layers,
             self.config.vocab_size,
-            memory_info,
-            prompt,
-            prompt.len()
-        );
+            self.checkpoint.parameter_count(),
+            self.shards.len(),
+            memory_info
+        ))
+    }
 
-        // Respect max_tokens setting
-        let words: Vec<&str> = response.split_whitespace().collect();
-        let limited_response = if words.len() > opts.max_tokens {
-            words[..opts.max_tokens].join(" ")
-        } else {
-            response
-        };
+    fn tokenize(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        self.tokenizer()?.encode(text, add_special)
+    }
 
-        Ok(limited_response)
+    fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer()?.decode(tokens, true)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // Weights and tokenizer only: see `generate`
+        Capabilities {
+            generate: false,
+            tokenize: self.tokenizer.is_some(),
+            ..Capabilities::text(self.config.max_sequence_length)
+        }
     }
 }
 
@@ -664,31 +852,69 @@ mod tests {
             template: Some("chatml".to_string()),
             ctx_len: 2048,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         let result = engine.load(&spec).await;
         assert!(result.is_err());
     }
 
-    #[test]
-    fn test_simple_tokenizer_creation() {
-        let tokenizer = SafeTensorsModel::create_simple_char_tokenizer().unwrap();
-        assert!(tokenizer.vocab.contains_key("<s>"));
-        assert!(tokenizer.vocab.contains_key("</s>"));
-        assert!(tokenizer.vocab.contains_key("A"));
-        assert_eq!(tokenizer.bos_token, 0);
-        assert_eq!(tokenizer.eos_token, 1);
+    #[tokio::test]
+    async fn test_tokenize_with_tokenizer_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let model_path = temp_dir.path().join("model.safetensors");
+        fs::write(&model_path, create_minimal_safetensors()).unwrap();
+        fs::write(
+            temp_dir.path().join("tokenizer.json"),
+            r#"{"version": "1.0", "truncation": null, "padding": null, "added_tokens": [],
+                "normalizer": {"type": "Lowercase"}, "pre_tokenizer": {"type": "Whitespace"},
+                "post_processor": null, "decoder": null,
+                "model": {"type": "WordLevel", "vocab": {"<unk>": 0, "hello": 1, "world": 2}, "unk_token": "<unk>"}}"#,
+        )
+        .unwrap();
+
+        let model = SafeTensorsEngine::new()
+            .load(&ModelSpec {
+                name: "test".to_string(),
+                base_path: model_path,
+                lora_path: None,
+                template: None,
+                ctx_len: 2048,
+                n_threads: None,
+                backend: None,
+                command: None,
+            })
+            .await
+            .unwrap();
+        let ids = model.tokenize("Hello world", false).unwrap();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(model.detokenize(&ids).unwrap(), "hello world");
     }
 
-    #[test]
-    fn test_simple_tokenizer_encode_decode() {
-        let tokenizer = SafeTensorsModel::create_simple_char_tokenizer().unwrap();
-
-        let text = "Hello";
-        let tokens = tokenizer.encode(text);
-        let decoded = tokenizer.decode(&tokens[1..tokens.len()]); // Skip BOS token
-
-        assert_eq!(decoded, text);
+    #[tokio::test]
+    async fn test_tokenize_without_tokenizer_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let model_path = temp_dir.path().join("model.safetensors");
+        fs::write(&model_path, create_minimal_safetensors()).unwrap();
+
+        let model = SafeTensorsEngine::new()
+            .load(&ModelSpec {
+                name: "bare".to_string(),
+                base_path: model_path,
+                lora_path: None,
+                template: None,
+                ctx_len: 2048,
+                n_threads: None,
+                backend: None,
+                command: None,
+            })
+            .await
+            .unwrap();
+        let err = model.tokenize("hello", false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("no tokenizer.json or tokenizer.model"));
     }
 
     #[test]
@@ -706,6 +932,135 @@ mod tests {
         assert_eq!(config.max_sequence_length, 2048);
     }
 
+    /// Two-shard checkpoint: embeddings + layer 0 in shard 1, layer 1 + lm_head in shard 2
+    fn write_sharded_checkpoint(dir: &Path, with_index: bool) {
+        write_shard(
+            &dir.join("model-00001-of-00002.safetensors"),
+            &[
+                ("embed_tokens.weight", &[8, 4]),
+                ("layers.0.mlp.weight", &[4, 4]),
+            ],
+        );
+        write_shard(
+            &dir.join("model-00002-of-00002.safetensors"),
+            &[
+                ("layers.1.mlp.weight", &[4, 4]),
+                ("lm_head.weight", &[8, 4]),
+            ],
+        );
+        if with_index {
+            fs::write(
+                dir.join("model.safetensors.index.json"),
+                r#"{"metadata": {"total_size": 320}, "weight_map": {
+                    "embed_tokens.weight": "model-00001-of-00002.safetensors",
+                    "layers.0.mlp.weight": "model-00001-of-00002.safetensors",
+                    "layers.1.mlp.weight": "model-00002-of-00002.safetensors",
+                    "lm_head.weight": "model-00002-of-00002.safetensors"}}"#,
+            )
+            .unwrap();
+        }
+    }
+
+    fn write_shard(path: &Path, tensors: &[(&str, &[usize])]) {
+        let data: Vec<Vec<u8>> = tensors
+            .iter()
+            .map(|(_, shape)| vec![0u8; shape.iter().product::<usize>() * 4])
+            .collect();
+        let views = tensors.iter().zip(&data).map(|((name, shape), bytes)| {
+            let view = TensorView::new(safetensors::Dtype::F32, shape.to_vec(), bytes).unwrap();
+            (name.to_string(), view)
+        });
+        fs::write(path, safetensors::serialize(views, &None).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_sharded_checkpoint_from_index() {
+        let temp_dir = TempDir::new().unwrap();
+        write_sharded_checkpoint(temp_dir.path(), true);
+
+        let checkpoint = SafeTensorsCheckpoint::open(temp_dir.path()).unwrap();
+        assert!(checkpoint.is_sharded());
+        assert_eq!(checkpoint.shards().len(), 2);
+        assert_eq!(checkpoint.tensor_count(), 4);
+        assert_eq!(checkpoint.parameter_count(), 32 + 16 + 16 + 32);
+        let on_disk: u64 = checkpoint
+            .shards()
+            .iter()
+            .map(|p| fs::metadata(p).unwrap().len())
+            .sum();
+        assert_eq!(checkpoint.total_size(), on_disk);
+        assert_eq!(checkpoint.shard_of("lm_head.weight"), Some(1));
+
+        // Any shard, or the index itself, opens the whole checkpoint
+        for entry in [
+            temp_dir.path().join("model-00002-of-00002.safetensors"),
+            temp_dir.path().join("model.safetensors.index.json"),
+        ] {
+            assert_eq!(
+                SafeTensorsCheckpoint::open(&entry).unwrap().shards().len(),
+                2
+            );
+        }
+    }
+
+    #[test]
+    fn test_sharded_checkpoint_without_index_uses_shard_names() {
+        let temp_dir = TempDir::new().unwrap();
+        write_sharded_checkpoint(temp_dir.path(), false);
+
+        let checkpoint =
+            SafeTensorsCheckpoint::open(&temp_dir.path().join("model-00001-of-00002.safetensors"))
+                .unwrap();
+        assert_eq!(checkpoint.shards().len(), 2);
+        assert_eq!(checkpoint.parameter_count(), 96);
+    }
+
+    #[test]
+    fn test_sharded_checkpoint_names_missing_shards() {
+        let temp_dir = TempDir::new().unwrap();
+        write_sharded_checkpoint(temp_dir.path(), true);
+        fs::remove_file(temp_dir.path().join("model-00002-of-00002.safetensors")).unwrap();
+
+        let err = SafeTensorsCheckpoint::open(temp_dir.path()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("missing shards: model-00002-of-00002.safetensors"));
+    }
+
+    #[test]
+    fn test_discover_collapses_sharded_checkpoint() {
+        let temp_dir = TempDir::new().unwrap();
+        write_sharded_checkpoint(temp_dir.path(), true);
+
+        let models = SafeTensorsEngine::discover_safetensors_models(temp_dir.path()).unwrap();
+        assert_eq!(models, vec![temp_dir.path().to_path_buf()]);
+    }
+
+    #[tokio::test]
+    async fn test_load_sharded_checkpoint_resolves_tensors_across_shards() {
+        let temp_dir = TempDir::new().unwrap();
+        write_sharded_checkpoint(temp_dir.path(), true);
+
+        let spec = ModelSpec {
+            name: "sharded".to_string(),
+            base_path: temp_dir.path().to_path_buf(),
+            lora_path: None,
+            template: None,
+            ctx_len: 2048,
+            n_threads: None,
+            backend: None,
+            command: None,
+        };
+        let model = SafeTensorsModel::load_and_cache(&spec, true).await.unwrap();
+        assert_eq!(model.shards.len(), 2);
+        // Shape inference found lm_head in shard 2 and the layers in both
+        assert_eq!(model.config.vocab_size, 8);
+        assert_eq!(model.config.num_layers, 2);
+        let lm_head = shard_tensor(&model.checkpoint, &model.shards, "lm_head.weight").unwrap();
+        assert_eq!(lm_head.shape(), &[8, 4]);
+        assert!(shard_tensor(&model.checkpoint, &model.shards, "missing").is_err());
+    }
+
     // Helper function to create minimal valid SafeTensors data
     fn create_minimal_safetensors() -> Vec<u8> {
         let metadata = r#"{"test_tensor":{"dtype":"F32","shape":[1,1],"data_offsets":[0,4]}}"#;
@@ -0,0 +1,130 @@
+// Split checkpoint naming shared by SafeTensors and GGUF:
+// `model-00001-of-00004.safetensors`, `llama-70b-q4_k_m-00001-of-00003.gguf`
+
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+
+/// One file of a split checkpoint, parsed from a `<prefix>-NNNNN-of-MMMMM.<ext>` name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardName {
+    pub prefix: String,
+    pub index: u32,
+    pub total: u32,
+    pub extension: String,
+    width: usize,
+}
+
+impl ShardName {
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let (stem, extension) = file_name.rsplit_once('.')?;
+        let (rest, total_str) = stem.rsplit_once("-of-")?;
+        let (prefix, index_str) = rest.rsplit_once('-')?;
+        let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if prefix.is_empty() || !digits(index_str) || !digits(total_str) {
+            return None;
+        }
+        let index: u32 = index_str.parse().ok()?;
+        let total: u32 = total_str.parse().ok()?;
+        if index == 0 || index > total {
+            return None;
+        }
+        Some(Self {
+            prefix: prefix.to_string(),
+            index,
+            total,
+            extension: extension.to_string(),
+            width: index_str.len(),
+        })
+    }
+
+    /// File name of shard `index` (1-based) of the same checkpoint
+    pub fn sibling(&self, index: u32) -> String {
+        format!(
+            "{}-{:0width$}-of-{:0width$}.{}",
+            self.prefix,
+            index,
+            self.total,
+            self.extension,
+            width = self.width
+        )
+    }
+
+    /// File names of every shard, in order
+    pub fn all(&self) -> Vec<String> {
+        (1..=self.total).map(|i| self.sibling(i)).collect()
+    }
+
+    /// Parse the file name of `path`, accepting only the given extension
+    pub fn from_path(path: &Path, extension: &str) -> Option<Self> {
+        let shard = Self::parse(path.file_name()?.to_str()?)?;
+        (shard.extension == extension).then_some(shard)
+    }
+
+    /// Paths of every shard in `dir`, erroring with the names of any that are absent
+    pub fn paths_in(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let names = self.all();
+        let missing: Vec<&str> = names
+            .iter()
+            .filter(|n| !dir.join(n).is_file())
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "split model '{}' in {} is missing {} of {} shards: {}",
+                self.prefix,
+                dir.display(),
+                missing.len(),
+                self.total,
+                missing.join(", ")
+            );
+        }
+        Ok(names.iter().map(|n| dir.join(n)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_safetensors_shard() {
+        let shard = ShardName::parse("model-00002-of-00004.safetensors").unwrap();
+        assert_eq!(shard.prefix, "model");
+        assert_eq!(shard.index, 2);
+        assert_eq!(shard.total, 4);
+        assert_eq!(shard.extension, "safetensors");
+        assert_eq!(shard.sibling(1), "model-00001-of-00004.safetensors");
+        assert_eq!(shard.all().len(), 4);
+    }
+
+    #[test]
+    fn test_parse_keeps_dashed_prefix() {
+        let shard = ShardName::parse("llama-3-70b-q4_k_m-00001-of-00003.gguf").unwrap();
+        assert_eq!(shard.prefix, "llama-3-70b-q4_k_m");
+        assert_eq!(shard.sibling(3), "llama-3-70b-q4_k_m-00003-of-00003.gguf");
+    }
+
+    #[test]
+    fn test_paths_in_names_missing_shards() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let shard = ShardName::parse("m-00001-of-00003.gguf").unwrap();
+        for name in [shard.sibling(1), shard.sibling(3)] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let err = shard.paths_in(dir.path()).unwrap_err().to_string();
+        assert!(err.contains("missing 1 of 3 shards: m-00002-of-00003.gguf"));
+
+        std::fs::write(dir.path().join(shard.sibling(2)), b"").unwrap();
+        let paths = shard.paths_in(dir.path()).unwrap();
+        assert_eq!(paths[0], dir.path().join("m-00001-of-00003.gguf"));
+        assert_eq!(paths.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_rejects_plain_names() {
+        assert!(ShardName::parse("model.safetensors").is_none());
+        assert!(ShardName::parse("phi-3-mini-4k.gguf").is_none());
+        assert!(ShardName::parse("model-00005-of-00004.safetensors").is_none());
+        assert!(ShardName::parse("-00001-of-00002.safetensors").is_none());
+    }
+}
@@ -0,0 +1,110 @@
+// Model weights shared across loaded models
+// Registry entries that point at the same base file (with different adapters,
+// ctx_len or templates) each get their own context and settings on top of one
+// copy of the weights. Models hold an `Arc`; the cache only keeps a `Weak`, so
+// the strong references are the loaded models in `crate::loaded_models`.
+// Weights stay in memory while any model using them is within its keep-alive,
+// and are freed when the last of those is evicted or unloaded.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::SystemTime;
+use tracing::info;
+
+/// Which file the weights came from. The modification time keeps a model
+/// replaced on disk from being served from the old copy.
+type Key = (PathBuf, Option<SystemTime>);
+
+pub struct WeightCache<T> {
+    entries: Mutex<HashMap<Key, Weak<T>>>,
+}
+
+impl<T> Default for WeightCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> WeightCache<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The weights for `path`, from a model that already has them loaded or
+    /// else from `load`
+    pub fn get_or_load(&self, path: &Path, load: impl FnOnce() -> Result<T>) -> Result<Arc<T>> {
+        let key = key_for(path);
+        if let Some(weights) = self.live(&key) {
+            info!(path = %path.display(), "Sharing weights with an already loaded model");
+            return Ok(weights);
+        }
+        // Not locked while loading, which takes a while: two first loads of
+        // one file may race, and the one that finishes first is kept
+        let loaded = Arc::new(load()?);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, weights| weights.strong_count() > 0);
+        if let Some(existing) = entries.get(&key).and_then(Weak::upgrade) {
+            return Ok(existing);
+        }
+        entries.insert(key, Arc::downgrade(&loaded));
+        Ok(loaded)
+    }
+
+    fn live(&self, key: &Key) -> Option<Arc<T>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(Weak::upgrade)
+    }
+}
+
+fn key_for(path: &Path) -> Key {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let modified = path.metadata().and_then(|m| m.modified()).ok();
+    (path, modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::fs;
+
+    #[test]
+    fn test_weights_are_shared_until_the_last_user_drops_them() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("model.gguf");
+        fs::write(&path, b"weights").unwrap();
+        let cache = WeightCache::new();
+        let mut loads = 0;
+        let mut load = || {
+            loads += 1;
+            Ok(fs::read(&path)?)
+        };
+
+        let first = cache.get_or_load(&path, &mut load).unwrap();
+        // The same file under another spelling
+        let relative = dir.path().join(".").join("model.gguf");
+        let second = cache.get_or_load(&relative, &mut load).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let weak = Arc::downgrade(&first);
+        drop((first, second));
+        assert!(weak.upgrade().is_none());
+        cache.get_or_load(&path, &mut load).unwrap();
+        assert_eq!(loads, 2);
+
+        let err = cache
+            .get_or_load(&dir.path().join("other.gguf"), || -> Result<Vec<u8>> {
+                Err(anyhow!("corrupt"))
+            })
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "corrupt");
+    }
+}
@@ -0,0 +1,562 @@
+// Structured generation events and the streams that carry them
+// `LoadedModel::generate_stream` yields `GenerationEvent`s; handlers and the
+// CLI consume that one stream instead of wiring callbacks to channels.
+
+use anyhow::{anyhow, Result};
+use futures_util::future::BoxFuture;
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+use super::{GenOptions, LoadedModel};
+use crate::error::ShimmyError;
+
+/// Why a generation stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// End of generation token or the engine's own stop condition
+    Stop,
+    /// `max_tokens` or the context window was reached
+    Length,
+    /// The engine withheld the rest of the output
+    ContentFilter,
+    Cancelled,
+}
+
+impl FinishReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::ContentFilter => "content_filter",
+            FinishReason::Cancelled => "cancelled",
+        }
+    }
+
+    /// From an engine's `finish_reason` string; unknown reasons count as `stop`
+    pub fn parse(reason: &str) -> Self {
+        match reason {
+            "length" => FinishReason::Length,
+            "content_filter" => FinishReason::ContentFilter,
+            "cancelled" => FinishReason::Cancelled,
+            _ => FinishReason::Stop,
+        }
+    }
+}
+
+/// Token counts and timings for a finished generation. Backends that cannot
+/// tokenize the prompt report `prompt_tokens` as 0; `completion_tokens` falls
+/// back to the number of streamed pieces.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GenerationStats {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Until the prompt was evaluated (the first token, for callback backends)
+    pub prompt_ms: u64,
+    pub total_ms: u64,
+}
+
+impl GenerationStats {
+    /// Timings measured from `started`, with the prompt done at `prompt_done`
+    pub fn timed(
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        started: Instant,
+        prompt_done: Option<Instant>,
+    ) -> Self {
+        let ms = |end: Instant| end.duration_since(started).as_millis() as u64;
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            prompt_ms: prompt_done.map(ms).unwrap_or(0),
+            total_ms: ms(Instant::now()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GenerationEvent {
+    /// `processed` of the prompt's `total` tokens have been evaluated
+    PromptProgress { processed: usize, total: usize },
+    /// Next piece of output. `text` may be empty while a multi-byte character
+    /// is incomplete; `id` and `logprob` are `None` when the backend does not know them.
+    Token {
+        id: Option<u32>,
+        text: String,
+        logprob: Option<f32>,
+    },
+    /// Always the last event of a successful generation
+    Done {
+        finish_reason: FinishReason,
+        stats: GenerationStats,
+    },
+    /// The generation failed; no events follow
+    Error { message: String },
+    /// `model` had too many requests queued, so the generation never started
+    Overloaded { model: String },
+}
+
+impl GenerationEvent {
+    /// A token whose id and probability are unknown
+    pub fn text(text: impl Into<String>) -> Self {
+        GenerationEvent::Token {
+            id: None,
+            text: text.into(),
+            logprob: None,
+        }
+    }
+
+    /// The last event of a generation that failed with `error`
+    pub fn failed(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<ShimmyError>() {
+            Some(ShimmyError::Overloaded { model }) => GenerationEvent::Overloaded {
+                model: model.clone(),
+            },
+            _ => GenerationEvent::Error {
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+pub type GenerationStream = Pin<Box<dyn Stream<Item = GenerationEvent> + Send>>;
+
+/// Events of several generations from one prompt, tagged with the index of
+/// the choice they belong to. Each choice ends with its own `Done`; an
+/// `Error` ends all of them.
+pub type ChoiceStream = Pin<Box<dyn Stream<Item = (usize, GenerationEvent)> + Send>>;
+
+/// Where a generation sends its events
+pub type EventSender = mpsc::UnboundedSender<GenerationEvent>;
+
+/// Where a multi-choice generation sends its tagged events
+pub type ChoiceSender = mpsc::UnboundedSender<(usize, GenerationEvent)>;
+
+/// Stream the events `run` sends while the stream polls it. An error returned
+/// by `run` becomes a final `Error` event. Dropping the stream drops `run`
+/// and closes the channel it sends on, so async backends stop with the future
+/// and backends on an inference thread stop once a send fails.
+pub fn from_future<F, Fut>(run: F) -> GenerationStream
+where
+    F: FnOnce(EventSender) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let (tx, events) = mpsc::unbounded_channel();
+    Box::pin(FutureStream {
+        run: Some(Box::pin(run(tx))),
+        events,
+        error: None,
+        on_error: |error| GenerationEvent::failed(&error),
+    })
+}
+
+/// `from_future` for generations that produce several choices. An error
+/// returned by `run` is reported on choice 0.
+pub fn choices_from_future<F, Fut>(run: F) -> ChoiceStream
+where
+    F: FnOnce(ChoiceSender) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let (tx, events) = mpsc::unbounded_channel();
+    Box::pin(FutureStream {
+        run: Some(Box::pin(run(tx))),
+        events,
+        error: None,
+        on_error: |error| (0, GenerationEvent::failed(&error)),
+    })
+}
+
+/// Options for choice `index` of a multi-choice generation: each choice
+/// samples with its own seed, offset from the requested one
+pub fn choice_options(opts: &GenOptions, base_seed: u32, index: usize) -> GenOptions {
+    GenOptions {
+        seed: Some(base_seed.wrapping_add(index as u32)),
+        ..opts.clone()
+    }
+}
+
+/// The seed choice 0 uses: the requested one, or a random one so that
+/// unseeded choices still differ from each other
+pub fn base_seed(opts: &GenOptions) -> u32 {
+    opts.seed.unwrap_or_else(rand::random)
+}
+
+/// `generate_choices` for backends that cannot share a prompt between
+/// sequences: the choices are generated one after another
+pub fn sequential_choices<M>(
+    model: Arc<M>,
+    prompt: String,
+    opts: GenOptions,
+    n: usize,
+) -> ChoiceStream
+where
+    M: LoadedModel + ?Sized,
+{
+    let seed = base_seed(&opts);
+    choices_from_future(move |tx| async move {
+        for index in 0..n {
+            let mut events = Arc::clone(&model)
+                .generate_stream(prompt.clone(), choice_options(&opts, seed, index));
+            while let Some(event) = events.next().await {
+                let failed = matches!(
+                    event,
+                    GenerationEvent::Error { .. } | GenerationEvent::Overloaded { .. }
+                );
+                if tx.send((index, event)).is_err() || failed {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// `generate_stream` for backends that only implement the callback-based
+/// `generate`: each callback becomes a `Token`, and `Done` is inferred from
+/// the number of pieces against `max_tokens`
+pub fn from_callbacks<M>(model: Arc<M>, prompt: String, opts: GenOptions) -> GenerationStream
+where
+    M: LoadedModel + ?Sized,
+{
+    from_future(move |tx| async move {
+        let started = Instant::now();
+        let prompt_tokens = model.tokenize(&prompt, true).map_or(0, |t| t.len());
+        let first_token = Arc::new(OnceLock::new());
+        let pieces = Arc::new(AtomicUsize::new(0));
+        let max_tokens = opts.max_tokens;
+        let on_token = {
+            let (tx, first_token, pieces) = (tx.clone(), first_token.clone(), pieces.clone());
+            move |text: String| {
+                first_token.get_or_init(Instant::now);
+                pieces.fetch_add(1, Ordering::SeqCst);
+                let _ = tx.send(GenerationEvent::text(text));
+            }
+        };
+        model
+            .generate(&prompt, opts, Some(Box::new(on_token)))
+            .await?;
+
+        let completion_tokens = pieces.load(Ordering::SeqCst);
+        let finish_reason = if completion_tokens >= max_tokens {
+            FinishReason::Length
+        } else {
+            FinishReason::Stop
+        };
+        let stats = GenerationStats::timed(
+            prompt_tokens,
+            completion_tokens,
+            started,
+            first_token.get().copied(),
+        );
+        let _ = tx.send(GenerationEvent::Done {
+            finish_reason,
+            stats,
+        });
+        Ok(())
+    })
+}
+
+/// Drive an event-producing generation for the callback-based `generate`:
+/// token text goes to `on_token` and the full text is returned
+pub fn collect_text<F>(
+    run: F,
+    mut on_token: Option<Box<dyn FnMut(String) + Send>>,
+) -> Result<String>
+where
+    F: FnOnce(&mut dyn FnMut(GenerationEvent) -> bool) -> Result<()>,
+{
+    let mut out = String::new();
+    run(&mut |event| {
+        if let GenerationEvent::Token { text, .. } = event {
+            if text.is_empty() {
+                return true;
+            }
+            out.push_str(&text);
+            if let Some(cb) = on_token.as_mut() {
+                cb(text);
+            }
+        }
+        true
+    })?;
+    Ok(out)
+}
+
+/// A whole generation, gathered from its stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub stats: GenerationStats,
+}
+
+/// Run `events` to the end, passing each token's text to `on_text`
+pub async fn complete(
+    mut events: GenerationStream,
+    mut on_text: impl FnMut(&str),
+) -> Result<Completion> {
+    let mut text = String::new();
+    while let Some(event) = events.next().await {
+        match event {
+            GenerationEvent::PromptProgress { .. } => {}
+            GenerationEvent::Token { text: piece, .. } => {
+                on_text(&piece);
+                text.push_str(&piece);
+            }
+            GenerationEvent::Done {
+                finish_reason,
+                stats,
+            } => {
+                return Ok(Completion {
+                    text,
+                    finish_reason,
+                    stats,
+                })
+            }
+            GenerationEvent::Error { message } => return Err(anyhow!("{}", message)),
+            GenerationEvent::Overloaded { model } => {
+                return Err(ShimmyError::Overloaded { model }.into())
+            }
+        }
+    }
+    Err(anyhow!("generation ended without finishing"))
+}
+
+/// Run a multi-choice generation to the end, returning the `n` choices in order
+pub async fn complete_choices(mut events: ChoiceStream, n: usize) -> Result<Vec<Completion>> {
+    let mut texts = vec![String::new(); n];
+    let mut done = vec![None; n];
+    while let Some((index, event)) = events.next().await {
+        match event {
+            GenerationEvent::PromptProgress { .. } => {}
+            GenerationEvent::Token { text, .. } => texts[index].push_str(&text),
+            GenerationEvent::Done {
+                finish_reason,
+                stats,
+            } => done[index] = Some((finish_reason, stats)),
+            GenerationEvent::Error { message } => return Err(anyhow!("{}", message)),
+            GenerationEvent::Overloaded { model } => {
+                return Err(ShimmyError::Overloaded { model }.into())
+            }
+        }
+    }
+    texts
+        .into_iter()
+        .zip(done)
+        .map(|(text, done)| {
+            let (finish_reason, stats) =
+                done.ok_or_else(|| anyhow!("generation ended without finishing"))?;
+            Ok(Completion {
+                text,
+                finish_reason,
+                stats,
+            })
+        })
+        .collect()
+}
+
+struct FutureStream<T> {
+    run: Option<BoxFuture<'static, Result<()>>>,
+    events: mpsc::UnboundedReceiver<T>,
+    error: Option<T>,
+    on_error: fn(anyhow::Error) -> T,
+}
+
+impl<T: Unpin> Stream for FutureStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(run) = this.run.as_mut() {
+            if let Poll::Ready(Some(event)) = this.events.poll_recv(cx) {
+                return Poll::Ready(Some(event));
+            }
+            match run.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => {
+                    this.run = None;
+                    this.error = result.err().map(this.on_error);
+                }
+            }
+        }
+        // Everything the generation sent comes before its error
+        if let Ok(event) = this.events.try_recv() {
+            return Poll::Ready(Some(event));
+        }
+        Poll::Ready(this.error.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+
+    struct Echo;
+
+    #[async_trait]
+    impl LoadedModel for Echo {
+        async fn generate(
+            &self,
+            prompt: &str,
+            opts: GenOptions,
+            mut on_token: Option<Box<dyn FnMut(String) + Send>>,
+        ) -> Result<String> {
+            if prompt == "fail" {
+                if let Some(cb) = on_token.as_mut() {
+                    cb("partial".to_string());
+                }
+                return Err(anyhow!("backend exploded"));
+            }
+            if prompt == "seed" {
+                let seed = format!("{:?}", opts.seed);
+                if let Some(cb) = on_token.as_mut() {
+                    cb(seed.clone());
+                }
+                return Ok(seed);
+            }
+            let words: Vec<&str> = prompt.split_whitespace().take(opts.max_tokens).collect();
+            for word in &words {
+                if let Some(cb) = on_token.as_mut() {
+                    cb(word.to_string());
+                }
+            }
+            Ok(words.concat())
+        }
+    }
+
+    fn opts(max_tokens: usize) -> GenOptions {
+        GenOptions {
+            max_tokens,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_callback_backends_stream_tokens_then_done() {
+        let events: Vec<_> = Arc::new(Echo)
+            .generate_stream("a b".to_string(), opts(5))
+            .collect()
+            .await;
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], GenerationEvent::text("a"));
+        assert_eq!(events[1], GenerationEvent::text("b"));
+        let GenerationEvent::Done {
+            finish_reason,
+            stats,
+        } = &events[2]
+        else {
+            panic!("expected Done, got {:?}", events[2]);
+        };
+        assert_eq!(*finish_reason, FinishReason::Stop);
+        assert_eq!(stats.completion_tokens, 2);
+
+        let done = complete(
+            Arc::new(Echo).generate_stream("a b c".into(), opts(2)),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(done.text, "ab");
+        assert_eq!(done.finish_reason, FinishReason::Length);
+    }
+
+    #[tokio::test]
+    async fn test_errors_end_the_stream_after_earlier_tokens() {
+        let events: Vec<_> = Arc::new(Echo)
+            .generate_stream("fail".to_string(), opts(5))
+            .collect()
+            .await;
+        assert_eq!(
+            events,
+            [
+                GenerationEvent::text("partial"),
+                GenerationEvent::Error {
+                    message: "backend exploded".to_string()
+                }
+            ]
+        );
+        let err = complete(
+            Arc::new(Echo).generate_stream("fail".into(), opts(5)),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "backend exploded");
+    }
+
+    #[tokio::test]
+    async fn test_choices_are_indexed_and_seeded_from_the_request() {
+        let seeded = GenOptions {
+            seed: Some(10),
+            ..opts(5)
+        };
+        let choices =
+            complete_choices(Arc::new(Echo).generate_choices("seed".into(), seeded, 3), 3)
+                .await
+                .unwrap();
+        let texts: Vec<_> = choices.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["Some(10)", "Some(11)", "Some(12)"]);
+        assert!(choices
+            .iter()
+            .all(|c| c.finish_reason == FinishReason::Stop));
+
+        let events: Vec<_> = Arc::new(Echo)
+            .generate_choices("a b".into(), opts(1), 2)
+            .collect()
+            .await;
+        let indices: Vec<_> = events.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indices, [0, 0, 1, 1]);
+        assert!(matches!(
+            events[3].1,
+            GenerationEvent::Done {
+                finish_reason: FinishReason::Length,
+                ..
+            }
+        ));
+
+        let err = complete_choices(
+            Arc::new(Echo).generate_choices("fail".into(), opts(5), 2),
+            2,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "backend exploded");
+    }
+
+    #[test]
+    fn test_event_generations_collect_to_text() {
+        let run = |emit: &mut dyn FnMut(GenerationEvent) -> bool| {
+            emit(GenerationEvent::PromptProgress {
+                processed: 3,
+                total: 3,
+            });
+            for (id, text) in [(7, "x"), (8, ""), (9, "y")] {
+                emit(GenerationEvent::Token {
+                    id: Some(id),
+                    text: text.to_string(),
+                    logprob: Some(-0.5),
+                });
+            }
+            emit(GenerationEvent::Done {
+                finish_reason: FinishReason::Stop,
+                stats: GenerationStats::default(),
+            });
+            Ok(())
+        };
+        let streamed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = Arc::clone(&streamed);
+        let text =
+            collect_text(run, Some(Box::new(move |t| sink.lock().unwrap().push(t)))).unwrap();
+        assert_eq!(text, "xy");
+        // Empty pieces are not passed on
+        assert_eq!(*streamed.lock().unwrap(), ["x", "y"]);
+    }
+}
@@ -0,0 +1,1513 @@
+// Pure Rust tokenizers shared by the native (non-llama.cpp) backends
+// Supports SentencePiece-style score merging and GPT-2 style byte-level BPE
+// built from GGUF metadata or SentencePiece `tokenizer.model` files, and
+// HuggingFace `tokenizer.json` pipelines via the `tokenizers` crate.
+
+use anyhow::{anyhow, bail, Result};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use super::gguf::{GgufHeader, GgufWriter, MetadataValue};
+
+const SPM_SPACE: char = '\u{2581}'; // '▁'
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizerKind {
+    /// SentencePiece BPE: merges chosen by piece score, byte fallback tokens
+    SentencePiece,
+    /// Byte-level BPE (GPT-2 / Llama 3 / Qwen): merges chosen by rank
+    BytePairEncoding,
+}
+
+/// Token attributes following the GGUF `tokenizer.ggml.token_type` ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenType {
+    Normal,
+    Unknown,
+    Control,
+    UserDefined,
+    Unused,
+    Byte,
+}
+
+impl TokenType {
+    fn from_id(id: i64) -> Self {
+        match id {
+            2 => TokenType::Unknown,
+            3 => TokenType::Control,
+            4 => TokenType::UserDefined,
+            5 => TokenType::Unused,
+            6 => TokenType::Byte,
+            _ => TokenType::Normal,
+        }
+    }
+
+    pub fn as_id(self) -> i32 {
+        match self {
+            TokenType::Normal => 1,
+            TokenType::Unknown => 2,
+            TokenType::Control => 3,
+            TokenType::UserDefined => 4,
+            TokenType::Unused => 5,
+            TokenType::Byte => 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Tokenizer {
+    kind: TokenizerKind,
+    pieces: Vec<String>,
+    token_types: Vec<TokenType>,
+    scores: Vec<f32>,
+    piece_to_id: HashMap<String, u32>,
+    merge_ranks: HashMap<(String, String), usize>,
+    /// Control and user-defined tokens matched verbatim before tokenization,
+    /// longest first
+    specials: Vec<(String, u32)>,
+    byte_tokens: Vec<Option<u32>>,
+    byte_encoder: Vec<char>,
+    byte_decoder: HashMap<char, u8>,
+    bos_id: Option<u32>,
+    eos_id: Option<u32>,
+    unk_id: Option<u32>,
+    eog_ids: Vec<u32>,
+    add_bos: bool,
+    add_space_prefix: bool,
+    digit_groups: bool,
+}
+
+impl Tokenizer {
+    /// Build a tokenizer from the `tokenizer.ggml.*` metadata of a GGUF file
+    pub fn from_gguf(header: &GgufHeader) -> Result<Self> {
+        let model = header.get_str("tokenizer.ggml.model").unwrap_or("llama");
+        let kind = match model {
+            "llama" | "replit" => TokenizerKind::SentencePiece,
+            "gpt2" => TokenizerKind::BytePairEncoding,
+            other => bail!("unsupported GGUF tokenizer model '{}'", other),
+        };
+
+        let pieces: Vec<String> = header
+            .get("tokenizer.ggml.tokens")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| anyhow!("GGUF file has no tokenizer.ggml.tokens"))?
+            .iter()
+            .map(|v| v.as_str().unwrap_or_default().to_string())
+            .collect();
+        let scores: Vec<f32> = header
+            .get("tokenizer.ggml.scores")
+            .and_then(|v| v.as_array())
+            .map(|a| a.iter().map(|v| v.as_f32().unwrap_or(0.0)).collect())
+            .unwrap_or_default();
+        let token_types: Vec<TokenType> = header
+            .get("tokenizer.ggml.token_type")
+            .and_then(|v| v.as_array())
+            .map(|a| {
+                a.iter()
+                    .map(|v| TokenType::from_id(v.as_i64().unwrap_or(1)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let merges: Vec<(String, String)> = header
+            .get("tokenizer.ggml.merges")
+            .and_then(|v| v.as_array())
+            .map(|a| {
+                a.iter()
+                    .filter_map(|v| v.as_str())
+                    .filter_map(|m| m.split_once(' '))
+                    .map(|(l, r)| (l.to_string(), r.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let id = |key: &str| header.get_u64(key).map(|v| v as u32);
+        let pre = header.get_str("tokenizer.ggml.pre").unwrap_or("default");
+        let add_bos = header
+            .get("tokenizer.ggml.add_bos_token")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(kind == TokenizerKind::SentencePiece);
+        let add_space_prefix = header
+            .get("tokenizer.ggml.add_space_prefix")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(kind == TokenizerKind::SentencePiece);
+
+        let mut tokenizer = Self::new(kind, pieces, scores, token_types, merges)?;
+        tokenizer.bos_id = id("tokenizer.ggml.bos_token_id");
+        tokenizer.eos_id = id("tokenizer.ggml.eos_token_id");
+        tokenizer.unk_id = id("tokenizer.ggml.unknown_token_id").or(tokenizer.unk_id);
+        tokenizer.add_bos = add_bos;
+        tokenizer.add_space_prefix = add_space_prefix;
+        tokenizer.digit_groups = pre != "default" && pre != "gpt-2";
+        let eot = id("tokenizer.ggml.eot_token_id");
+        tokenizer.eog_ids = tokenizer.collect_eog_ids(eot);
+        Ok(tokenizer)
+    }
+
+    /// Build a tokenizer from raw vocabulary parts
+    pub fn new(
+        kind: TokenizerKind,
+        pieces: Vec<String>,
+        scores: Vec<f32>,
+        mut token_types: Vec<TokenType>,
+        merges: Vec<(String, String)>,
+    ) -> Result<Self> {
+        if pieces.is_empty() {
+            bail!("tokenizer vocabulary is empty");
+        }
+        token_types.resize(pieces.len(), TokenType::Normal);
+
+        let piece_to_id: HashMap<String, u32> = pieces
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.clone(), i as u32))
+            .collect();
+        let merge_ranks = merges
+            .into_iter()
+            .enumerate()
+            .map(|(rank, pair)| (pair, rank))
+            .collect();
+
+        let mut specials: Vec<(String, u32)> = pieces
+            .iter()
+            .zip(&token_types)
+            .enumerate()
+            .filter(|(_, (p, t))| {
+                !p.is_empty() && matches!(t, TokenType::Control | TokenType::UserDefined)
+            })
+            .map(|(i, (p, _))| (p.clone(), i as u32))
+            .collect();
+        specials.sort_by_key(|s| std::cmp::Reverse(s.0.len()));
+
+        let mut byte_tokens = vec![None; 256];
+        for (b, slot) in byte_tokens.iter_mut().enumerate() {
+            *slot = piece_to_id.get(&format!("<0x{:02X}>", b)).copied();
+        }
+
+        let byte_encoder = bytes_to_unicode();
+        let byte_decoder = byte_encoder
+            .iter()
+            .enumerate()
+            .map(|(b, &c)| (c, b as u8))
+            .collect();
+
+        let unk_id = token_types
+            .iter()
+            .position(|t| *t == TokenType::Unknown)
+            .map(|i| i as u32);
+
+        Ok(Self {
+            kind,
+            pieces,
+            token_types,
+            scores,
+            piece_to_id,
+            merge_ranks,
+            specials,
+            byte_tokens,
+            byte_encoder,
+            byte_decoder,
+            bos_id: None,
+            eos_id: None,
+            unk_id,
+            eog_ids: Vec::new(),
+            add_bos: kind == TokenizerKind::SentencePiece,
+            add_space_prefix: kind == TokenizerKind::SentencePiece,
+            digit_groups: false,
+        })
+    }
+
+    fn collect_eog_ids(&self, eot: Option<u32>) -> Vec<u32> {
+        const END_MARKERS: [&str; 6] = [
+            "<|eot_id|>",
+            "<|im_end|>",
+            "<|end|>",
+            "<end_of_turn>",
+            "<|endoftext|>",
+            "</s>",
+        ];
+        let mut ids: Vec<u32> = self.eos_id.into_iter().chain(eot).collect();
+        for marker in END_MARKERS {
+            if let Some(&id) = self.piece_to_id.get(marker) {
+                if self.token_types[id as usize] == TokenType::Control {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn token_to_id(&self, piece: &str) -> Option<u32> {
+        self.piece_to_id.get(piece).copied()
+    }
+
+    pub fn id_to_piece(&self, id: u32) -> Option<&str> {
+        self.pieces.get(id as usize).map(|s| s.as_str())
+    }
+
+    /// End-of-generation tokens (eos, eot and chat turn terminators)
+    pub fn is_eog(&self, id: u32) -> bool {
+        self.eog_ids.contains(&id)
+    }
+
+    /// Encode text. With `add_special`, a BOS token is prepended when the
+    /// vocabulary asks for one and the text does not already start with it.
+    pub fn encode(&self, text: &str, add_special: bool) -> Vec<u32> {
+        let mut ids = Vec::new();
+        if add_special && self.add_bos {
+            if let Some(bos) = self.bos_id {
+                let bos_text = self.id_to_piece(bos).unwrap_or_default();
+                if bos_text.is_empty() || !text.starts_with(bos_text) {
+                    ids.push(bos);
+                }
+            }
+        }
+
+        let mut prev_special = true;
+        for fragment in self.split_specials(text) {
+            match fragment {
+                Fragment::Special(id) => {
+                    ids.push(id);
+                    prev_special = true;
+                }
+                Fragment::Text(t) => {
+                    match self.kind {
+                        TokenizerKind::SentencePiece => {
+                            self.encode_spm(t, prev_special && self.add_space_prefix, &mut ids)
+                        }
+                        TokenizerKind::BytePairEncoding => self.encode_bpe(t, &mut ids),
+                    }
+                    prev_special = false;
+                }
+            }
+        }
+        ids
+    }
+
+    fn split_specials<'a>(&self, text: &'a str) -> Vec<Fragment<'a>> {
+        let mut out = Vec::new();
+        let mut start = 0;
+        let mut pos = 0;
+        while pos < text.len() {
+            let rest = &text[pos..];
+            if let Some((piece, id)) = self.specials.iter().find(|(p, _)| rest.starts_with(p)) {
+                if start < pos {
+                    out.push(Fragment::Text(&text[start..pos]));
+                }
+                out.push(Fragment::Special(*id));
+                pos += piece.len();
+                start = pos;
+            } else {
+                pos += rest.chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+            }
+        }
+        if start < text.len() {
+            out.push(Fragment::Text(&text[start..]));
+        }
+        out
+    }
+
+    fn encode_spm(&self, text: &str, space_prefix: bool, ids: &mut Vec<u32>) {
+        let mut normalized = String::with_capacity(text.len() + 3);
+        if space_prefix {
+            normalized.push(SPM_SPACE);
+        }
+        normalized.extend(text.chars().map(|c| if c == ' ' { SPM_SPACE } else { c }));
+        if normalized.is_empty() {
+            return;
+        }
+
+        // Linked list of symbols, merged greedily by highest piece score
+        let mut syms: Vec<(usize, usize)> = normalized
+            .char_indices()
+            .map(|(i, c)| (i, c.len_utf8()))
+            .collect();
+        let n = syms.len();
+        let mut prev: Vec<isize> = (0..n as isize).map(|i| i - 1).collect();
+        let mut next: Vec<usize> = (1..=n).collect();
+        let mut heap = BinaryHeap::new();
+
+        let push_pair =
+            |heap: &mut BinaryHeap<Bigram>, syms: &[(usize, usize)], l: usize, r: usize| {
+                let start = syms[l].0;
+                let len = syms[l].1 + syms[r].1;
+                if let Some(&id) = self.piece_to_id.get(&normalized[start..start + len]) {
+                    heap.push(Bigram {
+                        score: self.scores.get(id as usize).copied().unwrap_or(0.0),
+                        left: l,
+                        right: r,
+                        len,
+                    });
+                }
+            };
+
+        for i in 1..n {
+            push_pair(&mut heap, &syms, i - 1, i);
+        }
+        while let Some(bigram) = heap.pop() {
+            let (l, r) = (bigram.left, bigram.right);
+            // Skip stale entries whose symbols were merged since being queued
+            if syms[l].1 == 0 || syms[r].1 == 0 || syms[l].1 + syms[r].1 != bigram.len {
+                continue;
+            }
+            syms[l].1 += syms[r].1;
+            syms[r].1 = 0;
+            next[l] = next[r];
+            if next[r] < n {
+                prev[next[r]] = l as isize;
+            }
+            if prev[l] >= 0 {
+                push_pair(&mut heap, &syms, prev[l] as usize, l);
+            }
+            if next[l] < n {
+                push_pair(&mut heap, &syms, l, next[l]);
+            }
+        }
+
+        let mut i = 0;
+        while i < n {
+            let (start, len) = syms[i];
+            let piece = &normalized[start..start + len];
+            match self.piece_to_id.get(piece) {
+                Some(&id) => ids.push(id),
+                None => {
+                    for b in piece.bytes() {
+                        match self.byte_tokens[b as usize].or(self.unk_id) {
+                            Some(id) => ids.push(id),
+                            None => continue,
+                        }
+                    }
+                }
+            }
+            i = next[i];
+        }
+    }
+
+    fn encode_bpe(&self, text: &str, ids: &mut Vec<u32>) {
+        for word in pre_tokenize(text, self.digit_groups) {
+            let mapped: String = word
+                .bytes()
+                .map(|b| self.byte_encoder[b as usize])
+                .collect();
+            if let Some(&id) = self.piece_to_id.get(&mapped) {
+                ids.push(id);
+                continue;
+            }
+            let mut parts: Vec<String> = mapped.chars().map(|c| c.to_string()).collect();
+            while parts.len() > 1 {
+                let best = parts
+                    .windows(2)
+                    .enumerate()
+                    .filter_map(|(i, w)| {
+                        self.merge_ranks
+                            .get(&(w[0].clone(), w[1].clone()))
+                            .map(|&rank| (rank, i))
+                    })
+                    .min();
+                let Some((_, i)) = best else { break };
+                let right = parts.remove(i + 1);
+                parts[i].push_str(&right);
+            }
+            for part in parts {
+                if let Some(id) = self.piece_to_id.get(&part).copied().or(self.unk_id) {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+
+    /// Raw bytes a token contributes to the output text
+    pub fn token_bytes(&self, id: u32) -> Vec<u8> {
+        let Some(piece) = self.pieces.get(id as usize) else {
+            return Vec::new();
+        };
+        match self.token_types[id as usize] {
+            TokenType::Control | TokenType::Unused | TokenType::Unknown => Vec::new(),
+            TokenType::UserDefined => piece.as_bytes().to_vec(),
+            TokenType::Byte => {
+                u8::from_str_radix(piece.trim_start_matches("<0x").trim_end_matches('>'), 16)
+                    .map(|b| vec![b])
+                    .unwrap_or_default()
+            }
+            TokenType::Normal => match self.kind {
+                TokenizerKind::SentencePiece => piece.replace(SPM_SPACE, " ").into_bytes(),
+                TokenizerKind::BytePairEncoding => piece
+                    .chars()
+                    .map(|c| match self.byte_decoder.get(&c) {
+                        Some(&b) => vec![b],
+                        None => c.to_string().into_bytes(),
+                    })
+                    .collect::<Vec<_>>()
+                    .concat(),
+            },
+        }
+    }
+
+    /// Decode a full sequence. Control tokens are dropped.
+    pub fn decode(&self, ids: &[u32]) -> String {
+        let mut decoder = self.stream_decoder();
+        let mut out = String::new();
+        for &id in ids {
+            out.push_str(&decoder.push(id));
+        }
+        out.push_str(&decoder.flush());
+        out
+    }
+
+    /// Incremental decoder that only emits complete UTF-8 sequences
+    pub fn stream_decoder(&self) -> StreamDecoder<'_> {
+        StreamDecoder {
+            tokenizer: self,
+            pending: Vec::new(),
+            at_start: true,
+        }
+    }
+}
+
+/// Turns token ids into text one at a time, holding back partial UTF-8
+/// sequences (byte-fallback tokens) until they are complete.
+pub struct StreamDecoder<'a> {
+    tokenizer: &'a Tokenizer,
+    pending: Vec<u8>,
+    at_start: bool,
+}
+
+impl StreamDecoder<'_> {
+    pub fn push(&mut self, id: u32) -> String {
+        let mut bytes = self.tokenizer.token_bytes(id);
+        if self.at_start && !bytes.is_empty() {
+            self.at_start = false;
+            // SentencePiece encodes a leading space that is not part of the text
+            if self.tokenizer.kind == TokenizerKind::SentencePiece
+                && self.tokenizer.add_space_prefix
+                && bytes.first() == Some(&b' ')
+            {
+                bytes.remove(0);
+            }
+        }
+        self.pending.extend_from_slice(&bytes);
+        match std::str::from_utf8(&self.pending) {
+            Ok(s) => {
+                let out = s.to_string();
+                self.pending.clear();
+                out
+            }
+            Err(e) => {
+                let valid = e.valid_up_to();
+                // Invalid (not merely incomplete) bytes are replaced rather than held forever
+                let cut = if e.error_len().is_some() {
+                    self.pending.len()
+                } else {
+                    valid
+                };
+                let out = String::from_utf8_lossy(&self.pending[..cut]).into_owned();
+                self.pending.drain(..cut);
+                out
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> String {
+        let out = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        out
+    }
+}
+
+enum Fragment<'a> {
+    Text(&'a str),
+    Special(u32),
+}
+
+#[derive(Debug)]
+struct Bigram {
+    score: f32,
+    left: usize,
+    right: usize,
+    len: usize,
+}
+
+impl PartialEq for Bigram {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Bigram {}
+
+impl PartialOrd for Bigram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Bigram {
+    // Highest score first, leftmost on ties
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.left.cmp(&self.left))
+    }
+}
+
+/// GPT-2's reversible byte -> printable unicode mapping
+pub(crate) fn bytes_to_unicode() -> Vec<char> {
+    let direct: Vec<u32> = (b'!' as u32..=b'~' as u32)
+        .chain(0xA1..=0xAC)
+        .chain(0xAE..=0xFF)
+        .collect();
+    let mut mapping = vec!['\0'; 256];
+    let mut extra = 0;
+    for b in 0..256u32 {
+        let c = if direct.contains(&b) {
+            b
+        } else {
+            extra += 1;
+            256 + extra - 1
+        };
+        mapping[b as usize] = char::from_u32(c).unwrap_or('?');
+    }
+    mapping
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum CharClass {
+    Letter,
+    Number,
+    Space,
+    Other,
+}
+
+fn classify(c: char) -> CharClass {
+    if c.is_alphabetic() {
+        CharClass::Letter
+    } else if c.is_numeric() {
+        CharClass::Number
+    } else if c.is_whitespace() {
+        CharClass::Space
+    } else {
+        CharClass::Other
+    }
+}
+
+/// Split text into words the way the GPT-2 / Llama 3 regex pre-tokenizers do:
+/// contractions, an optional leading space followed by a run of letters,
+/// digits or punctuation, and whitespace runs.
+pub fn pre_tokenize(text: &str, digit_groups: bool) -> Vec<&str> {
+    const CONTRACTIONS: [&str; 7] = ["'s", "'t", "'re", "'ve", "'m", "'ll", "'d"];
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let end_of = |i: usize| chars.get(i).map(|&(b, _)| b).unwrap_or(text.len());
+    let mut words = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        let rest = &text[start..];
+
+        if c == '\'' {
+            let lower = rest.chars().take(3).collect::<String>().to_lowercase();
+            if let Some(m) = CONTRACTIONS.iter().find(|m| lower.starts_with(*m)) {
+                let n = m.chars().count();
+                words.push(&text[start..end_of(i + n)]);
+                i += n;
+                continue;
+            }
+        }
+
+        let mut j = i;
+        let mut class = classify(c);
+        if c == ' ' {
+            if let Some(&(_, nc)) = chars.get(i + 1) {
+                let nclass = classify(nc);
+                if nclass != CharClass::Space && !(digit_groups && nclass == CharClass::Number) {
+                    j += 1;
+                    class = nclass;
+                }
+            }
+        }
+
+        match class {
+            CharClass::Space => {
+                while j < chars.len() && classify(chars[j].1) == CharClass::Space {
+                    j += 1;
+                }
+                // Leave the last space to prefix the following word
+                if j < chars.len() && j - i > 1 && chars[j - 1].1 == ' ' {
+                    j -= 1;
+                }
+            }
+            CharClass::Number if digit_groups => {
+                let limit = j + 3;
+                while j < chars.len() && j < limit && classify(chars[j].1) == CharClass::Number {
+                    j += 1;
+                }
+            }
+            _ => {
+                while j < chars.len() && classify(chars[j].1) == class {
+                    if class == CharClass::Other && chars[j].1 == '\'' && j > i {
+                        break;
+                    }
+                    j += 1;
+                }
+            }
+        }
+        let j = j.max(i + 1);
+        words.push(&text[start..end_of(j)]);
+        i = j;
+    }
+    words
+}
+
+/// Tokenizer shared by every non-llama.cpp backend and the tokenize APIs: the
+/// native implementation above (GGUF vocabularies, SentencePiece BPE models),
+/// or a HuggingFace `tokenizer.json` pipeline with its normalizers,
+/// pre-tokenizers, added tokens and BPE/Unigram/WordPiece model.
+#[derive(Debug)]
+pub enum TextTokenizer {
+    Native(Box<Tokenizer>),
+    HuggingFace(Box<tokenizers::Tokenizer>),
+}
+
+type HfDecodeStream<'a> = tokenizers::DecodeStream<
+    'a,
+    tokenizers::ModelWrapper,
+    tokenizers::NormalizerWrapper,
+    tokenizers::PreTokenizerWrapper,
+    tokenizers::PostProcessorWrapper,
+    tokenizers::DecoderWrapper,
+>;
+
+impl TextTokenizer {
+    /// Load `tokenizer.json`, or SentencePiece `tokenizer.model` when there is
+    /// no JSON, from a model directory
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        for name in ["tokenizer.json", "tokenizer.model"] {
+            let path = dir.join(name);
+            if path.is_file() {
+                return Self::from_file(&path);
+            }
+        }
+        bail!("no tokenizer.json or tokenizer.model in {}", dir.display())
+    }
+
+    /// Load a `tokenizer.json` or a SentencePiece model file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        if path.extension().and_then(|e| e.to_str()) == Some("json") {
+            let tokenizer = tokenizers::Tokenizer::from_file(path)
+                .map_err(|e| anyhow!("failed to load {}: {}", path.display(), e))?;
+            return Ok(Self::HuggingFace(Box::new(tokenizer)));
+        }
+        let bytes = fs::read(path)?;
+        Self::from_sentencepiece(&bytes)
+            .map_err(|e| anyhow!("failed to load {}: {}", path.display(), e))
+    }
+
+    /// Build from a serialized SentencePiece `ModelProto`. BPE models use the
+    /// native score-merging tokenizer; Unigram models run Viterbi segmentation.
+    pub fn from_sentencepiece(bytes: &[u8]) -> Result<Self> {
+        let model = SentencePieceModel::parse(bytes)?;
+        match model.model_type {
+            SPM_MODEL_BPE => {
+                let types = model.pieces.iter().map(|p| p.2).collect();
+                let scores = model.pieces.iter().map(|p| p.1).collect();
+                let pieces = model.pieces.into_iter().map(|p| p.0).collect();
+                let mut tokenizer = Tokenizer::new(
+                    TokenizerKind::SentencePiece,
+                    pieces,
+                    scores,
+                    types,
+                    Vec::new(),
+                )?;
+                tokenizer.bos_id = model.bos_id;
+                tokenizer.eos_id = model.eos_id;
+                tokenizer.unk_id = model.unk_id.or(tokenizer.unk_id);
+                tokenizer.add_bos = model.bos_id.is_some();
+                tokenizer.add_space_prefix = model.add_dummy_prefix;
+                tokenizer.eog_ids = tokenizer.collect_eog_ids(None);
+                Ok(Self::Native(Box::new(tokenizer)))
+            }
+            SPM_MODEL_UNIGRAM => model.into_unigram().map(|t| Self::HuggingFace(Box::new(t))),
+            other => bail!("unsupported SentencePiece model type {}", other),
+        }
+    }
+
+    pub fn encode(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        match self {
+            Self::Native(t) => Ok(t.encode(text, add_special)),
+            Self::HuggingFace(t) => Ok(t
+                .encode(text, add_special)
+                .map_err(|e| anyhow!("tokenization failed: {}", e))?
+                .get_ids()
+                .to_vec()),
+        }
+    }
+
+    /// Decode a full sequence. The native tokenizer always drops control tokens.
+    pub fn decode(&self, ids: &[u32], skip_special: bool) -> Result<String> {
+        match self {
+            Self::Native(t) => Ok(t.decode(ids)),
+            Self::HuggingFace(t) => t
+                .decode(ids, skip_special)
+                .map_err(|e| anyhow!("detokenization failed: {}", e)),
+        }
+    }
+
+    // Candle looks up its stop tokens by name
+    #[cfg_attr(not(feature = "candle"), allow(dead_code))]
+    pub fn token_to_id(&self, token: &str) -> Option<u32> {
+        match self {
+            Self::Native(t) => t.token_to_id(token),
+            Self::HuggingFace(t) => t.token_to_id(token),
+        }
+    }
+
+    /// Incremental decoder for streaming; special tokens are skipped
+    // Only the candle backend streams through a `TextTokenizer`
+    #[cfg_attr(not(feature = "candle"), allow(dead_code))]
+    pub fn stream_decoder(&self) -> TextStreamDecoder<'_> {
+        match self {
+            Self::Native(t) => TextStreamDecoder::Native(t.stream_decoder()),
+            Self::HuggingFace(t) => TextStreamDecoder::HuggingFace(t.decode_stream(true)),
+        }
+    }
+}
+
+/// Streaming counterpart of [`TextTokenizer::decode`]: each pushed token yields
+/// the text it completes, which may be empty while a multi-byte character or
+/// a word boundary is still pending.
+#[cfg_attr(not(feature = "candle"), allow(dead_code))]
+pub enum TextStreamDecoder<'a> {
+    Native(StreamDecoder<'a>),
+    HuggingFace(HfDecodeStream<'a>),
+}
+
+#[cfg_attr(not(feature = "candle"), allow(dead_code))]
+impl TextStreamDecoder<'_> {
+    pub fn push(&mut self, id: u32) -> Result<String> {
+        match self {
+            Self::Native(d) => Ok(d.push(id)),
+            Self::HuggingFace(d) => Ok(d
+                .step(id)
+                .map_err(|e| anyhow!("detokenization failed: {}", e))?
+                .unwrap_or_default()),
+        }
+    }
+
+    /// Text still held back at the end of generation
+    pub fn flush(&mut self) -> String {
+        match self {
+            Self::Native(d) => d.flush(),
+            // DecodeStream only withholds incomplete UTF-8, which can never be emitted
+            Self::HuggingFace(_) => String::new(),
+        }
+    }
+}
+
+/// A vocabulary in the form GGUF stores it (`tokenizer.ggml.*`), exported
+/// from a HuggingFace model directory for conversion
+#[derive(Debug, Clone)]
+pub struct GgufVocab {
+    /// `llama` (SentencePiece scores) or `gpt2` (byte-level BPE merges)
+    pub model: &'static str,
+    pub pre: &'static str,
+    pub tokens: Vec<String>,
+    pub scores: Vec<f32>,
+    pub types: Vec<TokenType>,
+    pub merges: Vec<String>,
+    pub bos_id: Option<u32>,
+    pub eos_id: Option<u32>,
+    pub unk_id: Option<u32>,
+    pub add_bos: bool,
+}
+
+impl GgufVocab {
+    /// A BPE SentencePiece `tokenizer.model` when present (it carries the real
+    /// scores), otherwise a BPE `tokenizer.json`
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        let spm = dir.join("tokenizer.model");
+        let json = dir.join("tokenizer.json");
+        if spm.is_file() {
+            match Self::from_sentencepiece(&fs::read(&spm)?) {
+                Ok(vocab) => return Ok(vocab),
+                Err(e) if !json.is_file() => return Err(e),
+                Err(_) => {}
+            }
+        }
+        if json.is_file() {
+            let value: serde_json::Value = serde_json::from_str(&fs::read_to_string(&json)?)
+                .map_err(|e| anyhow!("invalid {}: {}", json.display(), e))?;
+            return Self::from_tokenizer_json(&value);
+        }
+        bail!("no tokenizer.model or tokenizer.json in {}", dir.display())
+    }
+
+    fn from_sentencepiece(bytes: &[u8]) -> Result<Self> {
+        let model = SentencePieceModel::parse(bytes)?;
+        if model.model_type != SPM_MODEL_BPE {
+            bail!("only BPE SentencePiece models can be stored in GGUF");
+        }
+        Ok(Self {
+            model: "llama",
+            pre: "default",
+            tokens: model.pieces.iter().map(|p| p.0.clone()).collect(),
+            scores: model.pieces.iter().map(|p| p.1).collect(),
+            types: model.pieces.iter().map(|p| p.2).collect(),
+            merges: Vec::new(),
+            bos_id: model.bos_id,
+            eos_id: model.eos_id,
+            unk_id: model.unk_id,
+            add_bos: true,
+        })
+    }
+
+    fn from_tokenizer_json(json: &serde_json::Value) -> Result<Self> {
+        let model = &json["model"];
+        let kind = model["type"].as_str().unwrap_or("unknown");
+        if kind != "BPE" {
+            bail!(
+                "tokenizer.json uses a {} model; only BPE vocabularies can be stored in GGUF",
+                kind
+            );
+        }
+        let vocab = model["vocab"]
+            .as_object()
+            .ok_or_else(|| anyhow!("tokenizer.json has no model.vocab"))?;
+        let added = json["added_tokens"].as_array().cloned().unwrap_or_default();
+
+        let mut by_id: Vec<Option<(String, TokenType)>> = Vec::new();
+        let mut set = |id: usize, piece: &str, ty: TokenType| {
+            if by_id.len() <= id {
+                by_id.resize(id + 1, None);
+            }
+            by_id[id] = Some((piece.to_string(), ty));
+        };
+        for (piece, id) in vocab {
+            if let Some(id) = id.as_u64() {
+                set(id as usize, piece, TokenType::Normal);
+            }
+        }
+        for token in &added {
+            if let (Some(id), Some(content)) = (token["id"].as_u64(), token["content"].as_str()) {
+                let ty = if token["special"].as_bool().unwrap_or(false) {
+                    TokenType::Control
+                } else {
+                    TokenType::UserDefined
+                };
+                set(id as usize, content, ty);
+            }
+        }
+
+        // SentencePiece-style BPE (Llama 2, Mistral) keeps byte tokens and is
+        // stored as a scored `llama` vocabulary; everything else is byte-level
+        let byte_fallback = model["byte_fallback"].as_bool().unwrap_or(false);
+        let (mut tokens, mut types) = (Vec::new(), Vec::new());
+        for (id, entry) in by_id.into_iter().enumerate() {
+            let (piece, mut ty) =
+                entry.unwrap_or_else(|| (format!("[PAD{}]", id), TokenType::Unused));
+            if byte_fallback && ty == TokenType::Normal && parse_byte_token(&piece).is_some() {
+                ty = TokenType::Byte;
+            }
+            tokens.push(piece);
+            types.push(ty);
+        }
+        let merges = model["merges"]
+            .as_array()
+            .map(|merges| {
+                merges
+                    .iter()
+                    .filter_map(|m| match m {
+                        serde_json::Value::String(s) => Some(s.clone()),
+                        serde_json::Value::Array(pair) => Some(format!(
+                            "{} {}",
+                            pair.first()?.as_str()?,
+                            pair.get(1)?.as_str()?
+                        )),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let id_of = |piece: Option<&str>| {
+            piece.and_then(|p| tokens.iter().position(|t| t == p).map(|i| i as u32))
+        };
+        let unk_id = id_of(model["unk_token"].as_str());
+        let post = json["post_processor"].to_string();
+
+        Ok(Self {
+            model: if byte_fallback { "llama" } else { "gpt2" },
+            pre: if json["pre_tokenizer"].to_string().contains("{1,3}") {
+                "llama-bpe"
+            } else {
+                "default"
+            },
+            scores: if byte_fallback {
+                (0..tokens.len()).map(|i| -(i as f32)).collect()
+            } else {
+                vec![0.0; tokens.len()]
+            },
+            merges: if byte_fallback { Vec::new() } else { merges },
+            bos_id: None,
+            eos_id: None,
+            unk_id,
+            add_bos: byte_fallback || post.contains("TemplateProcessing"),
+            tokens,
+            types,
+        })
+    }
+
+    /// Write the `tokenizer.ggml.*` keys
+    pub fn write_metadata(&self, w: &mut GgufWriter) {
+        let string = |s: &str| MetadataValue::String(s.to_string());
+        w.add_metadata("tokenizer.ggml.model", string(self.model))
+            .add_metadata("tokenizer.ggml.pre", string(self.pre))
+            .add_metadata(
+                "tokenizer.ggml.tokens",
+                MetadataValue::Array(self.tokens.iter().map(|t| string(t)).collect()),
+            )
+            .add_metadata(
+                "tokenizer.ggml.scores",
+                MetadataValue::Array(self.scores.iter().map(|&s| MetadataValue::F32(s)).collect()),
+            )
+            .add_metadata(
+                "tokenizer.ggml.token_type",
+                MetadataValue::Array(
+                    self.types
+                        .iter()
+                        .map(|t| MetadataValue::I32(t.as_id()))
+                        .collect(),
+                ),
+            )
+            .add_metadata(
+                "tokenizer.ggml.add_bos_token",
+                MetadataValue::Bool(self.add_bos),
+            );
+        if !self.merges.is_empty() {
+            w.add_metadata(
+                "tokenizer.ggml.merges",
+                MetadataValue::Array(self.merges.iter().map(|m| string(m)).collect()),
+            );
+        }
+        for (key, id) in [
+            ("tokenizer.ggml.bos_token_id", self.bos_id),
+            ("tokenizer.ggml.eos_token_id", self.eos_id),
+            ("tokenizer.ggml.unknown_token_id", self.unk_id),
+        ] {
+            if let Some(id) = id {
+                w.add_metadata(key, MetadataValue::U32(id));
+            }
+        }
+    }
+}
+
+/// `<0x0A>`-style byte fallback piece
+fn parse_byte_token(piece: &str) -> Option<u8> {
+    let hex = piece.strip_prefix("<0x")?.strip_suffix('>')?;
+    if hex.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
+const SPM_MODEL_UNIGRAM: u64 = 1;
+const SPM_MODEL_BPE: u64 = 2;
+
+/// The parts of a SentencePiece `ModelProto` needed to tokenize
+struct SentencePieceModel {
+    pieces: Vec<(String, f32, TokenType)>,
+    model_type: u64,
+    byte_fallback: bool,
+    unk_id: Option<u32>,
+    bos_id: Option<u32>,
+    eos_id: Option<u32>,
+    add_dummy_prefix: bool,
+}
+
+enum ProtoField<'a> {
+    Varint(u64),
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+impl SentencePieceModel {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut model = Self {
+            pieces: Vec::new(),
+            model_type: SPM_MODEL_UNIGRAM,
+            byte_fallback: false,
+            unk_id: Some(0),
+            bos_id: Some(1),
+            eos_id: Some(2),
+            add_dummy_prefix: true,
+        };
+        // int32 ids are sign-extended varints; -1 disables the token
+        let id = |v: u64| u32::try_from(v as i64).ok();
+        for (field, value) in proto_fields(bytes)? {
+            match (field, value) {
+                (1, ProtoField::Bytes(piece)) => {
+                    let (mut text, mut score, mut kind) = (String::new(), 0.0, 1);
+                    for (f, v) in proto_fields(piece)? {
+                        match (f, v) {
+                            (1, ProtoField::Bytes(s)) => text = String::from_utf8_lossy(s).into(),
+                            (2, ProtoField::Fixed32(bits)) => score = f32::from_bits(bits),
+                            (3, ProtoField::Varint(t)) => kind = t as i64,
+                            _ => {}
+                        }
+                    }
+                    model.pieces.push((text, score, TokenType::from_id(kind)));
+                }
+                (2, ProtoField::Bytes(trainer)) => {
+                    for (f, v) in proto_fields(trainer)? {
+                        match (f, v) {
+                            (3, ProtoField::Varint(t)) => model.model_type = t,
+                            (35, ProtoField::Varint(b)) => model.byte_fallback = b != 0,
+                            (40, ProtoField::Varint(i)) => model.unk_id = id(i),
+                            (41, ProtoField::Varint(i)) => model.bos_id = id(i),
+                            (42, ProtoField::Varint(i)) => model.eos_id = id(i),
+                            _ => {}
+                        }
+                    }
+                }
+                (3, ProtoField::Bytes(normalizer)) => {
+                    for (f, v) in proto_fields(normalizer)? {
+                        if let (3, ProtoField::Varint(b)) = (f, v) {
+                            model.add_dummy_prefix = b != 0;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if model.pieces.is_empty() {
+            bail!("SentencePiece model has no pieces");
+        }
+        let in_vocab = |id: Option<u32>| id.filter(|&i| (i as usize) < model.pieces.len());
+        model.unk_id = in_vocab(model.unk_id);
+        model.bos_id = in_vocab(model.bos_id);
+        model.eos_id = in_vocab(model.eos_id);
+        Ok(model)
+    }
+
+    fn into_unigram(self) -> Result<tokenizers::Tokenizer> {
+        use tokenizers::decoders::{byte_fallback::ByteFallback, fuse::Fuse, sequence::Sequence};
+        use tokenizers::models::unigram::Unigram;
+        use tokenizers::pre_tokenizers::metaspace::{Metaspace, PrependScheme};
+        use tokenizers::processors::template::TemplateProcessing;
+        use tokenizers::AddedToken;
+
+        let vocab = self
+            .pieces
+            .iter()
+            .map(|(p, s, _)| (p.clone(), *s as f64))
+            .collect();
+        let unigram = Unigram::from(vocab, self.unk_id.map(|i| i as usize), self.byte_fallback)
+            .map_err(|e| anyhow!("invalid Unigram vocabulary: {}", e))?;
+        let mut tokenizer = tokenizers::Tokenizer::new(unigram);
+
+        let prepend = if self.add_dummy_prefix {
+            PrependScheme::First
+        } else {
+            PrependScheme::Never
+        };
+        let metaspace = Metaspace::new(SPM_SPACE, prepend, true);
+        tokenizer.with_pre_tokenizer(Some(metaspace.clone()));
+        tokenizer.with_decoder(Some(Sequence::new(vec![
+            ByteFallback::new().into(),
+            metaspace.into(),
+            Fuse::new().into(),
+        ])));
+
+        let added: Vec<AddedToken> = self
+            .pieces
+            .iter()
+            .filter(|(_, _, t)| matches!(t, TokenType::Control | TokenType::UserDefined))
+            .map(|(p, _, t)| AddedToken::from(p.clone(), *t == TokenType::Control))
+            .collect();
+        tokenizer.add_special_tokens(&added);
+
+        if let Some(bos) = self.bos_id {
+            let piece = self.pieces[bos as usize].0.clone();
+            let template = TemplateProcessing::builder()
+                .try_single(format!("{} $A", piece))
+                .map_err(|e| anyhow!("invalid BOS template: {}", e))?
+                .special_tokens(vec![(piece, bos)])
+                .build()
+                .map_err(|e| anyhow!("invalid BOS template: {}", e))?;
+            tokenizer.with_post_processor(Some(template));
+        }
+        Ok(tokenizer)
+    }
+}
+
+/// Split a protobuf message into (field number, value) pairs
+fn proto_fields(mut buf: &[u8]) -> Result<Vec<(u64, ProtoField<'_>)>> {
+    fn varint(buf: &mut &[u8]) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = buf
+                .split_first()
+                .ok_or_else(|| anyhow!("truncated protobuf varint"))?;
+            *buf = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("protobuf varint too long")
+    }
+    fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+        if buf.len() < n {
+            bail!("truncated protobuf field");
+        }
+        let (head, rest) = buf.split_at(n);
+        *buf = rest;
+        Ok(head)
+    }
+
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        let value = match key & 7 {
+            0 => ProtoField::Varint(varint(&mut buf)?),
+            1 => {
+                take(&mut buf, 8)?;
+                continue;
+            }
+            2 => {
+                let len = varint(&mut buf)? as usize;
+                ProtoField::Bytes(take(&mut buf, len)?)
+            }
+            5 => {
+                let b = take(&mut buf, 4)?;
+                ProtoField::Fixed32(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            }
+            wire => bail!("unsupported protobuf wire type {}", wire),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spm_tokenizer() -> Tokenizer {
+        let mut pieces = vec!["<unk>".to_string(), "<s>".to_string(), "</s>".to_string()];
+        let mut types = vec![TokenType::Unknown, TokenType::Control, TokenType::Control];
+        for b in 0..256 {
+            pieces.push(format!("<0x{:02X}>", b));
+            types.push(TokenType::Byte);
+        }
+        let words = [
+            "▁", "h", "e", "l", "o", "he", "ll", "hell", "hello", "▁hello",
+        ];
+        for w in words {
+            pieces.push(w.to_string());
+            types.push(TokenType::Normal);
+        }
+        let scores = (0..pieces.len()).map(|i| i as f32).collect();
+        let mut tok =
+            Tokenizer::new(TokenizerKind::SentencePiece, pieces, scores, types, vec![]).unwrap();
+        tok.bos_id = Some(1);
+        tok.eos_id = Some(2);
+        tok.eog_ids = tok.collect_eog_ids(None);
+        tok
+    }
+
+    #[test]
+    fn test_spm_merges_by_score() {
+        let tok = spm_tokenizer();
+        let ids = tok.encode("hello", true);
+        assert_eq!(ids[0], 1);
+        assert_eq!(tok.id_to_piece(ids[1]), Some("▁hello"));
+        assert_eq!(ids.len(), 2);
+        assert_eq!(tok.decode(&ids), "hello");
+    }
+
+    #[test]
+    fn test_spm_byte_fallback_roundtrip() {
+        let tok = spm_tokenizer();
+        let ids = tok.encode("hé", false);
+        // 'é' is not in the vocabulary and must fall back to two byte tokens
+        assert!(ids.iter().any(|&id| tok.id_to_piece(id) == Some("<0xC3>")));
+        assert_eq!(tok.decode(&ids), "hé");
+    }
+
+    #[test]
+    fn test_stream_decoder_holds_partial_utf8() {
+        let tok = spm_tokenizer();
+        let c3 = tok.token_to_id("<0xC3>").unwrap();
+        let a9 = tok.token_to_id("<0xA9>").unwrap();
+        let mut dec = tok.stream_decoder();
+        assert_eq!(dec.push(c3), "");
+        assert_eq!(dec.push(a9), "é");
+    }
+
+    #[test]
+    fn test_special_tokens_are_matched_verbatim() {
+        let tok = spm_tokenizer();
+        let ids = tok.encode("hello</s>", false);
+        assert_eq!(*ids.last().unwrap(), 2);
+        assert!(tok.is_eog(2));
+        assert_eq!(tok.decode(&ids), "hello");
+    }
+
+    #[test]
+    fn test_bpe_merges_by_rank() {
+        let enc = bytes_to_unicode();
+        let space = enc[b' ' as usize].to_string();
+        let pieces = vec![
+            "h".to_string(),
+            "i".to_string(),
+            space.clone(),
+            "hi".to_string(),
+            format!("{}h", space),
+            format!("{}hi", space),
+        ];
+        let merges = vec![
+            ("h".to_string(), "i".to_string()),
+            (space.clone(), "h".to_string()),
+            (format!("{}h", space), "i".to_string()),
+        ];
+        let tok = Tokenizer::new(
+            TokenizerKind::BytePairEncoding,
+            pieces,
+            vec![],
+            vec![],
+            merges,
+        )
+        .unwrap();
+        let ids = tok.encode("hi hi", false);
+        assert_eq!(ids, vec![3, 5]);
+        assert_eq!(tok.decode(&ids), "hi hi");
+    }
+
+    #[test]
+    fn test_pre_tokenize_splits_like_gpt2() {
+        assert_eq!(
+            pre_tokenize("Hello world, it's  42!", false),
+            vec!["Hello", " world", ",", " it", "'s", " ", " 42", "!"]
+        );
+        assert_eq!(pre_tokenize("12345", true), vec!["123", "45"]);
+    }
+
+    #[test]
+    fn test_bytes_to_unicode_is_bijective() {
+        let enc = bytes_to_unicode();
+        let mut seen = std::collections::HashSet::new();
+        assert!(enc.iter().all(|c| seen.insert(*c)));
+        assert_eq!(enc[b'a' as usize], 'a');
+        assert_eq!(enc[b' ' as usize], 'Ġ');
+    }
+
+    const WORDPIECE_JSON: &str = r###"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [
+            {"id": 0, "content": "[UNK]", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true},
+            {"id": 1, "content": "[CLS]", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true},
+            {"id": 2, "content": "[SEP]", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}
+        ],
+        "normalizer": {"type": "BertNormalizer", "clean_text": true, "handle_chinese_chars": true, "strip_accents": null, "lowercase": true},
+        "pre_tokenizer": {"type": "BertPreTokenizer"},
+        "post_processor": null,
+        "decoder": {"type": "WordPiece", "prefix": "##", "cleanup": true},
+        "model": {
+            "type": "WordPiece",
+            "unk_token": "[UNK]",
+            "continuing_subword_prefix": "##",
+            "max_input_chars_per_word": 100,
+            "vocab": {"[UNK]": 0, "[CLS]": 1, "[SEP]": 2, "hello": 3, "world": 4, "!": 5, "play": 6, "##ing": 7}
+        }
+    }"###;
+
+    const BYTE_LEVEL_BPE_JSON: &str = r###"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [
+            {"id": 5, "content": "<|im_end|>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}
+        ],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true},
+        "post_processor": null,
+        "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true},
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "vocab": {"h": 0, "i": 1, "Ġ": 2, "hi": 3, "Ġhi": 4, "<|im_end|>": 5},
+            "merges": ["h i", "Ġ hi"]
+        }
+    }"###;
+
+    fn hf_tokenizer(json: &str) -> TextTokenizer {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("tokenizer.json"), json).unwrap();
+        TextTokenizer::from_dir(dir.path()).unwrap()
+    }
+
+    /// Encode a SentencePiece ModelProto with the given pieces
+    fn spm_proto(pieces: &[(&str, f32, u64)], model_type: u64) -> Vec<u8> {
+        fn varint(mut v: u64, out: &mut Vec<u8>) {
+            while v >= 0x80 {
+                out.push((v as u8) | 0x80);
+                v >>= 7;
+            }
+            out.push(v as u8);
+        }
+        fn bytes_field(field: u64, data: &[u8], out: &mut Vec<u8>) {
+            varint(field << 3 | 2, out);
+            varint(data.len() as u64, out);
+            out.extend_from_slice(data);
+        }
+        let mut out = Vec::new();
+        for (piece, score, kind) in pieces {
+            let mut p = Vec::new();
+            bytes_field(1, piece.as_bytes(), &mut p);
+            varint(2 << 3 | 5, &mut p);
+            p.extend_from_slice(&score.to_le_bytes());
+            varint(3 << 3, &mut p);
+            varint(*kind, &mut p);
+            bytes_field(1, &p, &mut out);
+        }
+        let mut trainer = Vec::new();
+        varint(3 << 3, &mut trainer);
+        varint(model_type, &mut trainer);
+        bytes_field(2, &trainer, &mut out);
+        out
+    }
+
+    const SPM_PIECES: &[(&str, f32, u64)] = &[
+        ("<unk>", 0.0, 2),
+        ("<s>", 0.0, 3),
+        ("</s>", 0.0, 3),
+        ("▁hello", -1.0, 1),
+        ("▁h", -3.0, 1),
+        ("ello", -3.0, 1),
+        ("▁world", -1.5, 1),
+        ("▁", -4.0, 1),
+        ("h", -5.0, 1),
+        ("e", -5.0, 1),
+        ("l", -5.0, 1),
+        ("o", -5.0, 1),
+        ("w", -5.0, 1),
+        ("r", -5.0, 1),
+        ("d", -5.0, 1),
+        // BPE needs every intermediate merge to be a piece
+        ("▁he", -2.1, 1),
+        ("▁hel", -2.2, 1),
+        ("▁hell", -2.3, 1),
+        ("▁w", -2.0, 1),
+        ("▁wo", -2.1, 1),
+        ("▁wor", -2.2, 1),
+        ("▁worl", -2.3, 1),
+    ];
+
+    #[test]
+    fn test_tokenizer_json_wordpiece() {
+        let tok = hf_tokenizer(WORDPIECE_JSON);
+        let ids = tok.encode("Hello world! Playing", false).unwrap();
+        assert_eq!(ids, vec![3, 4, 5, 6, 7]);
+        assert_eq!(tok.decode(&ids, true).unwrap(), "hello world! playing");
+        assert_eq!(tok.token_to_id("[SEP]"), Some(2));
+    }
+
+    #[test]
+    fn test_tokenizer_json_added_tokens_are_special() {
+        let tok = hf_tokenizer(WORDPIECE_JSON);
+        let ids = tok.encode("hello [SEP]", false).unwrap();
+        assert_eq!(ids, vec![3, 2]);
+        assert_eq!(tok.decode(&ids, true).unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_tokenizer_json_byte_level_bpe_streams() {
+        let tok = hf_tokenizer(BYTE_LEVEL_BPE_JSON);
+        let ids = tok.encode("hi hi<|im_end|>", false).unwrap();
+        assert_eq!(ids, vec![3, 4, 5]);
+
+        let mut decoder = tok.stream_decoder();
+        let mut streamed = String::new();
+        for &id in &ids {
+            streamed.push_str(&decoder.push(id).unwrap());
+        }
+        streamed.push_str(&decoder.flush());
+        assert_eq!(streamed, "hi hi");
+        assert_eq!(tok.decode(&ids, true).unwrap(), streamed);
+    }
+
+    #[test]
+    fn test_sentencepiece_model_bpe_uses_native_merges() {
+        let tok = TextTokenizer::from_sentencepiece(&spm_proto(SPM_PIECES, SPM_MODEL_BPE)).unwrap();
+        assert!(matches!(tok, TextTokenizer::Native(_)));
+        let ids = tok.encode("hello world", true).unwrap();
+        assert_eq!(ids, vec![1, 3, 6]);
+        assert_eq!(tok.decode(&ids, true).unwrap(), "hello world");
+    }
+
+    #[test]
+    fn test_sentencepiece_model_unigram_viterbi() {
+        let tok =
+            TextTokenizer::from_sentencepiece(&spm_proto(SPM_PIECES, SPM_MODEL_UNIGRAM)).unwrap();
+        assert!(matches!(tok, TextTokenizer::HuggingFace(_)));
+        // "▁hello" (-1.0) beats "▁h" + "ello" (-6.0)
+        let ids = tok.encode("hello world", true).unwrap();
+        assert_eq!(ids, vec![1, 3, 6]);
+        assert_eq!(tok.decode(&ids, true).unwrap(), "hello world");
+    }
+
+    fn gguf_roundtrip(vocab: &GgufVocab) -> Tokenizer {
+        let mut w = GgufWriter::new();
+        vocab.write_metadata(&mut w);
+        let mut bytes = Vec::new();
+        w.write_to(&mut bytes).unwrap();
+        Tokenizer::from_gguf(&GgufHeader::parse(&bytes).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_gguf_vocab_from_byte_level_bpe_json() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("tokenizer.json"), BYTE_LEVEL_BPE_JSON).unwrap();
+        let vocab = GgufVocab::from_dir(dir.path()).unwrap();
+        assert_eq!(vocab.model, "gpt2");
+        assert_eq!(vocab.merges, vec!["h i", "Ġ hi"]);
+        assert_eq!(vocab.types[5], TokenType::Control);
+
+        let tok = gguf_roundtrip(&vocab);
+        assert_eq!(tok.encode("hi hi", false), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_gguf_vocab_from_sentencepiece_model() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("tokenizer.model"),
+            spm_proto(SPM_PIECES, SPM_MODEL_BPE),
+        )
+        .unwrap();
+        let vocab = GgufVocab::from_dir(dir.path()).unwrap();
+        assert_eq!(vocab.model, "llama");
+        assert_eq!(vocab.tokens.len(), SPM_PIECES.len());
+
+        let tok = gguf_roundtrip(&vocab);
+        assert_eq!(tok.encode("hello world", true), vec![1, 3, 6]);
+    }
+
+    #[test]
+    fn test_sentencepiece_model_rejects_garbage() {
+        assert!(TextTokenizer::from_sentencepiece(&[0xff, 0xff]).is_err());
+        assert!(TextTokenizer::from_dir(Path::new("/nonexistent")).is_err());
+    }
+}
@@ -40,7 +40,35 @@ impl UniversalEngine for ShimmyUniversalEngine {
                 Ok(Box::new(UniversalModelAdapter { model: loaded }))
             }
             ModelBackend::HuggingFace { .. } => self.huggingface_engine.load(spec).await,
-            ModelBackend::Candle { .. } => Err(anyhow!("Candle backend not yet implemented")),
+            ModelBackend::Exec { .. } => {
+                let legacy_spec = spec.clone().try_into()?;
+                let loaded = super::exec::ExecEngine::new().load(&legacy_spec).await?;
+                Ok(Box::new(UniversalModelAdapter { model: loaded }))
+            }
+            #[cfg(feature = "candle")]
+            ModelBackend::Candle {
+                model_path,
+                adapter_path,
+            } => {
+                let legacy_spec = super::ModelSpec {
+                    name: spec.name.clone(),
+                    base_path: model_path.clone(),
+                    lora_path: adapter_path.clone(),
+                    template: spec.template.clone(),
+                    ctx_len: spec.ctx_len,
+                    n_threads: spec.n_threads,
+                    backend: None,
+                    command: None,
+                };
+                let loaded = super::candle::CandleEngine::new()
+                    .load(&legacy_spec)
+                    .await?;
+                Ok(Box::new(UniversalModelAdapter { model: loaded }))
+            }
+            #[cfg(not(feature = "candle"))]
+            ModelBackend::Candle { .. } => Err(anyhow!(
+                "Candle backend not enabled in this build; rebuild with --features candle"
+            )),
         }
     }
 }
@@ -61,9 +89,13 @@ impl UniversalModel for UniversalModelAdapter {
     ) -> Result<String> {
         self.model.generate(prompt, opts, on_token).await
     }
+
+    fn capabilities(&self) -> super::Capabilities {
+        self.model.capabilities()
+    }
 }
 
-/// Convert UniversalModelSpec to legacy ModelSpec for LlamaEngine compatibility
+/// Convert UniversalModelSpec to legacy ModelSpec for LlamaEngine and ExecEngine compatibility
 impl TryFrom<UniversalModelSpec> for super::ModelSpec {
     type Error = anyhow::Error;
 
@@ -79,6 +111,22 @@ impl TryFrom<UniversalModelSpec> for super::ModelSpec {
                 template: spec.template,
                 ctx_len: spec.ctx_len,
                 n_threads: spec.n_threads,
+                backend: None,
+                command: None,
+            }),
+            ModelBackend::Exec {
+                command,
+                model_path,
+                adapter_path,
+            } => Ok(super::ModelSpec {
+                name: spec.name,
+                base_path: model_path,
+                lora_path: adapter_path,
+                template: spec.template,
+                ctx_len: spec.ctx_len,
+                n_threads: spec.n_threads,
+                backend: Some(super::exec::BACKEND_NAME.to_string()),
+                command: Some(command),
             }),
             _ => Err(anyhow!(
                 "Cannot convert non-GGUF backend to legacy ModelSpec"
@@ -300,7 +348,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_universal_engine_load_candle_not_implemented() {
+    async fn test_universal_engine_load_candle_missing_model() {
         let engine = ShimmyUniversalEngine::new();
         let spec = UniversalModelSpec {
             name: "test-candle".to_string(),
@@ -317,9 +365,12 @@ mod tests {
         let result = engine.load(&spec).await;
         assert!(result.is_err());
 
-        // Verify the specific error message
+        // No config.json beside the weights, so loading stops there
         match result {
-            Err(e) => assert!(e.to_string().contains("Candle backend not yet implemented")),
+            #[cfg(feature = "candle")]
+            Err(e) => assert!(e.to_string().contains("failed to read config.json"), "{e}"),
+            #[cfg(not(feature = "candle"))]
+            Err(e) => assert!(e.to_string().contains("Candle backend not enabled"), "{e}"),
             Ok(_) => panic!("Expected error but got success"),
         }
     }
@@ -343,7 +394,14 @@ mod tests {
         assert!(result.is_err());
 
         match result {
-            Err(e) => assert!(e.to_string().contains("Candle backend not yet implemented")),
+            #[cfg(feature = "candle")]
+            Err(e) => assert!(
+                e.to_string()
+                    .contains("LoRA adapters are not supported by the Candle backend"),
+                "{e}"
+            ),
+            #[cfg(not(feature = "candle"))]
+            Err(e) => assert!(e.to_string().contains("Candle backend not enabled"), "{e}"),
             Ok(_) => panic!("Expected error but got success"),
         }
     }
@@ -366,6 +424,7 @@ mod tests {
             repeat_penalty: 1.1,
             seed: Some(42),
             stream: true,
+            ..Default::default()
         };
 
         let result = adapter.generate("Hello world", opts, None).await;
@@ -3,6 +3,8 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum ShimmyError {
+    // Library error; the server reports unknown models with `ApiError`
+    #[allow(dead_code)]
     #[error("Model not found: {name}")]
     ModelNotFound { name: String },
 
@@ -22,6 +24,11 @@ pub enum ShimmyError {
     #[error("Backend not available: {backend}")]
     BackendNotAvailable { backend: String },
 
+    #[error("Model '{model}' has too many requests queued")]
+    Overloaded { model: String },
+
+    // Library error; the server's templates render infallibly
+    #[allow(dead_code)]
     #[error("Template rendering failed: {template}")]
     TemplateError {
         template: String,
@@ -39,6 +46,8 @@ pub enum ShimmyError {
     SerdeError(#[from] serde_json::Error),
 }
 
+// For library users; the server's own code returns `anyhow::Result`
+#[allow(dead_code)]
 pub type Result<T> = std::result::Result<T, ShimmyError>;
 
 impl From<anyhow::Error> for ShimmyError {
@@ -406,6 +415,7 @@ mod tests {
                 ShimmyError::GenerationError { .. } => {}
                 ShimmyError::ConfigError { .. } => {}
                 ShimmyError::BackendNotAvailable { .. } => {}
+                ShimmyError::Overloaded { .. } => {}
                 ShimmyError::TemplateError { .. } => {}
                 ShimmyError::AsyncError(_) => {}
                 ShimmyError::IoError(_) => {}
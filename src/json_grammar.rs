@@ -0,0 +1,341 @@
+// JSON output constraints as GBNF grammars
+// `response_format`, `text.format`, Ollama's `format` and llama-server's
+// `json_schema` ask for JSON, or for JSON matching a schema. Engines that
+// announce `grammar` enforce a GBNF grammar while sampling, so each of these
+// is converted to one here. Schemas cover the keywords models are usually
+// given (`type`, `properties`, `required`, `items`, `enum`, `const`, `anyOf`,
+// `oneOf`, `$ref`); a schema using none of them accepts any JSON value.
+
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// Rules every grammar here may refer to
+const PRIMITIVES: &str = r#"space ::= | " " | "\n" [ \t]{0,20}
+value ::= object | array | string | number | boolean | null
+object ::= "{" space ( string ":" space value ( "," space string ":" space value )* )? "}" space
+array ::= "[" space ( value ( "," space value )* )? "]" space
+string ::= "\"" ( [^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} ) )* "\"" space
+number ::= integer ( "." [0-9]+ )? ( [eE] [-+]? [0-9]{1,15} )? space
+integer ::= "-"? ( [0-9] | [1-9] [0-9]{0,15} ) space
+boolean ::= ( "true" | "false" ) space
+null ::= "null" space
+"#;
+
+/// Any JSON object, for `json_object` and Ollama's `"json"`
+pub fn object() -> String {
+    format!("root ::= object\n{}", PRIMITIVES)
+}
+
+/// JSON matching `schema`
+pub fn from_schema(schema: &Value) -> String {
+    let mut converter = Converter {
+        root: schema,
+        rules: Vec::new(),
+        names: HashSet::new(),
+        refs: HashMap::new(),
+    };
+    converter.names.insert("root".to_string());
+    let top = converter.visit(schema, "root".to_string());
+    if top != "root" {
+        // The whole schema is a `$ref`
+        converter.rules.insert(0, ("root".to_string(), top));
+    }
+    let mut grammar: String = converter
+        .rules
+        .iter()
+        .map(|(name, body)| format!("{} ::= {}\n", name, body))
+        .collect();
+    grammar.push_str(PRIMITIVES);
+    grammar
+}
+
+/// The grammar a `{"type": ...}` output format asks for: none for `text`,
+/// `schema` for `json_schema`, and any JSON object otherwise
+pub fn for_format(kind: &str, schema: Option<&Value>) -> Option<String> {
+    match kind {
+        "text" => None,
+        "json_schema" => Some(from_schema(schema.unwrap_or(&Value::Null))),
+        _ => Some(object()),
+    }
+}
+
+struct Converter<'a> {
+    /// The whole schema, which `$ref`s point into
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    names: HashSet<String>,
+    /// Rule for each `$ref` seen, so recursive schemas refer back to it
+    refs: HashMap<String, String>,
+}
+
+impl Converter<'_> {
+    /// A rule name derived from `base` that no other rule uses
+    fn fresh(&mut self, base: &str) -> String {
+        let base: String = base
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let mut name = base.clone();
+        let mut n = 1;
+        while !self.names.insert(name.clone()) {
+            name = format!("{}{}", base, n);
+            n += 1;
+        }
+        name
+    }
+
+    /// Adds `name ::= <schema>` and returns `name`, or the rule `schema`
+    /// refers to
+    fn visit(&mut self, schema: &Value, name: String) -> String {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return self.reference(reference);
+        }
+        let body = self.body(schema, &name);
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    fn reference(&mut self, reference: &str) -> String {
+        if let Some(name) = self.refs.get(reference) {
+            return name.clone();
+        }
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer));
+        let Some(target) = target else {
+            return "value".to_string();
+        };
+        let base = reference.rsplit('/').next().unwrap_or_default();
+        let name = self.fresh(&format!("ref-{}", base));
+        // Registered before visiting, so the schema may refer to itself
+        self.refs.insert(reference.to_string(), name.clone());
+        self.visit(target, name)
+    }
+
+    fn body(&mut self, schema: &Value, name: &str) -> String {
+        if let Some(value) = schema.get("const") {
+            return literal(value);
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            return alternatives(values.iter().map(literal));
+        }
+        let variants = schema.get("anyOf").or_else(|| schema.get("oneOf"));
+        if let Some(variants) = variants.and_then(Value::as_array) {
+            let rules: Vec<String> = variants
+                .iter()
+                .enumerate()
+                .map(|(i, variant)| {
+                    let rule = self.fresh(&format!("{}-{}", name, i));
+                    self.visit(variant, rule)
+                })
+                .collect();
+            return alternatives(rules);
+        }
+        match schema.get("type") {
+            Some(Value::String(kind)) => self.typed(schema, kind, name),
+            Some(Value::Array(kinds)) => {
+                let bodies: Vec<String> = kinds
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(|kind| self.typed(schema, kind, name))
+                    .collect();
+                alternatives(bodies)
+            }
+            _ if schema.get("properties").is_some() => self.typed(schema, "object", name),
+            _ => "value".to_string(),
+        }
+    }
+
+    fn typed(&mut self, schema: &Value, kind: &str, name: &str) -> String {
+        match kind {
+            "object" => self.object(schema, name),
+            "array" => match schema.get("items") {
+                Some(items) => {
+                    let rule = self.fresh(&format!("{}-item", name));
+                    let item = self.visit(items, rule);
+                    format!(
+                        r#""[" space ( {item} ( "," space {item} )* )? "]" space"#,
+                        item = item
+                    )
+                }
+                None => "array".to_string(),
+            },
+            "string" | "number" | "integer" | "boolean" | "null" => kind.to_string(),
+            _ => "value".to_string(),
+        }
+    }
+
+    /// Declared properties in order, required ones first; undeclared
+    /// properties are not generated
+    fn object(&mut self, schema: &Value, name: &str) -> String {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return "object".to_string();
+        };
+        let required: HashSet<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|names| names.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let mut mandatory = Vec::new();
+        let mut optional = Vec::new();
+        for (key, property) in properties {
+            let rule = self.fresh(&format!("{}-{}", name, key));
+            let value = self.visit(property, rule);
+            let pair = format!(
+                r#"{} ":" space {}"#,
+                literal(&Value::String(key.clone())),
+                value
+            );
+            if required.contains(key.as_str()) {
+                mandatory.push(pair);
+            } else {
+                optional.push(pair);
+            }
+        }
+        let members = if mandatory.is_empty() {
+            // The first member present has no leading comma
+            let starts: Vec<String> = (0..optional.len())
+                .map(|i| {
+                    let rest: String = optional[i + 1..]
+                        .iter()
+                        .map(|pair| format!(r#" ( "," space {} )?"#, pair))
+                        .collect();
+                    format!("{}{}", optional[i], rest)
+                })
+                .collect();
+            if starts.is_empty() {
+                String::new()
+            } else {
+                format!("{}?", alternatives(starts))
+            }
+        } else {
+            let rest: String = optional
+                .iter()
+                .map(|pair| format!(r#" ( "," space {} )?"#, pair))
+                .collect();
+            format!("{}{}", mandatory.join(r#" "," space "#), rest)
+        };
+        format!(r#""{{" space {} "}}" space"#, members)
+    }
+}
+
+/// `value` exactly, as its compact JSON text
+fn literal(value: &Value) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.to_string().chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push_str("\" space");
+    quoted
+}
+
+fn alternatives(bodies: impl IntoIterator<Item = String>) -> String {
+    let bodies: Vec<String> = bodies.into_iter().collect();
+    match bodies.len() {
+        0 => "value".to_string(),
+        1 => bodies.into_iter().next().unwrap_or_default(),
+        _ => format!("( {} )", bodies.join(" | ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule<'a>(grammar: &'a str, name: &str) -> &'a str {
+        let prefix = format!("{} ::= ", name);
+        grammar
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix.as_str()))
+            .unwrap_or_else(|| panic!("no rule {} in\n{}", name, grammar))
+    }
+
+    #[test]
+    fn test_object_grammar() {
+        let grammar = object();
+        assert_eq!(rule(&grammar, "root"), "object");
+        assert!(rule(&grammar, "object").starts_with(r#""{" space"#));
+    }
+
+    #[test]
+    fn test_required_properties_come_first() {
+        let grammar = from_schema(&json!({
+            "type": "object",
+            "properties": {
+                "age": {"type": "integer"},
+                "name": {"type": "string"}
+            },
+            "required": ["name"]
+        }));
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""{" space "\"name\"" space ":" space root-name ( "," space "\"age\"" space ":" space root-age )? "}" space"#
+        );
+        assert_eq!(rule(&grammar, "root-name"), "string");
+        assert_eq!(rule(&grammar, "root-age"), "integer");
+    }
+
+    #[test]
+    fn test_optional_properties_without_required() {
+        let grammar = from_schema(&json!({
+            "properties": {"a": {"type": "boolean"}, "b": {"type": "null"}}
+        }));
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""{" space ( "\"a\"" space ":" space root-a ( "," space "\"b\"" space ":" space root-b )? | "\"b\"" space ":" space root-b )? "}" space"#
+        );
+    }
+
+    #[test]
+    fn test_enum_and_array_items() {
+        let grammar = from_schema(&json!({
+            "type": "array",
+            "items": {"enum": ["red", 1, null]}
+        }));
+        assert_eq!(
+            rule(&grammar, "root"),
+            r#""[" space ( root-item ( "," space root-item )* )? "]" space"#
+        );
+        assert_eq!(
+            rule(&grammar, "root-item"),
+            r#"( "\"red\"" space | "1" space | "null" space )"#
+        );
+    }
+
+    #[test]
+    fn test_recursive_ref() {
+        let grammar = from_schema(&json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "children": {"type": "array", "items": {"$ref": "#/$defs/node"}}
+                    },
+                    "required": ["children"]
+                }
+            },
+            "$ref": "#/$defs/node"
+        }));
+        assert_eq!(
+            rule(&grammar, "ref-node-children"),
+            r#""[" space ( ref-node ( "," space ref-node )* )? "]" space"#
+        );
+        assert_eq!(rule(&grammar, "root"), "ref-node");
+        assert_eq!(grammar.matches("ref-node ::=").count(), 1);
+    }
+
+    #[test]
+    fn test_unknown_schema_accepts_any_value() {
+        assert_eq!(rule(&from_schema(&json!({})), "root"), "value");
+        assert_eq!(for_format("text", None), None);
+        assert_eq!(for_format("json_object", None), Some(object()));
+    }
+}
@@ -1,3 +1,4 @@
+pub mod anthropic_compat;
 pub mod api;
 pub mod api_errors;
 pub mod auto_discovery;
@@ -6,16 +7,22 @@ pub mod cli;
 pub mod discovery;
 pub mod engine;
 pub mod error;
+pub mod json_grammar;
+pub mod llama_server_compat;
+pub mod loaded_models;
 pub mod main_integration;
 pub mod metrics;
 pub mod model_manager;
 pub mod model_registry;
+pub mod ollama_compat;
 pub mod openai_compat;
 pub mod port_manager;
+pub mod responses_compat;
 pub mod rustchain_compat;
 pub mod safetensors_adapter;
 pub mod server;
 pub mod templates;
+pub mod tool_calling;
 pub mod tools;
 pub mod util {
     pub mod diag;
@@ -35,4 +42,26 @@ pub mod test_utils;
 pub struct AppState {
     pub engine: Box<dyn engine::InferenceEngine>,
     pub registry: model_registry::Registry,
+    /// Models kept in memory between requests
+    pub models: loaded_models::LoadedModels,
+    /// Responses kept for `previous_response_id`
+    pub responses: responses_compat::ResponseStore,
+    /// Generations in flight through the llama.cpp server endpoints
+    pub slots: llama_server_compat::BusySlots,
+}
+
+impl AppState {
+    /// State whose models stay loaded for `SHIMMY_KEEP_ALIVE`
+    pub fn new(
+        engine: Box<dyn engine::InferenceEngine>,
+        registry: model_registry::Registry,
+    ) -> Self {
+        Self {
+            engine,
+            registry,
+            models: loaded_models::LoadedModels::from_env(),
+            responses: responses_compat::ResponseStore::default(),
+            slots: llama_server_compat::BusySlots::default(),
+        }
+    }
 }
@@ -0,0 +1,1067 @@
+// llama.cpp server compatibility
+// `/completion`, `/infill`, `/tokenize`, `/detokenize`, `/props` and
+// `/slots` take `llama-server`'s parameter names (`n_predict`,
+// `cache_prompt`, `id_slot`/`slot_id`, `grammar`, `json_schema`) and answer
+// with its objects; `/completion` and `/infill` stream its SSE chunks,
+// ending with a `"stop": true` chunk and no `[DONE]`. Each model is one
+// slot, as every loaded model decodes one generation at a time. Requests
+// without `model` go to `SHIMMY_DEFAULT_MODEL`, or the only model available.
+
+use crate::api::{check_capabilities, context_room, RequestedFeatures};
+use crate::api_errors::{ApiError, Json};
+use crate::engine::stream::{FinishReason, GenerationStats};
+use crate::engine::{GenOptions, GenerationEvent, GenerationStream, LoadedModel, ModelSpec};
+use crate::json_grammar;
+use crate::openai_compat::{load_model, template_family, StopSequences};
+use crate::templates::fim_prompt;
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::stream::BoxStream;
+use futures_util::{future, stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Generations running through these endpoints, by model, for `/slots`
+#[derive(Default)]
+pub struct BusySlots {
+    counts: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl BusySlots {
+    /// Mark `model`'s slot busy until the returned guard drops
+    fn acquire(&self, model: &str) -> Busy {
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(model.to_string())
+            .or_default() += 1;
+        Busy {
+            counts: Arc::clone(&self.counts),
+            model: model.to_string(),
+        }
+    }
+
+    fn is_processing(&self, model: &str) -> bool {
+        self.counts
+            .lock()
+            .unwrap()
+            .get(model)
+            .is_some_and(|&n| n > 0)
+    }
+}
+
+/// Marks a model's slot busy while alive
+struct Busy {
+    counts: Arc<Mutex<HashMap<String, usize>>>,
+    model: String,
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(n) = counts.get_mut(&self.model) {
+            *n -= 1;
+            if *n == 0 {
+                counts.remove(&self.model);
+            }
+        }
+    }
+}
+
+/// The model a request names, else `SHIMMY_DEFAULT_MODEL`, else the only
+/// model available
+fn model_name(state: &AppState, requested: Option<&str>) -> Result<String, ApiError> {
+    if let Some(model) = requested {
+        return Ok(model.to_string());
+    }
+    if let Ok(model) = std::env::var("SHIMMY_DEFAULT_MODEL") {
+        return Ok(model);
+    }
+    match state.registry.list_all_available().as_slice() {
+        [only] => Ok(only.clone()),
+        _ => Err(ApiError::InvalidRequest(
+            "model is required when more than one model is available; \
+             name it in the request or set SHIMMY_DEFAULT_MODEL"
+                .to_string(),
+        )),
+    }
+}
+
+/// `/completion`, and the generation parameters of `/infill`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CompletionRequest {
+    pub model: Option<String>,
+    /// A string, token ids mixed with strings, or a list of those for
+    /// several completions
+    pub prompt: Option<Value>,
+    /// -1 (the default) generates until the context is full
+    pub n_predict: Option<i64>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    /// -1 picks a random seed
+    pub seed: Option<i64>,
+    pub stop: Vec<String>,
+    pub stream: bool,
+    /// GBNF grammar the output must follow
+    pub grammar: Option<String>,
+    pub json_schema: Option<Value>,
+    /// Report each token's log probability; only the sampled token's is known
+    pub n_probs: usize,
+    /// Accepted; prompts are evaluated in full on every request
+    pub cache_prompt: Option<bool>,
+    /// -1 or 0: each model has one slot
+    #[serde(alias = "slot_id")]
+    pub id_slot: Option<i64>,
+    /// Include the generated token ids
+    pub return_tokens: bool,
+}
+
+impl CompletionRequest {
+    fn features(&self) -> RequestedFeatures {
+        RequestedFeatures {
+            grammar: self.grammar().is_some(),
+            logprobs: self.n_probs > 0,
+            max_tokens: self.token_limit(),
+            ..Default::default()
+        }
+    }
+
+    /// `grammar`, or else `json_schema` converted to one
+    fn grammar(&self) -> Option<String> {
+        self.grammar.clone().or_else(|| {
+            self.json_schema
+                .as_ref()
+                .filter(|schema| !schema.is_null())
+                .map(json_grammar::from_schema)
+        })
+    }
+
+    fn token_limit(&self) -> Option<usize> {
+        self.n_predict.and_then(|n| usize::try_from(n).ok())
+    }
+
+    fn options(&self) -> GenOptions {
+        let mut opts = GenOptions::default();
+        if let Some(t) = self.temperature {
+            opts.temperature = t;
+        }
+        if let Some(k) = self.top_k {
+            opts.top_k = k;
+        }
+        if let Some(p) = self.top_p {
+            opts.top_p = p;
+        }
+        if let Some(r) = self.repeat_penalty {
+            opts.repeat_penalty = r;
+        }
+        opts.seed = self.seed.and_then(|seed| u32::try_from(seed).ok());
+        opts.stream = self.stream;
+        opts.grammar = self.grammar();
+        opts
+    }
+
+    fn check_slot(&self) -> Result<(), ApiError> {
+        match self.id_slot {
+            None | Some(-1) | Some(0) => Ok(()),
+            Some(id) => Err(ApiError::InvalidRequest(format!(
+                "invalid slot id {}: each model has one slot, 0",
+                id
+            ))),
+        }
+    }
+
+    fn settings(&self, opts: &GenOptions) -> Settings {
+        Settings {
+            n_predict: self.n_predict.unwrap_or(-1),
+            seed: opts.seed.map_or(-1, i64::from),
+            temperature: opts.temperature,
+            top_k: opts.top_k,
+            top_p: opts.top_p,
+            repeat_penalty: opts.repeat_penalty,
+            stop: self.stop.clone(),
+            stream: self.stream,
+            cache_prompt: self.cache_prompt.unwrap_or(true),
+            grammar: opts.grammar.clone().unwrap_or_default(),
+            n_probs: self.n_probs,
+        }
+    }
+}
+
+/// A piece of a prompt given as a list
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Token(u32),
+    Text(String),
+}
+
+/// The prompts in `prompt`. An array with any token ids is one prompt
+/// mixing ids and text; otherwise each element of an array is a prompt.
+fn prompt_pieces(prompt: &Value) -> Result<Vec<Vec<Piece>>, ApiError> {
+    let invalid = || {
+        ApiError::InvalidRequest(
+            "prompt must be a string, token ids mixed with strings, or a list of those".to_string(),
+        )
+    };
+    let mixed = |items: &[Value]| -> Result<Vec<Piece>, ApiError> {
+        items
+            .iter()
+            .map(|item| match item {
+                Value::String(text) => Ok(Piece::Text(text.clone())),
+                Value::Number(id) => id
+                    .as_u64()
+                    .and_then(|id| u32::try_from(id).ok())
+                    .map(Piece::Token)
+                    .ok_or_else(invalid),
+                _ => Err(invalid()),
+            })
+            .collect()
+    };
+    match prompt {
+        Value::String(text) => Ok(vec![vec![Piece::Text(text.clone())]]),
+        Value::Array(items) if items.iter().any(Value::is_number) => Ok(vec![mixed(items)?]),
+        Value::Array(items) if !items.is_empty() => items
+            .iter()
+            .map(|item| match item {
+                Value::String(text) => Ok(vec![Piece::Text(text.clone())]),
+                Value::Array(inner) => mixed(inner),
+                _ => Err(invalid()),
+            })
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+/// A prompt's text, with runs of token ids detokenized by the model
+fn prompt_text(pieces: &[Piece], loaded: &dyn LoadedModel) -> Result<String, ApiError> {
+    let mut text = String::new();
+    let mut ids = Vec::new();
+    let flush = |ids: &mut Vec<u32>, text: &mut String| -> Result<(), ApiError> {
+        if !ids.is_empty() {
+            let decoded = loaded.detokenize(ids).map_err(|e| {
+                ApiError::InvalidRequest(format!("cannot use token ids with this model: {}", e))
+            })?;
+            text.push_str(&decoded);
+            ids.clear();
+        }
+        Ok(())
+    };
+    for piece in pieces {
+        match piece {
+            Piece::Token(id) => ids.push(*id),
+            Piece::Text(piece) => {
+                flush(&mut ids, &mut text)?;
+                text.push_str(piece);
+            }
+        }
+    }
+    flush(&mut ids, &mut text)?;
+    Ok(text)
+}
+
+/// `/infill`: the code around the cursor, plus `/completion`'s parameters
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct InfillRequest {
+    pub input_prefix: String,
+    pub input_suffix: String,
+    /// Other files, placed before the prefix
+    pub input_extra: Vec<ExtraChunk>,
+    #[serde(flatten)]
+    pub base: CompletionRequest,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ExtraChunk {
+    pub filename: String,
+    pub text: String,
+}
+
+impl InfillRequest {
+    /// The fill-in-the-middle prompt in the markers `model`'s family was
+    /// trained with. A `prompt` starts the middle.
+    fn prompt(&self, model: &str) -> String {
+        let mut prefix: String = self
+            .input_extra
+            .iter()
+            .map(|chunk| format!("{}\n{}\n", chunk.filename, chunk.text))
+            .collect();
+        prefix.push_str(&self.input_prefix);
+        let mut prompt = fim_prompt(model, &prefix, &self.input_suffix);
+        if let Some(Value::String(start)) = &self.base.prompt {
+            prompt.push_str(start);
+        }
+        prompt
+    }
+}
+
+/// The sampling settings a generation ran with
+#[derive(Debug, Clone, Serialize)]
+pub struct Settings {
+    pub n_predict: i64,
+    pub seed: i64,
+    pub temperature: f32,
+    pub top_k: i32,
+    pub top_p: f32,
+    pub repeat_penalty: f32,
+    pub stop: Vec<String>,
+    pub stream: bool,
+    pub cache_prompt: bool,
+    pub grammar: String,
+    pub n_probs: usize,
+}
+
+/// A `/completion` result, or with `summary` absent one of its stream chunks
+#[derive(Debug, Serialize)]
+pub struct CompletionResponse {
+    pub index: usize,
+    pub content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<u32>,
+    pub id_slot: usize,
+    pub stop: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_probabilities: Option<Vec<TokenProbability>>,
+    #[serde(flatten)]
+    pub summary: Option<Summary>,
+}
+
+/// What the final chunk adds
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub model: String,
+    pub tokens_predicted: usize,
+    pub tokens_evaluated: usize,
+    pub generation_settings: Settings,
+    pub prompt: String,
+    pub has_new_line: bool,
+    pub truncated: bool,
+    /// `eos`, `limit`, `word` or `none`
+    pub stop_type: &'static str,
+    pub stopping_word: String,
+    pub tokens_cached: usize,
+    pub timings: Timings,
+}
+
+/// A generated token's probability. Backends report the sampled token's
+/// only, so `top_logprobs` is always empty.
+#[derive(Debug, Serialize)]
+pub struct TokenProbability {
+    pub id: Option<u32>,
+    pub token: String,
+    pub bytes: Vec<u8>,
+    pub logprob: Option<f32>,
+    pub top_logprobs: Vec<Value>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Timings {
+    pub prompt_n: usize,
+    pub prompt_ms: f64,
+    pub prompt_per_token_ms: f64,
+    pub prompt_per_second: f64,
+    pub predicted_n: usize,
+    pub predicted_ms: f64,
+    pub predicted_per_token_ms: f64,
+    pub predicted_per_second: f64,
+}
+
+impl From<&GenerationStats> for Timings {
+    fn from(stats: &GenerationStats) -> Self {
+        let per_token = |ms: f64, n: usize| if n > 0 { ms / n as f64 } else { 0.0 };
+        let per_second = |ms: f64, n: usize| {
+            if ms > 0.0 {
+                n as f64 * 1000.0 / ms
+            } else {
+                0.0
+            }
+        };
+        let prompt_ms = stats.prompt_ms as f64;
+        let predicted_ms = stats.total_ms.saturating_sub(stats.prompt_ms) as f64;
+        Self {
+            prompt_n: stats.prompt_tokens,
+            prompt_ms,
+            prompt_per_token_ms: per_token(prompt_ms, stats.prompt_tokens),
+            prompt_per_second: per_second(prompt_ms, stats.prompt_tokens),
+            predicted_n: stats.completion_tokens,
+            predicted_ms,
+            predicted_per_token_ms: per_token(predicted_ms, stats.completion_tokens),
+            predicted_per_second: per_second(predicted_ms, stats.completion_tokens),
+        }
+    }
+}
+
+/// One prompt's generation, turned into chunks as its tokens arrive
+struct Completion {
+    index: usize,
+    model: String,
+    prompt: String,
+    settings: Settings,
+    stop: StopSequences,
+    stopped: bool,
+    /// Text not sent in a chunk yet; all of it when not streaming
+    text: String,
+    return_tokens: bool,
+    tokens: Vec<u32>,
+    probs: Vec<TokenProbability>,
+    has_new_line: bool,
+    prompt_tokens: usize,
+    predicted: usize,
+    started: Instant,
+    first_token: Option<Instant>,
+}
+
+impl Completion {
+    fn push(&mut self, id: Option<u32>, piece: &str, logprob: Option<f32>) {
+        self.predicted += 1;
+        self.first_token.get_or_insert_with(Instant::now);
+        if self.settings.n_probs > 0 {
+            self.probs.push(TokenProbability {
+                id,
+                token: piece.to_string(),
+                bytes: piece.as_bytes().to_vec(),
+                logprob,
+                top_logprobs: vec![],
+            });
+        }
+        if let Some(id) = id.filter(|_| self.return_tokens) {
+            self.tokens.push(id);
+        }
+        let (ready, stopped) = self.stop.push(piece);
+        self.has_new_line |= ready.contains('\n');
+        self.text.push_str(&ready);
+        self.stopped = stopped;
+    }
+
+    fn chunk(&mut self, content: String, stop: bool) -> CompletionResponse {
+        CompletionResponse {
+            index: self.index,
+            content,
+            tokens: std::mem::take(&mut self.tokens),
+            id_slot: 0,
+            stop,
+            completion_probabilities: (self.settings.n_probs > 0)
+                .then(|| std::mem::take(&mut self.probs)),
+            summary: None,
+        }
+    }
+
+    /// The next stream chunk, if the latest tokens completed any text
+    fn next_chunk(&mut self) -> Option<CompletionResponse> {
+        if self.text.is_empty() {
+            return None;
+        }
+        let content = std::mem::take(&mut self.text);
+        Some(self.chunk(content, false))
+    }
+
+    /// The final chunk, or the whole result when not streaming
+    fn finish(
+        &mut self,
+        finish_reason: Option<FinishReason>,
+        stats: Option<GenerationStats>,
+    ) -> CompletionResponse {
+        let rest = self.stop.finish();
+        self.has_new_line |= rest.contains('\n');
+        self.text.push_str(&rest);
+        let stop_type = match finish_reason {
+            _ if self.stopped => "word",
+            Some(FinishReason::Stop) => "eos",
+            Some(FinishReason::Length) => "limit",
+            _ => "none",
+        };
+        let stats = stats.unwrap_or_else(|| {
+            GenerationStats::timed(
+                self.prompt_tokens,
+                self.predicted,
+                self.started,
+                self.first_token,
+            )
+        });
+        let content = std::mem::take(&mut self.text);
+        let mut last = self.chunk(content, true);
+        last.summary = Some(Summary {
+            model: self.model.clone(),
+            tokens_predicted: stats.completion_tokens,
+            tokens_evaluated: stats.prompt_tokens,
+            generation_settings: self.settings.clone(),
+            prompt: self.prompt.clone(),
+            has_new_line: self.has_new_line,
+            truncated: false,
+            stop_type,
+            stopping_word: self.stop.matched().unwrap_or_default().to_string(),
+            tokens_cached: stats.prompt_tokens + stats.completion_tokens,
+            timings: Timings::from(&stats),
+        });
+        last
+    }
+}
+
+/// The chunks of one generation: while streaming, one per piece of final
+/// text, then the last with `stop: true`; otherwise only the last, holding
+/// everything. `Err` carries the error of a failed generation.
+fn chunks(
+    events: GenerationStream,
+    completion: Completion,
+    busy: Busy,
+) -> BoxStream<'static, Result<CompletionResponse, ApiError>> {
+    stream::unfold(Some((events, completion, busy)), |run| async move {
+        let (mut events, mut completion, busy) = run?;
+        while let Some(event) = events.next().await {
+            match event {
+                GenerationEvent::PromptProgress { .. } => {}
+                GenerationEvent::Token { id, text, logprob } => {
+                    completion.push(id, &text, logprob);
+                    if completion.stopped {
+                        return Some((Ok(completion.finish(None, None)), None));
+                    }
+                    if !completion.settings.stream {
+                        continue;
+                    }
+                    if let Some(chunk) = completion.next_chunk() {
+                        return Some((Ok(chunk), Some((events, completion, busy))));
+                    }
+                }
+                GenerationEvent::Done {
+                    finish_reason,
+                    stats,
+                } => {
+                    return Some((
+                        Ok(completion.finish(Some(finish_reason), Some(stats))),
+                        None,
+                    ))
+                }
+                GenerationEvent::Error { message } => {
+                    return Some((Err(ApiError::GenerationFailed(message)), None))
+                }
+                GenerationEvent::Overloaded { model } => {
+                    return Some((Err(ApiError::Overloaded(model)), None))
+                }
+            }
+        }
+        Some((Ok(completion.finish(None, None)), None))
+    })
+    .boxed()
+}
+
+/// Start generating `prompt`, of `prompt_tokens` when counted, as completion
+/// `index`
+fn run(
+    state: &AppState,
+    loaded: &Arc<dyn LoadedModel>,
+    model: &str,
+    index: usize,
+    (prompt, prompt_tokens): (String, Option<usize>),
+    req: &CompletionRequest,
+) -> BoxStream<'static, Result<CompletionResponse, ApiError>> {
+    let mut opts = req.options();
+    let settings = req.settings(&opts);
+    opts.max_tokens = match req.token_limit() {
+        Some(max) => max,
+        None => context_room(&loaded.capabilities(), prompt_tokens).unwrap_or(opts.max_tokens),
+    };
+    let completion = Completion {
+        index,
+        model: model.to_string(),
+        prompt: prompt.clone(),
+        settings,
+        stop: StopSequences::new(&req.stop),
+        stopped: false,
+        text: String::new(),
+        return_tokens: req.return_tokens,
+        tokens: Vec::new(),
+        probs: Vec::new(),
+        has_new_line: false,
+        prompt_tokens: prompt_tokens.unwrap_or(0),
+        predicted: 0,
+        started: Instant::now(),
+        first_token: None,
+    };
+    let busy = state.slots.acquire(model);
+    chunks(
+        Arc::clone(loaded).generate_stream(prompt, opts),
+        completion,
+        busy,
+    )
+}
+
+/// Resolve and load the model a request is for
+async fn open(
+    state: &AppState,
+    requested: Option<&str>,
+) -> Result<(String, Arc<dyn LoadedModel>), ApiError> {
+    let model = model_name(state, requested)?;
+    let (_, loaded) = load_model(state, &model).await?;
+    Ok((model, loaded))
+}
+
+/// Generate each of `prompts`, as an SSE stream or one JSON body
+async fn respond(
+    state: &AppState,
+    model: &str,
+    loaded: Arc<dyn LoadedModel>,
+    req: &CompletionRequest,
+    prompts: Vec<String>,
+) -> Response {
+    if req.stream && prompts.len() > 1 {
+        return ApiError::InvalidRequest("streaming takes a single prompt".to_string())
+            .into_response();
+    }
+    let wants = req.features();
+    let mut counted = Vec::with_capacity(prompts.len());
+    for prompt in prompts {
+        match check_capabilities(state, model, &loaded, &prompt, &wants).await {
+            Ok(prompt_tokens) => counted.push((prompt, prompt_tokens)),
+            Err(e) => return e.into_response(),
+        }
+    }
+
+    if req.stream {
+        let prompt = counted.into_iter().next().unwrap_or_default();
+        let model = model.to_string();
+        let events = run(state, &loaded, &model, 0, prompt, req).map(move |chunk| {
+            let data = match chunk {
+                Ok(chunk) => serde_json::to_string(&chunk).unwrap_or_default(),
+                Err(error) => {
+                    let code = error.status().as_u16();
+                    let kind = match error {
+                        ApiError::Overloaded(_) => "unavailable_error",
+                        _ => "server_error",
+                    };
+                    let message = error.into_body().error.message;
+                    tracing::error!("Failed to generate for model '{}': {}", model, message);
+                    json!({
+                        "error": { "code": code, "message": message, "type": kind }
+                    })
+                    .to_string()
+                }
+            };
+            Ok::<Event, std::convert::Infallible>(Event::default().data(data))
+        });
+        return Sse::new(events).into_response();
+    }
+
+    // One slot per model, so several prompts are generated in turn
+    let mut results = Vec::with_capacity(counted.len());
+    for (index, prompt) in counted.into_iter().enumerate() {
+        let last = run(state, &loaded, model, index, prompt, req)
+            .filter(|chunk| future::ready(!matches!(chunk, Ok(c) if !c.stop)))
+            .next()
+            .await;
+        match last {
+            Some(Ok(result)) => results.push(result),
+            Some(Err(error)) => return error.into_response(),
+            None => {
+                return ApiError::GenerationFailed("generation ended without a result".to_string())
+                    .into_response()
+            }
+        }
+    }
+    match results.len() {
+        1 => Json(results.remove(0)).into_response(),
+        _ => Json(results).into_response(),
+    }
+}
+
+pub async fn completion(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CompletionRequest>,
+) -> Response {
+    if let Err(e) = req.check_slot() {
+        return e.into_response();
+    }
+    let pieces = match prompt_pieces(req.prompt.as_ref().unwrap_or(&Value::Null)) {
+        Ok(pieces) => pieces,
+        Err(e) => return e.into_response(),
+    };
+    let (model, loaded) = match open(&state, req.model.as_deref()).await {
+        Ok(opened) => opened,
+        Err(e) => return e.into_response(),
+    };
+    let prompts = match pieces
+        .iter()
+        .map(|pieces| prompt_text(pieces, loaded.as_ref()))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(prompts) => prompts,
+        Err(e) => return e.into_response(),
+    };
+    respond(&state, &model, loaded, &req, prompts).await
+}
+
+pub async fn infill(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<InfillRequest>,
+) -> Response {
+    if let Err(e) = req.base.check_slot() {
+        return e.into_response();
+    }
+    let (model, loaded) = match open(&state, req.base.model.as_deref()).await {
+        Ok(opened) => opened,
+        Err(e) => return e.into_response(),
+    };
+    let prompt = req.prompt(&model);
+    respond(&state, &model, loaded, &req.base, vec![prompt]).await
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TokenizeRequest {
+    pub model: Option<String>,
+    pub content: String,
+    /// Add the model's BOS token
+    pub add_special: bool,
+    /// Return `{id, piece}` pairs instead of bare ids
+    pub with_pieces: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenPiece {
+    pub id: u32,
+    /// Empty for special tokens, which detokenize to nothing
+    pub piece: String,
+}
+
+/// 400 unless the model exposes its tokenizer
+fn tokenizer(model: &str, loaded: &dyn LoadedModel) -> Result<(), ApiError> {
+    if loaded.capabilities().tokenize {
+        return Ok(());
+    }
+    Err(ApiError::InvalidRequest(format!(
+        "model '{}' does not support tokenization",
+        model
+    )))
+}
+
+pub async fn tokenize(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<TokenizeRequest>,
+) -> Response {
+    let (model, loaded) = match open(&state, req.model.as_deref()).await {
+        Ok(opened) => opened,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = tokenizer(&model, loaded.as_ref()) {
+        return e.into_response();
+    }
+    let tokens = match loaded.tokenize(&req.content, req.add_special) {
+        Ok(tokens) => tokens,
+        Err(e) => return ApiError::InvalidRequest(e.to_string()).into_response(),
+    };
+    if !req.with_pieces {
+        return Json(json!({ "tokens": tokens })).into_response();
+    }
+    let pieces: Vec<TokenPiece> = tokens
+        .into_iter()
+        .map(|id| TokenPiece {
+            id,
+            piece: loaded.detokenize(&[id]).unwrap_or_default(),
+        })
+        .collect();
+    Json(json!({ "tokens": pieces })).into_response()
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DetokenizeRequest {
+    pub model: Option<String>,
+    pub tokens: Vec<u32>,
+}
+
+pub async fn detokenize(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<DetokenizeRequest>,
+) -> Response {
+    let (model, loaded) = match open(&state, req.model.as_deref()).await {
+        Ok(opened) => opened,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = tokenizer(&model, loaded.as_ref()) {
+        return e.into_response();
+    }
+    match loaded.detokenize(&req.tokens) {
+        Ok(content) => Json(json!({ "content": content })).into_response(),
+        Err(e) => ApiError::InvalidRequest(e.to_string()).into_response(),
+    }
+}
+
+/// `?model=` on `/props` and `/slots`
+#[derive(Debug, Default, Deserialize)]
+pub struct ModelQuery {
+    pub model: Option<String>,
+}
+
+/// A model's one slot
+#[derive(Debug, Serialize)]
+pub struct Slot {
+    pub id: usize,
+    /// -1 when idle, as upstream; shimmy does not number tasks, so 0 while busy
+    pub id_task: i64,
+    pub n_ctx: usize,
+    pub is_processing: bool,
+    pub model: String,
+    pub params: Settings,
+}
+
+/// The slot of `model`, whose context length is known once it has been
+/// loaded and else comes from its config
+fn slot(state: &AppState, model: &str) -> Result<(Slot, ModelSpec), ApiError> {
+    let spec = state
+        .registry
+        .to_spec(model)
+        .ok_or_else(|| ApiError::ModelNotFound(model.to_string()))?;
+    let n_ctx = match state.registry.capabilities(model) {
+        Some(caps) if caps.context_length > 0 => caps.context_length,
+        _ => spec.ctx_len,
+    };
+    let defaults = CompletionRequest::default();
+    let is_processing = state.slots.is_processing(model);
+    let slot = Slot {
+        id: 0,
+        id_task: if is_processing { 0 } else { -1 },
+        n_ctx,
+        is_processing,
+        model: model.to_string(),
+        params: defaults.settings(&defaults.options()),
+    };
+    Ok((slot, spec))
+}
+
+pub async fn props(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ModelQuery>,
+) -> Response {
+    let props = model_name(&state, query.model.as_deref()).and_then(|model| {
+        let (slot, spec) = slot(&state, &model)?;
+        let chat_template = spec
+            .template
+            .clone()
+            .unwrap_or_else(|| format!("{:?}", template_family(&spec, &model)).to_lowercase());
+        Ok(json!({
+            "default_generation_settings": slot,
+            "total_slots": 1,
+            "model_path": spec.base_path.display().to_string(),
+            "chat_template": chat_template,
+            "build_info": format!("shimmy {}", env!("CARGO_PKG_VERSION")),
+        }))
+    });
+    match props {
+        Ok(props) => Json(props).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+pub async fn slots(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ModelQuery>,
+) -> Response {
+    match model_name(&state, query.model.as_deref()).and_then(|model| slot(&state, &model)) {
+        Ok((slot, _)) => Json(vec![slot]).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Capabilities;
+    use crate::test_utils::{body, json_body, json_request, FakeModel};
+
+    fn scripted(pieces: &[&str], grammar: bool) -> FakeModel {
+        FakeModel::new(pieces).with_capabilities(Capabilities {
+            logprobs: true,
+            grammar,
+            tokenize: true,
+            ..Capabilities::text(128)
+        })
+    }
+
+    async fn send(state: &Arc<AppState>, request: Value) -> (axum::http::StatusCode, Value) {
+        json_body(completion(State(Arc::clone(state)), json_request(request)).await).await
+    }
+
+    #[test]
+    fn test_prompt_pieces() {
+        assert_eq!(
+            prompt_pieces(&json!("hi")).unwrap(),
+            vec![vec![Piece::Text("hi".to_string())]]
+        );
+        assert_eq!(
+            prompt_pieces(&json!([12, "a", 34])).unwrap(),
+            vec![vec![
+                Piece::Token(12),
+                Piece::Text("a".to_string()),
+                Piece::Token(34)
+            ]]
+        );
+        assert_eq!(prompt_pieces(&json!(["a", [5], "b"])).unwrap().len(), 3);
+        assert!(prompt_pieces(&Value::Null).is_err());
+        assert!(prompt_pieces(&json!([])).is_err());
+        assert!(prompt_pieces(&json!([1, {"x": 1}])).is_err());
+
+        let loaded = scripted(&[], false);
+        let pieces = prompt_pieces(&json!([1, 104, 105, " there"])).unwrap();
+        assert_eq!(prompt_text(&pieces[0], &loaded).unwrap(), "hi there");
+    }
+
+    #[tokio::test]
+    async fn test_completion_stops_on_word_and_reports_summary() {
+        let model = scripted(&["Hel", "lo", " wor", "ld"], false);
+        let state = model.state("qwen-coder", Some("chatml"));
+        let (status, response) = send(
+            &state,
+            json!({
+                "prompt": [1, 72, 105], "n_predict": 8, "stop": ["wor"],
+                "return_tokens": true, "n_probs": 1, "slot_id": 0
+            }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(model.prompt(), "Hi");
+        assert_eq!(response["content"], "Hello ");
+        assert_eq!(response["stop"], true);
+        assert_eq!(response["stop_type"], "word");
+        assert_eq!(response["stopping_word"], "wor");
+        assert_eq!(response["tokens"], json!([100, 101, 102]));
+        assert_eq!(response["completion_probabilities"][0]["token"], "Hel");
+        assert_eq!(response["completion_probabilities"][0]["logprob"], -0.5);
+        assert_eq!(response["model"], "qwen-coder");
+        assert_eq!(response["tokens_evaluated"], 3);
+        assert_eq!(response["generation_settings"]["n_predict"], 8);
+        assert_eq!(response["timings"]["predicted_n"], 3);
+
+        let (status, response) = send(&state, json!({"prompt": ["a", "b"]})).await;
+        assert_eq!(status, 200);
+        assert_eq!(response[1]["index"], 1);
+        assert_eq!(response[1]["content"], "Hello world");
+        assert_eq!(response[1]["stop_type"], "eos");
+        assert!(response[1].get("tokens").is_none());
+
+        let (status, _) = send(&state, json!({"prompt": ["a", "b"], "stream": true})).await;
+        assert_eq!(status, 400);
+        let (status, _) = send(&state, json!({"prompt": "a", "id_slot": 2})).await;
+        assert_eq!(status, 400);
+        let (status, _) = send(&state, json!({"prompt": "a", "grammar": "root ::= \"x\""})).await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn test_completion_passes_grammar_on() {
+        let model = scripted(&["x"], true);
+        let state = model.state("qwen-coder", Some("chatml"));
+        let (status, response) =
+            send(&state, json!({"prompt": "a", "grammar": "root ::= \"x\""})).await;
+        assert_eq!(status, 200);
+        assert_eq!(response["generation_settings"]["grammar"], "root ::= \"x\"");
+
+        let schema = json!({"type": "object", "properties": {"a": {"type": "integer"}}});
+        let (status, response) = send(&state, json!({"prompt": "a", "json_schema": schema})).await;
+        assert_eq!(status, 200);
+        let grammar = response["generation_settings"]["grammar"].as_str().unwrap();
+        assert_eq!(grammar, json_grammar::from_schema(&schema));
+        assert_eq!(model.options().grammar.as_deref(), Some(grammar));
+    }
+
+    #[tokio::test]
+    async fn test_completion_streams_chunks() {
+        let state = scripted(&["Hel", "lo"], false).state("qwen-coder", Some("chatml"));
+        let request = json_request(json!({"prompt": "Hi", "stream": true}));
+        let (status, body) = body(completion(State(state), request).await).await;
+        assert_eq!(status, 200);
+        let chunks: Vec<Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0]["content"], "Hel");
+        assert_eq!(chunks[0]["stop"], false);
+        assert!(chunks[0].get("timings").is_none());
+        assert_eq!(chunks[2]["content"], "");
+        assert_eq!(chunks[2]["stop"], true);
+        assert_eq!(chunks[2]["tokens_predicted"], 2);
+        assert!(!body.contains("[DONE]"));
+    }
+
+    #[tokio::test]
+    async fn test_tokenize_props_and_slots() {
+        let state = scripted(&[], false).state("qwen-coder", Some("chatml"));
+        let request = json_request(json!({"content": "hi", "with_pieces": true}));
+        let (_, tokens) = body(tokenize(State(Arc::clone(&state)), request).await).await;
+        let tokens: Value = serde_json::from_str(&tokens).unwrap();
+        assert_eq!(
+            tokens["tokens"],
+            json!([{"id": 104, "piece": "h"}, {"id": 105, "piece": "i"}])
+        );
+
+        let request = json_request(json!({"tokens": [1, 104, 105]}));
+        let (_, content) = body(detokenize(State(Arc::clone(&state)), request).await).await;
+        assert_eq!(content, r#"{"content":"hi"}"#);
+
+        let query = Query(ModelQuery::default());
+        let (_, props) = body(props(State(Arc::clone(&state)), query).await).await;
+        let props: Value = serde_json::from_str(&props).unwrap();
+        assert_eq!(props["total_slots"], 1);
+        assert_eq!(props["chat_template"], "chatml");
+        assert_eq!(props["default_generation_settings"]["n_ctx"], 128);
+
+        let query = Query(ModelQuery {
+            model: Some("missing".to_string()),
+        });
+        let (status, _) = body(slots(State(Arc::clone(&state)), query).await).await;
+        assert_eq!(status, 404);
+        let (status, slots) = body(slots(State(state), Query(ModelQuery::default())).await).await;
+        assert_eq!(status, 200);
+        let slots: Value = serde_json::from_str(&slots).unwrap();
+        assert_eq!(slots[0]["id"], 0);
+        assert_eq!(slots[0]["is_processing"], false);
+        assert_eq!(slots[0]["params"]["n_predict"], -1);
+    }
+
+    #[tokio::test]
+    async fn test_slots_are_busy_per_server() {
+        let state = scripted(&["hi"], false).state("m", Some("chatml"));
+        let other = scripted(&["hi"], false).state("m", Some("chatml"));
+        let busy = state.slots.acquire("m");
+
+        let is_processing = |state: &Arc<AppState>| {
+            let state = Arc::clone(state);
+            async move {
+                let (_, slots) =
+                    body(slots(State(state), Query(ModelQuery::default())).await).await;
+                serde_json::from_str::<Value>(&slots).unwrap()[0]["is_processing"].clone()
+            }
+        };
+        assert_eq!(is_processing(&state).await, true);
+        assert_eq!(is_processing(&other).await, false);
+        drop(busy);
+        assert_eq!(is_processing(&state).await, false);
+    }
+
+    #[tokio::test]
+    async fn test_infill_uses_fim_markers() {
+        let request = json!({
+            "input_prefix": "fn main() {", "input_suffix": "}",
+            "input_extra": [{"filename": "lib.rs", "text": "// lib"}], "n_predict": 4
+        });
+        // The prompt is built here, so the engine needs no infill support
+        let model = scripted(&["x"], false);
+        let coder = model.state("qwen-coder", Some("chatml"));
+        let (status, response) = body(infill(State(coder), json_request(request)).await).await;
+        assert_eq!(status, 200);
+        assert!(response.contains(r#""content":"x""#));
+        assert_eq!(
+            model.prompt(),
+            "<|fim_prefix|>lib.rs\n// lib\nfn main() {<|fim_suffix|>}<|fim_middle|>"
+        );
+    }
+}
@@ -0,0 +1,433 @@
+// Models kept in memory between requests
+// The first request for a model loads it; later requests reuse the same
+// instance, and with it the model's weights, inference queue and worker
+// process. A model stays loaded for its keep-alive after the request that
+// last used it, five minutes unless `SHIMMY_KEEP_ALIVE` or Ollama's
+// `keep_alive` say otherwise. Expired models are dropped once no request
+// is still generating with them.
+
+use crate::engine::{InferenceEngine, LoadedModel, ModelSpec};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+/// How long a model stays loaded after a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepAlive {
+    /// Until the server stops or the model is unloaded
+    Forever,
+    /// Zero unloads the model once the request is done
+    For(Duration),
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive::For(Duration::from_secs(5 * 60))
+    }
+}
+
+impl KeepAlive {
+    /// A duration as Ollama takes it: seconds, or a string such as "30s",
+    /// "5m" or "1h30m". Negative values keep the model loaded indefinitely.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if let Ok(secs) = value.parse::<f64>() {
+            return Self::from_secs(secs);
+        }
+        let (negative, mut rest) = match value.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, value.strip_prefix('+').unwrap_or(value)),
+        };
+        if rest.is_empty() {
+            return None;
+        }
+        let mut secs = 0.0;
+        while !rest.is_empty() {
+            let digits = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+            let (number, tail) = rest.split_at(digits);
+            let unit_len = tail
+                .find(|c: char| c.is_ascii_digit() || c == '.')
+                .unwrap_or(tail.len());
+            let (unit, tail) = tail.split_at(unit_len);
+            let scale = match unit {
+                "ns" => 1e-9,
+                "us" | "µs" => 1e-6,
+                "ms" => 1e-3,
+                "s" => 1.0,
+                "m" => 60.0,
+                "h" => 3600.0,
+                _ => return None,
+            };
+            secs += number.parse::<f64>().ok()? * scale;
+            rest = tail;
+        }
+        Self::from_secs(if negative { -secs } else { secs })
+    }
+
+    /// `secs` seconds, or forever when negative
+    pub fn from_secs(secs: f64) -> Option<Self> {
+        if secs < 0.0 {
+            return Some(KeepAlive::Forever);
+        }
+        Duration::try_from_secs_f64(secs).ok().map(KeepAlive::For)
+    }
+
+    fn unloads(self) -> bool {
+        self == KeepAlive::For(Duration::ZERO)
+    }
+
+    fn expiry(self, now: SystemTime) -> Option<SystemTime> {
+        match self {
+            KeepAlive::Forever => None,
+            KeepAlive::For(duration) => now.checked_add(duration),
+        }
+    }
+}
+
+struct Entry {
+    model: Arc<OnceCell<Arc<dyn LoadedModel>>>,
+    /// `None` keeps the model until it is unloaded
+    expires_at: Option<SystemTime>,
+}
+
+impl Entry {
+    /// Whether a request is still holding the model
+    fn in_use(&self) -> bool {
+        self.model
+            .get()
+            .is_some_and(|model| Arc::strong_count(model) > 1)
+    }
+}
+
+/// A model held in memory, as `/api/ps` lists it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resident {
+    pub name: String,
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Loaded models by registry name
+pub struct LoadedModels {
+    keep_alive: KeepAlive,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl LoadedModels {
+    /// Models are kept for `keep_alive` unless a request asks otherwise
+    pub fn new(keep_alive: KeepAlive) -> Self {
+        Self {
+            keep_alive,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep-alive from `SHIMMY_KEEP_ALIVE`, else the five minute default
+    pub fn from_env() -> Self {
+        let keep_alive = match std::env::var("SHIMMY_KEEP_ALIVE") {
+            Ok(value) => KeepAlive::parse(&value).unwrap_or_else(|| {
+                warn!(%value, "Ignoring invalid SHIMMY_KEEP_ALIVE");
+                KeepAlive::default()
+            }),
+            Err(_) => KeepAlive::default(),
+        };
+        Self::new(keep_alive)
+    }
+
+    /// The loaded `spec`, loading it with `engine` if no request has yet.
+    /// Concurrent first requests wait for one load. `keep_alive` replaces
+    /// the default for this request; zero unloads the model afterwards.
+    pub async fn get_or_load(
+        &self,
+        engine: &dyn InferenceEngine,
+        spec: &ModelSpec,
+        keep_alive: Option<KeepAlive>,
+    ) -> Result<Arc<dyn LoadedModel>> {
+        let keep_alive = keep_alive.unwrap_or(self.keep_alive);
+        let cell = {
+            let mut entries = self.entries.lock().unwrap();
+            let now = SystemTime::now();
+            evict(&mut entries, now);
+            let entry = entries.entry(spec.name.clone()).or_insert_with(|| Entry {
+                model: Arc::default(),
+                expires_at: None,
+            });
+            entry.expires_at = keep_alive.expiry(now);
+            Arc::clone(&entry.model)
+        };
+        let loaded = cell
+            .get_or_try_init(|| async {
+                info!(model = %spec.name, "Loading model");
+                engine.load(spec).await.map(Arc::from)
+            })
+            .await
+            .map(Arc::clone);
+        if loaded.is_err() || keep_alive.unloads() {
+            self.remove(&spec.name, &cell);
+        }
+        loaded
+    }
+
+    /// Drop `name` from memory once the requests using it are done.
+    /// Returns whether it was loaded.
+    pub fn unload(&self, name: &str) -> bool {
+        let removed = self.entries.lock().unwrap().remove(name);
+        removed.is_some_and(|entry| entry.model.initialized())
+    }
+
+    /// Models in memory, by name
+    pub fn loaded(&self) -> Vec<Resident> {
+        let mut entries = self.entries.lock().unwrap();
+        evict(&mut entries, SystemTime::now());
+        let mut loaded: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| entry.model.initialized())
+            .map(|(name, entry)| Resident {
+                name: name.clone(),
+                expires_at: entry.expires_at,
+            })
+            .collect();
+        loaded.sort_by(|a, b| a.name.cmp(&b.name));
+        loaded
+    }
+
+    /// Drop models whose keep-alive has run out and that no request is using
+    pub fn evict_expired(&self) {
+        evict(&mut self.entries.lock().unwrap(), SystemTime::now());
+    }
+
+    /// Remove `name` if it is still the entry holding `cell`
+    fn remove(&self, name: &str, cell: &Arc<OnceCell<Arc<dyn LoadedModel>>>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .get(name)
+            .is_some_and(|entry| Arc::ptr_eq(&entry.model, cell))
+        {
+            entries.remove(name);
+        }
+    }
+}
+
+fn evict(entries: &mut HashMap<String, Entry>, now: SystemTime) {
+    entries.retain(|name, entry| {
+        let keep = entry.expires_at.is_none_or(|at| at > now) || entry.in_use();
+        if !keep {
+            info!(model = %name, "Unloading model after its keep-alive");
+        }
+        keep
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::executor::ModelExecutor;
+    use crate::engine::GenOptions;
+    use crate::error::ShimmyError;
+    use crate::test_utils::FakeModel;
+    use anyhow::anyhow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    fn spec(name: &str) -> ModelSpec {
+        ModelSpec {
+            name: name.to_string(),
+            base_path: format!("./{}.gguf", name).into(),
+            lora_path: None,
+            template: None,
+            ctx_len: 64,
+            n_threads: None,
+            backend: None,
+            command: None,
+        }
+    }
+
+    fn names(models: &LoadedModels) -> Vec<String> {
+        models
+            .loaded()
+            .into_iter()
+            .map(|model| model.name)
+            .collect()
+    }
+
+    struct Broken;
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for Broken {
+        async fn load(&self, _spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+            Err(anyhow!("corrupt"))
+        }
+    }
+
+    /// A model that decodes on its own inference thread, each generation
+    /// waiting for a release, as the real backends' models do
+    struct Queued {
+        executor: ModelExecutor,
+        started: mpsc::Sender<()>,
+        release: Arc<Mutex<mpsc::Receiver<()>>>,
+        running: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl LoadedModel for Queued {
+        async fn generate(
+            &self,
+            prompt: &str,
+            _opts: GenOptions,
+            _on_token: Option<Box<dyn FnMut(String) + Send>>,
+        ) -> Result<String> {
+            let (started, release) = (self.started.clone(), Arc::clone(&self.release));
+            let (running, peak) = (Arc::clone(&self.running), Arc::clone(&self.peak));
+            let prompt = prompt.to_string();
+            self.executor
+                .run(move || {
+                    peak.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    started.send(()).unwrap();
+                    release.lock().unwrap().recv().unwrap();
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    prompt
+                })
+                .await
+        }
+    }
+
+    struct QueuedEngine {
+        loads: AtomicUsize,
+        started: mpsc::Sender<()>,
+        release: Arc<Mutex<mpsc::Receiver<()>>>,
+        running: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl InferenceEngine for QueuedEngine {
+        async fn load(&self, spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+            self.loads.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(Queued {
+                executor: ModelExecutor::with_queue_depth(&spec.name, 1)?,
+                started: self.started.clone(),
+                release: Arc::clone(&self.release),
+                running: Arc::clone(&self.running),
+                peak: Arc::clone(&self.peak),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_keep_alive_parses_ollama_durations() {
+        let secs = |s| Some(KeepAlive::For(Duration::from_secs(s)));
+        assert_eq!(KeepAlive::parse("5m"), secs(300));
+        assert_eq!(KeepAlive::parse("1h30m"), secs(5400));
+        assert_eq!(KeepAlive::parse("300"), secs(300));
+        assert_eq!(KeepAlive::parse("0s"), secs(0));
+        assert_eq!(
+            KeepAlive::parse("1.5s"),
+            Some(KeepAlive::For(Duration::from_millis(1500)))
+        );
+        assert_eq!(KeepAlive::parse("-1"), Some(KeepAlive::Forever));
+        assert_eq!(KeepAlive::parse("-5m"), Some(KeepAlive::Forever));
+        for invalid in ["", "m", "5 minutes", "5d", "inf"] {
+            assert_eq!(KeepAlive::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_models_load_once_and_stay_until_unloaded() {
+        let fake = FakeModel::new(&["hi"]);
+        let models = LoadedModels::new(KeepAlive::default());
+        let a = spec("a");
+        let (first, second) = tokio::join!(
+            models.get_or_load(&fake, &a, None),
+            models.get_or_load(&fake, &a, None)
+        );
+        assert!(Arc::ptr_eq(&first.unwrap(), &second.unwrap()));
+        assert_eq!(fake.loads(), 1);
+        let loaded = models.loaded();
+        assert_eq!(names(&models), ["a"]);
+        let expires_at = loaded[0].expires_at.unwrap();
+        assert!(expires_at > SystemTime::now() + Duration::from_secs(240));
+
+        assert!(models.unload("a"));
+        assert!(!models.unload("a"));
+        assert!(models.loaded().is_empty());
+        models.get_or_load(&fake, &a, None).await.unwrap();
+        assert_eq!(fake.loads(), 2);
+
+        let err = models.get_or_load(&Broken, &spec("b"), None).await.err();
+        assert_eq!(err.unwrap().to_string(), "corrupt");
+        assert_eq!(names(&models), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_models_unload_after_their_keep_alive() {
+        let fake = FakeModel::new(&[]);
+        let models = LoadedModels::new(KeepAlive::default());
+        let zero = Some(KeepAlive::For(Duration::ZERO));
+        // The request still holds the model, but later ones load it again
+        let _model = models.get_or_load(&fake, &spec("a"), zero).await.unwrap();
+        assert!(models.loaded().is_empty());
+
+        let forever = Some(KeepAlive::Forever);
+        models
+            .get_or_load(&fake, &spec("b"), forever)
+            .await
+            .unwrap();
+        let brief = Some(KeepAlive::For(Duration::from_millis(1)));
+        let held = models.get_or_load(&fake, &spec("c"), brief).await.unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        models.evict_expired();
+        // Expired, but not while a request is generating with it
+        assert_eq!(names(&models), ["b", "c"]);
+        drop(held);
+        models.evict_expired();
+        assert_eq!(names(&models), ["b"]);
+        assert_eq!(models.loaded()[0].expires_at, None);
+    }
+
+    #[tokio::test]
+    async fn test_requests_share_one_inference_queue_per_model() {
+        let (started, started_rx) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        let engine = QueuedEngine {
+            loads: AtomicUsize::new(0),
+            started,
+            release: Arc::new(Mutex::new(release_rx)),
+            running: Arc::default(),
+            peak: Arc::default(),
+        };
+        let models = LoadedModels::new(KeepAlive::default());
+        let spec = spec("queued");
+        let request = |prompt: &'static str| {
+            let models = &models;
+            let (engine, spec) = (&engine, &spec);
+            async move {
+                let model = models.get_or_load(engine, spec, None).await.unwrap();
+                model.generate(prompt, GenOptions::default(), None).await
+            }
+        };
+
+        let mut first = Box::pin(request("first"));
+        assert!(futures_util::poll!(&mut first).is_pending());
+        started_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        // Queued behind the first on the same thread, not run beside it
+        let mut second = Box::pin(request("second"));
+        assert!(futures_util::poll!(&mut second).is_pending());
+        assert!(started_rx.try_recv().is_err());
+
+        let err = request("third").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ShimmyError>(),
+            Some(ShimmyError::Overloaded { model }) if model == "queued"
+        ));
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        assert_eq!(first.await.unwrap(), "first");
+        assert_eq!(second.await.unwrap(), "second");
+        assert_eq!(engine.loads.load(Ordering::SeqCst), 1);
+        assert_eq!(engine.peak.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,14 +1,26 @@
+mod anthropic_compat;
 mod api;
 mod api_errors;
 mod auto_discovery;
 mod cli;
 mod engine;
+mod error;
+mod json_grammar;
+mod llama_server_compat;
+mod loaded_models;
 mod main_integration;
 mod model_registry;
+mod ollama_compat;
 mod openai_compat;
 mod port_manager;
+mod responses_compat;
 mod server;
 mod templates;
+#[cfg(test)]
+#[allow(dead_code)]
+mod test_utils;
+mod tool_calling;
+mod tools;
 mod util {
     pub mod diag;
 }
@@ -22,10 +34,60 @@ use tracing::info;
 pub struct AppState {
     pub engine: Box<dyn engine::InferenceEngine>,
     pub registry: Registry,
+    /// Models kept in memory between requests
+    pub models: loaded_models::LoadedModels,
+    /// Responses kept for `previous_response_id`
+    pub responses: responses_compat::ResponseStore,
+    /// Generations in flight through the llama.cpp server endpoints
+    pub slots: llama_server_compat::BusySlots,
+}
+
+impl AppState {
+    /// State whose models stay loaded for `SHIMMY_KEEP_ALIVE`
+    pub fn new(engine: Box<dyn engine::InferenceEngine>, registry: Registry) -> Self {
+        Self {
+            engine,
+            registry,
+            models: loaded_models::LoadedModels::from_env(),
+            responses: responses_compat::ResponseStore::default(),
+            slots: llama_server_compat::BusySlots::default(),
+        }
+    }
+}
+
+/// The adapter that routes each model to its backend, running generations
+/// in worker processes when `isolate` is set
+fn inference_engine(isolate: bool) -> anyhow::Result<Box<dyn engine::InferenceEngine>> {
+    let mut adapter = engine::adapter::InferenceEngineAdapter::new();
+    if isolate {
+        adapter = adapter.isolated(std::env::current_exe()?);
+    }
+    Ok(Box::new(adapter))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = cli::Cli::parse();
+
+    // A worker's stdout carries the protocol, so its logs go to stderr
+    if let cli::Command::Worker {
+        name,
+        backend,
+        threads,
+    } = cli.cmd
+    {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .with_writer(std::io::stderr)
+            .init();
+        let options = engine::isolation::WorkerOptions {
+            name,
+            backend,
+            n_threads: threads,
+        };
+        return engine::isolation::run_worker(options).await;
+    }
+
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
@@ -34,8 +96,6 @@ async fn main() -> anyhow::Result<()> {
     #[cfg(all(target_arch = "aarch64", target_os = "macos", not(feature = "llama")))]
     info!("llama.cpp temporarily disabled on macOS ARM64 due to upstream i8mm build incompatibility; using SafeTensors backend");
 
-    let cli = cli::Cli::parse();
-
     // Add custom model directories from command line to environment
     if let Some(model_dirs) = &cli.model_dirs {
         std::env::set_var("SHIMMY_MODEL_PATHS", model_dirs);
@@ -54,15 +114,17 @@ async fn main() -> anyhow::Result<()> {
         template: Some("chatml".into()),
         ctx_len: Some(4096),
         n_threads: None,
+        backend: None,
+        command: None,
     });
+    if let Some(path) = &cli.config {
+        let count = reg.load_config(path)?;
+        info!(path = %path.display(), count, "Registered models from config");
+    }
 
-    let engine: Box<dyn engine::InferenceEngine> =
-        Box::new(engine::adapter::InferenceEngineAdapter::new());
-    let state = AppState {
-        engine,
-        registry: reg,
-    };
-    let state = Arc::new(state);
+    let isolate = matches!(cli.cmd, cli::Command::Serve { isolate: true, .. });
+    let engine = inference_engine(isolate)?;
+    let state = Arc::new(AppState::new(engine, reg));
 
     match cli.cmd {
         cli::Command::Serve { .. } => {
@@ -75,10 +137,8 @@ async fn main() -> anyhow::Result<()> {
             let manual_count = state.registry.list().len();
             if manual_count <= 1 {
                 // Only the default phi3-lora entry
-                let mut enhanced_state = AppState {
-                    engine: Box::new(engine::llama::LlamaEngine::new()),
-                    registry: state.registry.clone(),
-                };
+                let mut enhanced_state =
+                    AppState::new(inference_engine(isolate)?, state.registry.clone());
                 enhanced_state.registry.auto_register_discovered();
                 let enhanced_state = Arc::new(enhanced_state);
 
@@ -201,22 +261,29 @@ async fn main() -> anyhow::Result<()> {
             let Some(spec) = state.registry.to_spec(&name) else {
                 anyhow::bail!("no model {name}");
             };
-            let loaded = state.engine.load(&spec).await?;
+            let loaded: Arc<dyn engine::LoadedModel> = state.engine.load(&spec).await?.into();
             let t0 = std::time::Instant::now();
-            let out = loaded
-                .generate(
-                    "Say hi.",
-                    engine::GenOptions {
-                        max_tokens,
-                        stream: false,
-                        ..Default::default()
-                    },
-                    None,
-                )
-                .await?;
+            let opts = engine::GenOptions {
+                max_tokens,
+                stream: false,
+                ..Default::default()
+            };
+            let done =
+                engine::stream::complete(loaded.generate_stream("Say hi.".into(), opts), |_| {})
+                    .await?;
             let elapsed = t0.elapsed();
+            let out = &done.text;
             println!("bench output (truncated): {}", &out[..out.len().min(120)]);
             println!("elapsed: {:?}", elapsed);
+            let stats = &done.stats;
+            println!(
+                "tokens: {} prompt, {} generated ({}); prompt {} ms, total {} ms",
+                stats.prompt_tokens,
+                stats.completion_tokens,
+                done.finish_reason.as_str(),
+                stats.prompt_ms,
+                stats.total_ms
+            );
         }
         cli::Command::Generate {
             name,
@@ -226,20 +293,23 @@ async fn main() -> anyhow::Result<()> {
             let Some(spec) = state.registry.to_spec(&name) else {
                 anyhow::bail!("no model {name}");
             };
-            let loaded = state.engine.load(&spec).await?;
-            let out = loaded
-                .generate(
-                    &prompt,
-                    engine::GenOptions {
-                        max_tokens,
-                        stream: false,
-                        ..Default::default()
-                    },
-                    None,
-                )
-                .await?;
-            println!("{}", out);
+            let loaded: Arc<dyn engine::LoadedModel> = state.engine.load(&spec).await?.into();
+            let opts = engine::GenOptions {
+                max_tokens,
+                stream: false,
+                ..Default::default()
+            };
+            // Print tokens as they arrive
+            use std::io::Write;
+            let mut stdout = std::io::stdout();
+            engine::stream::complete(loaded.generate_stream(prompt, opts), |text| {
+                let _ = write!(stdout, "{}", text);
+                let _ = stdout.flush();
+            })
+            .await?;
+            println!();
         }
+        cli::Command::Worker { .. } => unreachable!("workers start before the registry"),
     }
     Ok(())
 }
@@ -298,6 +368,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(4096),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         // Test engine creation (line 42)
@@ -305,10 +377,7 @@ mod tests {
             Box::new(engine::adapter::InferenceEngineAdapter::new());
 
         // Test state creation (lines 43-44)
-        let state = AppState {
-            engine,
-            registry: reg,
-        };
+        let state = AppState::new(engine, reg);
         let _state_arc = Arc::new(state);
 
         assert!(true); // We reached here without panicking
@@ -380,6 +449,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let manual_models = registry.list();
@@ -432,6 +503,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = MockEngine;
@@ -469,6 +542,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = MockEngine;
@@ -519,6 +594,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = MockEngine;
@@ -589,7 +666,7 @@ mod tests {
         // Test state creation paths
         let registry = Registry::with_discovery();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = std::sync::Arc::new(crate::AppState { engine, registry });
+        let state = std::sync::Arc::new(crate::AppState::new(engine, registry));
 
         // Validate state is properly created
         assert_ne!(std::mem::size_of_val(&state), 0);
@@ -602,10 +679,10 @@ mod tests {
         // Test enhanced state creation for serve command (lines 53-58)
         let registry = model_registry::Registry::with_discovery();
 
-        let mut enhanced_state = AppState {
-            engine: Box::new(engine::llama::LlamaEngine::new()),
-            registry: registry.clone(),
-        };
+        let mut enhanced_state = AppState::new(
+            Box::new(engine::llama::LlamaEngine::new()),
+            registry.clone(),
+        );
 
         // Test auto-registration call (line 57)
         enhanced_state.registry.auto_register_discovered();
@@ -643,6 +720,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(4096),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let models = reg.list();
@@ -671,6 +750,8 @@ mod tests {
             template: None,
             ctx_len: None,
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let after_count = registry.list().len();
@@ -691,7 +772,7 @@ mod tests {
 
         let engine: Box<dyn engine::InferenceEngine> = Box::new(InferenceEngineAdapter::new());
         let registry = Registry::with_discovery();
-        let state = AppState { engine, registry };
+        let state = AppState::new(engine, registry);
 
         assert!(state.registry.list().len() >= 0);
     }
@@ -723,14 +804,13 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(4096),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine: Box<dyn engine::InferenceEngine> =
             Box::new(engine::adapter::InferenceEngineAdapter::new());
-        let state = AppState {
-            engine,
-            registry: reg,
-        };
+        let state = AppState::new(engine, reg);
         let state = Arc::new(state);
 
         // Simulate serve command logic with dynamic port allocation
@@ -746,10 +826,10 @@ mod tests {
 
         if manual_count <= 1 {
             // Simulate enhanced state creation (lines 53-58)
-            let mut enhanced_state = AppState {
-                engine: Box::new(engine::llama::LlamaEngine::new()),
-                registry: state.registry.clone(),
-            };
+            let mut enhanced_state = AppState::new(
+                Box::new(engine::llama::LlamaEngine::new()),
+                state.registry.clone(),
+            );
             enhanced_state.registry.auto_register_discovered();
             let enhanced_state_arc = Arc::new(enhanced_state);
 
@@ -794,12 +874,14 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
         let engine = MockEngine;
-        let state = Arc::new(AppState {
-            engine: Box::new(engine::adapter::InferenceEngineAdapter::new()),
-            registry: reg,
-        });
+        let state = Arc::new(AppState::new(
+            Box::new(engine::adapter::InferenceEngineAdapter::new()),
+            reg,
+        ));
 
         // Test List command branch (lines 86-121)
         {
@@ -980,6 +1062,8 @@ mod tests {
             template: None,
             ctx_len: None,
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         // Test maximal entry
@@ -990,6 +1074,8 @@ mod tests {
             template: Some("llama3".to_string()),
             ctx_len: Some(8192),
             n_threads: Some(8),
+            backend: None,
+            command: None,
         });
 
         let models = registry.list();
@@ -1020,6 +1106,8 @@ mod tests {
             template: None,
             ctx_len: 1024,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         let loaded = engine.load(&minimal_spec).await.unwrap();
@@ -1107,10 +1195,10 @@ mod tests {
         // This should be <= 1 and trigger enhanced state creation
         if manual_count <= 1 {
             // Simulate enhanced state logic (lines 53-58)
-            let mut enhanced_state = AppState {
-                engine: Box::new(engine::llama::LlamaEngine::new()),
-                registry: empty_registry.clone(),
-            };
+            let mut enhanced_state = AppState::new(
+                Box::new(engine::llama::LlamaEngine::new()),
+                empty_registry.clone(),
+            );
 
             // Test auto-register call
             enhanced_state.registry.auto_register_discovered();
@@ -1214,6 +1302,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = MockEngine;
@@ -1249,6 +1339,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = MockEngine;
@@ -1359,6 +1451,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         // Create an engine that might fail
@@ -52,6 +52,8 @@ mod tests {
             template: Some("chatml".to_string()),
             ctx_len: Some(4096),
             n_threads: Some(4),
+            backend: None,
+            command: None,
         };
 
         registry.register(test_entry);
@@ -92,6 +94,8 @@ mod tests {
             template: None,
             ctx_len: None,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         registry1_mut.register(test_entry);
@@ -175,6 +179,8 @@ mod tests {
             template: Some("llama3".to_string()),
             ctx_len: Some(8192),
             n_threads: Some(8),
+            backend: None,
+            command: None,
         };
 
         registry_mut.register(production_model);
@@ -217,6 +223,8 @@ mod tests {
             template: Some("chatml".to_string()),
             ctx_len: Some(2048),
             n_threads: Some(2),
+            backend: None,
+            command: None,
         };
 
         registry.register(test_model);
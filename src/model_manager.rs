@@ -87,6 +87,8 @@ mod tests {
             template: None,
             ctx_len: 2048,
             n_threads: None,
+            backend: None,
+            command: None,
         }
     }
 
@@ -120,6 +122,8 @@ mod tests {
             template: None,
             ctx_len: 2048,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         let result = manager.load_model("test-model".to_string(), spec).await;
@@ -174,6 +178,8 @@ mod tests {
             template: None,
             ctx_len: 2048,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         manager
@@ -1,7 +1,10 @@
-use super::engine::ModelSpec;
+use super::engine::{Capabilities, ModelSpec};
 use crate::auto_discovery::{DiscoveredModel, ModelAutoDiscovery};
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelEntry {
@@ -11,12 +14,27 @@ pub struct ModelEntry {
     pub template: Option<String>,
     pub ctx_len: Option<usize>,
     pub n_threads: Option<i32>,
+    /// `"exec"` to serve the model through an external engine; otherwise the
+    /// backend is detected from `base_path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    /// Executable and arguments for `backend = "exec"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<Vec<String>>,
+}
+
+/// A model config file, as `--config` takes it
+#[derive(Debug, Deserialize)]
+struct ModelConfig {
+    models: Vec<ModelEntry>,
 }
 
 #[derive(Default, Clone)]
 pub struct Registry {
     inner: HashMap<String, ModelEntry>,
     pub discovered_models: HashMap<String, DiscoveredModel>,
+    // What each model reported when it was last loaded; shared between clones
+    capabilities: Arc<RwLock<HashMap<String, Capabilities>>>,
 }
 
 // Alias for backward compatibility and mission expectations
@@ -27,6 +45,7 @@ impl Registry {
         Self {
             inner: HashMap::new(),
             discovered_models: HashMap::new(),
+            capabilities: Arc::default(),
         }
     }
 
@@ -57,6 +76,8 @@ impl Registry {
                     template: Some(self.infer_template(name)),
                     ctx_len: Some(4096),
                     n_threads: None,
+                    backend: None,
+                    command: None,
                 };
                 self.inner.insert(name.clone(), entry);
             }
@@ -85,6 +106,20 @@ impl Registry {
     pub fn register(&mut self, e: ModelEntry) {
         self.inner.insert(e.name.clone(), e);
     }
+
+    /// Register the entries of a JSON config file, `{"models": [...]}`, over
+    /// any of the same name. Returns how many there were.
+    pub fn load_config(&mut self, path: &Path) -> anyhow::Result<usize> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read model config {}", path.display()))?;
+        let config: ModelConfig = serde_json::from_str(&text)
+            .with_context(|| format!("invalid model config {}", path.display()))?;
+        let count = config.models.len();
+        for entry in config.models {
+            self.register(entry);
+        }
+        Ok(count)
+    }
     pub fn get(&self, name: &str) -> Option<&ModelEntry> {
         // First check manually registered models, then auto-discovered
         self.inner.get(name)
@@ -92,6 +127,20 @@ impl Registry {
     pub fn list(&self) -> Vec<&ModelEntry> {
         self.inner.values().collect()
     }
+    /// Remember a loaded model's capabilities so listings can report them
+    /// without loading it again
+    pub fn record_capabilities(&self, name: &str, capabilities: Capabilities) {
+        self.capabilities
+            .write()
+            .unwrap()
+            .insert(name.to_string(), capabilities);
+    }
+
+    /// Capabilities from the model's last load, `None` if it was never loaded
+    pub fn capabilities(&self, name: &str) -> Option<Capabilities> {
+        self.capabilities.read().unwrap().get(name).cloned()
+    }
+
     pub fn list_all_available(&self) -> Vec<String> {
         let mut available = Vec::new();
         available.extend(self.inner.keys().cloned());
@@ -111,6 +160,8 @@ impl Registry {
                 template: e.template.clone(),
                 ctx_len: e.ctx_len.unwrap_or(4096),
                 n_threads: e.n_threads,
+                backend: e.backend.clone(),
+                command: e.command.clone(),
             });
         }
 
@@ -123,6 +174,8 @@ impl Registry {
                 template: Some(self.infer_template(&discovered.name)),
                 ctx_len: 4096,
                 n_threads: None,
+                backend: None,
+                command: None,
             });
         }
 
@@ -141,6 +194,34 @@ mod tests {
         assert!(registry.discovered_models.is_empty());
     }
 
+    #[test]
+    fn test_load_config_registers_its_models() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("models.json");
+        std::fs::write(
+            &path,
+            r#"{"models": [
+                {"name": "chat", "base_path": "/models/chat.gguf", "ctx_len": 8192},
+                {"name": "inhouse", "base_path": "/models/inhouse",
+                 "backend": "exec", "command": ["/opt/engine", "--fast"]}
+            ]}"#,
+        )
+        .unwrap();
+
+        let mut registry = Registry::new();
+        assert_eq!(registry.load_config(&path).unwrap(), 2);
+        assert_eq!(registry.to_spec("chat").unwrap().ctx_len, 8192);
+        let spec = registry.to_spec("inhouse").unwrap();
+        assert_eq!(spec.backend.as_deref(), Some("exec"));
+        assert_eq!(spec.command.unwrap(), ["/opt/engine", "--fast"]);
+
+        std::fs::write(&path, r#"{"models": [{"name": "no-path"}]}"#).unwrap();
+        assert!(registry.load_config(&path).is_err());
+        assert!(registry
+            .load_config(&dir.path().join("missing.json"))
+            .is_err());
+    }
+
     #[test]
     fn test_registry_default() {
         let registry = Registry::default();
@@ -157,6 +238,8 @@ mod tests {
             template: Some("chatml".to_string()),
             ctx_len: Some(4096),
             n_threads: Some(4),
+            backend: None,
+            command: None,
         };
 
         registry.register(entry.clone());
@@ -174,6 +257,8 @@ mod tests {
             template: None,
             ctx_len: None,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         registry.register(entry);
@@ -181,4 +266,17 @@ mod tests {
         assert_eq!(models.len(), 1);
         assert_eq!(models[0].name, "test");
     }
+
+    #[test]
+    fn test_capabilities_are_shared_between_clones() {
+        let registry = Registry::new();
+        let served = registry.clone();
+        assert!(registry.capabilities("phi3").is_none());
+
+        served.record_capabilities("phi3", Capabilities::text(4096));
+        assert_eq!(
+            registry.capabilities("phi3"),
+            Some(Capabilities::text(4096))
+        );
+    }
 }
@@ -0,0 +1,1413 @@
+// Ollama API compatibility
+// `/api/generate`, `/api/chat`, `/api/tags`, `/api/show`, `/api/ps`,
+// `/api/embed` and `/api/version` take Ollama's request shapes and answer
+// with its objects, streamed as NDJSON with the final line carrying
+// `done_reason` and the timing fields. Prompts go through the same
+// templates, tool calling and engine path as `openai_compat`. A
+// `/api/generate` request that accepts `text/event-stream` gets shimmy's
+// native SSE stream instead.
+
+use crate::api::{
+    self, check_capabilities, context_room, ChatMessage, ChatToolCall, RequestedFeatures,
+};
+use crate::api_errors::{ApiError, Json};
+use crate::engine::stream::{FinishReason, GenerationStats};
+use crate::engine::{GenOptions, GenerationEvent, GenerationStream, LoadedModel};
+use crate::json_grammar;
+use crate::loaded_models::KeepAlive;
+use crate::openai_compat::{
+    chat_prompt, load_model, load_model_keeping, template_family, ChatTool, OneOrMany, ReplyParser,
+};
+use crate::templates::{fim_prompt, TemplateFamily};
+use crate::tool_calling::{Parsed, ToolCallParser, ToolFormat, ToolMode};
+use crate::tools::{ToolCall, ToolDefinition};
+use crate::AppState;
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::stream::{self, BoxStream};
+use futures_util::{future, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// `/api/generate`: Ollama's fields on top of shimmy's native request, whose
+/// `messages`, `max_tokens` and sampling fields keep working
+#[derive(Debug, Deserialize)]
+pub struct GenerateRequest {
+    #[serde(flatten)]
+    pub base: api::GenerateRequest,
+    #[serde(default)]
+    pub options: Options,
+    /// From a previous response, to continue that conversation
+    #[serde(default)]
+    pub context: Option<Vec<u32>>,
+    /// Send the prompt as is, without the chat template
+    #[serde(default)]
+    pub raw: bool,
+    /// A Modelfile-style template to use instead of the model's
+    #[serde(default)]
+    pub template: Option<String>,
+    /// `"json"` or a JSON schema
+    #[serde(default)]
+    pub format: Option<Value>,
+    #[serde(default)]
+    pub keep_alive: Option<Value>,
+}
+
+/// Ollama's `options`. Others, such as `num_ctx` or `mirostat`, are ignored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Options {
+    /// -1 (the default) or -2 generate until the context is full
+    pub num_predict: Option<i64>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<i32>,
+    pub repeat_penalty: Option<f32>,
+    /// Negative seeds pick a random one
+    pub seed: Option<i64>,
+    pub stop: Option<Vec<String>>,
+}
+
+impl Options {
+    fn apply(&self, opts: &mut GenOptions) {
+        if let Some(t) = self.temperature {
+            opts.temperature = t;
+        }
+        if let Some(p) = self.top_p {
+            opts.top_p = p;
+        }
+        if let Some(k) = self.top_k {
+            opts.top_k = k;
+        }
+        if let Some(r) = self.repeat_penalty {
+            opts.repeat_penalty = r;
+        }
+        if let Some(seed) = self.seed {
+            opts.seed = u32::try_from(seed).ok();
+        }
+    }
+
+    /// The token limit asked for, else `native`. `None` generates until the
+    /// context is full, as Ollama does by default.
+    fn token_limit(&self, native: Option<usize>) -> Option<usize> {
+        match self.num_predict {
+            Some(n) => usize::try_from(n).ok(),
+            None => native,
+        }
+    }
+}
+
+impl GenerateRequest {
+    /// Without a prompt, Ollama clients are only loading or unloading the model
+    fn load_only(&self) -> bool {
+        self.base.messages.is_none()
+            && self.base.suffix.is_none()
+            && self.base.prompt.as_deref().unwrap_or_default().is_empty()
+    }
+
+    fn features(&self) -> RequestedFeatures {
+        let mut wants = self.base.features();
+        // The suffix goes into the prompt, so the model needs no infill support
+        wants.infill = false;
+        wants.grammar = self.grammar().is_some();
+        wants.max_tokens = self.options.token_limit(self.base.max_tokens);
+        wants
+    }
+
+    /// A raw `grammar` wins over `format`
+    fn grammar(&self) -> Option<String> {
+        self.base
+            .grammar
+            .clone()
+            .or_else(|| format_grammar(self.format.as_ref()))
+    }
+
+    fn options(&self) -> GenOptions {
+        let mut opts = GenOptions::default();
+        if let Some(t) = self.base.temperature {
+            opts.temperature = t;
+        }
+        if let Some(p) = self.base.top_p {
+            opts.top_p = p;
+        }
+        if let Some(k) = self.base.top_k {
+            opts.top_k = k;
+        }
+        self.options.apply(&mut opts);
+        opts.grammar = self.grammar();
+        opts.images = self.base.images.clone().unwrap_or_default();
+        opts
+    }
+
+    /// The conversation so far, from `context`, followed by this turn. With a
+    /// `suffix` it is only the prompt and suffix in the fill-in-the-middle
+    /// markers of `model`'s family.
+    fn prompt(&self, model: &str, fam: &TemplateFamily) -> Result<String, ApiError> {
+        let text = self.base.prompt.as_deref().unwrap_or_default();
+        if let Some(suffix) = &self.base.suffix {
+            return Ok(fim_prompt(model, text, suffix));
+        }
+        let mut prompt = match &self.context {
+            Some(context) => decode_context(context)?,
+            None => String::new(),
+        };
+        let system = self.base.system.as_deref();
+        if let Some(messages) = &self.base.messages {
+            let mut turns: Vec<(String, String)> = system
+                .map(|s| ("system".to_string(), s.to_string()))
+                .into_iter()
+                .collect();
+            turns.extend(messages.iter().map(|m| (m.role.clone(), m.content.clone())));
+            prompt.push_str(&fam.render_chat(&turns));
+        } else if self.raw {
+            prompt.push_str(text);
+        } else if let Some(template) = &self.template {
+            prompt.push_str(&render_template(template, system.unwrap_or_default(), text));
+        } else {
+            let mut turns: Vec<(String, String)> = system
+                .map(|s| ("system".to_string(), s.to_string()))
+                .into_iter()
+                .collect();
+            turns.push(("user".to_string(), text.to_string()));
+            prompt.push_str(&fam.render_chat(&turns));
+        }
+        Ok(prompt)
+    }
+}
+
+/// `context` is an opaque encoding of the conversation: its UTF-8 bytes.
+/// Unlike token ids, these survive the trip through any backend with the
+/// template's special tokens intact.
+fn encode_context(text: &str) -> Vec<u32> {
+    text.bytes().map(u32::from).collect()
+}
+
+fn decode_context(context: &[u32]) -> Result<String, ApiError> {
+    let invalid =
+        || ApiError::InvalidRequest("context was not produced by this server".to_string());
+    let bytes = context
+        .iter()
+        .map(|&b| u8::try_from(b))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| invalid())?;
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+/// GBNF for `format`: `"json"` for any JSON object, or a JSON schema
+fn format_grammar(format: Option<&Value>) -> Option<String> {
+    match format? {
+        Value::Null => None,
+        Value::String(s) if s.is_empty() => None,
+        Value::String(_) => Some(json_grammar::object()),
+        schema => Some(json_grammar::from_schema(schema)),
+    }
+}
+
+/// How long to keep the model after the request: seconds as a number, or
+/// a duration string such as "5m". Negative keeps it until unloaded.
+fn keep_alive(value: Option<&Value>) -> Result<Option<KeepAlive>, ApiError> {
+    let parsed = match value {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::Number(n)) => n.as_f64().and_then(KeepAlive::from_secs),
+        Some(Value::String(s)) => KeepAlive::parse(s),
+        Some(_) => None,
+    };
+    match parsed {
+        Some(keep_alive) => Ok(Some(keep_alive)),
+        None => Err(ApiError::InvalidRequest(format!(
+            "invalid keep_alive: {}",
+            value.unwrap_or(&Value::Null)
+        ))),
+    }
+}
+
+/// `done_reason` for a request that only loads its model, or unloads it
+/// with a `keep_alive` of zero
+fn load_reason(keep_alive: Option<KeepAlive>) -> String {
+    let unloads = keep_alive == Some(KeepAlive::For(Duration::ZERO));
+    if unloads { "unload" } else { "load" }.to_string()
+}
+
+/// `template` rendered with the part of Go's template syntax that
+/// single-turn Modelfile templates use: `{{ .System }}`, `{{ .Prompt }}`,
+/// `{{ if .X }}...{{ else }}...{{ end }}` and `{{-`/`-}}` trimming. Output
+/// stops at `{{ .Response }}`, where the model takes over.
+pub fn render_template(template: &str, system: &str, prompt: &str) -> String {
+    let mut out = String::new();
+    // Whether each enclosing block is being output
+    let mut blocks: Vec<bool> = Vec::new();
+    let mut rest = template;
+    let mut trim_next = false;
+    loop {
+        let (literal, action) = match rest.find("{{") {
+            Some(start) => (&rest[..start], Some(&rest[start + 2..])),
+            None => (rest, None),
+        };
+        let emitting = blocks.iter().all(|&on| on);
+        let literal = if trim_next {
+            literal.trim_start()
+        } else {
+            literal
+        };
+        if emitting {
+            out.push_str(literal);
+        }
+        let Some(action) = action else {
+            break;
+        };
+        let Some(end) = action.find("}}") else {
+            break;
+        };
+        rest = &action[end + 2..];
+        let mut inner = &action[..end];
+        if let Some(trimmed) = inner.strip_prefix('-') {
+            inner = trimmed;
+            if emitting {
+                out.truncate(out.trim_end().len());
+            }
+        }
+        trim_next = inner.ends_with('-');
+        let inner = inner.trim_end_matches('-').trim();
+        let value = |field: &&str| match *field {
+            ".System" => system,
+            ".Prompt" => prompt,
+            _ => "",
+        };
+        match inner.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [".Response"] if emitting => break,
+            ["if", field] => blocks.push(!value(field).is_empty()),
+            ["else"] => {
+                if let Some(on) = blocks.last_mut() {
+                    *on = !*on;
+                }
+            }
+            ["end"] => {
+                blocks.pop();
+            }
+            // Blocks this renderer can't evaluate are left out
+            ["if" | "range" | "with", ..] => blocks.push(false),
+            [field] if emitting => out.push_str(value(field)),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// `/api/chat`
+#[derive(Debug, Deserialize)]
+pub struct ChatRequest {
+    pub model: String,
+    #[serde(default)]
+    pub messages: Vec<Message>,
+    /// `{"type": "function", "function": {...}}`, as OpenAI's
+    #[serde(default)]
+    pub tools: Option<Vec<ChatTool>>,
+    #[serde(default)]
+    pub format: Option<Value>,
+    #[serde(default)]
+    pub options: Options,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    #[serde(default)]
+    pub keep_alive: Option<Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Message {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+    /// Base64-encoded images for multimodal models
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub images: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<MessageToolCall>>,
+}
+
+/// `{"function": {"name", "arguments"}}`, with the arguments as an object
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MessageToolCall {
+    pub function: ToolCall,
+}
+
+impl Message {
+    fn assistant(content: String, calls: Vec<ToolCall>) -> Self {
+        Self {
+            role: "assistant".to_string(),
+            content,
+            images: None,
+            tool_calls: (!calls.is_empty()).then(|| {
+                calls
+                    .into_iter()
+                    .map(|function| MessageToolCall { function })
+                    .collect()
+            }),
+        }
+    }
+}
+
+impl ChatRequest {
+    fn features(&self) -> RequestedFeatures {
+        RequestedFeatures {
+            grammar: format_grammar(self.format.as_ref()).is_some(),
+            images: self
+                .messages
+                .iter()
+                .any(|m| m.images.as_ref().is_some_and(|i| !i.is_empty())),
+            max_tokens: self.options.token_limit(None),
+            ..Default::default()
+        }
+    }
+
+    fn options(&self) -> GenOptions {
+        let mut opts = GenOptions::default();
+        self.options.apply(&mut opts);
+        opts.grammar = format_grammar(self.format.as_ref());
+        opts.images = self
+            .messages
+            .iter()
+            .flat_map(|m| m.images.iter().flatten().cloned())
+            .collect();
+        opts
+    }
+
+    fn chat_messages(&self) -> Vec<ChatMessage> {
+        self.messages
+            .iter()
+            .map(|m| ChatMessage {
+                role: m.role.clone(),
+                content: m.content.clone(),
+                tool_calls: m.tool_calls.as_ref().map(|calls| {
+                    calls
+                        .iter()
+                        .map(|call| ChatToolCall::new(call.function.clone()))
+                        .collect()
+                }),
+                tool_call_id: None,
+            })
+            .collect()
+    }
+
+    /// Ollama has no `tool_choice`: offered tools may be called or not
+    fn tool_mode(&self) -> Option<(Vec<ToolDefinition>, ToolMode)> {
+        let tools: Vec<ToolDefinition> = self
+            .tools
+            .iter()
+            .flatten()
+            .filter(|tool| tool.kind == "function")
+            .map(|tool| tool.function.clone())
+            .collect();
+        (!tools.is_empty()).then_some((tools, ToolMode::Auto))
+    }
+}
+
+/// Ollama's timing fields, in nanoseconds
+#[derive(Debug, Default, Serialize)]
+pub struct Timings {
+    pub total_duration: u64,
+    pub load_duration: u64,
+    pub prompt_eval_count: usize,
+    pub prompt_eval_duration: u64,
+    pub eval_count: usize,
+    pub eval_duration: u64,
+}
+
+/// When a request started and how long loading its model took
+#[derive(Clone, Copy)]
+struct Clock {
+    started: Instant,
+    load: Duration,
+}
+
+impl Clock {
+    fn timings(&self, stats: &GenerationStats) -> Timings {
+        const NANOS_PER_MS: u64 = 1_000_000;
+        Timings {
+            total_duration: self.started.elapsed().as_nanos() as u64,
+            load_duration: self.load.as_nanos() as u64,
+            prompt_eval_count: stats.prompt_tokens,
+            prompt_eval_duration: stats.prompt_ms * NANOS_PER_MS,
+            eval_count: stats.completion_tokens,
+            eval_duration: stats.total_ms.saturating_sub(stats.prompt_ms) * NANOS_PER_MS,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct GenerateResponse {
+    pub model: String,
+    pub created_at: String,
+    pub response: String,
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Vec<u32>>,
+    #[serde(flatten)]
+    pub timings: Option<Timings>,
+}
+
+impl GenerateResponse {
+    fn new(model: &str, response: String) -> Self {
+        Self {
+            model: model.to_string(),
+            created_at: now(),
+            response,
+            done: false,
+            done_reason: None,
+            context: None,
+            timings: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatResponse {
+    pub model: String,
+    pub created_at: String,
+    pub message: Message,
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done_reason: Option<String>,
+    #[serde(flatten)]
+    pub timings: Option<Timings>,
+}
+
+impl ChatResponse {
+    fn new(model: &str, message: Message) -> Self {
+        Self {
+            model: model.to_string(),
+            created_at: now(),
+            message,
+            done: false,
+            done_reason: None,
+            timings: None,
+        }
+    }
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+/// What a generation produced since the last update
+enum Update {
+    /// Text and tool calls the latest token completed
+    Delta(String, Vec<ToolCall>),
+    /// `done_reason` and the generation's stats
+    Done(&'static str, GenerationStats),
+    Failed(ApiError),
+}
+
+/// Generated text with stop sequences applied and, for chats with tools,
+/// tool calls parsed out
+struct Reply {
+    parser: ReplyParser,
+    prompt_tokens: usize,
+    tokens: usize,
+    started: Instant,
+    first_token: Option<Instant>,
+}
+
+impl Reply {
+    fn new(stop: &[String], parser: Option<ToolCallParser>) -> Self {
+        Self {
+            parser: ReplyParser::new(stop, parser),
+            prompt_tokens: 0,
+            tokens: 0,
+            started: Instant::now(),
+            first_token: None,
+        }
+    }
+
+    fn push(&mut self, piece: &str) -> Option<Update> {
+        self.tokens += 1;
+        self.first_token.get_or_insert_with(Instant::now);
+        delta(self.parser.push(piece))
+    }
+
+    /// The rest of the reply, once the generation ended on its own
+    fn finish(&mut self) -> Option<Update> {
+        delta(self.parser.finish())
+    }
+
+    /// Stats for a generation a stop sequence cut short
+    fn stats(&self) -> GenerationStats {
+        GenerationStats::timed(
+            self.prompt_tokens,
+            self.tokens,
+            self.started,
+            self.first_token,
+        )
+    }
+}
+
+fn delta(parsed: Vec<Parsed>) -> Option<Update> {
+    let mut text = String::new();
+    let mut calls = Vec::new();
+    for piece in parsed {
+        match piece {
+            Parsed::Content(content) => text.push_str(&content),
+            Parsed::Call(call) => calls.push(call),
+        }
+    }
+    (!text.is_empty() || !calls.is_empty()).then_some(Update::Delta(text, calls))
+}
+
+fn done_reason(finish_reason: FinishReason) -> &'static str {
+    match finish_reason {
+        FinishReason::Length => "length",
+        _ => "stop",
+    }
+}
+
+/// Ending the stream after a stop sequence drops the generation, which
+/// cancels it
+fn updates(events: GenerationStream, mut reply: Reply) -> BoxStream<'static, Update> {
+    events
+        .scan(false, move |finished, event| {
+            if *finished {
+                return future::ready(None);
+            }
+            let out: Vec<Update> = match event {
+                GenerationEvent::PromptProgress { .. } => vec![],
+                GenerationEvent::Token { text, .. } => {
+                    let mut out: Vec<_> = reply.push(&text).into_iter().collect();
+                    if reply.parser.stopped() {
+                        *finished = true;
+                        out.push(Update::Done("stop", reply.stats()));
+                    }
+                    out
+                }
+                GenerationEvent::Done {
+                    finish_reason,
+                    stats,
+                } => {
+                    *finished = true;
+                    let mut out: Vec<_> = reply.finish().into_iter().collect();
+                    out.push(Update::Done(done_reason(finish_reason), stats));
+                    out
+                }
+                GenerationEvent::Error { message } => {
+                    *finished = true;
+                    vec![Update::Failed(ApiError::GenerationFailed(message))]
+                }
+                GenerationEvent::Overloaded { model } => {
+                    *finished = true;
+                    vec![Update::Failed(ApiError::Overloaded(model))]
+                }
+            };
+            future::ready(Some(stream::iter(out)))
+        })
+        .flatten()
+        .boxed()
+}
+
+/// Check the request against the model and start generating `prompt`
+async fn start(
+    state: &AppState,
+    model: &str,
+    loaded: Arc<dyn LoadedModel>,
+    prompt: String,
+    wants: &RequestedFeatures,
+    mut opts: GenOptions,
+    mut reply: Reply,
+) -> Result<BoxStream<'static, Update>, ApiError> {
+    let prompt_tokens = check_capabilities(state, model, &loaded, &prompt, wants).await?;
+    opts.max_tokens = match wants.max_tokens {
+        Some(max) => max,
+        None => context_room(&loaded.capabilities(), prompt_tokens).unwrap_or(opts.max_tokens),
+    };
+    reply.prompt_tokens = prompt_tokens.unwrap_or(0);
+    Ok(updates(loaded.generate_stream(prompt, opts), reply))
+}
+
+/// Ollama names models `name:tag` and clients add `:latest` to bare names
+fn resolve(state: &AppState, model: &str) -> String {
+    match model.strip_suffix(":latest") {
+        Some(bare) if state.registry.to_spec(model).is_none() => bare.to_string(),
+        _ => model.to_string(),
+    }
+}
+
+/// An NDJSON body: one JSON object per line, sent as each is ready
+fn ndjson(lines: impl Stream<Item = String> + Send + 'static) -> Response {
+    let body = axum::body::Body::from_stream(lines.map(Ok::<String, std::convert::Infallible>));
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response()
+}
+
+fn line(value: &impl Serialize) -> String {
+    let mut line = serde_json::to_string(value).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+/// A generation that failed after the stream started ends with an error line
+fn failure(model: &str, error: ApiError) -> String {
+    let message = error.into_body().error.message;
+    tracing::error!("Failed to generate for model '{}': {}", model, message);
+    line(&json!({ "error": message }))
+}
+
+pub async fn generate(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<GenerateRequest>,
+) -> Response {
+    let sse = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    if sse {
+        return api::generate(State(state), Json(req.base))
+            .await
+            .into_response();
+    }
+
+    let started = Instant::now();
+    let name = resolve(&state, &req.base.model);
+    let keep_alive = match keep_alive(req.keep_alive.as_ref()) {
+        Ok(keep_alive) => keep_alive,
+        Err(e) => return e.into_response(),
+    };
+    let (spec, loaded) = match load_model_keeping(&state, &name, keep_alive).await {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+    let clock = Clock {
+        started,
+        load: started.elapsed(),
+    };
+    let model = req.base.model.clone();
+    if req.load_only() {
+        state
+            .registry
+            .record_capabilities(&name, loaded.capabilities());
+        let mut response = GenerateResponse::new(&model, String::new());
+        response.done = true;
+        response.done_reason = Some(load_reason(keep_alive));
+        return Json(response).into_response();
+    }
+
+    let fam = template_family(&spec, &name);
+    let prompt = match req.prompt(&name, &fam) {
+        Ok(prompt) => prompt,
+        Err(e) => return e.into_response(),
+    };
+    let reply = Reply::new(req.options.stop.as_deref().unwrap_or_default(), None);
+    let mut updates = match start(
+        &state,
+        &name,
+        loaded,
+        prompt.clone(),
+        &req.features(),
+        req.options(),
+        reply,
+    )
+    .await
+    {
+        Ok(updates) => updates,
+        Err(e) => return e.into_response(),
+    };
+    // Raw and fill-in-the-middle prompts carry no conversation to continue
+    let keeps_context = !req.raw && req.base.suffix.is_none();
+    let last = move |response: String, reason: &str, stats: &GenerationStats, text: &str| {
+        let mut last = GenerateResponse::new(&model, response);
+        last.done = true;
+        last.done_reason = Some(reason.to_string());
+        last.context = keeps_context.then(|| encode_context(&format!("{}{}", prompt, text)));
+        last.timings = Some(clock.timings(stats));
+        last
+    };
+
+    if req.base.stream.unwrap_or(true) {
+        let model = req.base.model.clone();
+        let mut text = String::new();
+        let lines = updates.map(move |update| match update {
+            Update::Delta(delta, _) => {
+                text.push_str(&delta);
+                line(&GenerateResponse::new(&model, delta))
+            }
+            Update::Done(reason, stats) => line(&last(String::new(), reason, &stats, &text)),
+            Update::Failed(error) => failure(&model, error),
+        });
+        return ndjson(lines);
+    }
+
+    let mut text = String::new();
+    while let Some(update) = updates.next().await {
+        match update {
+            Update::Delta(delta, _) => text.push_str(&delta),
+            Update::Done(reason, stats) => {
+                return Json(last(text.clone(), reason, &stats, &text)).into_response()
+            }
+            Update::Failed(error) => {
+                tracing::error!(
+                    "Failed to generate for model '{}': {:?}",
+                    req.base.model,
+                    error
+                );
+                return error.into_response();
+            }
+        }
+    }
+    ApiError::GenerationFailed("generation ended without finishing".to_string()).into_response()
+}
+
+pub async fn chat(State(state): State<Arc<AppState>>, Json(req): Json<ChatRequest>) -> Response {
+    let started = Instant::now();
+    let name = resolve(&state, &req.model);
+    let keep_alive = match keep_alive(req.keep_alive.as_ref()) {
+        Ok(keep_alive) => keep_alive,
+        Err(e) => return e.into_response(),
+    };
+    let (spec, loaded) = match load_model_keeping(&state, &name, keep_alive).await {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+    let clock = Clock {
+        started,
+        load: started.elapsed(),
+    };
+    if req.messages.is_empty() {
+        state
+            .registry
+            .record_capabilities(&name, loaded.capabilities());
+        let mut response = ChatResponse::new(&req.model, Message::assistant(String::new(), vec![]));
+        response.done = true;
+        response.done_reason = Some(load_reason(keep_alive));
+        return Json(response).into_response();
+    }
+
+    let tools = req.tool_mode();
+    let fam = template_family(&spec, &name);
+    let format = ToolFormat::for_template(&fam, &name);
+    let (prompt, prefill) = chat_prompt(&fam, format, &req.chat_messages(), tools.as_ref());
+    let parser = tools
+        .as_ref()
+        .map(|_| ToolCallParser::new(format, prefill.as_deref()));
+    let reply = Reply::new(req.options.stop.as_deref().unwrap_or_default(), parser);
+    let mut updates = match start(
+        &state,
+        &name,
+        loaded,
+        prompt,
+        &req.features(),
+        req.options(),
+        reply,
+    )
+    .await
+    {
+        Ok(updates) => updates,
+        Err(e) => return e.into_response(),
+    };
+    let model = req.model.clone();
+    let last = move |message: Message, reason: &str, stats: &GenerationStats| {
+        let mut last = ChatResponse::new(&model, message);
+        last.done = true;
+        last.done_reason = Some(reason.to_string());
+        last.timings = Some(clock.timings(stats));
+        last
+    };
+
+    if req.stream.unwrap_or(true) {
+        let model = req.model.clone();
+        let lines = updates.map(move |update| match update {
+            Update::Delta(text, calls) => {
+                line(&ChatResponse::new(&model, Message::assistant(text, calls)))
+            }
+            Update::Done(reason, stats) => line(&last(
+                Message::assistant(String::new(), vec![]),
+                reason,
+                &stats,
+            )),
+            Update::Failed(error) => failure(&model, error),
+        });
+        return ndjson(lines);
+    }
+
+    let mut content = String::new();
+    let mut calls = Vec::new();
+    while let Some(update) = updates.next().await {
+        match update {
+            Update::Delta(text, more) => {
+                content.push_str(&text);
+                calls.extend(more);
+            }
+            Update::Done(reason, stats) => {
+                if !calls.is_empty() {
+                    content = content.trim().to_string();
+                }
+                let message = Message::assistant(content, calls);
+                return Json(last(message, reason, &stats)).into_response();
+            }
+            Update::Failed(error) => {
+                tracing::error!("Failed to chat with model '{}': {:?}", req.model, error);
+                return error.into_response();
+            }
+        }
+    }
+    ApiError::GenerationFailed("generation ended without finishing".to_string()).into_response()
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelList {
+    pub models: Vec<ModelSummary>,
+}
+
+/// A model as `/api/tags` and `/api/ps` list it
+#[derive(Debug, Serialize)]
+pub struct ModelSummary {
+    pub name: String,
+    pub model: String,
+    pub modified_at: String,
+    pub size: u64,
+    pub digest: String,
+    pub details: ModelDetails,
+    /// When `/api/ps` expects the model to unload; absent for models kept
+    /// until they are unloaded, and in `/api/tags`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelDetails {
+    pub parent_model: String,
+    pub format: String,
+    pub family: String,
+    pub families: Vec<String>,
+    pub parameter_size: String,
+    pub quantization_level: String,
+}
+
+/// The architecture a model name suggests
+fn family(name: &str) -> &'static str {
+    let name = name.to_lowercase();
+    [
+        "llama", "mistral", "mixtral", "qwen", "gemma", "phi", "deepseek",
+    ]
+    .into_iter()
+    .find(|family| name.contains(family))
+    .unwrap_or("unknown")
+}
+
+/// Ollama blobs are named after their digest; other files get a stable
+/// stand-in derived from their path
+fn digest(path: &Path) -> String {
+    use std::hash::{Hash, Hasher};
+
+    let file = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or_default();
+    match file.strip_prefix("sha256-") {
+        Some(hash) => hash.to_string(),
+        None => {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            path.hash(&mut hasher);
+            format!("{:016x}", hasher.finish())
+        }
+    }
+}
+
+fn summarize(state: &AppState, name: &str) -> Option<ModelSummary> {
+    let spec = state.registry.to_spec(name)?;
+    let discovered = state.registry.discovered_models.get(name);
+    let metadata = std::fs::metadata(&spec.base_path).ok();
+    let size = discovered
+        .map(|d| d.size_bytes)
+        .or_else(|| metadata.as_ref().filter(|m| m.is_file()).map(|m| m.len()))
+        .unwrap_or(0);
+    let modified_at = metadata
+        .as_ref()
+        .and_then(|m| m.modified().ok())
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(Utc::now);
+    let extension = spec
+        .base_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let format = if metadata.as_ref().is_some_and(|m| m.is_dir()) {
+        "safetensors".to_string()
+    } else if extension.is_empty() {
+        // Ollama's blobs, which discovery only picks up if they are GGUF
+        "gguf".to_string()
+    } else {
+        extension
+    };
+    let family = family(name).to_string();
+    Some(ModelSummary {
+        name: name.to_string(),
+        model: name.to_string(),
+        modified_at: modified_at.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        size,
+        digest: digest(&spec.base_path),
+        details: ModelDetails {
+            parent_model: String::new(),
+            format,
+            families: vec![family.clone()],
+            family,
+            parameter_size: discovered
+                .and_then(|d| d.parameter_count.clone())
+                .unwrap_or_default(),
+            quantization_level: discovered
+                .and_then(|d| d.quantization.clone())
+                .unwrap_or_default(),
+        },
+        expires_at: None,
+    })
+}
+
+pub async fn tags(State(state): State<Arc<AppState>>) -> Json<ModelList> {
+    let models = state
+        .registry
+        .list_all_available()
+        .iter()
+        .filter_map(|name| summarize(&state, name))
+        .collect();
+    Json(ModelList { models })
+}
+
+/// Models held in memory and when their keep-alive runs out
+pub async fn ps(State(state): State<Arc<AppState>>) -> Json<ModelList> {
+    let models = state
+        .models
+        .loaded()
+        .into_iter()
+        .filter_map(|resident| {
+            let mut summary = summarize(&state, &resident.name)?;
+            summary.expires_at = resident
+                .expires_at
+                .map(|at| DateTime::<Utc>::from(at).to_rfc3339_opts(SecondsFormat::Nanos, true));
+            Some(summary)
+        })
+        .collect();
+    Json(ModelList { models })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShowRequest {
+    #[serde(alias = "name")]
+    pub model: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShowResponse {
+    pub modelfile: String,
+    pub parameters: String,
+    /// The template family shimmy renders prompts with
+    pub template: String,
+    pub details: ModelDetails,
+    pub model_info: Map<String, Value>,
+    pub capabilities: Vec<&'static str>,
+    pub modified_at: String,
+}
+
+pub async fn show(State(state): State<Arc<AppState>>, Json(req): Json<ShowRequest>) -> Response {
+    let name = resolve(&state, &req.model);
+    let (Some(summary), Some(spec)) = (summarize(&state, &name), state.registry.to_spec(&name))
+    else {
+        return ApiError::ModelNotFound(req.model).into_response();
+    };
+    // Loading the model is the only way to learn what it supports
+    let caps = match state.registry.capabilities(&name) {
+        Some(caps) => caps,
+        None => match load_model(&state, &name).await {
+            Ok((_, loaded)) => {
+                let caps = loaded.capabilities();
+                state.registry.record_capabilities(&name, caps.clone());
+                caps
+            }
+            Err(e) => return e.into_response(),
+        },
+    };
+    let context_length = match caps.context_length {
+        0 => spec.ctx_len,
+        n => n,
+    };
+    let mut model_info = Map::new();
+    model_info.insert(
+        "general.architecture".to_string(),
+        summary.details.family.clone().into(),
+    );
+    model_info.insert(
+        format!("{}.context_length", summary.details.family),
+        context_length.into(),
+    );
+    let capabilities = [
+        (caps.generate, "completion"),
+        // Every generating model gets tools through its template family
+        (caps.generate, "tools"),
+        (caps.embeddings, "embedding"),
+        (caps.images, "vision"),
+        (caps.infill, "insert"),
+    ]
+    .into_iter()
+    .filter_map(|(has, capability)| has.then_some(capability))
+    .collect();
+    let template = spec
+        .template
+        .clone()
+        .unwrap_or_else(|| format!("{:?}", template_family(&spec, &name)).to_lowercase());
+    Json(ShowResponse {
+        modelfile: String::new(),
+        parameters: format!("num_ctx {}", spec.ctx_len),
+        template,
+        details: summary.details,
+        model_info,
+        capabilities,
+        modified_at: summary.modified_at,
+    })
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmbedRequest {
+    pub model: String,
+    pub input: OneOrMany,
+    #[serde(default)]
+    pub keep_alive: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmbedResponse {
+    pub model: String,
+    pub embeddings: Vec<Vec<f32>>,
+    pub total_duration: u64,
+    pub load_duration: u64,
+    pub prompt_eval_count: usize,
+}
+
+pub async fn embed(State(state): State<Arc<AppState>>, Json(req): Json<EmbedRequest>) -> Response {
+    let started = Instant::now();
+    let name = resolve(&state, &req.model);
+    let keep_alive = match keep_alive(req.keep_alive.as_ref()) {
+        Ok(keep_alive) => keep_alive,
+        Err(e) => return e.into_response(),
+    };
+    let (_, loaded) = match load_model_keeping(&state, &name, keep_alive).await {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+    let load = started.elapsed();
+    let caps = loaded.capabilities();
+    state.registry.record_capabilities(&name, caps.clone());
+    if !caps.embeddings {
+        return ApiError::InvalidRequest(format!(
+            "model '{}' does not support embeddings",
+            req.model
+        ))
+        .into_response();
+    }
+
+    let inputs = req.input.into_vec();
+    // Embedding is blocking model work, like generation
+    let embedded = tokio::task::spawn_blocking(move || {
+        let tokens: usize = inputs
+            .iter()
+            .map(|text| loaded.tokenize(text, true).map_or(0, |t| t.len()))
+            .sum();
+        let embeddings = inputs
+            .iter()
+            .map(|text| loaded.embed(text))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok::<_, anyhow::Error>((embeddings, tokens))
+    })
+    .await;
+    match embedded {
+        Ok(Ok((embeddings, prompt_eval_count))) => Json(EmbedResponse {
+            model: req.model,
+            embeddings,
+            total_duration: started.elapsed().as_nanos() as u64,
+            load_duration: load.as_nanos() as u64,
+            prompt_eval_count,
+        })
+        .into_response(),
+        Ok(Err(e)) => ApiError::GenerationFailed(e.to_string()).into_response(),
+        Err(e) => ApiError::GenerationFailed(e.to_string()).into_response(),
+    }
+}
+
+pub async fn version() -> Json<Value> {
+    Json(json!({ "version": env!("CARGO_PKG_VERSION") }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Capabilities;
+    use crate::test_utils::{body, json_request, FakeModel};
+
+    /// Streams `pieces` and runs out of tokens
+    fn scripted(pieces: &[&str]) -> FakeModel {
+        FakeModel::finishing(pieces, FinishReason::Length).with_capabilities(Capabilities {
+            embeddings: true,
+            ..Capabilities::text(0)
+        })
+    }
+
+    async fn send_generate(
+        state: &Arc<AppState>,
+        request: Value,
+    ) -> (axum::http::StatusCode, String) {
+        body(
+            generate(
+                State(Arc::clone(state)),
+                HeaderMap::new(),
+                json_request(request),
+            )
+            .await,
+        )
+        .await
+    }
+
+    fn lines(body: &str) -> Vec<Value> {
+        body.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_render_template_subset() {
+        let template = "{{ if .System }}<<SYS>>{{ .System }}<</SYS>> {{ end }}[INST] {{ .Prompt }} [/INST]{{ .Response }}</s>";
+        assert_eq!(render_template(template, "", "hi"), "[INST] hi [/INST]");
+        assert_eq!(
+            render_template(template, "Be brief.", "hi"),
+            "<<SYS>>Be brief.<</SYS>> [INST] hi [/INST]"
+        );
+        assert_eq!(render_template("A: {{- .Prompt -}}  \n B", "", "x"), "A:xB");
+        assert_eq!(
+            render_template("{{ if .System }}s{{ else }}none{{ end }}", "", ""),
+            "none"
+        );
+    }
+
+    #[test]
+    fn test_options_and_keep_alive() {
+        let request: GenerateRequest = serde_json::from_value(json!({
+            "model": "m", "prompt": "hi", "max_tokens": 9, "temperature": 0.1,
+            "options": {"temperature": 0.5, "seed": -1, "num_ctx": 4096, "top_k": 3}
+        }))
+        .unwrap();
+        let opts = request.options();
+        assert_eq!(opts.temperature, 0.5);
+        assert_eq!(opts.top_k, 3);
+        assert_eq!(opts.seed, None);
+        assert_eq!(request.features().max_tokens, Some(9));
+        assert_eq!(Options::default().token_limit(None), None);
+        let unlimited = Options {
+            num_predict: Some(-1),
+            ..Default::default()
+        };
+        assert_eq!(unlimited.token_limit(Some(9)), None);
+
+        let unload = keep_alive(Some(&json!("0s"))).unwrap();
+        assert_eq!(keep_alive(Some(&json!(0))).unwrap(), unload);
+        assert_eq!(load_reason(unload), "unload");
+        let five_minutes = keep_alive(Some(&json!("5m"))).unwrap();
+        assert_eq!(five_minutes, Some(KeepAlive::For(Duration::from_secs(300))));
+        assert_eq!(load_reason(five_minutes), "load");
+        assert_eq!(
+            keep_alive(Some(&json!(-1))).unwrap(),
+            Some(KeepAlive::Forever)
+        );
+        assert_eq!(keep_alive(None).unwrap(), None);
+        assert!(keep_alive(Some(&json!("soon"))).is_err());
+        assert_eq!(load_reason(None), "load");
+        assert_eq!(decode_context(&encode_context("héllo")).unwrap(), "héllo");
+        assert!(decode_context(&[300]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_generate_streams_ndjson_and_continues_from_context() {
+        let model = scripted(&["Hel", "lo", " there"]);
+        let state = model.state("hermes", Some("chatml"));
+        let (status, response) = send_generate(
+            &state,
+            json!({"model": "hermes:latest", "prompt": "hi", "system": "Be brief."}),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let lines = lines(&response);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["model"], "hermes:latest");
+        assert_eq!(lines[0]["response"], "Hel");
+        assert_eq!(lines[0]["done"], false);
+        let last = &lines[3];
+        assert_eq!(last["done"], true);
+        assert_eq!(last["done_reason"], "length");
+        assert_eq!(last["response"], "");
+        assert_eq!(last["prompt_eval_count"], 3);
+        assert_eq!(last["eval_count"], 3);
+        assert_eq!(last["eval_duration"], 3_000_000);
+        assert!(last["total_duration"].as_u64().is_some());
+        let first_prompt = model.prompt();
+        assert!(first_prompt.starts_with("<|im_start|>system\nBe brief."));
+
+        // The next turn starts from the whole first exchange
+        let context = last["context"].clone();
+        let (_, response) = send_generate(
+            &state,
+            json!({"model": "hermes", "prompt": "again", "stream": false,
+                "context": context, "options": {"stop": ["lo"]}}),
+        )
+        .await;
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["response"], "Hel");
+        assert_eq!(response["done_reason"], "stop");
+        let second_prompt = model.prompt();
+        assert!(second_prompt.starts_with(&format!("{}Hello there", first_prompt)));
+        assert!(second_prompt.contains("again"));
+
+        // Raw prompts skip the template and return no context
+        let (_, response) = send_generate(
+            &state,
+            json!({"model": "hermes", "prompt": "[INST] hi", "raw": true, "stream": false}),
+        )
+        .await;
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert!(response.get("context").is_none());
+        assert_eq!(model.prompt(), "[INST] hi");
+
+        // A suffix is wrapped in fill-in-the-middle markers, so the model
+        // needs no infill support
+        let (status, response) = send_generate(
+            &state,
+            json!({"model": "hermes", "prompt": "fn main() {", "suffix": "}", "stream": false}),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert!(response.get("context").is_none());
+        assert_eq!(
+            model.prompt(),
+            "<|fim_prefix|>fn main() {<|fim_suffix|>}<|fim_middle|>"
+        );
+        assert_eq!(model.options().suffix, None);
+
+        let Json(running) = ps(State(Arc::clone(&state))).await;
+        assert_eq!(running.models.len(), 1);
+        let (_, response) =
+            send_generate(&state, json!({"model": "hermes", "keep_alive": 0})).await;
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["done_reason"], "unload");
+        let Json(running) = ps(State(Arc::clone(&state))).await;
+        assert!(running.models.is_empty());
+
+        let (status, _) = send_generate(&state, json!({"model": "missing", "prompt": "hi"})).await;
+        assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_generate_keeps_sse_for_event_stream_clients() {
+        let state = scripted(&["Hi"]).state("hermes", Some("chatml"));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "text/event-stream".parse().unwrap());
+        let request = json_request(json!({"model": "hermes", "prompt": "hi"}));
+        let (_, response) = body(generate(State(Arc::clone(&state)), headers, request).await).await;
+        assert!(response.contains("data: Hi"));
+        assert!(response.contains("data: [DONE]"));
+    }
+
+    #[tokio::test]
+    async fn test_chat_tool_calls_and_streaming() {
+        let model = scripted(&[
+            "Sure. <tool_call>\n{\"name\": \"weather\", ",
+            "\"arguments\": {\"city\": \"Oslo\"}}\n</tool_call>",
+        ]);
+        let state = model.state("hermes", Some("chatml"));
+        let tools = json!([{"type": "function", "function": {
+            "name": "weather", "parameters": {"type": "object"}}}]);
+        let request = json!({"model": "hermes", "stream": false, "tools": tools,
+        "messages": [
+            {"role": "user", "content": "Weather in Paris?"},
+            {"role": "assistant", "content": "",
+                "tool_calls": [{"function": {"name": "weather", "arguments": {"city": "Paris"}}}]},
+            {"role": "tool", "content": "rain"},
+            {"role": "user", "content": "And Oslo?"}
+        ]});
+        let response = chat(State(Arc::clone(&state)), json_request(request.clone())).await;
+        let (status, response) = body(response).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["message"]["role"], "assistant");
+        assert_eq!(response["message"]["content"], "Sure.");
+        let call = &response["message"]["tool_calls"][0]["function"];
+        assert_eq!(call["name"], "weather");
+        assert_eq!(call["arguments"], json!({"city": "Oslo"}));
+        assert_eq!(response["eval_count"], 2);
+        let sent = model.prompt();
+        assert!(sent.contains("Paris") && sent.contains("rain") && sent.contains("\"weather\""));
+
+        let mut request = request;
+        request["stream"] = json!(true);
+        let response = chat(State(Arc::clone(&state)), json_request(request)).await;
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-ndjson"
+        );
+        let (_, response) = body(response).await;
+        let lines = lines(&response);
+        let calls: Vec<_> = lines
+            .iter()
+            .filter_map(|line| line["message"].get("tool_calls"))
+            .collect();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0][0]["function"]["arguments"]["city"], "Oslo");
+        let last = lines.last().unwrap();
+        assert_eq!(last["done"], true);
+        assert_eq!(last["message"]["content"], "");
+    }
+
+    #[tokio::test]
+    async fn test_tags_show_embed_and_version() {
+        let state = scripted(&[]).state("hermes", Some("chatml"));
+        let Json(tags) = tags(State(Arc::clone(&state))).await;
+        assert_eq!(tags.models.len(), 1);
+        assert_eq!(tags.models[0].name, "hermes");
+        assert_eq!(tags.models[0].details.format, "gguf");
+        let Json(running) = ps(State(Arc::clone(&state))).await;
+        assert!(running.models.is_empty());
+
+        let request = json_request(json!({"name": "hermes"}));
+        let (status, shown) = body(show(State(Arc::clone(&state)), request).await).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let shown: Value = serde_json::from_str(&shown).unwrap();
+        assert_eq!(shown["template"], "chatml");
+        assert_eq!(
+            shown["capabilities"],
+            json!(["completion", "tools", "embedding"])
+        );
+        // Showing loaded it, for the default keep-alive
+        let Json(running) = ps(State(Arc::clone(&state))).await;
+        assert_eq!(running.models.len(), 1);
+        let expires_at = running.models[0].expires_at.as_deref().unwrap();
+        assert!(DateTime::parse_from_rfc3339(expires_at).unwrap() > Utc::now());
+        let Json(listed) = super::tags(State(Arc::clone(&state))).await;
+        assert!(listed.models[0].expires_at.is_none());
+
+        // A negative keep-alive holds it until it is unloaded
+        let (_, response) =
+            send_generate(&state, json!({"model": "hermes", "keep_alive": "-1"})).await;
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["done_reason"], "load");
+        let Json(running) = ps(State(Arc::clone(&state))).await;
+        assert!(running.models[0].expires_at.is_none());
+
+        let request = json_request(json!({"model": "hermes", "input": ["a", "bcd"]}));
+        let (status, embedded) = body(embed(State(Arc::clone(&state)), request).await).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let embedded: Value = serde_json::from_str(&embedded).unwrap();
+        assert_eq!(embedded["embeddings"], json!([[1.0, 1.0], [3.0, 1.0]]));
+
+        let Json(version) = version().await;
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    }
+}
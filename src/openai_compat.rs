@@ -1,8 +1,20 @@
 #![allow(dead_code)]
 
-use crate::{api::ChatMessage, AppState};
-use axum::{extract::State, response::IntoResponse, Json};
+use crate::api::{check_capabilities, ChatMessage, ChatToolCall, RequestedFeatures};
+use crate::api_errors::{ApiError, Json};
+use crate::engine::stream::{ChoiceStream, FinishReason, GenerationStats};
+use crate::engine::{Capabilities, GenOptions, GenerationEvent, LoadedModel, ModelSpec};
+use crate::json_grammar;
+use crate::loaded_models::KeepAlive;
+use crate::templates::{fim_prompt, TemplateFamily};
+use crate::tool_calling::{Parsed, ToolCallParser, ToolFormat, ToolMode};
+use crate::tools::{ToolDefinition, ToolResult, GLOBAL_TOOL_REGISTRY};
+use crate::AppState;
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 
 #[derive(Debug, Deserialize)]
@@ -17,6 +29,205 @@ pub struct ChatCompletionRequest {
     pub max_tokens: Option<usize>,
     #[serde(default)]
     pub top_p: Option<f32>,
+    #[serde(default)]
+    pub logprobs: Option<bool>,
+    #[serde(default)]
+    pub top_logprobs: Option<u32>,
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(default)]
+    pub tools: Option<Vec<ChatTool>>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    /// `"server"` to run tool calls from the server's tool registry and
+    /// answer with the final reply; `"client"` (the default) returns them
+    #[serde(default)]
+    pub tool_execution: Option<String>,
+    #[serde(default)]
+    pub max_tool_iterations: Option<usize>,
+    /// Choices to generate; they share one evaluation of the prompt
+    #[serde(default)]
+    pub n: Option<usize>,
+    /// Choice `i` samples with `seed + i`
+    #[serde(default)]
+    pub seed: Option<u32>,
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>,
+}
+
+/// `{"include_usage": true}` adds a final chunk carrying `usage`
+#[derive(Debug, Deserialize)]
+pub struct StreamOptions {
+    #[serde(default)]
+    pub include_usage: Option<bool>,
+}
+
+/// Most choices one chat completion may ask for, as upstream
+pub const MAX_CHAT_CHOICES: usize = 128;
+
+/// `{"type": "text" | "json_object" | "json_schema", ...}`
+#[derive(Debug, Deserialize)]
+pub struct ResponseFormat {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// `name`, `schema` and `strict` of a `json_schema` format
+    #[serde(default)]
+    pub json_schema: Option<serde_json::Value>,
+}
+
+impl ResponseFormat {
+    /// GBNF for the output this format asks for
+    fn grammar(&self) -> Option<String> {
+        let schema = self.json_schema.as_ref().and_then(|f| f.get("schema"));
+        json_grammar::for_format(&self.kind, schema)
+    }
+}
+
+/// `{"type": "function", "function": {"name", "description", "parameters"}}`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChatTool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: ToolDefinition,
+}
+
+/// `"none" | "auto" | "required"`, or `{"type": "function", "function": {"name"}}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Function { function: ToolChoiceFunction },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ToolChoiceFunction {
+    pub name: String,
+}
+
+impl ChatCompletionRequest {
+    fn features(&self) -> RequestedFeatures {
+        RequestedFeatures {
+            grammar: self
+                .response_format
+                .as_ref()
+                .is_some_and(|f| f.kind != "text"),
+            logprobs: self.logprobs.unwrap_or(false) || self.top_logprobs.is_some(),
+            max_tokens: self.max_tokens,
+            ..Default::default()
+        }
+    }
+
+    fn options(&self) -> GenOptions {
+        let mut opts = GenOptions::default();
+        if let Some(t) = self.temperature {
+            opts.temperature = t;
+        }
+        if let Some(p) = self.top_p {
+            opts.top_p = p;
+        }
+        if let Some(m) = self.max_tokens {
+            opts.max_tokens = m;
+        }
+        // OpenAI streams only when asked to
+        opts.stream = self.stream.unwrap_or(false);
+        opts.seed = self.seed;
+        opts.grammar = self
+            .response_format
+            .as_ref()
+            .and_then(ResponseFormat::grammar);
+        opts
+    }
+
+    /// How many choices to generate
+    fn choices(&self) -> Result<usize, ApiError> {
+        match self.n.unwrap_or(1) {
+            0 => Err(ApiError::InvalidRequest("n must be at least 1".to_string())),
+            n if n > MAX_CHAT_CHOICES => Err(ApiError::InvalidRequest(format!(
+                "n must be at most {}",
+                MAX_CHAT_CHOICES
+            ))),
+            n if n > 1 && self.tool_execution.as_deref() == Some("server") => Err(
+                ApiError::InvalidRequest("tool_execution 'server' needs n = 1".to_string()),
+            ),
+            n => Ok(n),
+        }
+    }
+
+    /// Whether the server runs the model's tool calls itself
+    fn executes_tools(&self) -> Result<bool, ApiError> {
+        let invalid = |message: &str| Err(ApiError::InvalidRequest(message.to_string()));
+        match self.tool_execution.as_deref() {
+            None | Some("client") => Ok(false),
+            Some("server") if self.stream.unwrap_or(false) => {
+                invalid("tool_execution 'server' does not support streaming")
+            }
+            Some("server") if self.max_tool_iterations == Some(0) => {
+                invalid("max_tool_iterations must be at least 1")
+            }
+            Some("server") => Ok(true),
+            Some(other) => Err(ApiError::InvalidRequest(format!(
+                "unknown tool_execution '{}'",
+                other
+            ))),
+        }
+    }
+
+    /// The function tools to offer and what `tool_choice` demands of them;
+    /// `None` when the request doesn't use tools
+    fn tool_mode(&self) -> Result<Option<(Vec<ToolDefinition>, ToolMode)>, ApiError> {
+        let server = self.executes_tools()?;
+        let tools: Vec<ToolDefinition> = match &self.tools {
+            // Without a list, the server offers every tool it can run
+            None if server => GLOBAL_TOOL_REGISTRY.list_tools(),
+            requested => requested
+                .iter()
+                .flatten()
+                .filter(|tool| tool.kind == "function")
+                .map(|tool| tool.function.clone())
+                .collect(),
+        };
+        if server {
+            let unknown = tools
+                .iter()
+                .find(|tool| GLOBAL_TOOL_REGISTRY.get_tool(&tool.name).is_none());
+            if let Some(tool) = unknown {
+                return Err(ApiError::InvalidRequest(format!(
+                    "tool '{}' is not registered on this server",
+                    tool.name
+                )));
+            }
+        }
+        let mode = match &self.tool_choice {
+            None => ToolMode::Auto,
+            Some(ToolChoice::Mode(mode)) => match mode.as_str() {
+                "none" => return Ok(None),
+                "auto" => ToolMode::Auto,
+                "required" => ToolMode::Required,
+                other => {
+                    return Err(ApiError::InvalidRequest(format!(
+                        "unknown tool_choice '{}'",
+                        other
+                    )))
+                }
+            },
+            Some(ToolChoice::Function { function }) => {
+                if !tools.iter().any(|tool| tool.name == function.name) {
+                    return Err(ApiError::InvalidRequest(format!(
+                        "tool_choice names function '{}', which is not in tools",
+                        function.name
+                    )));
+                }
+                ToolMode::Function(function.name.clone())
+            }
+        };
+        match mode {
+            ToolMode::Auto if tools.is_empty() => Ok(None),
+            ToolMode::Required if tools.is_empty() => Err(ApiError::InvalidRequest(
+                "tool_choice 'required' needs at least one tool".to_string(),
+            )),
+            mode => Ok(Some((tools, mode))),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -27,8 +238,23 @@ pub struct ChatCompletionResponse {
     pub model: String,
     pub choices: Vec<Choice>,
     pub usage: Usage,
+    /// Tool calls the server ran, with `"tool_execution": "server"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_trace: Option<Vec<ToolStep>>,
 }
 
+/// A tool call the server ran during a chat completion
+#[derive(Debug, Serialize)]
+pub struct ToolStep {
+    /// The generation that made the call, counting from 1
+    pub iteration: usize,
+    pub call: ChatToolCall,
+    pub result: ToolResult,
+}
+
+/// Default for `max_tool_iterations`
+pub const DEFAULT_TOOL_ITERATIONS: usize = 5;
+
 #[derive(Debug, Serialize)]
 pub struct Choice {
     pub index: usize,
@@ -36,7 +262,7 @@ pub struct Choice {
     pub finish_reason: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Usage {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
@@ -50,6 +276,9 @@ pub struct ChatCompletionChunk {
     pub created: u64,
     pub model: String,
     pub choices: Vec<ChunkChoice>,
+    /// Only on the final chunk, with `stream_options.include_usage`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -59,10 +288,21 @@ pub struct ChunkChoice {
     pub finish_reason: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Delta {
     pub content: Option<String>,
     pub role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// A tool call in a stream chunk. Calls are sent whole once parsed, so the
+/// first delta for an `index` is also its last.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    #[serde(flatten)]
+    pub call: ChatToolCall,
 }
 
 #[derive(Debug, Serialize)]
@@ -77,6 +317,9 @@ pub struct Model {
     pub object: String,
     pub created: u64,
     pub owned_by: String,
+    /// Known once the model has been loaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
 }
 
 pub async fn models(State(state): State<Arc<AppState>>) -> impl IntoResponse {
@@ -85,6 +328,7 @@ pub async fn models(State(state): State<Arc<AppState>>) -> impl IntoResponse {
         .list_all_available()
         .into_iter()
         .map(|name| Model {
+            capabilities: state.registry.capabilities(&name),
             id: name,
             object: "model".to_string(),
             created: 0, // Fixed timestamp for simplicity
@@ -98,214 +342,469 @@ pub async fn models(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     })
 }
 
-pub async fn chat_completions(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<ChatCompletionRequest>,
-) -> impl IntoResponse {
-    use axum::http::StatusCode;
+/// Look up `model` and load it unless it is already in memory, or the
+/// error response for a request naming it
+pub(crate) async fn load_model(
+    state: &AppState,
+    model: &str,
+) -> Result<(ModelSpec, Arc<dyn LoadedModel>), ApiError> {
+    load_model_keeping(state, model, None).await
+}
 
-    // Load and validate model
-    let Some(spec) = state.registry.to_spec(&req.model) else {
-        tracing::warn!("Model '{}' not found in registry", req.model);
-        return StatusCode::NOT_FOUND.into_response();
+/// [`load_model`], keeping the model in memory for `keep_alive` instead of
+/// the server's default
+pub(crate) async fn load_model_keeping(
+    state: &AppState,
+    model: &str,
+    keep_alive: Option<KeepAlive>,
+) -> Result<(ModelSpec, Arc<dyn LoadedModel>), ApiError> {
+    let Some(spec) = state.registry.to_spec(model) else {
+        tracing::warn!("Model '{}' not found in registry", model);
+        return Err(ApiError::ModelNotFound(model.to_string()));
     };
-    tracing::debug!("Found model spec for '{}': {:?}", req.model, spec);
-    let engine = &state.engine;
-    let loaded = match engine.load(&spec).await {
-        Ok(loaded) => loaded,
+    tracing::debug!("Found model spec for '{}': {:?}", model, spec);
+    match state
+        .models
+        .get_or_load(state.engine.as_ref(), &spec, keep_alive)
+        .await
+    {
+        Ok(loaded) => Ok((spec, loaded)),
         Err(e) => {
-            tracing::error!("Failed to load model '{}': {:?}", req.model, e);
-            return StatusCode::BAD_GATEWAY.into_response();
+            tracing::error!("Failed to load model '{}': {:?}", model, e);
+            Err(ApiError::load_failed(model, &e))
         }
-    };
+    }
+}
 
-    // Construct prompt from messages
-    let fam = match spec.template.as_deref() {
-        Some("chatml") => crate::templates::TemplateFamily::ChatML,
-        Some("llama3") | Some("llama-3") => crate::templates::TemplateFamily::Llama3,
+/// OpenAI's `finish_reason`. It has no "cancelled": a generation cut short
+/// by its client reports "stop", as one ended by a stop sequence does.
+pub(crate) fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Cancelled => "stop",
+        other => other.as_str(),
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The chat template for `model`: its configured template, else a guess from its name
+pub(crate) fn template_family(spec: &ModelSpec, model: &str) -> TemplateFamily {
+    match spec.template.as_deref() {
+        Some("chatml") => TemplateFamily::ChatML,
+        Some("llama3") | Some("llama-3") => TemplateFamily::Llama3,
+        Some("mistral") => TemplateFamily::Mistral,
         _ => {
-            // Auto-detect template based on model name
-            if req.model.to_lowercase().contains("qwen")
-                || req.model.to_lowercase().contains("chatglm")
-            {
-                crate::templates::TemplateFamily::ChatML
-            } else if req.model.to_lowercase().contains("llama") {
-                crate::templates::TemplateFamily::Llama3
+            let name = model.to_lowercase();
+            if name.contains("qwen") || name.contains("chatglm") {
+                TemplateFamily::ChatML
+            } else if name.contains("llama") {
+                TemplateFamily::Llama3
+            } else if name.contains("mistral") || name.contains("mixtral") {
+                TemplateFamily::Mistral
             } else {
-                crate::templates::TemplateFamily::OpenChat
+                TemplateFamily::OpenChat
             }
         }
-    };
-    let pairs = req
-        .messages
-        .iter()
-        .map(|m| (m.role.clone(), m.content.clone()))
-        .collect::<Vec<_>>();
-
-    // For chat completions, we need to trigger assistant response
-    // Extract the last user message to use as input parameter
-    let last_user_message = req
-        .messages
-        .iter()
-        .filter(|m| m.role == "user")
-        .last()
-        .map(|m| m.content.as_str());
-
-    // Build conversation history without the last user message
-    let history: Vec<_> = if last_user_message.is_some() {
-        req.messages
-            .iter()
-            .take(req.messages.len().saturating_sub(1))
-            .map(|m| (m.role.clone(), m.content.clone()))
-            .collect()
-    } else {
-        pairs.clone()
-    };
+    }
+}
 
-    let prompt = fam.render(None, &history, last_user_message);
+/// A message as a template turn, with tool calls and results written the
+/// way the model was trained to see them
+fn render_message(format: ToolFormat, message: &ChatMessage) -> (String, String) {
+    match (message.role.as_str(), &message.tool_calls) {
+        ("assistant", Some(calls)) if !calls.is_empty() => {
+            let calls: Vec<_> = calls
+                .iter()
+                .map(|call| (call.to_tool_call(), call.id.as_str()))
+                .collect();
+            (
+                message.role.clone(),
+                format.render_calls(&message.content, &calls),
+            )
+        }
+        ("tool", _) => format.render_result(&message.content, message.tool_call_id.as_deref()),
+        _ => (message.role.clone(), message.content.clone()),
+    }
+}
+
+/// The prompt for `messages`, offering `tools` in the template's format,
+/// and the text it ends with to start the reply inside a call
+pub(crate) fn chat_prompt(
+    fam: &TemplateFamily,
+    format: ToolFormat,
+    messages: &[ChatMessage],
+    tools: Option<&(Vec<ToolDefinition>, ToolMode)>,
+) -> (String, Option<String>) {
+    let mut turns: Vec<(String, String)> =
+        messages.iter().map(|m| render_message(format, m)).collect();
+    if let Some((defs, mode)) = tools {
+        format.inject(&mut turns, defs, mode);
+    }
+    let prefill = tools.and_then(|(_, mode)| format.prefill(mode));
+    let mut prompt = fam.render_chat(&turns);
+    prompt.push_str(prefill.as_deref().unwrap_or_default());
+    (prompt, prefill)
+}
 
-    // Set generation options
-    let mut opts = crate::engine::GenOptions::default();
-    if let Some(t) = req.temperature {
-        opts.temperature = t;
+/// A tool's result as the content of a `tool` message
+fn tool_output(result: &ToolResult) -> String {
+    match (&result.error, &result.result) {
+        (Some(error), _) => format!("error: {}", error),
+        (None, serde_json::Value::String(text)) => text.clone(),
+        (None, value) => value.to_string(),
     }
-    if let Some(p) = req.top_p {
-        opts.top_p = p;
+}
+
+/// `"tool_execution": "server"`: run each generation's tool calls from the
+/// registry and feed the results back, until the model answers without a
+/// call or `max_tool_iterations` generations have run
+async fn run_tool_loop(
+    state: &AppState,
+    req: &ChatCompletionRequest,
+    loaded: Arc<dyn LoadedModel>,
+    fam: &TemplateFamily,
+    format: ToolFormat,
+    (defs, mut mode): (Vec<ToolDefinition>, ToolMode),
+) -> Response {
+    let max_iterations = req.max_tool_iterations.unwrap_or(DEFAULT_TOOL_ITERATIONS);
+    let opts = req.options();
+    let mut messages = req.messages.clone();
+    let mut trace = Vec::new();
+    let mut usage = Usage {
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        total_tokens: 0,
+    };
+    let mut iteration = 0;
+    loop {
+        iteration += 1;
+        let tools = (defs.clone(), mode.clone());
+        let (prompt, prefill) = chat_prompt(fam, format, &messages, Some(&tools));
+        if let Err(e) =
+            check_capabilities(state, &req.model, &loaded, &prompt, &req.features()).await
+        {
+            return e.into_response();
+        }
+        let events = loaded.clone().generate_stream(prompt, opts.clone());
+        let done = match crate::engine::stream::complete(events, |_| {}).await {
+            Ok(done) => done,
+            Err(e) => {
+                tracing::error!(
+                    "Failed to generate response for model '{}': {:?}",
+                    req.model,
+                    e
+                );
+                return ApiError::generation_failed(&e).into_response();
+            }
+        };
+        usage.prompt_tokens += done.stats.prompt_tokens;
+        usage.completion_tokens += done.stats.completion_tokens;
+        let (content, calls) = ToolCallParser::new(format, prefill.as_deref()).parse(&done.text);
+        let calls: Vec<ChatToolCall> = calls.into_iter().map(ChatToolCall::new).collect();
+
+        if calls.is_empty() || iteration == max_iterations {
+            // Calls still pending at the limit go back to the client unexecuted
+            let (content, finish_reason) = if calls.is_empty() {
+                (content, finish_reason(done.finish_reason))
+            } else {
+                (content.trim().to_string(), "tool_calls")
+            };
+            usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+            let response = ChatCompletionResponse {
+                id: format!("chatcmpl-{}", uuid::Uuid::new_v4().simple()),
+                object: "chat.completion".to_string(),
+                created: unix_now(),
+                model: req.model.clone(),
+                choices: vec![Choice {
+                    index: 0,
+                    message: ChatMessage {
+                        role: "assistant".to_string(),
+                        content,
+                        tool_calls: (!calls.is_empty()).then_some(calls),
+                        tool_call_id: None,
+                    },
+                    finish_reason: Some(finish_reason.to_string()),
+                }],
+                usage,
+                tool_trace: Some(trace),
+            };
+            return Json(response).into_response();
+        }
+
+        messages.push(ChatMessage {
+            role: "assistant".to_string(),
+            content: content.trim().to_string(),
+            tool_calls: Some(calls.clone()),
+            tool_call_id: None,
+        });
+        for call in calls {
+            let result =
+                crate::tools::execute_blocking(&GLOBAL_TOOL_REGISTRY, call.to_tool_call()).await;
+            tracing::debug!(
+                "Tool '{}' for model '{}': success={}",
+                call.function.name,
+                req.model,
+                result.success
+            );
+            messages.push(ChatMessage {
+                role: "tool".to_string(),
+                content: tool_output(&result),
+                tool_call_id: Some(call.id.clone()),
+                ..Default::default()
+            });
+            trace.push(ToolStep {
+                iteration,
+                call,
+                result,
+            });
+        }
+        // Only the first generation is held to `tool_choice`
+        mode = ToolMode::Auto;
     }
-    if let Some(m) = req.max_tokens {
-        opts.max_tokens = m;
+}
+
+pub async fn chat_completions(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ChatCompletionRequest>,
+) -> impl IntoResponse {
+    let (tools, n) = match req
+        .tool_mode()
+        .and_then(|tools| Ok((tools, req.choices()?)))
+    {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+    let (spec, loaded) = match load_model(&state, &req.model).await {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+
+    // Construct prompt from messages, offering tools in the template's format
+    let fam = template_family(&spec, &req.model);
+    let format = ToolFormat::for_template(&fam, &req.model);
+    if req.tool_execution.as_deref() == Some("server") {
+        if let Some(tools) = tools {
+            return run_tool_loop(&state, &req, loaded, &fam, format, tools).await;
+        }
     }
-    if let Some(s) = req.stream {
-        opts.stream = s;
+    let (prompt, prefill) = chat_prompt(&fam, format, &req.messages, tools.as_ref());
+    if let Err(e) = check_capabilities(&state, &req.model, &loaded, &prompt, &req.features()).await
+    {
+        return e.into_response();
     }
 
+    let opts = req.options();
+
+    let events = choice_stream(&loaded, prompt, &opts, n);
+    let id = format!("chatcmpl-{}", uuid::Uuid::new_v4().simple());
+    let created = unix_now();
+
     if opts.stream {
         // Handle streaming response with proper OpenAI format
         use axum::response::sse::{Event, Sse};
-        use tokio_stream::wrappers::UnboundedReceiverStream;
-        use tokio_stream::StreamExt;
-
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<String>();
-        let mut opts_clone = opts.clone();
-        opts_clone.stream = false;
-        let prompt_clone = prompt.clone();
-        let model_clone = req.model.clone();
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-        let id = format!("chatcmpl-{}", uuid::Uuid::new_v4().simple());
-
-        tokio::spawn(async move {
-            let tx_tokens = tx.clone();
-            let id_for_tokens = id.clone();
-            let model_for_tokens = model_clone.clone();
-            let id_for_final = id.clone();
-            let model_for_final = model_clone.clone();
-
-            // Send initial chunk with role
-            let initial_chunk = ChatCompletionChunk {
-                id: id_for_tokens.clone(),
+        use futures_util::stream;
+
+        let model = req.model.clone();
+        let envelope = move |choices: Vec<ChunkChoice>, usage: Option<Usage>| {
+            let chunk = ChatCompletionChunk {
+                id: id.clone(),
                 object: "chat.completion.chunk".to_string(),
-                created: timestamp,
-                model: model_for_tokens.clone(),
-                choices: vec![ChunkChoice {
-                    index: 0,
-                    delta: Delta {
+                created,
+                model: model.clone(),
+                choices,
+                usage,
+            };
+            serde_json::to_string(&chunk).unwrap()
+        };
+        let chunk = {
+            let envelope = envelope.clone();
+            move |index: usize, delta: Delta, finish_reason: Option<&str>| {
+                let choice = ChunkChoice {
+                    index,
+                    delta,
+                    finish_reason: finish_reason.map(str::to_string),
+                };
+                envelope(vec![choice], None)
+            }
+        };
+        let first: Vec<String> = (0..n)
+            .map(|index| {
+                chunk(
+                    index,
+                    Delta {
                         role: Some("assistant".to_string()),
-                        content: None,
+                        ..Default::default()
                     },
-                    finish_reason: None,
-                }],
-            };
-            let _ = tx_tokens.send(format!(
-                "data: {}\n\n",
-                serde_json::to_string(&initial_chunk).unwrap()
-            ));
-
-            // Generate and stream tokens
-            let _ = loaded
-                .generate(
-                    &prompt_clone,
-                    opts_clone,
-                    Some(Box::new(move |tok| {
-                        let chunk = ChatCompletionChunk {
-                            id: id_for_tokens.clone(),
-                            object: "chat.completion.chunk".to_string(),
-                            created: timestamp,
-                            model: model_for_tokens.clone(),
-                            choices: vec![ChunkChoice {
-                                index: 0,
-                                delta: Delta {
-                                    role: None,
-                                    content: Some(tok),
-                                },
-                                finish_reason: None,
-                            }],
-                        };
-                        let _ = tx_tokens.send(format!(
-                            "data: {}\n\n",
-                            serde_json::to_string(&chunk).unwrap()
-                        ));
-                    })),
+                    None,
                 )
-                .await;
-
-            // Send final chunk
-            let final_chunk = ChatCompletionChunk {
-                id: id_for_final,
-                object: "chat.completion.chunk".to_string(),
-                created: timestamp,
-                model: model_for_final,
-                choices: vec![ChunkChoice {
-                    index: 0,
-                    delta: Delta {
-                        role: None,
-                        content: None,
-                    },
-                    finish_reason: Some("stop".to_string()),
-                }],
+            })
+            .collect();
+        let model = req.model.clone();
+        let mut parsers: Vec<_> = (0..n)
+            .map(|_| {
+                tools
+                    .as_ref()
+                    .map(|_| ToolCallParser::new(format, prefill.as_deref()))
+            })
+            .collect();
+        let mut calls = vec![0; n];
+        let mut running = n;
+        let include_usage = req
+            .stream_options
+            .as_ref()
+            .and_then(|o| o.include_usage)
+            .unwrap_or(false);
+        let mut usage = Usage::default();
+        let rest = events.flat_map(move |(index, event)| {
+            let parser = &mut parsers[index];
+            let (pieces, finish_reason) = match event {
+                GenerationEvent::Token { text, .. } => match parser.as_mut() {
+                    Some(parser) => (parser.push(&text), None),
+                    None if text.is_empty() => (vec![], None),
+                    None => (vec![Parsed::Content(text)], None),
+                },
+                GenerationEvent::Done {
+                    finish_reason,
+                    stats,
+                } => {
+                    // Choices share the prompt, so it is counted once
+                    usage.prompt_tokens = stats.prompt_tokens;
+                    usage.completion_tokens += stats.completion_tokens;
+                    (
+                        parser
+                            .as_mut()
+                            .map(ToolCallParser::finish)
+                            .unwrap_or_default(),
+                        Some(self::finish_reason(finish_reason)),
+                    )
+                }
+                GenerationEvent::Error { message } => {
+                    tracing::error!(
+                        "Failed to generate response for model '{}': {}",
+                        model,
+                        message
+                    );
+                    return stream::iter(vec![ApiError::GenerationFailed(message).into_json()]);
+                }
+                GenerationEvent::Overloaded { model } => {
+                    return stream::iter(vec![ApiError::Overloaded(model).into_json()]);
+                }
+                GenerationEvent::PromptProgress { .. } => (vec![], None),
             };
-            let _ = tx.send(format!(
-                "data: {}\n\n",
-                serde_json::to_string(&final_chunk).unwrap()
-            ));
-            let _ = tx.send("data: [DONE]\n\n".to_string());
+            // Each tool call is sent whole, in its own chunk, once it has been parsed
+            let mut data: Vec<String> = pieces
+                .into_iter()
+                .map(|piece| match piece {
+                    Parsed::Content(text) => chunk(
+                        index,
+                        Delta {
+                            content: Some(text),
+                            ..Default::default()
+                        },
+                        None,
+                    ),
+                    Parsed::Call(call) => {
+                        let delta = ToolCallDelta {
+                            index: calls[index],
+                            call: ChatToolCall::new(call),
+                        };
+                        calls[index] += 1;
+                        chunk(
+                            index,
+                            Delta {
+                                tool_calls: Some(vec![delta]),
+                                ..Default::default()
+                            },
+                            None,
+                        )
+                    }
+                })
+                .collect();
+            if let Some(reason) = finish_reason {
+                let reason = if calls[index] > 0 {
+                    "tool_calls"
+                } else {
+                    reason
+                };
+                data.push(chunk(index, Delta::default(), Some(reason)));
+                running -= 1;
+                if running == 0 {
+                    // With `include_usage`, usage follows the last choice in
+                    // a chunk of its own
+                    if include_usage {
+                        usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+                        data.push(envelope(vec![], Some(usage.clone())));
+                    }
+                    data.push("[DONE]".to_string());
+                }
+            }
+            stream::iter(data)
         });
-
-        let stream = UnboundedReceiverStream::new(rx)
-            .map(|s| Ok::<Event, std::convert::Infallible>(Event::default().data(s)));
+        let stream = stream::iter(first)
+            .chain(rest)
+            .map(|data| Ok::<Event, std::convert::Infallible>(Event::default().data(data)));
         Sse::new(stream).into_response()
     } else {
         // Handle non-streaming response
-        match loaded.generate(&prompt, opts, None).await {
-            Ok(content) => {
+        match crate::engine::stream::complete_choices(events, n).await {
+            Ok(completions) => {
                 tracing::debug!(
-                    "Generated response for model '{}': {} chars",
-                    req.model,
-                    content.len()
+                    "Generated {} choice(s) for model '{}'",
+                    completions.len(),
+                    req.model
                 );
+                // The prompt was evaluated once, however many choices share it
+                let prompt_tokens = completions[0].stats.prompt_tokens;
+                let completion_tokens = completions
+                    .iter()
+                    .map(|done| done.stats.completion_tokens)
+                    .sum();
+                let choices = completions
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, done)| {
+                        let (content, calls) = match &tools {
+                            Some(_) => {
+                                ToolCallParser::new(format, prefill.as_deref()).parse(&done.text)
+                            }
+                            None => (done.text, vec![]),
+                        };
+                        let (content, finish_reason) = if calls.is_empty() {
+                            (content, finish_reason(done.finish_reason))
+                        } else {
+                            (content.trim().to_string(), "tool_calls")
+                        };
+                        let tool_calls = (!calls.is_empty())
+                            .then(|| calls.into_iter().map(ChatToolCall::new).collect());
+                        Choice {
+                            index,
+                            message: ChatMessage {
+                                role: "assistant".to_string(),
+                                content,
+                                tool_calls,
+                                tool_call_id: None,
+                            },
+                            finish_reason: Some(finish_reason.to_string()),
+                        }
+                    })
+                    .collect();
                 let response = ChatCompletionResponse {
-                    id: format!("chatcmpl-{}", uuid::Uuid::new_v4().simple()),
+                    id,
                     object: "chat.completion".to_string(),
-                    created: std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_secs(),
+                    created,
                     model: req.model,
-                    choices: vec![Choice {
-                        index: 0,
-                        message: ChatMessage {
-                            role: "assistant".to_string(),
-                            content,
-                        },
-                        finish_reason: Some("stop".to_string()),
-                    }],
+                    choices,
                     usage: Usage {
-                        prompt_tokens: 0, // Token counting not needed for local inference
-                        completion_tokens: 0,
-                        total_tokens: 0,
+                        prompt_tokens,
+                        completion_tokens,
+                        total_tokens: prompt_tokens + completion_tokens,
                     },
+                    tool_trace: None,
                 };
                 Json(response).into_response()
             }
@@ -315,26 +814,649 @@ pub async fn chat_completions(
                     req.model,
                     e
                 );
-                StatusCode::BAD_GATEWAY.into_response()
+                ApiError::generation_failed(&e).into_response()
             }
         }
     }
 }
 
+/// A string or an array of strings, as legacy completions take `prompt` and `stop`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(one) => vec![one],
+            OneOrMany::Many(many) => many,
+        }
+    }
+}
+
+/// OpenAI's default `max_tokens` for legacy completions
+pub const DEFAULT_COMPLETION_TOKENS: usize = 16;
+
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    #[serde(default)]
+    pub prompt: Option<OneOrMany>,
+    #[serde(default)]
+    pub suffix: Option<String>,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub n: Option<usize>,
+    #[serde(default)]
+    pub best_of: Option<usize>,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    #[serde(default)]
+    pub logprobs: Option<u32>,
+    #[serde(default)]
+    pub echo: Option<bool>,
+    #[serde(default)]
+    pub stop: Option<OneOrMany>,
+    #[serde(default)]
+    pub seed: Option<u32>,
+}
+
+impl CompletionRequest {
+    fn features(&self, n: usize, best_of: usize) -> RequestedFeatures {
+        RequestedFeatures {
+            // Picking the best of several candidates ranks them by logprob
+            logprobs: self.logprobs.is_some() || best_of > n,
+            max_tokens: self.max_tokens,
+            ..Default::default()
+        }
+    }
+}
+
+/// A completion, or with `usage` absent one of its stream chunks
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompletionChoice {
+    pub text: String,
+    pub index: usize,
+    pub logprobs: Option<CompletionLogprobs>,
+    pub finish_reason: Option<String>,
+}
+
+/// Per-token log probabilities. Backends report the sampled token's only,
+/// so `top_logprobs` is always null.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<Option<f32>>,
+    pub top_logprobs: Option<Vec<HashMap<String, f32>>>,
+    pub text_offset: Vec<usize>,
+}
+
+/// Cuts generated text at the first stop sequence. Text that could still
+/// be the start of one is held back until the next tokens decide it.
+pub(crate) struct StopSequences {
+    stops: Vec<String>,
+    pending: String,
+    matched: Option<String>,
+}
+
+impl StopSequences {
+    pub(crate) fn new(stops: &[String]) -> Self {
+        Self {
+            stops: stops.iter().filter(|s| !s.is_empty()).cloned().collect(),
+            pending: String::new(),
+            matched: None,
+        }
+    }
+
+    /// Add `piece`; returns the text that is now final and whether a stop
+    /// sequence ended the generation
+    pub(crate) fn push(&mut self, piece: &str) -> (String, bool) {
+        self.pending.push_str(piece);
+        let found = self
+            .stops
+            .iter()
+            .filter_map(|stop| Some((self.pending.find(stop.as_str())?, stop)))
+            .min_by_key(|(at, _)| *at);
+        if let Some((at, stop)) = found {
+            self.matched = Some(stop.clone());
+            self.pending.truncate(at);
+            return (std::mem::take(&mut self.pending), true);
+        }
+        let held = self
+            .stops
+            .iter()
+            .map(|stop| partial_match(&self.pending, stop))
+            .max()
+            .unwrap_or(0);
+        let held = self.pending.split_off(self.pending.len() - held);
+        (std::mem::replace(&mut self.pending, held), false)
+    }
+
+    /// The held back text, once the generation ended on its own
+    pub(crate) fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// The stop sequence that ended the generation
+    pub(crate) fn matched(&self) -> Option<&str> {
+        self.matched.as_deref()
+    }
+}
+
+/// A streamed reply with stop sequences applied and, when given a parser,
+/// tool calls split out of its text
+pub(crate) struct ReplyParser {
+    stop: StopSequences,
+    parser: Option<ToolCallParser>,
+    stopped: bool,
+}
+
+impl ReplyParser {
+    pub(crate) fn new(stops: &[String], parser: Option<ToolCallParser>) -> Self {
+        Self {
+            stop: StopSequences::new(stops),
+            parser,
+            stopped: false,
+        }
+    }
+
+    /// What `piece` completes. Once a stop sequence is found, the rest of
+    /// the reply is flushed and `stopped` turns true.
+    pub(crate) fn push(&mut self, piece: &str) -> Vec<Parsed> {
+        let (ready, stopped) = self.stop.push(piece);
+        self.stopped = stopped;
+        let mut parsed = self.parse(&ready);
+        if stopped {
+            parsed.extend(self.flush());
+        }
+        parsed
+    }
+
+    /// The rest of the reply, once the generation ended on its own
+    pub(crate) fn finish(&mut self) -> Vec<Parsed> {
+        let rest = self.stop.finish();
+        let mut parsed = self.parse(&rest);
+        parsed.extend(self.flush());
+        parsed
+    }
+
+    /// Whether a stop sequence ended the reply
+    pub(crate) fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub(crate) fn stop_sequence(&self) -> Option<&str> {
+        self.stop.matched()
+    }
+
+    fn parse(&mut self, text: &str) -> Vec<Parsed> {
+        match self.parser.as_mut() {
+            Some(parser) => parser.push(text),
+            None if text.is_empty() => vec![],
+            None => vec![Parsed::Content(text.to_string())],
+        }
+    }
+
+    fn flush(&mut self) -> Vec<Parsed> {
+        self.parser
+            .as_mut()
+            .map(ToolCallParser::finish)
+            .unwrap_or_default()
+    }
+}
+
+/// Length of the longest end of `text` that `stop` starts with
+pub(crate) fn partial_match(text: &str, stop: &str) -> usize {
+    (1..stop.len())
+        .rev()
+        .filter(|&len| stop.is_char_boundary(len))
+        .find(|&len| text.ends_with(&stop[..len]))
+        .unwrap_or(0)
+}
+
+/// One generated choice, before `echo` is applied
+struct Candidate {
+    text: String,
+    finish_reason: &'static str,
+    tokens: Vec<(String, Option<f32>)>,
+    stats: GenerationStats,
+}
+
+impl Candidate {
+    /// Logprobs of the tokens that made it into `text`, whose offsets start at `offset`
+    fn logprobs(&self, offset: usize) -> CompletionLogprobs {
+        let mut logprobs = CompletionLogprobs::default();
+        let mut at = 0;
+        for (token, logprob) in &self.tokens {
+            if at >= self.text.len() {
+                break;
+            }
+            logprobs.tokens.push(token.clone());
+            logprobs.token_logprobs.push(*logprob);
+            logprobs.text_offset.push(offset + at);
+            at += token.len();
+        }
+        logprobs
+    }
+
+    /// Mean logprob per token, for ranking `best_of` candidates
+    fn score(&self) -> f32 {
+        let logprobs = self.logprobs(0).token_logprobs;
+        let known: Vec<f32> = logprobs.into_iter().flatten().collect();
+        if known.is_empty() {
+            return f32::NEG_INFINITY;
+        }
+        known.iter().sum::<f32>() / known.len() as f32
+    }
+}
+
+/// The logprobs of streamed completion text. Stop sequences can hold text
+/// back, so each token goes out with the chunk its text starts in.
+struct StreamedLogprobs {
+    tokens: VecDeque<(String, Option<f32>)>,
+    /// Offset of the next token's text
+    at: usize,
+    /// End of the text sent so far
+    sent: usize,
+}
+
+impl StreamedLogprobs {
+    fn new(offset: usize) -> Self {
+        Self {
+            tokens: VecDeque::new(),
+            at: offset,
+            sent: offset,
+        }
+    }
+
+    fn push(&mut self, token: String, logprob: Option<f32>) {
+        self.tokens.push_back((token, logprob));
+    }
+
+    /// The logprobs for `text`, the next chunk sent
+    fn chunk(&mut self, text: &str) -> CompletionLogprobs {
+        self.sent += text.len();
+        let mut logprobs = CompletionLogprobs::default();
+        while self.at < self.sent {
+            let Some((token, logprob)) = self.tokens.pop_front() else {
+                break;
+            };
+            logprobs.text_offset.push(self.at);
+            self.at += token.len();
+            logprobs.tokens.push(token);
+            logprobs.token_logprobs.push(logprob);
+        }
+        logprobs
+    }
+}
+
+/// `n` generations of `prompt`, as chat completions run their choices: a
+/// single one keeps the backend's own sampling defaults, several are
+/// seeded apart and may share the prompt's evaluation
+fn choice_stream(
+    model: &Arc<dyn LoadedModel>,
+    prompt: String,
+    opts: &GenOptions,
+    n: usize,
+) -> ChoiceStream {
+    if n == 1 {
+        Box::pin(
+            model
+                .clone()
+                .generate_stream(prompt, opts.clone())
+                .map(|e| (0, e)),
+        )
+    } else {
+        model.clone().generate_choices(prompt, opts.clone(), n)
+    }
+}
+
+/// A candidate still being generated
+struct Partial {
+    stop: StopSequences,
+    text: String,
+    tokens: Vec<(String, Option<f32>)>,
+}
+
+impl Partial {
+    fn finish(&mut self, finish_reason: &'static str, stats: GenerationStats) -> Candidate {
+        Candidate {
+            text: std::mem::take(&mut self.text),
+            finish_reason,
+            tokens: std::mem::take(&mut self.tokens),
+            stats,
+        }
+    }
+}
+
+async fn generate_candidates(
+    model: &Arc<dyn LoadedModel>,
+    prompt: &str,
+    prompt_tokens: usize,
+    opts: &GenOptions,
+    count: usize,
+    stops: &[String],
+) -> anyhow::Result<Vec<Candidate>> {
+    let mut events = choice_stream(model, prompt.to_string(), opts, count);
+    let mut partials: Vec<Partial> = (0..count)
+        .map(|_| Partial {
+            stop: StopSequences::new(stops),
+            text: String::new(),
+            tokens: Vec::new(),
+        })
+        .collect();
+    let mut candidates: Vec<Option<Candidate>> = (0..count).map(|_| None).collect();
+    while let Some((index, event)) = events.next().await {
+        if candidates[index].is_some() {
+            continue;
+        }
+        let partial = &mut partials[index];
+        match event {
+            GenerationEvent::PromptProgress { .. } => {}
+            GenerationEvent::Token {
+                text: piece,
+                logprob,
+                ..
+            } => {
+                let (ready, stopped) = partial.stop.push(&piece);
+                partial.text.push_str(&ready);
+                partial.tokens.push((piece, logprob));
+                if stopped {
+                    let stats = GenerationStats {
+                        prompt_tokens,
+                        completion_tokens: partial.tokens.len(),
+                        ..Default::default()
+                    };
+                    candidates[index] = Some(partial.finish("stop", stats));
+                }
+            }
+            GenerationEvent::Done {
+                finish_reason,
+                stats,
+            } => {
+                let rest = partial.stop.finish();
+                partial.text.push_str(&rest);
+                candidates[index] = Some(partial.finish(self::finish_reason(finish_reason), stats));
+            }
+            GenerationEvent::Error { message } => return Err(anyhow::anyhow!("{}", message)),
+            GenerationEvent::Overloaded { model } => {
+                return Err(crate::error::ShimmyError::Overloaded { model }.into())
+            }
+        }
+        if candidates.iter().all(Option::is_some) {
+            // Returning drops `events`, which cancels what is still generating
+            return Ok(candidates.into_iter().flatten().collect());
+        }
+    }
+    Err(anyhow::anyhow!("generation ended without finishing"))
+}
+
+/// `POST /v1/completions`: OpenAI's legacy text completions on the same
+/// engine path as chat completions, without a chat template
+pub async fn completions(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CompletionRequest>,
+) -> impl IntoResponse {
+    let invalid = |message: &str| ApiError::InvalidRequest(message.to_string()).into_response();
+    let n = req.n.unwrap_or(1);
+    let best_of = req.best_of.unwrap_or(n);
+    let stream = req.stream.unwrap_or(false);
+    if n == 0 {
+        return invalid("n must be at least 1");
+    }
+    if best_of < n {
+        return invalid("best_of must be at least n");
+    }
+    if best_of > MAX_CHAT_CHOICES {
+        return ApiError::InvalidRequest(format!(
+            "n and best_of must be at most {}",
+            MAX_CHAT_CHOICES
+        ))
+        .into_response();
+    }
+    if req.echo == Some(true) && req.suffix.is_some() {
+        return invalid("echo is not supported with suffix");
+    }
+    if stream && best_of > n {
+        return invalid("best_of is not supported when streaming");
+    }
+    let mut prompts = req
+        .prompt
+        .clone()
+        .map_or_else(|| vec![String::new()], OneOrMany::into_vec);
+    if prompts.is_empty() {
+        return invalid("prompt must not be an empty array");
+    }
+    // With a suffix the prompt is the code before the hole
+    if let Some(suffix) = &req.suffix {
+        for prompt in &mut prompts {
+            *prompt = fim_prompt(&req.model, prompt, suffix);
+        }
+    }
+
+    let (_, loaded) = match load_model(&state, &req.model).await {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+    let features = req.features(n, best_of);
+    let mut prompt_tokens = Vec::with_capacity(prompts.len());
+    for prompt in &prompts {
+        match check_capabilities(&state, &req.model, &loaded, prompt, &features).await {
+            Ok(counted) => prompt_tokens.push(counted.unwrap_or(0)),
+            Err(e) => return e.into_response(),
+        }
+    }
+
+    let defaults = GenOptions::default();
+    let opts = GenOptions {
+        max_tokens: req.max_tokens.unwrap_or(DEFAULT_COMPLETION_TOKENS),
+        temperature: req.temperature.unwrap_or(defaults.temperature),
+        top_p: req.top_p.unwrap_or(defaults.top_p),
+        seed: req.seed,
+        stream,
+        ..defaults
+    };
+    let stops = req
+        .stop
+        .clone()
+        .map(OneOrMany::into_vec)
+        .unwrap_or_default();
+    let echo = req.echo.unwrap_or(false);
+    let response = {
+        let id = format!("cmpl-{}", uuid::Uuid::new_v4().simple());
+        let created = unix_now();
+        let model = req.model.clone();
+        move |choices, usage| CompletionResponse {
+            id: id.clone(),
+            object: "text_completion".to_string(),
+            created,
+            model: model.clone(),
+            choices,
+            usage,
+        }
+    };
+
+    if stream {
+        use axum::response::sse::{Event, Sse};
+        use futures_util::{future, stream};
+
+        let chunk = move |index, text, logprobs, finish_reason: Option<&str>| {
+            let choice = CompletionChoice {
+                text,
+                index,
+                logprobs,
+                finish_reason: finish_reason.map(str::to_string),
+            };
+            serde_json::to_string(&response(vec![choice], None)).unwrap()
+        };
+        let model = req.model.clone();
+        let wants_logprobs = req.logprobs.is_some();
+        let choices = stream::iter(prompts.into_iter().enumerate()).flat_map(move |(i, prompt)| {
+            // Choice `i * n + j` is the `j`th completion of prompt `i`
+            let chunk = chunk.clone();
+            let heads: Vec<String> = if echo {
+                (0..n)
+                    .map(|j| chunk(i * n + j, prompt.clone(), None, None))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let model = model.clone();
+            let text = move |index,
+                             text: String,
+                             logprobs: &mut StreamedLogprobs,
+                             finish_reason: Option<&str>| {
+                let mut data = Vec::new();
+                if !text.is_empty() {
+                    let logprobs = wants_logprobs.then(|| logprobs.chunk(&text));
+                    data.push(chunk(index, text, logprobs, None));
+                }
+                if finish_reason.is_some() {
+                    data.push(chunk(index, String::new(), None, finish_reason));
+                }
+                data
+            };
+            let offset = if echo { prompt.len() } else { 0 };
+            let state: (Vec<StopSequences>, Vec<StreamedLogprobs>, Vec<bool>) = (
+                (0..n).map(|_| StopSequences::new(&stops)).collect(),
+                (0..n).map(|_| StreamedLogprobs::new(offset)).collect(),
+                vec![false; n],
+            );
+            // Ending the stream once every choice stopped drops the
+            // generation, which cancels it
+            let generated = choice_stream(&loaded, prompt, &opts, n)
+                .scan(state, move |(stop, logprobs, finished), (j, event)| {
+                    if finished.iter().all(|&f| f) {
+                        return future::ready(None);
+                    }
+                    let index = i * n + j;
+                    let data = match event {
+                        _ if finished[j] => vec![],
+                        GenerationEvent::PromptProgress { .. } => vec![],
+                        GenerationEvent::Token {
+                            text: piece,
+                            logprob,
+                            ..
+                        } => {
+                            let (ready, stopped) = stop[j].push(&piece);
+                            logprobs[j].push(piece, logprob);
+                            finished[j] = stopped;
+                            text(index, ready, &mut logprobs[j], stopped.then_some("stop"))
+                        }
+                        GenerationEvent::Done { finish_reason, .. } => {
+                            finished[j] = true;
+                            text(
+                                index,
+                                stop[j].finish(),
+                                &mut logprobs[j],
+                                Some(self::finish_reason(finish_reason)),
+                            )
+                        }
+                        GenerationEvent::Error { message } => {
+                            finished.fill(true);
+                            tracing::error!(
+                                "Failed to generate completion for model '{}': {}",
+                                model,
+                                message
+                            );
+                            vec![ApiError::GenerationFailed(message).into_json()]
+                        }
+                        GenerationEvent::Overloaded { model } => {
+                            finished.fill(true);
+                            vec![ApiError::Overloaded(model).into_json()]
+                        }
+                    };
+                    future::ready(Some(stream::iter(data)))
+                })
+                .flatten();
+            stream::iter(heads).chain(generated)
+        });
+        let stream = choices
+            .chain(stream::once(future::ready("[DONE]".to_string())))
+            .map(|data| Ok::<Event, std::convert::Infallible>(Event::default().data(data)));
+        return Sse::new(stream).into_response();
+    }
+
+    let mut choices = Vec::new();
+    let mut usage = Usage {
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        total_tokens: 0,
+    };
+    for (prompt, &prompt_tokens) in prompts.iter().zip(&prompt_tokens) {
+        let mut candidates =
+            match generate_candidates(&loaded, prompt, prompt_tokens, &opts, best_of, &stops).await
+            {
+                Ok(candidates) => candidates,
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to generate completion for model '{}': {:?}",
+                        req.model,
+                        e
+                    );
+                    return ApiError::generation_failed(&e).into_response();
+                }
+            };
+        usage.prompt_tokens += candidates[0].stats.prompt_tokens;
+        usage.completion_tokens += candidates
+            .iter()
+            .map(|c| c.stats.completion_tokens)
+            .sum::<usize>();
+        if best_of > n {
+            candidates.sort_by(|a, b| b.score().total_cmp(&a.score()));
+        }
+        let offset = if echo { prompt.len() } else { 0 };
+        for candidate in candidates.into_iter().take(n) {
+            choices.push(CompletionChoice {
+                index: choices.len(),
+                logprobs: req.logprobs.map(|_| candidate.logprobs(offset)),
+                text: if echo {
+                    format!("{}{}", prompt, candidate.text)
+                } else {
+                    candidate.text
+                },
+                finish_reason: Some(candidate.finish_reason.to_string()),
+            });
+        }
+    }
+    usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+    Json(response(choices, Some(usage))).into_response()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::engine::adapter::InferenceEngineAdapter;
     use crate::model_registry::Registry;
+    use crate::test_utils::{body, done, json_body, json_request, FakeModel};
     use crate::AppState;
-    use axum::{extract::State, Json};
+    use axum::extract::State;
     use std::sync::Arc;
 
     #[tokio::test]
     async fn test_chat_completions_handler_execution() {
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let request = ChatCompletionRequest {
             model: "test".to_string(),
@@ -343,6 +1465,16 @@ mod tests {
             max_tokens: None,
             top_p: None,
             stream: Some(false),
+            logprobs: None,
+            top_logprobs: None,
+            response_format: None,
+            tools: None,
+            tool_choice: None,
+            tool_execution: None,
+            max_tool_iterations: None,
+            n: None,
+            seed: None,
+            stream_options: None,
         };
 
         // Exercise handler code path (will gracefully fail due to no model)
@@ -354,7 +1486,7 @@ mod tests {
     async fn test_models_handler_execution() {
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         // Exercise models handler code path
         let _result = models(State(state)).await;
@@ -373,6 +1505,7 @@ mod tests {
                 message: ChatMessage {
                     role: "assistant".to_string(),
                     content: "Hello world".to_string(),
+                    ..Default::default()
                 },
                 finish_reason: Some("stop".to_string()),
             }],
@@ -381,6 +1514,7 @@ mod tests {
                 completion_tokens: 5,
                 total_tokens: 15,
             },
+            tool_trace: None,
         };
 
         assert_eq!(response.id, "test-id");
@@ -395,6 +1529,7 @@ mod tests {
             delta: Delta {
                 role: Some("assistant".to_string()),
                 content: Some("token".to_string()),
+                ..Default::default()
             },
             finish_reason: None,
         };
@@ -407,15 +1542,26 @@ mod tests {
     async fn test_chat_completions_model_not_found() {
         let registry = Registry::default();
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let request = ChatCompletionRequest {
             model: "nonexistent-model".to_string(),
             messages: vec![ChatMessage {
                 role: "user".to_string(),
                 content: "Hello".to_string(),
+                ..Default::default()
             }],
             stream: Some(false),
+            logprobs: None,
+            top_logprobs: None,
+            response_format: None,
+            tools: None,
+            tool_choice: None,
+            tool_execution: None,
+            max_tool_iterations: None,
+            n: None,
+            seed: None,
+            stream_options: None,
             temperature: None,
             max_tokens: None,
             top_p: None,
@@ -441,18 +1587,31 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let request = ChatCompletionRequest {
             model: "test-streaming".to_string(),
             messages: vec![ChatMessage {
                 role: "user".to_string(),
                 content: "Hello".to_string(),
+                ..Default::default()
             }],
             stream: Some(true), // Enable streaming (line 132)
+            logprobs: None,
+            top_logprobs: None,
+            response_format: None,
+            tools: None,
+            tool_choice: None,
+            tool_execution: None,
+            max_tool_iterations: None,
+            n: None,
+            seed: None,
+            stream_options: None,
             temperature: Some(0.7),
             max_tokens: Some(100),
             top_p: Some(0.9),
@@ -476,10 +1635,12 @@ mod tests {
             template: Some("llama3".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         let request = ChatCompletionRequest {
             model: "test-non-streaming".to_string(),
@@ -487,13 +1648,25 @@ mod tests {
                 ChatMessage {
                     role: "user".to_string(),
                     content: "Hello".to_string(),
+                    ..Default::default()
                 },
                 ChatMessage {
                     role: "assistant".to_string(),
                     content: "Hi there!".to_string(),
+                    ..Default::default()
                 },
             ],
             stream: Some(false), // Disable streaming (line 214)
+            logprobs: None,
+            top_logprobs: None,
+            response_format: None,
+            tools: None,
+            tool_choice: None,
+            tool_execution: None,
+            max_tool_iterations: None,
+            n: None,
+            seed: None,
+            stream_options: None,
             temperature: Some(0.5),
             max_tokens: Some(50),
             top_p: Some(0.8),
@@ -517,6 +1690,8 @@ mod tests {
             template: Some("chatml".to_string()),
             ctx_len: 2048,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         let fam = match spec_chatml.template.as_deref() {
@@ -534,6 +1709,8 @@ mod tests {
             template: Some("llama3".to_string()),
             ctx_len: 2048,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         let fam = match spec_llama3.template.as_deref() {
@@ -551,6 +1728,8 @@ mod tests {
             template: Some("unknown".to_string()),
             ctx_len: 2048,
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         let fam = match spec_default.template.as_deref() {
@@ -607,12 +1786,15 @@ mod tests {
                 delta: Delta {
                     role: Some("assistant".to_string()),
                     content: Some("Hello".to_string()),
+                    ..Default::default()
                 },
                 finish_reason: None,
             }],
+            usage: None,
         };
 
         let json = serde_json::to_string(&chunk).unwrap();
+        assert!(!json.contains("usage"));
         assert!(json.contains("chatcmpl-test123"));
         assert!(json.contains("chat.completion.chunk"));
         assert!(json.contains("Hello"));
@@ -627,6 +1809,7 @@ mod tests {
         let delta = Delta {
             role: Some("assistant".to_string()),
             content: None,
+            ..Default::default()
         };
 
         assert_eq!(delta.role.as_ref().unwrap(), "assistant");
@@ -638,6 +1821,7 @@ mod tests {
         let delta = Delta {
             role: None,
             content: Some("token".to_string()),
+            ..Default::default()
         };
 
         assert!(delta.role.is_none());
@@ -671,12 +1855,14 @@ mod tests {
                     object: "model".to_string(),
                     created: 1234567890,
                     owned_by: "shimmy".to_string(),
+                    capabilities: None,
                 },
                 Model {
                     id: "model2".to_string(),
                     object: "model".to_string(),
                     created: 1234567890,
                     owned_by: "shimmy".to_string(),
+                    capabilities: None,
                 },
             ],
         };
@@ -732,6 +1918,7 @@ mod tests {
             message: ChatMessage {
                 role: "assistant".to_string(),
                 content: "Response".to_string(),
+                ..Default::default()
             },
             finish_reason: Some("stop".to_string()),
         };
@@ -743,6 +1930,7 @@ mod tests {
             delta: Delta {
                 role: None,
                 content: None,
+                ..Default::default()
             },
             finish_reason: Some("length".to_string()),
         };
@@ -757,10 +1945,12 @@ mod tests {
             ChatMessage {
                 role: "user".to_string(),
                 content: "Hello".to_string(),
+                ..Default::default()
             },
             ChatMessage {
                 role: "assistant".to_string(),
                 content: "Hi there!".to_string(),
+                ..Default::default()
             },
         ];
 
@@ -788,6 +1978,8 @@ mod tests {
             template: Some("chatml".into()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         });
         registry.register(ModelEntry {
             name: "another-model".to_string(),
@@ -796,10 +1988,12 @@ mod tests {
             template: Some("llama3".into()),
             ctx_len: Some(4096),
             n_threads: None,
+            backend: None,
+            command: None,
         });
 
         let engine = Box::new(InferenceEngineAdapter::new());
-        let state = Arc::new(AppState { engine, registry });
+        let state = Arc::new(AppState::new(engine, registry));
 
         // Exercise models endpoint (lines 82-96)
         let _response = models(State(state)).await;
@@ -807,4 +2001,491 @@ mod tests {
         // The response should include the registered models
         assert!(true); // Successfully executed the endpoint
     }
+
+    #[test]
+    fn test_stop_sequences_hold_back_partial_matches() {
+        let stops = ["\n\n".to_string(), "END".to_string(), String::new()];
+        let mut stop = StopSequences::new(&stops);
+        assert_eq!(stop.push("one"), ("one".to_string(), false));
+        assert_eq!(stop.push("\nE"), ("\n".to_string(), false));
+        assert_eq!(stop.push("N"), (String::new(), false));
+        // "EN" turned out not to start "END"
+        assert_eq!(stop.push("Z"), ("ENZ".to_string(), false));
+        assert_eq!(stop.push("two\n"), ("two".to_string(), false));
+        assert_eq!(stop.push("\nthree"), (String::new(), true));
+        assert_eq!(stop.matched(), Some("\n\n"));
+
+        let mut stop = StopSequences::new(&stops);
+        assert_eq!(stop.push("a E"), ("a ".to_string(), false));
+        assert_eq!(stop.finish(), "E");
+        assert_eq!(partial_match("caf\u{e9}", "\u{e9}t\u{e9}"), 2);
+    }
+
+    #[test]
+    fn test_completion_request_accepts_strings_or_arrays() {
+        let request: CompletionRequest =
+            serde_json::from_str(r#"{"model": "m", "prompt": "hi", "stop": "\n"}"#).unwrap();
+        assert_eq!(request.prompt.unwrap().into_vec(), ["hi"]);
+        assert_eq!(request.stop.unwrap().into_vec(), ["\n"]);
+
+        let request: CompletionRequest = serde_json::from_str(
+            r#"{"model": "m", "prompt": ["a", "b"], "stop": ["x", "y"], "n": 2,
+                "best_of": 3, "logprobs": 0, "echo": true, "suffix": "z"}"#,
+        )
+        .unwrap();
+        assert_eq!(request.prompt.clone().unwrap().into_vec(), ["a", "b"]);
+        assert_eq!(request.stop.clone().unwrap().into_vec(), ["x", "y"]);
+        let features = request.features(2, 3);
+        // The suffix goes into the prompt, so the model needs no infill support
+        assert!(features.logprobs && !features.infill);
+        assert!(request.max_tokens.is_none());
+    }
+
+    /// Answers its `k`th generation with "k " then "end", ranking every
+    /// third one highest
+    fn ranked() -> FakeModel {
+        FakeModel::replying(|_, _, k| {
+            let token = |text: String, logprob| GenerationEvent::Token {
+                id: None,
+                text,
+                logprob: Some(logprob),
+            };
+            vec![
+                token(format!("{} ", k), -(((k + 1) % 3) as f32)),
+                token("end".to_string(), -0.5),
+                GenerationEvent::Done {
+                    finish_reason: FinishReason::Stop,
+                    stats: GenerationStats::default(),
+                },
+            ]
+        })
+        .with_capabilities(Capabilities {
+            logprobs: true,
+            ..Capabilities::text(64)
+        })
+    }
+
+    #[tokio::test]
+    async fn test_completions_pick_best_of_by_logprob() {
+        let state = ranked().state("ranked", None);
+        let complete = |request: serde_json::Value| {
+            let state = Arc::clone(&state);
+            async move {
+                json_body(
+                    completions(State(state), json_request(request))
+                        .await
+                        .into_response(),
+                )
+                .await
+            }
+        };
+
+        let (status, body) = complete(serde_json::json!({"model": "ranked",
+            "prompt": ["a", "b"], "best_of": 3, "logprobs": 0, "echo": true, "stop": "end"}))
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        assert_eq!(body["object"], "text_completion");
+        let choices = body["choices"].as_array().unwrap();
+        assert_eq!(choices.len(), 2);
+        assert_eq!(choices[0]["text"], "a2 ");
+        assert_eq!(choices[1]["text"], "b5 ");
+        assert_eq!(choices[1]["index"], 1);
+        assert_eq!(choices[1]["finish_reason"], "stop");
+        // The stop sequence's token is not reported; offsets count the echo
+        assert_eq!(choices[0]["logprobs"]["tokens"], serde_json::json!(["2 "]));
+        assert_eq!(
+            choices[0]["logprobs"]["token_logprobs"],
+            serde_json::json!([0.0])
+        );
+        assert_eq!(
+            choices[0]["logprobs"]["text_offset"],
+            serde_json::json!([1])
+        );
+        // Every candidate counts, including the ones not returned
+        assert_eq!(body["usage"]["completion_tokens"], 12);
+
+        for invalid in [
+            serde_json::json!({"model": "ranked", "n": 2, "best_of": 1}),
+            serde_json::json!({"model": "ranked", "n": 0}),
+            serde_json::json!({"model": "ranked", "best_of": 2, "stream": true}),
+            serde_json::json!({"model": "ranked", "prompt": []}),
+            serde_json::json!({"model": "ranked", "best_of": MAX_CHAT_CHOICES + 1}),
+            serde_json::json!({"model": "ranked", "n": usize::MAX}),
+            serde_json::json!({"model": "ranked", "suffix": "}", "echo": true}),
+        ] {
+            let (status, _) = complete(invalid).await;
+            assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        }
+        let (status, _) = complete(serde_json::json!({"model": "missing"})).await;
+        assert_eq!(status, axum::http::StatusCode::NOT_FOUND);
+
+        // Models without infill support get the suffix through the prompt
+        let coder = FakeModel::new(&["0"]).with_capabilities(Capabilities::text(64));
+        let state = coder.state("codellama-7b", None);
+        let request = serde_json::json!({"model": "codellama-7b",
+            "prompt": "fn main() {", "suffix": "}"});
+        let (status, _) = json_body(
+            completions(State(state), json_request(request))
+                .await
+                .into_response(),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        assert_eq!(coder.prompt(), "<PRE> fn main() { <SUF>} <MID>");
+    }
+
+    #[tokio::test]
+    async fn test_completions_stream_logprobs_per_chunk() {
+        let state = ranked().state("ranked", None);
+        let request = serde_json::json!({"model": "ranked", "prompt": "a",
+            "stream": true, "logprobs": 0, "echo": true});
+        let (status, body) = body(
+            completions(State(state), json_request(request))
+                .await
+                .into_response(),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let chunks: Vec<serde_json::Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let logprobs = |i: usize| chunks[i]["choices"][0]["logprobs"].clone();
+        // The echoed prompt has none; offsets count it
+        assert_eq!(chunks[0]["choices"][0]["text"], "a");
+        assert!(logprobs(0).is_null());
+        assert_eq!(logprobs(1)["tokens"], serde_json::json!(["0 "]));
+        assert_eq!(logprobs(1)["token_logprobs"], serde_json::json!([-1.0]));
+        assert_eq!(logprobs(1)["text_offset"], serde_json::json!([1]));
+        assert_eq!(logprobs(2)["tokens"], serde_json::json!(["end"]));
+        assert_eq!(logprobs(2)["text_offset"], serde_json::json!([3]));
+        assert_eq!(chunks[3]["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_generations_finish_with_stop() {
+        let state = FakeModel::finishing(&["Hi"], FinishReason::Cancelled).state("cut", None);
+        let request = serde_json::json!({"model": "cut",
+            "messages": [{"role": "user", "content": "hi"}]});
+        let (_, chat) = json_body(
+            chat_completions(State(Arc::clone(&state)), json_request(request))
+                .await
+                .into_response(),
+        )
+        .await;
+        assert_eq!(chat["choices"][0]["finish_reason"], "stop");
+        let request = serde_json::json!({"model": "cut", "prompt": "hi"});
+        let (_, completion) = json_body(
+            completions(State(state), json_request(request))
+                .await
+                .into_response(),
+        )
+        .await;
+        assert_eq!(completion["choices"][0]["finish_reason"], "stop");
+        // The native API keeps the engine's own reason
+        assert_eq!(FinishReason::Cancelled.as_str(), "cancelled");
+    }
+
+    #[tokio::test]
+    async fn test_completion_choices_sample_with_their_own_seeds() {
+        let seeded = FakeModel::replying(|_, opts, _| {
+            vec![
+                GenerationEvent::text(format!("{:?}", opts.seed)),
+                done(FinishReason::Stop, 1),
+            ]
+        });
+        let state = seeded.state("seeded", None);
+        let request = serde_json::json!({"model": "seeded", "prompt": ["a", "b"],
+            "n": 2, "seed": 7});
+        let (status, response) = json_body(
+            completions(State(Arc::clone(&state)), json_request(request.clone()))
+                .await
+                .into_response(),
+        )
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let texts: Vec<_> = response["choices"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|choice| choice["text"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(texts, ["Some(7)", "Some(8)", "Some(7)", "Some(8)"]);
+
+        let mut request = request;
+        request["stream"] = serde_json::json!(true);
+        let (_, streamed) = body(
+            completions(State(state), json_request(request))
+                .await
+                .into_response(),
+        )
+        .await;
+        for (index, seed) in [(0, 7), (1, 8), (2, 7), (3, 8)] {
+            let chunk = format!(r#""text":"Some({})","index":{}"#, seed, index);
+            assert!(streamed.contains(&chunk), "{}", streamed);
+        }
+    }
+
+    /// Replies with a Hermes tool call
+    fn caller() -> FakeModel {
+        FakeModel::new(&[
+            "Checking. <tool",
+            "_call>\n{\"name\": \"weather\", ",
+            "\"arguments\": {\"city\": \"Oslo\"}}\n</tool_call>",
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_chat_tool_calls_are_offered_and_parsed() {
+        let model = caller();
+        let state = model.state("hermes", Some("chatml"));
+        let chat = |extra: serde_json::Value| {
+            let state = Arc::clone(&state);
+            async move {
+                let mut request = serde_json::json!({
+                    "model": "hermes",
+                    "messages": [
+                        {"role": "user", "content": "Weather in Oslo?"},
+                        {"role": "assistant", "content": null, "tool_calls": [{"id": "call_1",
+                            "type": "function", "function": {"name": "weather",
+                            "arguments": "{\"city\":\"Paris\"}"}}]},
+                        {"role": "tool", "tool_call_id": "call_1", "content": "sunny"}
+                    ],
+                    "tools": [{"type": "function", "function": {"name": "weather",
+                        "parameters": {"type": "object"}}}]
+                });
+                request
+                    .as_object_mut()
+                    .unwrap()
+                    .extend(extra.as_object().unwrap().clone());
+                body(
+                    chat_completions(State(state), json_request(request))
+                        .await
+                        .into_response(),
+                )
+                .await
+            }
+        };
+
+        let (status, body) = chat(serde_json::json!({})).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let sent = model.prompt();
+        assert!(sent.starts_with("<|im_start|>system\nYou are a function calling AI model."));
+        assert!(sent.contains("<tool_call>\n{\""));
+        assert!(sent.contains("{\"city\":\"Paris\"}"));
+        assert!(sent.ends_with(
+            "<|im_start|>tool\n<tool_response>\nsunny\n</tool_response><|im_end|>\n<|im_start|>assistant\n"
+        ));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let choice = &body["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(choice["message"]["content"], "Checking.");
+        let call = &choice["message"]["tool_calls"][0];
+        assert_eq!(call["type"], "function");
+        assert_eq!(call["function"]["name"], "weather");
+        assert_eq!(call["function"]["arguments"], "{\"city\":\"Oslo\"}");
+
+        let (_, body) = chat(serde_json::json!({"stream": true})).await;
+        let chunks: Vec<serde_json::Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let deltas: Vec<_> = chunks.iter().map(|c| &c["choices"][0]["delta"]).collect();
+        assert_eq!(deltas[1]["content"], "Checking. ");
+        assert_eq!(deltas[2]["tool_calls"][0]["index"], 0);
+        assert_eq!(deltas[2]["tool_calls"][0]["function"]["name"], "weather");
+        assert_eq!(
+            chunks.last().unwrap()["choices"][0]["finish_reason"],
+            "tool_calls"
+        );
+
+        // A named tool_choice starts the reply inside the call
+        let (status, _) = chat(serde_json::json!({"tool_choice":
+            {"type": "function", "function": {"name": "weather"}}}))
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        assert!(model
+            .prompt()
+            .ends_with("<tool_call>\n{\"name\": \"weather\", \"arguments\": "));
+
+        let (_, body) = chat(serde_json::json!({"tool_choice": "none"})).await;
+        assert!(!model.prompt().contains("<tools>"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body["choices"][0]["message"]["tool_calls"].is_null());
+
+        for invalid in [
+            serde_json::json!({"tool_choice": "sometimes"}),
+            serde_json::json!({"tool_choice": {"type": "function", "function": {"name": "time"}}}),
+            serde_json::json!({"tools": [], "tool_choice": "required"}),
+        ] {
+            let (status, _) = chat(invalid).await;
+            assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    /// Calls the calculator until a tool response is in its prompt, then answers
+    fn agent() -> FakeModel {
+        FakeModel::replying(|prompt, _, _| {
+            let text = if prompt.contains("<tool_response>\n5.0\n</tool_response>") {
+                "The answer is 5."
+            } else {
+                "<tool_call>\n{\"name\": \"calculator\", \"arguments\": {\"expression\": \"2 + 3\"}}\n</tool_call>"
+            };
+            vec![GenerationEvent::text(text), done(FinishReason::Stop, 1)]
+        })
+    }
+
+    #[tokio::test]
+    async fn test_server_tool_execution_loop() {
+        let state = agent().state("agent", Some("chatml"));
+        let chat = |extra: serde_json::Value| {
+            let state = Arc::clone(&state);
+            async move {
+                let mut request = serde_json::json!({
+                    "model": "agent",
+                    "messages": [{"role": "user", "content": "What is 2 + 3?"}],
+                    "tool_execution": "server"
+                });
+                request
+                    .as_object_mut()
+                    .unwrap()
+                    .extend(extra.as_object().unwrap().clone());
+                json_body(
+                    chat_completions(State(state), json_request(request))
+                        .await
+                        .into_response(),
+                )
+                .await
+            }
+        };
+
+        let (status, body) = chat(serde_json::json!({})).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        assert_eq!(body["choices"][0]["message"]["content"], "The answer is 5.");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        let trace = body["tool_trace"].as_array().unwrap();
+        assert_eq!(trace.len(), 1);
+        assert_eq!(trace[0]["iteration"], 1);
+        assert_eq!(trace[0]["call"]["function"]["name"], "calculator");
+        assert_eq!(trace[0]["result"]["result"], 5.0);
+
+        // At the limit the pending call is returned instead of run
+        let (_, body) = chat(serde_json::json!({"max_tool_iterations": 1})).await;
+        assert_eq!(body["choices"][0]["finish_reason"], "tool_calls");
+        assert_eq!(
+            body["choices"][0]["message"]["tool_calls"][0]["function"]["name"],
+            "calculator"
+        );
+        assert_eq!(body["tool_trace"], serde_json::json!([]));
+
+        for invalid in [
+            serde_json::json!({"stream": true}),
+            serde_json::json!({"max_tool_iterations": 0}),
+            serde_json::json!({"tool_execution": "elsewhere"}),
+            serde_json::json!({"tools": [{"type": "function", "function": {"name": "weather"}}]}),
+        ] {
+            let (status, _) = chat(invalid).await;
+            assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    /// Replies with the seed it was given, stopping only on even seeds
+    fn seeded() -> FakeModel {
+        FakeModel::replying(|_, opts, _| {
+            let seed = opts.seed.unwrap_or_default();
+            let finish_reason = if seed % 2 == 0 {
+                FinishReason::Stop
+            } else {
+                FinishReason::Length
+            };
+            vec![
+                GenerationEvent::text(format!("seed {}", seed)),
+                done(finish_reason, 2),
+            ]
+        })
+    }
+
+    #[tokio::test]
+    async fn test_chat_n_returns_indexed_choices() {
+        let state = seeded().state("seeded", Some("chatml"));
+        let chat = |extra: serde_json::Value| {
+            let state = Arc::clone(&state);
+            async move {
+                let mut request = serde_json::json!({
+                    "model": "seeded",
+                    "messages": [{"role": "user", "content": "hi"}],
+                    "n": 3,
+                    "seed": 10
+                });
+                request
+                    .as_object_mut()
+                    .unwrap()
+                    .extend(extra.as_object().unwrap().clone());
+                body(
+                    chat_completions(State(state), json_request(request))
+                        .await
+                        .into_response(),
+                )
+                .await
+            }
+        };
+
+        let (status, body) = chat(serde_json::json!({})).await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let choices = body["choices"].as_array().unwrap();
+        assert_eq!(choices.len(), 3);
+        for (i, choice) in choices.iter().enumerate() {
+            assert_eq!(choice["index"], i);
+            assert_eq!(choice["message"]["content"], format!("seed {}", 10 + i));
+        }
+        assert_eq!(choices[0]["finish_reason"], "stop");
+        assert_eq!(choices[1]["finish_reason"], "length");
+        // The shared prompt is counted once
+        assert_eq!(body["usage"]["prompt_tokens"], 3);
+        assert_eq!(body["usage"]["completion_tokens"], 6);
+
+        let (status, body) = chat(serde_json::json!({"stream": true,
+            "stream_options": {"include_usage": true}}))
+        .await;
+        assert_eq!(status, axum::http::StatusCode::OK);
+        let chunks: Vec<serde_json::Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        for i in 0..3 {
+            let of_choice: Vec<_> = chunks
+                .iter()
+                .map(|chunk| &chunk["choices"][0])
+                .filter(|choice| choice["index"] == i)
+                .collect();
+            assert_eq!(of_choice[0]["delta"]["role"], "assistant");
+            assert_eq!(of_choice[1]["delta"]["content"], format!("seed {}", 10 + i));
+            assert!(of_choice[2]["finish_reason"].is_string());
+        }
+        assert!(body.trim_end().ends_with("data: [DONE]"));
+        assert_eq!(body.matches("[DONE]").count(), 1);
+        let usage = chunks.last().unwrap();
+        assert_eq!(usage["choices"], serde_json::json!([]));
+        assert_eq!(usage["usage"]["prompt_tokens"], 3);
+        assert_eq!(usage["usage"]["total_tokens"], 9);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|chunk| chunk.get("usage").is_none()));
+
+        for invalid in [
+            serde_json::json!({"n": 0}),
+            serde_json::json!({"n": MAX_CHAT_CHOICES + 1}),
+            serde_json::json!({"tool_execution": "server"}),
+        ] {
+            let (status, _) = chat(invalid).await;
+            assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        }
+    }
 }
@@ -0,0 +1,1185 @@
+// OpenAI Responses API compatibility
+// `POST /v1/responses` takes input items and `instructions`, and answers
+// with a response object or typed SSE events (`response.created`,
+// `response.output_text.delta`, ... `response.completed`). Responses are
+// kept in a server-side store so `previous_response_id` can continue a
+// conversation; `GET` and `DELETE /v1/responses/{id}` read and drop them.
+// Prompts are rendered and generated through the same template, tool calling
+// and engine path as `openai_compat`.
+
+use crate::api::{check_capabilities, ChatMessage, ChatToolCall, FunctionCall, RequestedFeatures};
+use crate::api_errors::{ApiError, Json};
+use crate::engine::stream::{FinishReason, GenerationStats};
+use crate::engine::{GenOptions, GenerationEvent};
+use crate::json_grammar;
+use crate::openai_compat::{chat_prompt, load_model, template_family, unix_now, ReplyParser};
+use crate::tool_calling::{Parsed, ToolCallParser, ToolFormat, ToolMode};
+use crate::tools::ToolDefinition;
+use crate::AppState;
+use axum::extract::{Path, State};
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Stored responses kept before the oldest are dropped
+pub const MAX_STORED_RESPONSES: usize = 1024;
+
+/// Responses created with `store`, with the conversation that led to each
+#[derive(Default)]
+pub struct ResponseStore {
+    inner: Mutex<StoredResponses>,
+}
+
+#[derive(Default)]
+struct StoredResponses {
+    entries: HashMap<String, Stored>,
+    order: VecDeque<String>,
+}
+
+#[derive(Clone)]
+struct Stored {
+    response: ResponseObject,
+    /// Every turn up to and including this response, without `instructions`
+    history: Vec<ChatMessage>,
+}
+
+impl ResponseStore {
+    fn insert(&self, response: ResponseObject, history: Vec<ChatMessage>) {
+        let mut stored = self.inner.lock().unwrap();
+        let id = response.id.clone();
+        stored.order.push_back(id.clone());
+        stored.entries.insert(id, Stored { response, history });
+        while stored.order.len() > MAX_STORED_RESPONSES {
+            if let Some(oldest) = stored.order.pop_front() {
+                stored.entries.remove(&oldest);
+            }
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<Stored> {
+        self.inner.lock().unwrap().entries.get(id).cloned()
+    }
+
+    fn remove(&self, id: &str) -> bool {
+        let mut stored = self.inner.lock().unwrap();
+        stored.order.retain(|stored| stored != id);
+        stored.entries.remove(id).is_some()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResponsesRequest {
+    pub model: String,
+    pub input: Input,
+    /// A system prompt for this response only; not carried over by
+    /// `previous_response_id`
+    #[serde(default)]
+    pub instructions: Option<String>,
+    #[serde(default)]
+    pub previous_response_id: Option<String>,
+    #[serde(default)]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>,
+    #[serde(default)]
+    pub text: Option<TextConfig>,
+    #[serde(default)]
+    pub max_output_tokens: Option<usize>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    /// Keep the response for `previous_response_id`; defaults to `true`
+    #[serde(default)]
+    pub store: Option<bool>,
+    #[serde(default)]
+    pub metadata: Option<Map<String, Value>>,
+}
+
+/// A user message as a plain string, or a list of input items
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Input {
+    Text(String),
+    Items(Vec<InputItem>),
+}
+
+/// Items may leave out `"type": "message"`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum InputItem {
+    Typed(Item),
+    Message { role: String, content: Content },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Item {
+    Message {
+        role: String,
+        content: Content,
+    },
+    FunctionCall {
+        call_id: String,
+        name: String,
+        arguments: String,
+    },
+    FunctionCallOutput {
+        call_id: String,
+        output: String,
+    },
+}
+
+/// A plain string, or a list of content parts
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    InputText {
+        text: String,
+    },
+    OutputText {
+        text: String,
+    },
+    Refusal {
+        refusal: String,
+    },
+    InputImage {
+        #[serde(default)]
+        image_url: Option<String>,
+    },
+}
+
+impl Content {
+    /// The text parts, one per line
+    fn text(&self) -> String {
+        match self {
+            Content::Text(text) => text.clone(),
+            Content::Parts(parts) => {
+                let texts: Vec<&str> = parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ContentPart::InputText { text } | ContentPart::OutputText { text } => {
+                            Some(text.as_str())
+                        }
+                        ContentPart::Refusal { refusal } => Some(refusal.as_str()),
+                        ContentPart::InputImage { .. } => None,
+                    })
+                    .collect();
+                texts.join("\n")
+            }
+        }
+    }
+
+    fn has_image(&self) -> bool {
+        matches!(self, Content::Parts(parts)
+            if parts.iter().any(|part| matches!(part, ContentPart::InputImage { .. })))
+    }
+
+    /// Base64 data of the `input_image` parts, which must be data URLs
+    fn images(&self) -> Result<Vec<String>, ApiError> {
+        let Content::Parts(parts) = self else {
+            return Ok(Vec::new());
+        };
+        parts
+            .iter()
+            .filter_map(|part| match part {
+                ContentPart::InputImage { image_url } => Some(image_url),
+                _ => None,
+            })
+            .map(|url| {
+                url.as_deref()
+                    .and_then(|url| url.strip_prefix("data:"))
+                    .and_then(|url| url.split_once(";base64,"))
+                    .map(|(_, data)| data.to_string())
+                    .ok_or_else(|| {
+                        ApiError::InvalidRequest(
+                            "input_image must be a base64 data URL; image URLs and file ids are not fetched"
+                                .to_string(),
+                        )
+                    })
+            })
+            .collect()
+    }
+}
+
+/// `{"type": "function", "name", "description", "parameters", "strict"}`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub parameters: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+/// `"none" | "auto" | "required"`, or `{"type": "function", "name"}`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Function {
+        #[serde(rename = "type")]
+        kind: String,
+        name: String,
+    },
+}
+
+/// `{"format": {"type": "text" | "json_object" | "json_schema", ...}}`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TextConfig {
+    pub format: TextFormat,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TextFormat {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// `name`, `schema` and `strict` of a `json_schema` format
+    #[serde(flatten)]
+    pub schema: Map<String, Value>,
+}
+
+impl Default for TextConfig {
+    fn default() -> Self {
+        Self {
+            format: TextFormat {
+                kind: "text".to_string(),
+                schema: Map::new(),
+            },
+        }
+    }
+}
+
+impl ResponsesRequest {
+    /// The content of each input message
+    fn contents(&self) -> impl Iterator<Item = &Content> {
+        let items = match &self.input {
+            Input::Text(_) => &[][..],
+            Input::Items(items) => items.as_slice(),
+        };
+        items.iter().filter_map(|item| match item {
+            InputItem::Typed(Item::Message { content, .. })
+            | InputItem::Message { content, .. } => Some(content),
+            _ => None,
+        })
+    }
+
+    fn images(&self) -> Result<Vec<String>, ApiError> {
+        let mut images = Vec::new();
+        for content in self.contents() {
+            images.extend(content.images()?);
+        }
+        Ok(images)
+    }
+
+    fn features(&self) -> RequestedFeatures {
+        RequestedFeatures {
+            grammar: self
+                .text
+                .as_ref()
+                .is_some_and(|text| text.format.kind != "text"),
+            images: self.contents().any(Content::has_image),
+            max_tokens: self.max_output_tokens,
+            ..Default::default()
+        }
+    }
+
+    fn options(&self) -> GenOptions {
+        let mut opts = GenOptions {
+            stream: self.stream.unwrap_or(false),
+            ..Default::default()
+        };
+        if let Some(max) = self.max_output_tokens {
+            opts.max_tokens = max;
+        }
+        if let Some(t) = self.temperature {
+            opts.temperature = t;
+        }
+        if let Some(p) = self.top_p {
+            opts.top_p = p;
+        }
+        opts.grammar = self.text.as_ref().and_then(|text| {
+            json_grammar::for_format(&text.format.kind, text.format.schema.get("schema"))
+        });
+        opts
+    }
+
+    /// The function tools to offer and what `tool_choice` demands of them;
+    /// `None` when the request doesn't use tools
+    fn tool_mode(&self) -> Result<Option<(Vec<ToolDefinition>, ToolMode)>, ApiError> {
+        let tools = self.tools.as_deref().unwrap_or_default();
+        if let Some(tool) = tools.iter().find(|tool| tool.kind != "function") {
+            return Err(ApiError::InvalidRequest(format!(
+                "tool type '{}' is not supported; only function tools are",
+                tool.kind
+            )));
+        }
+        let tools: Vec<ToolDefinition> = tools
+            .iter()
+            .map(|tool| ToolDefinition {
+                name: tool.name.clone(),
+                description: tool.description.clone().unwrap_or_default(),
+                parameters: tool.parameters.clone(),
+            })
+            .collect();
+        let mode = match &self.tool_choice {
+            None => ToolMode::Auto,
+            Some(ToolChoice::Mode(mode)) => match mode.as_str() {
+                "none" => return Ok(None),
+                "auto" => ToolMode::Auto,
+                "required" => ToolMode::Required,
+                other => {
+                    return Err(ApiError::InvalidRequest(format!(
+                        "unknown tool_choice '{}'",
+                        other
+                    )))
+                }
+            },
+            Some(ToolChoice::Function { name, .. }) => {
+                if !tools.iter().any(|tool| &tool.name == name) {
+                    return Err(ApiError::InvalidRequest(format!(
+                        "tool_choice names function '{}', which is not in tools",
+                        name
+                    )));
+                }
+                ToolMode::Function(name.clone())
+            }
+        };
+        match mode {
+            ToolMode::Auto if tools.is_empty() => Ok(None),
+            ToolMode::Required if tools.is_empty() => Err(ApiError::InvalidRequest(
+                "tool_choice 'required' needs at least one tool".to_string(),
+            )),
+            mode => Ok(Some((tools, mode))),
+        }
+    }
+
+    /// The conversation so far: the turns of `previous_response_id`, then
+    /// this request's input
+    fn history(&self, store: &ResponseStore) -> Result<Vec<ChatMessage>, ApiError> {
+        let mut messages = match &self.previous_response_id {
+            None => Vec::new(),
+            Some(id) => match store.get(id) {
+                Some(stored) => stored.history.clone(),
+                None => {
+                    return Err(ApiError::InvalidRequest(format!(
+                        "previous response '{}' not found",
+                        id
+                    )))
+                }
+            },
+        };
+        let items = match &self.input {
+            Input::Text(text) => {
+                messages.push(ChatMessage {
+                    role: "user".to_string(),
+                    content: text.clone(),
+                    ..Default::default()
+                });
+                return Ok(messages);
+            }
+            Input::Items(items) => items,
+        };
+        for item in items {
+            match item {
+                InputItem::Typed(Item::Message { role, content })
+                | InputItem::Message { role, content } => {
+                    let role = match role.as_str() {
+                        "developer" | "system" => "system",
+                        "user" | "assistant" => role.as_str(),
+                        other => {
+                            return Err(ApiError::InvalidRequest(format!(
+                                "unknown message role '{}'",
+                                other
+                            )))
+                        }
+                    };
+                    messages.push(ChatMessage {
+                        role: role.to_string(),
+                        content: content.text(),
+                        ..Default::default()
+                    });
+                }
+                InputItem::Typed(Item::FunctionCall {
+                    call_id,
+                    name,
+                    arguments,
+                }) => {
+                    let call = ChatToolCall {
+                        id: call_id.clone(),
+                        kind: "function".to_string(),
+                        function: FunctionCall {
+                            name: name.clone(),
+                            arguments: arguments.clone(),
+                        },
+                    };
+                    // Calls made together are one assistant turn
+                    match messages.last_mut() {
+                        Some(last) if last.role == "assistant" => {
+                            last.tool_calls.get_or_insert_with(Vec::new).push(call)
+                        }
+                        _ => messages.push(ChatMessage {
+                            role: "assistant".to_string(),
+                            tool_calls: Some(vec![call]),
+                            ..Default::default()
+                        }),
+                    }
+                }
+                InputItem::Typed(Item::FunctionCallOutput { call_id, output }) => {
+                    messages.push(ChatMessage {
+                        role: "tool".to_string(),
+                        content: output.clone(),
+                        tool_call_id: Some(call_id.clone()),
+                        ..Default::default()
+                    })
+                }
+            }
+        }
+        Ok(messages)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResponseObject {
+    pub id: String,
+    pub object: String,
+    pub created_at: u64,
+    /// `in_progress`, `completed`, `incomplete` or `failed`
+    pub status: String,
+    pub incomplete_details: Option<Value>,
+    pub error: Option<Value>,
+    pub model: String,
+    pub instructions: Option<String>,
+    pub previous_response_id: Option<String>,
+    pub output: Vec<OutputItem>,
+    pub tools: Vec<Tool>,
+    pub tool_choice: ToolChoice,
+    pub text: TextConfig,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_output_tokens: Option<usize>,
+    pub store: bool,
+    pub metadata: Map<String, Value>,
+    pub usage: Option<ResponseUsage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputItem {
+    Message {
+        id: String,
+        status: String,
+        role: String,
+        content: Vec<OutputText>,
+    },
+    FunctionCall {
+        id: String,
+        call_id: String,
+        name: String,
+        arguments: String,
+        status: String,
+    },
+}
+
+/// `{"type": "output_text", "text", "annotations": []}`
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputText {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub text: String,
+    pub annotations: Vec<Value>,
+}
+
+impl OutputText {
+    fn new(text: String) -> Self {
+        Self {
+            kind: "output_text".to_string(),
+            text,
+            annotations: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResponseUsage {
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl ResponseObject {
+    /// The response before anything has been generated
+    fn new(req: &ResponsesRequest) -> Self {
+        Self {
+            id: format!("resp_{}", uuid::Uuid::new_v4().simple()),
+            object: "response".to_string(),
+            created_at: unix_now(),
+            status: "in_progress".to_string(),
+            incomplete_details: None,
+            error: None,
+            model: req.model.clone(),
+            instructions: req.instructions.clone(),
+            previous_response_id: req.previous_response_id.clone(),
+            output: vec![],
+            tools: req.tools.clone().unwrap_or_default(),
+            tool_choice: req
+                .tool_choice
+                .clone()
+                .unwrap_or_else(|| ToolChoice::Mode("auto".to_string())),
+            text: req.text.clone().unwrap_or_default(),
+            temperature: req.temperature,
+            top_p: req.top_p,
+            max_output_tokens: req.max_output_tokens,
+            store: req.store.unwrap_or(true),
+            metadata: req.metadata.clone().unwrap_or_default(),
+            usage: None,
+        }
+    }
+
+    /// The finished response, `incomplete` when the generation was cut short
+    fn finish(&mut self, output: &Output, finish_reason: FinishReason, stats: &GenerationStats) {
+        let incomplete = match finish_reason {
+            FinishReason::Length => Some("max_output_tokens"),
+            FinishReason::ContentFilter => Some("content_filter"),
+            _ => None,
+        };
+        self.status = match incomplete {
+            Some(_) => "incomplete",
+            None => "completed",
+        }
+        .to_string();
+        self.incomplete_details = incomplete.map(|reason| json!({ "reason": reason }));
+        self.output = output.items();
+        self.usage = Some(ResponseUsage {
+            input_tokens: stats.prompt_tokens,
+            output_tokens: stats.completion_tokens,
+            total_tokens: stats.prompt_tokens + stats.completion_tokens,
+        });
+    }
+
+    fn fail(&mut self, code: &str, message: &str) {
+        self.status = "failed".to_string();
+        self.error = Some(json!({ "code": code, "message": message }));
+    }
+
+    /// Keep the response, with the turns that led to it, for
+    /// `previous_response_id`
+    fn save(&self, store: &ResponseStore, mut history: Vec<ChatMessage>) {
+        if !self.store {
+            return;
+        }
+        history.push(assistant_turn(&self.output));
+        store.insert(self.clone(), history);
+    }
+}
+
+/// The output items as the assistant turn of a later request
+fn assistant_turn(items: &[OutputItem]) -> ChatMessage {
+    let mut texts = Vec::new();
+    let mut calls = Vec::new();
+    for item in items {
+        match item {
+            OutputItem::Message { content, .. } => {
+                texts.extend(content.iter().map(|part| part.text.as_str()))
+            }
+            OutputItem::FunctionCall {
+                call_id,
+                name,
+                arguments,
+                ..
+            } => calls.push(ChatToolCall {
+                id: call_id.clone(),
+                kind: "function".to_string(),
+                function: FunctionCall {
+                    name: name.clone(),
+                    arguments: arguments.clone(),
+                },
+            }),
+        }
+    }
+    ChatMessage {
+        role: "assistant".to_string(),
+        content: texts.join("\n"),
+        tool_calls: (!calls.is_empty()).then_some(calls),
+        ..Default::default()
+    }
+}
+
+/// Output items built as the tokens arrive: text goes into a message item
+/// until a tool call in the template's format ends it. Each step is also
+/// returned as the data of its streaming event.
+struct Output {
+    parser: ReplyParser,
+    items: Vec<OutputItem>,
+    /// Id and text of the message receiving text
+    open: Option<(String, String)>,
+}
+
+impl Output {
+    fn push(&mut self, piece: &str) -> Vec<Value> {
+        let parsed = self.parser.push(piece);
+        self.write(parsed)
+    }
+
+    /// The rest of the output, once the generation ended
+    fn finish(&mut self) -> Vec<Value> {
+        let parsed = self.parser.finish();
+        let mut events = self.write(parsed);
+        events.extend(self.close());
+        events
+    }
+
+    fn write(&mut self, parsed: Vec<Parsed>) -> Vec<Value> {
+        let mut events = Vec::new();
+        for piece in parsed {
+            match piece {
+                // Whitespace alone, such as the newline after a call, opens no message
+                Parsed::Content(text) if self.open.is_none() && text.trim().is_empty() => {}
+                Parsed::Content(text) => {
+                    let output_index = self.items.len();
+                    let (id, open) = self.open.get_or_insert_with(|| {
+                        let id = format!("msg_{}", uuid::Uuid::new_v4().simple());
+                        let item = OutputItem::Message {
+                            id: id.clone(),
+                            status: "in_progress".to_string(),
+                            role: "assistant".to_string(),
+                            content: vec![],
+                        };
+                        events.push(json!({"type": "response.output_item.added",
+                            "output_index": output_index, "item": item}));
+                        events.push(json!({"type": "response.content_part.added",
+                            "item_id": id, "output_index": output_index, "content_index": 0,
+                            "part": OutputText::new(String::new())}));
+                        (id, String::new())
+                    });
+                    open.push_str(&text);
+                    events.push(json!({"type": "response.output_text.delta",
+                        "item_id": id, "output_index": output_index, "content_index": 0,
+                        "delta": text}));
+                }
+                // Calls arrive whole, so their arguments are one delta
+                Parsed::Call(call) => {
+                    events.extend(self.close());
+                    let output_index = self.items.len();
+                    let call = ChatToolCall::new(call);
+                    let id = format!("fc_{}", uuid::Uuid::new_v4().simple());
+                    let item = |arguments: &str, status: &str| OutputItem::FunctionCall {
+                        id: id.clone(),
+                        call_id: call.id.clone(),
+                        name: call.function.name.clone(),
+                        arguments: arguments.to_string(),
+                        status: status.to_string(),
+                    };
+                    let arguments = &call.function.arguments;
+                    events.push(json!({"type": "response.output_item.added",
+                        "output_index": output_index, "item": item("", "in_progress")}));
+                    events.push(json!({"type": "response.function_call_arguments.delta",
+                        "item_id": id, "output_index": output_index, "delta": arguments}));
+                    events.push(json!({"type": "response.function_call_arguments.done",
+                        "item_id": id, "output_index": output_index, "arguments": arguments}));
+                    let done = item(arguments, "completed");
+                    events.push(json!({"type": "response.output_item.done",
+                        "output_index": output_index, "item": done}));
+                    self.items.push(done);
+                }
+            }
+        }
+        events
+    }
+
+    /// End the open message
+    fn close(&mut self) -> Vec<Value> {
+        let Some((id, text)) = self.open.take() else {
+            return vec![];
+        };
+        let output_index = self.items.len();
+        let part = OutputText::new(text.clone());
+        let item = OutputItem::Message {
+            id: id.clone(),
+            status: "completed".to_string(),
+            role: "assistant".to_string(),
+            content: vec![part.clone()],
+        };
+        let events = vec![
+            json!({"type": "response.output_text.done", "item_id": id,
+                "output_index": output_index, "content_index": 0, "text": text}),
+            json!({"type": "response.content_part.done", "item_id": id,
+                "output_index": output_index, "content_index": 0, "part": part}),
+            json!({"type": "response.output_item.done",
+                "output_index": output_index, "item": item}),
+        ];
+        self.items.push(item);
+        events
+    }
+
+    /// The finished items; next to tool calls, message text is trimmed and
+    /// empty messages dropped
+    fn items(&self) -> Vec<OutputItem> {
+        let calls = self
+            .items
+            .iter()
+            .any(|item| matches!(item, OutputItem::FunctionCall { .. }));
+        self.items
+            .iter()
+            .cloned()
+            .filter_map(|item| match item {
+                OutputItem::Message {
+                    id,
+                    status,
+                    role,
+                    content,
+                } if calls => {
+                    let content: Vec<OutputText> = content
+                        .into_iter()
+                        .map(|part| OutputText::new(part.text.trim().to_string()))
+                        .filter(|part| !part.text.is_empty())
+                        .collect();
+                    (!content.is_empty()).then_some(OutputItem::Message {
+                        id,
+                        status,
+                        role,
+                        content,
+                    })
+                }
+                item => Some(item),
+            })
+            .collect()
+    }
+}
+
+/// Numbers streaming events and names each after its `type`
+#[derive(Default)]
+struct Sequence(usize);
+
+impl Sequence {
+    fn event(&mut self, mut data: Value) -> Event {
+        data["sequence_number"] = self.0.into();
+        self.0 += 1;
+        let name = data["type"].as_str().unwrap_or_default().to_string();
+        Event::default().event(name).data(data.to_string())
+    }
+}
+
+pub async fn create(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ResponsesRequest>,
+) -> Response {
+    let (tools, history) = match req
+        .tool_mode()
+        .and_then(|tools| Ok((tools, req.history(&state.responses)?)))
+    {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+    let (spec, loaded) = match load_model(&state, &req.model).await {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+
+    let fam = template_family(&spec, &req.model);
+    let format = ToolFormat::for_template(&fam, &req.model);
+    let messages: Vec<ChatMessage> = req
+        .instructions
+        .iter()
+        .map(|instructions| ChatMessage {
+            role: "system".to_string(),
+            content: instructions.clone(),
+            ..Default::default()
+        })
+        .chain(history.iter().cloned())
+        .collect();
+    let (prompt, prefill) = chat_prompt(&fam, format, &messages, tools.as_ref());
+    if let Err(e) = check_capabilities(&state, &req.model, &loaded, &prompt, &req.features()).await
+    {
+        return e.into_response();
+    }
+    let images = match req.images() {
+        Ok(images) => images,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut events = loaded.generate_stream(
+        prompt,
+        GenOptions {
+            images,
+            ..req.options()
+        },
+    );
+    // The Responses API has no stop sequences
+    let mut output = Output {
+        parser: ReplyParser::new(
+            &[],
+            tools
+                .as_ref()
+                .map(|_| ToolCallParser::new(format, prefill.as_deref())),
+        ),
+        items: vec![],
+        open: None,
+    };
+    let mut response = ResponseObject::new(&req);
+
+    if req.stream.unwrap_or(false) {
+        use axum::response::sse::Sse;
+        use futures_util::{future, stream};
+
+        let mut sequence = Sequence::default();
+        let start = [
+            sequence.event(json!({"type": "response.created", "response": response})),
+            sequence.event(json!({"type": "response.in_progress", "response": response})),
+        ];
+        let body = events
+            .scan(false, move |finished, event| {
+                if *finished {
+                    return future::ready(None);
+                }
+                let data = match event {
+                    GenerationEvent::PromptProgress { .. } => vec![],
+                    GenerationEvent::Token { text, .. } => output.push(&text),
+                    GenerationEvent::Done {
+                        finish_reason,
+                        stats,
+                    } => {
+                        *finished = true;
+                        let mut data = output.finish();
+                        response.finish(&output, finish_reason, &stats);
+                        response.save(&state.responses, history.clone());
+                        let kind = match response.status.as_str() {
+                            "incomplete" => "response.incomplete",
+                            _ => "response.completed",
+                        };
+                        data.push(json!({"type": kind, "response": response}));
+                        data
+                    }
+                    GenerationEvent::Error { message } => {
+                        *finished = true;
+                        tracing::error!(
+                            "Failed to generate response for model '{}': {}",
+                            response.model,
+                            message
+                        );
+                        response.fail("server_error", &message);
+                        vec![
+                            json!({"type": "error", "code": "server_error",
+                                "message": message, "param": null}),
+                            json!({"type": "response.failed", "response": response}),
+                        ]
+                    }
+                    GenerationEvent::Overloaded { model } => {
+                        *finished = true;
+                        let message = ApiError::Overloaded(model).into_body().error.message;
+                        response.fail("overloaded", &message);
+                        vec![
+                            json!({"type": "error", "code": "overloaded",
+                                "message": message, "param": null}),
+                            json!({"type": "response.failed", "response": response}),
+                        ]
+                    }
+                };
+                let events: Vec<Event> = data.into_iter().map(|d| sequence.event(d)).collect();
+                future::ready(Some(stream::iter(events)))
+            })
+            .flatten();
+        let stream = stream::iter(start)
+            .chain(body)
+            .map(Ok::<Event, std::convert::Infallible>);
+        return Sse::new(stream).into_response();
+    }
+
+    while let Some(event) = events.next().await {
+        match event {
+            GenerationEvent::PromptProgress { .. } => {}
+            GenerationEvent::Token { text, .. } => {
+                output.push(&text);
+            }
+            GenerationEvent::Done {
+                finish_reason,
+                stats,
+            } => {
+                output.finish();
+                response.finish(&output, finish_reason, &stats);
+                response.save(&state.responses, history);
+                return Json(response).into_response();
+            }
+            GenerationEvent::Error { message } => {
+                tracing::error!(
+                    "Failed to generate response for model '{}': {}",
+                    req.model,
+                    message
+                );
+                return ApiError::GenerationFailed(message).into_response();
+            }
+            GenerationEvent::Overloaded { model } => {
+                return ApiError::Overloaded(model).into_response();
+            }
+        }
+    }
+    ApiError::GenerationFailed("generation ended without finishing".to_string()).into_response()
+}
+
+pub async fn retrieve(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    match state.responses.get(&id) {
+        Some(stored) => Json(stored.response.clone()).into_response(),
+        None => ApiError::NotFound(format!("No response found with id '{}'", id)).into_response(),
+    }
+}
+
+pub async fn delete(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    if !state.responses.remove(&id) {
+        return ApiError::NotFound(format!("No response found with id '{}'", id)).into_response();
+    }
+    Json(json!({ "id": id, "object": "response", "deleted": true })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Capabilities;
+    use crate::test_utils::{body, json_body, json_request, FakeModel};
+    use axum::http::StatusCode;
+
+    async fn send(state: &Arc<AppState>, request: Value) -> (StatusCode, Value) {
+        json_body(create(State(Arc::clone(state)), json_request(request)).await).await
+    }
+
+    #[test]
+    fn test_input_items_map_to_chat_messages() {
+        let request: ResponsesRequest = serde_json::from_value(json!({
+            "model": "m",
+            "input": [
+                {"role": "developer", "content": "Be brief."},
+                {"type": "message", "role": "user",
+                    "content": [{"type": "input_text", "text": "Weather in Oslo?"}]},
+                {"type": "function_call", "call_id": "call_1", "name": "weather",
+                    "arguments": "{\"city\":\"Oslo\"}"},
+                {"type": "function_call", "call_id": "call_2", "name": "weather",
+                    "arguments": "{\"city\":\"Bergen\"}"},
+                {"type": "function_call_output", "call_id": "call_1", "output": "sunny"}
+            ]
+        }))
+        .unwrap();
+        let store = ResponseStore::default();
+        let messages = request.history(&store).unwrap();
+        let roles: Vec<_> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "tool"]);
+        assert_eq!(messages[1].content, "Weather in Oslo?");
+        let calls = messages[2].tool_calls.as_ref().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].to_tool_call().arguments["city"], "Oslo");
+        assert_eq!(messages[3].tool_call_id.as_deref(), Some("call_1"));
+
+        let chained: ResponsesRequest = serde_json::from_value(json!({
+            "model": "m", "input": "hi", "previous_response_id": "resp_missing"
+        }))
+        .unwrap();
+        assert!(chained.history(&store).is_err());
+        let web: ResponsesRequest = serde_json::from_value(json!({
+            "model": "m", "input": "hi", "tools": [{"type": "web_search"}]
+        }))
+        .unwrap();
+        assert!(web.tool_mode().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_responses_chain_through_the_store() {
+        let model = FakeModel::new(&["Hello", " there."]);
+        let state = model.state("hermes", Some("chatml"));
+        let (status, first) = send(
+            &state,
+            json!({"model": "hermes", "instructions": "Be brief.", "input": "My name is Ada."}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["object"], "response");
+        assert_eq!(first["status"], "completed");
+        assert_eq!(first["output"][0]["type"], "message");
+        assert_eq!(first["output"][0]["content"][0]["text"], "Hello there.");
+        assert_eq!(first["usage"]["total_tokens"], 5);
+        assert!(model.prompt().contains("Be brief."));
+
+        let id = first["id"].as_str().unwrap().to_string();
+        let (status, second) = send(
+            &state,
+            json!({"model": "hermes", "input": "What is my name?", "previous_response_id": id}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(second["previous_response_id"], id.as_str());
+        let chained = model.prompt();
+        assert!(chained.contains("My name is Ada.") && chained.contains("Hello there."));
+        // Instructions only apply to the response they were sent with
+        assert!(!chained.contains("Be brief."));
+
+        let (status, stored) =
+            body(retrieve(State(Arc::clone(&state)), Path(id.clone())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(stored.contains(&id));
+        let (status, _) = body(delete(State(Arc::clone(&state)), Path(id.clone())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, missing) = body(retrieve(State(Arc::clone(&state)), Path(id)).await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(missing.contains(r#""code":"not_found""#));
+
+        let (status, unstored) = send(
+            &state,
+            json!({"model": "hermes", "input": "hi", "store": false}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = body(
+            retrieve(
+                State(Arc::clone(&state)),
+                Path(unstored["id"].as_str().unwrap().into()),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = send(
+            &state,
+            json!({"model": "hermes", "input": "hi",
+                "text": {"format": {"type": "json_schema", "name": "x", "schema": {}}}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_text_format_and_images_reach_the_engine() {
+        let model = FakeModel::new(&["{}"]).with_capabilities(Capabilities {
+            grammar: true,
+            images: true,
+            ..Capabilities::text(0)
+        });
+        let state = model.state("hermes", Some("chatml"));
+        let schema = json!({"type": "object", "properties": {"a": {"type": "string"}}});
+        let (status, response) = send(
+            &state,
+            json!({"model": "hermes", "input": "hi",
+                "text": {"format": {"type": "json_schema", "name": "x", "schema": schema}}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["text"]["format"]["name"], "x");
+        assert_eq!(
+            model.options().grammar,
+            Some(json_grammar::from_schema(&schema))
+        );
+        let (status, _) = send(
+            &state,
+            json!({"model": "hermes", "input": "hi", "text": {"format": {"type": "json_object"}}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(model.options().grammar, Some(json_grammar::object()));
+
+        let image = |url: &str| {
+            json!({"model": "hermes", "input": [{"role": "user", "content": [
+                {"type": "input_text", "text": "What is this?"},
+                {"type": "input_image", "image_url": url}
+            ]}]})
+        };
+        let (status, _) = send(&state, image("data:image/png;base64,iVBORw0K")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(model.options().images, ["iVBORw0K"]);
+        assert!(model.options().grammar.is_none());
+        let (status, _) = send(&state, image("https://example.com/cat.png")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_function_calls_become_output_items() {
+        let model = FakeModel::new(&[
+            "Sure. <tool_call>\n{\"name\": \"weather\", ",
+            "\"arguments\": {\"city\": \"Oslo\"}}\n</tool_call>",
+        ]);
+        let state = model.state("hermes", Some("chatml"));
+        let tools = json!([{"type": "function", "name": "weather",
+            "parameters": {"type": "object"}}]);
+        let (status, response) = send(
+            &state,
+            json!({"model": "hermes", "input": "Weather?", "tools": tools}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["output"][0]["content"][0]["text"], "Sure.");
+        let call = &response["output"][1];
+        assert_eq!(call["type"], "function_call");
+        assert_eq!(call["name"], "weather");
+        let arguments: Value = serde_json::from_str(call["arguments"].as_str().unwrap()).unwrap();
+        assert_eq!(arguments["city"], "Oslo");
+        assert_eq!(response["tools"][0]["name"], "weather");
+        assert!(model.prompt().contains("\"weather\""));
+
+        let (status, _) = send(
+            &state,
+            json!({"model": "hermes", "input": "Weather?", "tools": tools,
+                "tool_choice": {"type": "function", "name": "missing"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_responses_stream_typed_events() {
+        let state = FakeModel::new(&[
+            "Hi. <tool_call>\n{\"name\": \"weather\", ",
+            "\"arguments\": {\"city\": \"Oslo\"}}\n</tool_call>",
+        ])
+        .state("hermes", Some("chatml"));
+        let request = json_request(json!({"model": "hermes", "input": "Weather?",
+            "stream": true, "tools": [{"type": "function", "name": "weather"}]}));
+        let (status, body) = body(create(State(state), request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let names: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "response.created",
+                "response.in_progress",
+                "response.output_item.added",
+                "response.content_part.added",
+                "response.output_text.delta",
+                "response.output_text.done",
+                "response.content_part.done",
+                "response.output_item.done",
+                "response.output_item.added",
+                "response.function_call_arguments.delta",
+                "response.function_call_arguments.done",
+                "response.output_item.done",
+                "response.completed"
+            ]
+        );
+        let data: Vec<Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(data[0]["response"]["status"], "in_progress");
+        assert_eq!(data[12]["sequence_number"], 12);
+        assert_eq!(data[8]["output_index"], 1);
+        assert_eq!(data[12]["response"]["status"], "completed");
+        assert_eq!(data[12]["response"]["output"][1]["type"], "function_call");
+    }
+}
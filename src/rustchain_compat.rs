@@ -1,7 +1,7 @@
 // RustChain compatibility layer
 #![allow(dead_code)]
 
-use axum::{http::StatusCode, response::Json};
+use crate::api_errors::{ApiError, Json};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
@@ -20,7 +20,7 @@ pub struct RustChainResponse {
 
 pub async fn rustchain_generate(
     Json(request): Json<RustChainRequest>,
-) -> Result<Json<RustChainResponse>, StatusCode> {
+) -> Result<Json<RustChainResponse>, ApiError> {
     // Convert to shimmy format and generate
     let _shimmy_request = crate::api::GenerateRequest {
         model: request.model.unwrap_or_default(),
@@ -32,6 +32,10 @@ pub async fn rustchain_generate(
         top_p: None,
         top_k: None,
         stream: Some(false),
+        grammar: None,
+        suffix: None,
+        images: None,
+        logprobs: None,
     };
 
     // For now, return a placeholder response since we don't have the full server context
@@ -45,7 +49,6 @@ pub async fn rustchain_generate(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::Json;
 
     #[test]
     fn test_rustchain_request_deserialization_minimal() {
@@ -5,6 +5,8 @@ pub enum TemplateFamily {
     ChatML,
     Llama3,
     OpenChat,
+    /// Mistral `[INST]` turns, with the v0.3 tool-calling markers
+    Mistral,
 }
 
 impl TemplateFamily {
@@ -62,8 +64,74 @@ impl TemplateFamily {
                 }
                 s
             }
+            TemplateFamily::Mistral => {
+                // A system prompt is folded into the user turn that follows it
+                let mut s = String::from("<s>");
+                let mut system = system.map(str::to_string);
+                let turns = messages
+                    .iter()
+                    .map(|(role, content)| (role.as_str(), content.as_str()))
+                    .chain(input.map(|inp| ("user", inp)));
+                for (role, content) in turns {
+                    match role {
+                        "system" => system = Some(content.to_string()),
+                        "user" => match system.take() {
+                            Some(sys) => {
+                                s.push_str(&format!("[INST] {}\n\n{} [/INST]", sys, content))
+                            }
+                            None => s.push_str(&format!("[INST] {} [/INST]", content)),
+                        },
+                        "available_tools" => {
+                            s.push_str(&format!("[AVAILABLE_TOOLS] {}[/AVAILABLE_TOOLS]", content))
+                        }
+                        "tool" => s.push_str(&format!("[TOOL_RESULTS] {}[/TOOL_RESULTS]", content)),
+                        _ => s.push_str(&format!(" {}</s>", content)),
+                    }
+                }
+                s
+            }
         }
     }
+
+    /// `messages` followed by whatever starts the assistant's reply. A final
+    /// user message is rendered as the input, as `render` does for it.
+    pub fn render_chat(&self, messages: &[(String, String)]) -> String {
+        if let Some(((role, content), history)) = messages.split_last() {
+            if role == "user" {
+                return self.render(None, history, Some(content));
+            }
+        }
+        let mut s = self.render(None, messages, None);
+        match self {
+            TemplateFamily::ChatML => s.push_str("<|im_start|>assistant\n"),
+            TemplateFamily::Llama3 => s.push_str("<|start_header_id|>assistant<|end_header_id|>\n"),
+            // `render` already ends with the assistant's turn
+            TemplateFamily::OpenChat | TemplateFamily::Mistral => {}
+        }
+        s
+    }
+}
+
+/// A fill-in-the-middle prompt in the markers `model`'s family was trained
+/// with, picked by name. The model generates the middle.
+pub fn fim_prompt(model: &str, prefix: &str, suffix: &str) -> String {
+    let name = model.to_lowercase();
+    if name.contains("codellama") || name.contains("code-llama") {
+        format!("<PRE> {} <SUF>{} <MID>", prefix, suffix)
+    } else if name.contains("deepseek") {
+        format!(
+            "<｜fim▁begin｜>{}<｜fim▁hole｜>{}<｜fim▁end｜>",
+            prefix, suffix
+        )
+    } else if name.contains("starcoder") {
+        format!("<fim_prefix>{}<fim_suffix>{}<fim_middle>", prefix, suffix)
+    } else {
+        // Qwen2.5-Coder and CodeGemma
+        format!(
+            "<|fim_prefix|>{}<|fim_suffix|>{}<|fim_middle|>",
+            prefix, suffix
+        )
+    }
 }
 
 #[cfg(test)]
@@ -98,4 +166,45 @@ mod tests {
         assert!(result.contains("user: Hi"));
         assert!(result.contains("assistant: "));
     }
+
+    #[test]
+    fn test_mistral_render() {
+        let template = TemplateFamily::Mistral;
+        let messages = vec![
+            ("system".to_string(), "Be brief.".to_string()),
+            ("user".to_string(), "Weather?".to_string()),
+            ("assistant".to_string(), "[TOOL_CALLS] []".to_string()),
+            ("tool".to_string(), "{}".to_string()),
+        ];
+        let result = template.render_chat(&messages);
+        assert_eq!(
+            result,
+            "<s>[INST] Be brief.\n\nWeather? [/INST] [TOOL_CALLS] []</s>[TOOL_RESULTS] {}[/TOOL_RESULTS]"
+        );
+    }
+
+    #[test]
+    fn test_render_chat_ends_with_assistant_turn() {
+        let messages = vec![
+            ("user".to_string(), "Hi".to_string()),
+            ("tool".to_string(), "42".to_string()),
+        ];
+        let result = TemplateFamily::ChatML.render_chat(&messages);
+        assert!(result.ends_with("<|im_start|>tool\n42<|im_end|>\n<|im_start|>assistant\n"));
+        let result = TemplateFamily::ChatML.render_chat(&messages[..1]);
+        assert!(result.ends_with("<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"));
+    }
+
+    #[test]
+    fn test_fim_prompt_follows_model_family() {
+        assert_eq!(fim_prompt("codellama-7b", "a", "b"), "<PRE> a <SUF>b <MID>");
+        assert_eq!(
+            fim_prompt("starcoder2-3b", "a", "b"),
+            "<fim_prefix>a<fim_suffix>b<fim_middle>"
+        );
+        assert_eq!(
+            fim_prompt("qwen2.5-coder", "a", "b"),
+            "<|fim_prefix|>a<|fim_suffix|>b<|fim_middle|>"
+        );
+    }
 }
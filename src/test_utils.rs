@@ -1,6 +1,20 @@
 // Test utilities for shimmy
+use crate::api_errors::Json;
+use crate::engine::stream::{FinishReason, GenerationStats};
+use crate::engine::{
+    Capabilities, GenOptions, GenerationEvent, GenerationStream, InferenceEngine, LoadedModel,
+    ModelSpec,
+};
+use crate::model_registry::{ModelEntry, Registry};
+use crate::AppState;
 use anyhow::Result;
+use axum::http::StatusCode;
+use axum::response::Response;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 /// Create a test SafeTensors file with given data
 pub fn create_test_safetensors(path: &str, data: &[u8]) -> Result<()> {
@@ -26,3 +40,201 @@ pub fn create_test_safetensors(path: &str, data: &[u8]) -> Result<()> {
 
     Ok(())
 }
+
+type Script = dyn Fn(&str, &GenOptions, usize) -> Vec<GenerationEvent> + Send + Sync;
+
+/// A model with a scripted reply, standing in for weights in handler tests.
+/// It is also its own engine: every load shares the script, the counts of
+/// loads and generations and the prompt and options the latest one was given.
+#[derive(Clone)]
+pub struct FakeModel {
+    script: Arc<Script>,
+    capabilities: Capabilities,
+    loads: Arc<AtomicUsize>,
+    generations: Arc<AtomicUsize>,
+    last: Arc<Mutex<(String, GenOptions)>>,
+}
+
+impl FakeModel {
+    /// Streams `pieces` as tokens, then stops
+    pub fn new(pieces: &[&str]) -> Self {
+        Self::finishing(pieces, FinishReason::Stop)
+    }
+
+    /// Streams `pieces` as tokens 100, 101, ... with a logprob of -0.5,
+    /// then ends with `finish_reason`
+    pub fn finishing(pieces: &[&str], finish_reason: FinishReason) -> Self {
+        let pieces: Vec<String> = pieces.iter().map(|piece| piece.to_string()).collect();
+        Self::replying(move |_, _, _| {
+            let mut events: Vec<_> = pieces
+                .iter()
+                .enumerate()
+                .map(|(i, piece)| GenerationEvent::Token {
+                    id: Some(100 + i as u32),
+                    text: piece.clone(),
+                    logprob: Some(-0.5),
+                })
+                .collect();
+            events.push(done(finish_reason, pieces.len()));
+            events
+        })
+    }
+
+    /// Replies with the events `script` returns for the prompt, the options
+    /// and the number of earlier generations
+    pub fn replying(
+        script: impl Fn(&str, &GenOptions, usize) -> Vec<GenerationEvent> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            script: Arc::new(script),
+            capabilities: Capabilities::text(0),
+            loads: Arc::default(),
+            generations: Arc::default(),
+            last: Arc::default(),
+        }
+    }
+
+    /// Tokens are the bytes of the text, after a BOS of 1, when
+    /// `capabilities.tokenize` is set; embeddings are the text's length
+    /// and 1 when `capabilities.embeddings` is
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// An app whose only model is this one, registered as `name`
+    pub fn state(&self, name: &str, template: Option<&str>) -> Arc<AppState> {
+        let context_length = self.capabilities.context_length;
+        let mut registry = Registry::default();
+        registry.register(ModelEntry {
+            name: name.to_string(),
+            base_path: format!("./{}.gguf", name).into(),
+            lora_path: None,
+            template: template.map(str::to_string),
+            ctx_len: (context_length > 0).then_some(context_length),
+            n_threads: None,
+            backend: None,
+            command: None,
+        });
+        Arc::new(AppState::new(Box::new(self.clone()), registry))
+    }
+
+    /// How many times the model has been loaded
+    pub fn loads(&self) -> usize {
+        self.loads.load(Ordering::SeqCst)
+    }
+
+    /// The prompt the latest generation was given
+    pub fn prompt(&self) -> String {
+        self.last.lock().unwrap().0.clone()
+    }
+
+    /// The options the latest generation was given
+    pub fn options(&self) -> GenOptions {
+        self.last.lock().unwrap().1.clone()
+    }
+
+    fn run(&self, prompt: &str, opts: &GenOptions) -> Vec<GenerationEvent> {
+        *self.last.lock().unwrap() = (prompt.to_string(), opts.clone());
+        let k = self.generations.fetch_add(1, Ordering::SeqCst);
+        (self.script)(prompt, opts, k)
+    }
+}
+
+#[async_trait::async_trait]
+impl LoadedModel for FakeModel {
+    async fn generate(
+        &self,
+        prompt: &str,
+        opts: GenOptions,
+        mut on_token: Option<Box<dyn FnMut(String) + Send>>,
+    ) -> Result<String> {
+        let mut text = String::new();
+        for event in self.run(prompt, &opts) {
+            if let GenerationEvent::Token { text: piece, .. } = event {
+                if let Some(on_token) = on_token.as_mut() {
+                    on_token(piece.clone());
+                }
+                text.push_str(&piece);
+            }
+        }
+        Ok(text)
+    }
+
+    fn generate_stream(self: Arc<Self>, prompt: String, opts: GenOptions) -> GenerationStream {
+        Box::pin(futures_util::stream::iter(self.run(&prompt, &opts)))
+    }
+
+    fn tokenize(&self, text: &str, add_special: bool) -> Result<Vec<u32>> {
+        if !self.capabilities.tokenize {
+            return Err(anyhow::anyhow!("this backend does not expose a tokenizer"));
+        }
+        let bos = add_special.then_some(1);
+        Ok(bos.into_iter().chain(text.bytes().map(u32::from)).collect())
+    }
+
+    fn detokenize(&self, tokens: &[u32]) -> Result<String> {
+        if !self.capabilities.tokenize {
+            return Err(anyhow::anyhow!("this backend does not expose a tokenizer"));
+        }
+        let bytes: Vec<u8> = tokens
+            .iter()
+            .filter(|&&t| t > 1)
+            .map(|&t| t as u8)
+            .collect();
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        if !self.capabilities.embeddings {
+            return Err(anyhow::anyhow!("this backend does not produce embeddings"));
+        }
+        Ok(vec![text.len() as f32, 1.0])
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl InferenceEngine for FakeModel {
+    async fn load(&self, _spec: &ModelSpec) -> Result<Box<dyn LoadedModel>> {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// The last event of a scripted generation: 3 prompt tokens evaluated in
+/// 2ms, and `completion_tokens` generated by 5ms
+pub fn done(finish_reason: FinishReason, completion_tokens: usize) -> GenerationEvent {
+    GenerationEvent::Done {
+        finish_reason,
+        stats: GenerationStats {
+            prompt_tokens: 3,
+            completion_tokens,
+            prompt_ms: 2,
+            total_ms: 5,
+        },
+    }
+}
+
+/// `request` as a handler's JSON body
+pub fn json_request<T: DeserializeOwned>(request: Value) -> Json<T> {
+    Json(serde_json::from_value(request).unwrap())
+}
+
+/// Status and text of a handler's response
+pub async fn body(response: Response) -> (StatusCode, String) {
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+/// Status and JSON of a handler's response; `Null` when it is not JSON
+pub async fn json_body(response: Response) -> (StatusCode, Value) {
+    let (status, body) = body(response).await;
+    (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+}
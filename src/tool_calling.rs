@@ -0,0 +1,530 @@
+// Tool definitions in prompts and tool calls in model output
+// Each chat template family was trained on its own way of offering tools and
+// of writing calls; `ToolFormat` renders the former and `ToolCallParser`
+// recovers `tools::ToolCall`s from the latter, token by token.
+
+use crate::openai_compat::partial_match;
+use crate::templates::TemplateFamily;
+use crate::tools::{ToolCall, ToolDefinition};
+use serde_json::Value;
+
+/// How a model expects to see tools and write calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolFormat {
+    /// NousResearch Hermes: `<tool_call>{json}</tool_call>` in ChatML
+    Hermes,
+    /// Qwen2/2.5: Hermes-style calls, tool results sent as user turns
+    Qwen,
+    /// Llama 3.1 JSON tool calling, results in `ipython` turns
+    Llama31,
+    /// Mistral v0.3: `[AVAILABLE_TOOLS]`, `[TOOL_CALLS]`, `[TOOL_RESULTS]`
+    Mistral,
+}
+
+/// What `tool_choice` asks of the model, once tools are offered at all
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolMode {
+    Auto,
+    Required,
+    /// Must call this function
+    Function(String),
+}
+
+impl ToolFormat {
+    pub fn for_template(family: &TemplateFamily, model: &str) -> Self {
+        match family {
+            TemplateFamily::ChatML if model.to_lowercase().contains("qwen") => ToolFormat::Qwen,
+            TemplateFamily::Llama3 => ToolFormat::Llama31,
+            TemplateFamily::Mistral => ToolFormat::Mistral,
+            _ => ToolFormat::Hermes,
+        }
+    }
+
+    /// Marker that starts a call in the model's output
+    fn open(self) -> &'static str {
+        match self {
+            ToolFormat::Hermes | ToolFormat::Qwen => "<tool_call>",
+            ToolFormat::Llama31 => "<|python_tag|>",
+            ToolFormat::Mistral => "[TOOL_CALLS]",
+        }
+    }
+
+    /// Marker that ends a call; calls without one run to the end of the output
+    fn close(self) -> Option<&'static str> {
+        match self {
+            ToolFormat::Hermes | ToolFormat::Qwen => Some("</tool_call>"),
+            ToolFormat::Llama31 | ToolFormat::Mistral => None,
+        }
+    }
+
+    /// Offer `tools` to the model by adding them to `messages`. A system
+    /// message is extended or created; Mistral gets an `available_tools`
+    /// turn before the last user message, as its template expects.
+    pub fn inject(
+        self,
+        messages: &mut Vec<(String, String)>,
+        tools: &[ToolDefinition],
+        mode: &ToolMode,
+    ) {
+        let tools: Vec<&ToolDefinition> = match mode {
+            ToolMode::Function(name) => tools.iter().filter(|t| &t.name == name).collect(),
+            _ => tools.iter().collect(),
+        };
+        let specs: Vec<String> = tools
+            .iter()
+            .map(|t| serde_json::json!({"type": "function", "function": t}).to_string())
+            .collect();
+
+        if self == ToolFormat::Mistral {
+            let at = messages
+                .iter()
+                .rposition(|(role, _)| role == "user")
+                .unwrap_or(messages.len());
+            let specs = format!("[{}]", specs.join(", "));
+            messages.insert(at, ("available_tools".to_string(), specs));
+            return;
+        }
+
+        let mut prompt = match self {
+            ToolFormat::Hermes => format!(
+                "You are a function calling AI model. You are provided with function signatures \
+                 within <tools></tools> XML tags. You may call one or more functions to assist \
+                 with the user query. Don't make assumptions about what values to plug into \
+                 functions. Here are the available tools: <tools>\n{}\n</tools>\n\
+                 For each function call return a json object with function name and arguments \
+                 within <tool_call></tool_call> XML tags as follows:\n\
+                 <tool_call>\n{{\"name\": <function-name>, \"arguments\": <args-dict>}}\n</tool_call>",
+                specs.join("\n")
+            ),
+            ToolFormat::Qwen => format!(
+                "# Tools\n\nYou may call one or more functions to assist with the user query.\n\n\
+                 You are provided with function signatures within <tools></tools> XML tags:\n\
+                 <tools>\n{}\n</tools>\n\n\
+                 For each function call, return a json object with function name and arguments \
+                 within <tool_call></tool_call> XML tags:\n\
+                 <tool_call>\n{{\"name\": <function-name>, \"arguments\": <args-json-object>}}\n</tool_call>",
+                specs.join("\n")
+            ),
+            _ => format!(
+                "You have access to the following functions. To call a function, please respond \
+                 with JSON for a function call. Respond in the format {{\"name\": function name, \
+                 \"parameters\": dictionary of argument name and its value}}. Do not use \
+                 variables.\n\n{}",
+                specs.join("\n\n")
+            ),
+        };
+        match mode {
+            ToolMode::Auto => {}
+            ToolMode::Required => prompt.push_str("\n\nYou must call at least one function."),
+            ToolMode::Function(name) => {
+                prompt.push_str(&format!("\n\nYou must call the function \"{}\".", name))
+            }
+        }
+
+        match messages.first_mut() {
+            Some((role, content)) if role == "system" => {
+                *content = format!("{}\n\n{}", content, prompt);
+            }
+            _ => messages.insert(0, ("system".to_string(), prompt)),
+        }
+    }
+
+    /// Text appended to the prompt so the reply starts inside a call, which
+    /// is how `required` and named `tool_choice`s are enforced
+    pub fn prefill(self, mode: &ToolMode) -> Option<String> {
+        let name = match mode {
+            ToolMode::Auto => return None,
+            ToolMode::Required => None,
+            ToolMode::Function(name) => Some(serde_json::to_string(name).unwrap()),
+        };
+        Some(match (self, name) {
+            (ToolFormat::Hermes | ToolFormat::Qwen, None) => "<tool_call>\n".to_string(),
+            (ToolFormat::Hermes | ToolFormat::Qwen, Some(name)) => {
+                format!("<tool_call>\n{{\"name\": {}, \"arguments\": ", name)
+            }
+            (ToolFormat::Llama31, None) => "{\"name\": ".to_string(),
+            (ToolFormat::Llama31, Some(name)) => {
+                format!("{{\"name\": {}, \"parameters\": ", name)
+            }
+            (ToolFormat::Mistral, None) => "[TOOL_CALLS] [".to_string(),
+            (ToolFormat::Mistral, Some(name)) => {
+                format!("[TOOL_CALLS] [{{\"name\": {}, \"arguments\": ", name)
+            }
+        })
+    }
+
+    /// An earlier assistant turn that called tools, as the model wrote it
+    pub fn render_calls(self, content: &str, calls: &[(ToolCall, &str)]) -> String {
+        let mut text = content.to_string();
+        match self {
+            ToolFormat::Hermes | ToolFormat::Qwen => {
+                for (call, _) in calls {
+                    let json = serde_json::json!({"name": call.name, "arguments": call.arguments});
+                    text.push_str(&format!("\n<tool_call>\n{}\n</tool_call>", json));
+                }
+            }
+            ToolFormat::Llama31 => {
+                for (call, _) in calls {
+                    let json = serde_json::json!({"name": call.name, "parameters": call.arguments});
+                    text.push_str(&format!("<|python_tag|>{}", json));
+                }
+            }
+            ToolFormat::Mistral => {
+                let json: Vec<Value> = calls
+                    .iter()
+                    .map(|(call, id)| {
+                        serde_json::json!({"name": call.name, "arguments": call.arguments, "id": id})
+                    })
+                    .collect();
+                text.push_str(&format!("[TOOL_CALLS] {}", Value::from(json)));
+            }
+        }
+        text.trim_start().to_string()
+    }
+
+    /// A tool's result as a `(role, content)` turn
+    pub fn render_result(self, content: &str, call_id: Option<&str>) -> (String, String) {
+        match self {
+            ToolFormat::Hermes => (
+                "tool".to_string(),
+                format!("<tool_response>\n{}\n</tool_response>", content),
+            ),
+            ToolFormat::Qwen => (
+                "user".to_string(),
+                format!("<tool_response>\n{}\n</tool_response>", content),
+            ),
+            ToolFormat::Llama31 => ("ipython".to_string(), content.to_string()),
+            ToolFormat::Mistral => (
+                "tool".to_string(),
+                serde_json::json!({"content": content, "call_id": call_id}).to_string(),
+            ),
+        }
+    }
+}
+
+/// A piece of model output: text for the user, or a finished tool call
+#[derive(Debug, Clone, PartialEq)]
+pub enum Parsed {
+    Content(String),
+    Call(ToolCall),
+}
+
+/// Splits streamed output into content and tool calls. Text that may still
+/// turn into a call marker is held back until the next piece decides it.
+pub struct ToolCallParser {
+    format: ToolFormat,
+    buf: String,
+    /// What opened the call being buffered, to restore if it fails to parse
+    opened: Option<String>,
+    /// Nothing but whitespace has been seen yet
+    at_start: bool,
+}
+
+impl ToolCallParser {
+    /// A parser for output that follows `prefill`, if the prompt had one
+    pub fn new(format: ToolFormat, prefill: Option<&str>) -> Self {
+        let mut parser = Self {
+            format,
+            buf: String::new(),
+            opened: None,
+            at_start: true,
+        };
+        if let Some(prefill) = prefill {
+            parser.push(prefill);
+        }
+        parser
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<Parsed> {
+        self.buf.push_str(text);
+        let mut out = Vec::new();
+        loop {
+            if let Some(opened) = &self.opened {
+                let Some(close) = self.format.close() else {
+                    break;
+                };
+                let Some(at) = self.buf.find(close) else {
+                    break;
+                };
+                let body: String = self.buf.drain(..at + close.len()).collect();
+                match parse_calls(&body[..at]) {
+                    Some(calls) => out.extend(calls.into_iter().map(Parsed::Call)),
+                    None => out.push(Parsed::Content(format!("{}{}", opened, body))),
+                }
+                self.opened = None;
+                continue;
+            }
+
+            // Llama 3.1 also writes calls as bare JSON replies, and backends
+            // that detokenize without special tokens drop Mistral's marker
+            if self.at_start {
+                let trimmed = self.buf.trim_start();
+                let bare = match self.format {
+                    ToolFormat::Hermes | ToolFormat::Qwen => Some(false),
+                    _ if trimmed.is_empty() => None,
+                    ToolFormat::Llama31 => Some(trimmed.starts_with('{')),
+                    ToolFormat::Mistral => starts_call_array(trimmed),
+                };
+                match bare {
+                    None => break,
+                    Some(true) => {
+                        self.buf = trimmed.to_string();
+                        self.opened = Some(String::new());
+                        self.at_start = false;
+                        continue;
+                    }
+                    Some(false) => {}
+                }
+            }
+
+            let open = self.format.open();
+            if let Some(at) = self.buf.find(open) {
+                let content: String = self.buf.drain(..at).collect();
+                self.buf.replace_range(..open.len(), "");
+                if !content.is_empty() {
+                    out.push(Parsed::Content(content));
+                }
+                self.opened = Some(open.to_string());
+                self.at_start = false;
+                continue;
+            }
+            let held = partial_match(&self.buf, open);
+            let content: String = self.buf.drain(..self.buf.len() - held).collect();
+            if !content.is_empty() {
+                out.push(Parsed::Content(content));
+                self.at_start = false;
+            }
+            break;
+        }
+        out
+    }
+
+    /// Whatever is left once the output has ended, including an unclosed call
+    pub fn finish(&mut self) -> Vec<Parsed> {
+        let rest = std::mem::take(&mut self.buf);
+        match self.opened.take() {
+            Some(opened) => match parse_calls(&rest) {
+                Some(calls) => calls.into_iter().map(Parsed::Call).collect(),
+                None => vec![Parsed::Content(format!("{}{}", opened, rest))],
+            },
+            None if rest.is_empty() => vec![],
+            None => vec![Parsed::Content(rest)],
+        }
+    }
+
+    /// Content and calls of a complete output
+    pub fn parse(mut self, text: &str) -> (String, Vec<ToolCall>) {
+        let mut content = String::new();
+        let mut calls = Vec::new();
+        let parsed = self.push(text);
+        for piece in parsed.into_iter().chain(self.finish()) {
+            match piece {
+                Parsed::Content(text) => content.push_str(&text),
+                Parsed::Call(call) => calls.push(call),
+            }
+        }
+        (content, calls)
+    }
+}
+
+/// Whether `text` opens a bare `[{"name"` call array, or `None` while it is
+/// too short to tell
+fn starts_call_array(text: &str) -> Option<bool> {
+    const START: &str = "[{\"name\"";
+    let seen: String = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .take(START.len())
+        .collect();
+    if seen.len() < START.len() && START.starts_with(&seen) {
+        return None;
+    }
+    Some(seen == START)
+}
+
+/// Calls in the JSON between call markers: one or more objects or arrays of
+/// them, optionally separated by `;`. `None` unless all of it is calls.
+fn parse_calls(body: &str) -> Option<Vec<ToolCall>> {
+    let mut values = Vec::new();
+    let mut rest = body;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ';');
+        if rest.is_empty() {
+            break;
+        }
+        let mut stream = serde_json::Deserializer::from_str(rest).into_iter::<Value>();
+        match stream.next() {
+            Some(Ok(Value::Array(items))) => values.extend(items),
+            Some(Ok(value)) => values.push(value),
+            _ => return None,
+        }
+        rest = &rest[stream.byte_offset()..];
+    }
+    if values.is_empty() {
+        return None;
+    }
+    values.into_iter().map(to_call).collect()
+}
+
+fn to_call(value: Value) -> Option<ToolCall> {
+    let name = value.get("name")?.as_str()?.to_string();
+    let arguments = match value.get("arguments").or_else(|| value.get("parameters")) {
+        // Some models write the arguments as a JSON string, as the API does
+        Some(Value::String(text)) => serde_json::from_str(text).ok()?,
+        Some(arguments) => arguments.clone(),
+        None => Value::Object(Default::default()),
+    };
+    Some(ToolCall { name, arguments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn call(name: &str, arguments: Value) -> Parsed {
+        Parsed::Call(ToolCall {
+            name: name.to_string(),
+            arguments,
+        })
+    }
+
+    fn stream(format: ToolFormat, prefill: Option<&str>, pieces: &[&str]) -> Vec<Parsed> {
+        let mut parser = ToolCallParser::new(format, prefill);
+        let mut out: Vec<Parsed> = pieces.iter().flat_map(|p| parser.push(p)).collect();
+        out.extend(parser.finish());
+        out
+    }
+
+    #[test]
+    fn test_hermes_calls_are_parsed_across_pieces() {
+        let out = stream(
+            ToolFormat::Hermes,
+            None,
+            &[
+                "Let me check.<tool",
+                "_call>\n{\"name\": \"weather\", ",
+                "\"arguments\": {\"city\": \"Oslo\"}}\n</tool_call>\n<tool_call>{\"name\": \"time\"}",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                Parsed::Content("Let me check.".to_string()),
+                call("weather", json!({"city": "Oslo"})),
+                Parsed::Content("\n".to_string()),
+                call("time", json!({})),
+            ]
+        );
+
+        // Not a call after all
+        let out = stream(ToolFormat::Qwen, None, &["a <tool", "box> b"]);
+        assert_eq!(
+            out,
+            [
+                Parsed::Content("a ".into()),
+                Parsed::Content("<toolbox> b".into())
+            ]
+        );
+        let out = stream(ToolFormat::Qwen, None, &["<tool_call>oops</tool_call>"]);
+        assert_eq!(out, [Parsed::Content("<tool_call>oops</tool_call>".into())]);
+    }
+
+    #[test]
+    fn test_llama_and_mistral_calls() {
+        let out = stream(
+            ToolFormat::Llama31,
+            None,
+            &[
+                "  {\"name\": \"weather\", \"parameters\": ",
+                "{\"city\": \"Oslo\"}}",
+            ],
+        );
+        assert_eq!(out, [call("weather", json!({"city": "Oslo"}))]);
+        let out = stream(ToolFormat::Llama31, None, &["Hi {there}"]);
+        assert_eq!(out, [Parsed::Content("Hi {there}".into())]);
+
+        let out = stream(
+            ToolFormat::Mistral,
+            None,
+            &[
+                "Sure. [TOOL_",
+                "CALLS] [{\"name\": \"a\", \"arguments\": \"{\\\"x\\\": 1}\"}, {\"name\": \"b\"}]",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                Parsed::Content("Sure. ".into()),
+                call("a", json!({"x": 1})),
+                call("b", json!({})),
+            ]
+        );
+
+        // llama.cpp detokenizes without the `[TOOL_CALLS]` control token
+        let out = stream(
+            ToolFormat::Mistral,
+            None,
+            &[
+                " [",
+                "{\"name\"",
+                ": \"weather\", \"arguments\": {\"city\": \"Oslo\"}}]",
+            ],
+        );
+        assert_eq!(out, [call("weather", json!({"city": "Oslo"}))]);
+        let out = stream(ToolFormat::Mistral, None, &["[1] first"]);
+        assert_eq!(out, [Parsed::Content("[1] first".into())]);
+    }
+
+    #[test]
+    fn test_prefill_forces_a_call() {
+        let prefill = ToolFormat::Hermes.prefill(&ToolMode::Function("weather".into()));
+        let out = stream(
+            ToolFormat::Hermes,
+            prefill.as_deref(),
+            &["{\"city\": \"Oslo\"}}</tool_call>"],
+        );
+        assert_eq!(out, [call("weather", json!({"city": "Oslo"}))]);
+
+        let prefill = ToolFormat::Mistral.prefill(&ToolMode::Required);
+        let out = stream(
+            ToolFormat::Mistral,
+            prefill.as_deref(),
+            &["{\"name\": \"b\"}]"],
+        );
+        assert_eq!(out, [call("b", json!({}))]);
+        assert_eq!(ToolFormat::Llama31.prefill(&ToolMode::Auto), None);
+    }
+
+    #[test]
+    fn test_tools_are_injected_per_format() {
+        let tools = [
+            ToolDefinition {
+                name: "weather".into(),
+                description: "Current weather".into(),
+                parameters: json!({"type": "object"}),
+            },
+            ToolDefinition {
+                name: "time".into(),
+                description: String::new(),
+                parameters: Value::Null,
+            },
+        ];
+        let mut messages = vec![
+            ("system".to_string(), "Be brief.".to_string()),
+            ("user".to_string(), "Weather?".to_string()),
+        ];
+        ToolFormat::Qwen.inject(&mut messages, &tools, &ToolMode::Function("weather".into()));
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].1.starts_with("Be brief.\n\n# Tools"));
+        assert!(messages[0].1.contains("\"name\":\"weather\""));
+        assert!(!messages[0].1.contains("\"name\":\"time\""));
+
+        let mut messages = vec![("user".to_string(), "Weather?".to_string())];
+        ToolFormat::Mistral.inject(&mut messages, &tools, &ToolMode::Auto);
+        assert_eq!(messages[0].0, "available_tools");
+        assert!(messages[0].1.starts_with("[{"));
+        assert!(messages[0].1.contains("\"name\":\"time\""));
+        assert_eq!(messages[1].0, "user");
+    }
+}
@@ -1,15 +1,18 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolDefinition {
     pub name: String,
+    #[serde(default)]
     pub description: String,
+    #[serde(default)]
     pub parameters: serde_json::Value, // JSON Schema
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ToolCall {
     pub name: String,
     pub arguments: serde_json::Value,
@@ -31,16 +34,32 @@ pub struct ToolRegistry {
     tools: HashMap<String, Box<dyn Tool>>,
 }
 
+lazy_static::lazy_static! {
+    /// The tools the server runs, for `/api/tools` and chat completions with
+    /// `"tool_execution": "server"`
+    pub static ref GLOBAL_TOOL_REGISTRY: ToolRegistry = ToolRegistry::new();
+}
+
 impl ToolRegistry {
+    /// The built-in tools; `file_read` only when `SHIMMY_TOOL_FILE_ROOT` names
+    /// the directory it may read from
     pub fn new() -> Self {
+        Self::with_file_root(std::env::var_os("SHIMMY_TOOL_FILE_ROOT").map(PathBuf::from))
+    }
+
+    pub fn with_file_root(file_root: Option<PathBuf>) -> Self {
         let mut registry = Self {
             tools: HashMap::new(),
         };
 
         // Register built-in tools
         registry.register(Box::new(CalculatorTool));
-        registry.register(Box::new(FileReadTool));
-        registry.register(Box::new(HttpGetTool));
+        if let Some(root) = file_root {
+            match FileReadTool::new(&root) {
+                Ok(tool) => registry.register(Box::new(tool)),
+                Err(e) => tracing::warn!("file_read tool disabled: {}", e),
+            }
+        }
 
         registry
     }
@@ -54,8 +73,11 @@ impl ToolRegistry {
         self.tools.get(name).map(|t| t.as_ref())
     }
 
+    /// Definitions sorted by name, so prompts listing them are stable
     pub fn list_tools(&self) -> Vec<ToolDefinition> {
-        self.tools.values().map(|t| t.definition()).collect()
+        let mut tools: Vec<_> = self.tools.values().map(|t| t.definition()).collect();
+        tools.sort_by(|a, b| a.name.cmp(&b.name));
+        tools
     }
 
     pub fn execute_tool(&self, call: &ToolCall) -> Result<ToolResult> {
@@ -71,6 +93,21 @@ impl ToolRegistry {
     }
 }
 
+/// Run `call` on a blocking thread, since tools may touch the filesystem or
+/// network. A tool that errors or panics yields an unsuccessful result.
+pub async fn execute_blocking(registry: &'static ToolRegistry, call: ToolCall) -> ToolResult {
+    let failed = |error: String| ToolResult {
+        success: false,
+        result: serde_json::Value::Null,
+        error: Some(error),
+    };
+    match tokio::task::spawn_blocking(move || registry.execute_tool(&call)).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => failed(e.to_string()),
+        Err(e) => failed(format!("tool panicked: {}", e)),
+    }
+}
+
 // Built-in tools
 
 pub struct CalculatorTool;
@@ -146,7 +183,23 @@ impl Tool for CalculatorTool {
     }
 }
 
-pub struct FileReadTool;
+/// Reads text files under one directory. Paths are resolved against it, and
+/// anything that resolves outside it, through `..` or symlinks, is refused.
+pub struct FileReadTool {
+    root: PathBuf,
+}
+
+impl FileReadTool {
+    pub fn new(root: &Path) -> Result<Self> {
+        let root = root
+            .canonicalize()
+            .map_err(|e| anyhow::anyhow!("cannot use {} as root: {}", root.display(), e))?;
+        if !root.is_dir() {
+            anyhow::bail!("{} is not a directory", root.display());
+        }
+        Ok(Self { root })
+    }
+}
 
 impl Tool for FileReadTool {
     fn definition(&self) -> ToolDefinition {
@@ -158,7 +211,7 @@ impl Tool for FileReadTool {
                 "properties": {
                     "path": {
                         "type": "string",
-                        "description": "Path to the file to read"
+                        "description": "Path of the file to read, relative to the tool's root directory"
                     }
                 },
                 "required": ["path"]
@@ -172,6 +225,19 @@ impl Tool for FileReadTool {
             .and_then(|v| v.as_str())
             .ok_or_else(|| anyhow::anyhow!("Missing path parameter"))?;
 
+        let inside = self
+            .root
+            .join(path)
+            .canonicalize()
+            .ok()
+            .filter(|path| path.starts_with(&self.root));
+        let Some(path) = inside else {
+            return Ok(ToolResult {
+                success: false,
+                result: serde_json::Value::Null,
+                error: Some(format!("'{}' is not a file under the tool root", path)),
+            });
+        };
         match std::fs::read_to_string(path) {
             Ok(content) => Ok(ToolResult {
                 success: true,
@@ -187,41 +253,6 @@ impl Tool for FileReadTool {
     }
 }
 
-pub struct HttpGetTool;
-
-impl Tool for HttpGetTool {
-    fn definition(&self) -> ToolDefinition {
-        ToolDefinition {
-            name: "http_get".to_string(),
-            description: "Make an HTTP GET request".to_string(),
-            parameters: serde_json::json!({
-                "type": "object",
-                "properties": {
-                    "url": {
-                        "type": "string",
-                        "description": "URL to fetch"
-                    }
-                },
-                "required": ["url"]
-            }),
-        }
-    }
-
-    fn execute(&self, arguments: serde_json::Value) -> Result<ToolResult> {
-        let _url = arguments
-            .get("url")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow::anyhow!("Missing url parameter"))?;
-
-        // Placeholder - in production this would make actual HTTP requests
-        Ok(ToolResult {
-            success: false,
-            result: serde_json::Value::Null,
-            error: Some("HTTP requests not implemented yet".to_string()),
-        })
-    }
-}
-
 impl Default for ToolRegistry {
     fn default() -> Self {
         Self::new()
@@ -234,8 +265,10 @@ mod tests {
 
     #[test]
     fn test_tool_registry_creation() {
-        let registry = ToolRegistry::new();
-        assert!(registry.tools.len() >= 3);
+        let registry = ToolRegistry::with_file_root(None);
+        assert_eq!(registry.tools.len(), 1);
+        assert!(registry.get_tool("file_read").is_none());
+        assert!(registry.get_tool("http_get").is_none());
     }
 
     #[test]
@@ -285,16 +318,70 @@ mod tests {
 
     #[test]
     fn test_file_read_tool_definition() {
-        let file_tool = FileReadTool;
+        let dir = tempfile::TempDir::new().unwrap();
+        let file_tool = FileReadTool::new(dir.path()).unwrap();
         let def = file_tool.definition();
         assert_eq!(def.name, "file_read");
+        assert!(FileReadTool::new(&dir.path().join("missing")).is_err());
     }
 
     #[test]
-    fn test_http_get_tool_definition() {
-        let http_tool = HttpGetTool;
-        let def = http_tool.definition();
-        assert_eq!(def.name, "http_get");
+    fn test_file_read_stays_under_its_root() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("notes.txt"), "hello").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "no").unwrap();
+
+        let registry = ToolRegistry::with_file_root(Some(root.clone()));
+        let read = |path: &str| {
+            registry
+                .execute_tool(&ToolCall {
+                    name: "file_read".to_string(),
+                    arguments: serde_json::json!({ "path": path }),
+                })
+                .unwrap()
+        };
+        let result = read("notes.txt");
+        assert!(result.success);
+        assert_eq!(result.result, serde_json::json!("hello"));
+        for outside in [
+            "../secret.txt",
+            dir.path().join("secret.txt").to_str().unwrap(),
+            "/etc/passwd",
+        ] {
+            let result = read(outside);
+            assert!(!result.success, "{} was readable", outside);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_blocking_reports_failures_as_results() {
+        let call = |name: &str, arguments| ToolCall {
+            name: name.to_string(),
+            arguments,
+        };
+        let registry = &*GLOBAL_TOOL_REGISTRY;
+        let result = execute_blocking(
+            registry,
+            call("calculator", serde_json::json!({"expression": "6 * 7"})),
+        )
+        .await;
+        assert!(result.success);
+        assert_eq!(result.result, serde_json::json!(42.0));
+
+        let result = execute_blocking(registry, call("calculator", serde_json::json!({}))).await;
+        assert!(!result.success);
+        assert_eq!(
+            result.error.as_deref(),
+            Some("Missing expression parameter")
+        );
+        let result = execute_blocking(registry, call("missing", serde_json::Value::Null)).await;
+        assert_eq!(result.error.as_deref(), Some("Tool 'missing' not found"));
+
+        let names: Vec<_> = registry.list_tools().into_iter().map(|t| t.name).collect();
+        assert!(names.contains(&"calculator".to_string()));
+        assert!(!names.contains(&"http_get".to_string()));
     }
 
     #[test]
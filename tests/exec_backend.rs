@@ -0,0 +1,449 @@
+// End-to-end tests for `backend = "exec"` against src/bin/exec_stub.rs
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use futures_util::StreamExt;
+use shimmy::api_errors::Json;
+use shimmy::engine::adapter::InferenceEngineAdapter;
+use shimmy::engine::exec::{self, ExecCommand, ExecWorker, LoadRequest};
+use shimmy::engine::stream::{complete, FinishReason};
+use shimmy::engine::{GenOptions, GenerationEvent, InferenceEngine, LoadedModel, ModelSpec};
+use shimmy::model_registry::{ModelEntry, Registry};
+use shimmy::{openai_compat, AppState};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const STUB: &str = env!("CARGO_BIN_EXE_exec_stub");
+const SHIMMY: &str = env!("CARGO_BIN_EXE_shimmy");
+
+fn stub_entry(name: &str, model: &str) -> ModelEntry {
+    ModelEntry {
+        name: name.to_string(),
+        base_path: model.into(),
+        lora_path: None,
+        template: None,
+        ctx_len: Some(2048),
+        n_threads: None,
+        backend: Some("exec".to_string()),
+        command: Some(vec![STUB.to_string()]),
+    }
+}
+
+fn stub_spec(model: &str) -> ModelSpec {
+    let mut registry = Registry::new();
+    registry.register(stub_entry("inhouse", model));
+    registry.to_spec("inhouse").unwrap()
+}
+
+async fn load_stub(model: &str) -> anyhow::Result<Box<dyn LoadedModel>> {
+    InferenceEngineAdapter::new().load(&stub_spec(model)).await
+}
+
+#[test]
+fn test_model_config_names_exec_backend() {
+    let entry: ModelEntry = serde_json::from_str(
+        r#"{"name": "inhouse", "base_path": "/models/inhouse", "lora_path": null,
+            "template": null, "ctx_len": null, "n_threads": null,
+            "backend": "exec", "command": ["/opt/engine", "--fast"]}"#,
+    )
+    .unwrap();
+    assert_eq!(entry.backend.as_deref(), Some("exec"));
+    assert_eq!(entry.command.unwrap(), ["/opt/engine", "--fast"]);
+}
+
+#[tokio::test]
+async fn test_exec_models_from_a_config_file_reach_the_engine() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("models.json");
+    let config = serde_json::json!({"models": [{"name": "inhouse", "base_path": "stub-model",
+        "backend": "exec", "command": [STUB]}]});
+    std::fs::write(&path, config.to_string()).unwrap();
+    let mut registry = Registry::new();
+    assert_eq!(registry.load_config(&path).unwrap(), 1);
+    let state = Arc::new(AppState::new(
+        Box::new(InferenceEngineAdapter::new()),
+        registry,
+    ));
+
+    let req = serde_json::json!({"model": "inhouse", "prompt": "hi"});
+    let response =
+        openai_compat::completions(State(state), Json(serde_json::from_value(req).unwrap()))
+            .await
+            .into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    // Only the stub engine answers with the prompt upper-cased
+    assert_eq!(body["choices"][0]["text"], "HI");
+}
+
+#[tokio::test]
+async fn test_generate_streams_tokens_from_engine() {
+    let model = load_stub("stub-model").await.unwrap();
+    let streamed = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&streamed);
+    let out = model
+        .generate(
+            "hi \"there\"\nnext",
+            GenOptions::default(),
+            Some(Box::new(move |t| sink.lock().unwrap().push(t))),
+        )
+        .await
+        .unwrap();
+    assert_eq!(out, "HI \"THERE\"\nNEXT");
+    assert_eq!(streamed.lock().unwrap().concat(), out);
+    assert_eq!(streamed.lock().unwrap().len(), out.len());
+
+    let opts = GenOptions {
+        max_tokens: 2,
+        ..Default::default()
+    };
+    assert_eq!(model.generate("hello", opts, None).await.unwrap(), "HE");
+}
+
+#[tokio::test]
+async fn test_tokenize_detokenize_and_embed() {
+    let model = load_stub("stub-model").await.unwrap();
+    let ids = model.tokenize("hi", true).unwrap();
+    assert_eq!(ids, [256, 104, 105]);
+    assert_eq!(model.detokenize(&ids).unwrap(), "hi");
+    assert_eq!(model.embed("two words").unwrap(), [9.0, 2.0]);
+}
+
+#[tokio::test]
+async fn test_dropped_generation_is_cancelled() {
+    let model = load_stub("stub-model").await.unwrap();
+    let forever = model.generate("forever", GenOptions::default(), None);
+    assert!(tokio::time::timeout(Duration::from_millis(100), forever)
+        .await
+        .is_err());
+    let seen = model
+        .generate("cancelled?", GenOptions::default(), None)
+        .await
+        .unwrap();
+    assert_eq!(seen, "1");
+}
+
+#[tokio::test]
+async fn test_event_stream_carries_engine_finish_reason_and_cancels_on_drop() {
+    let model: Arc<dyn LoadedModel> = load_stub("stub-model").await.unwrap().into();
+    let opts = GenOptions {
+        max_tokens: 2,
+        ..Default::default()
+    };
+    let done = complete(model.clone().generate_stream("hello".into(), opts), |_| {})
+        .await
+        .unwrap();
+    assert_eq!(done.text, "HE");
+    assert_eq!(done.finish_reason, FinishReason::Length);
+    assert_eq!(done.stats.completion_tokens, 2);
+    // The engine's own count: "hello" plus BOS
+    assert_eq!(done.stats.prompt_tokens, 6);
+
+    let mut forever = model
+        .clone()
+        .generate_stream("forever".into(), GenOptions::default());
+    assert!(matches!(
+        forever.next().await,
+        Some(GenerationEvent::Token { .. })
+    ));
+    drop(forever);
+    // The engine answers with `done` alone; its text still arrives as a token
+    let seen = complete(
+        model.generate_stream("cancelled?".into(), GenOptions::default()),
+        |_| {},
+    )
+    .await
+    .unwrap();
+    assert_eq!(seen.text, "1");
+}
+
+#[tokio::test]
+async fn test_crashed_engine_restarts_with_same_load() {
+    let command = ExecCommand {
+        program: STUB.to_string(),
+        args: vec![],
+    };
+    let load = LoadRequest {
+        model: "stub-model".to_string(),
+        adapter: Some("/adapters/mine".to_string()),
+        device: None,
+        ctx_len: 2048,
+    };
+    let worker = ExecWorker::start(command, load).await.unwrap();
+    worker.health().unwrap();
+
+    let err = worker
+        .generate("crash", &GenOptions::default(), None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("stub engine crashed on purpose"));
+    assert_eq!(
+        worker
+            .generate("adapter?", &GenOptions::default(), None)
+            .await
+            .unwrap(),
+        "/adapters/mine"
+    );
+    assert_eq!(worker.restarts(), 1);
+    worker.health().unwrap();
+}
+
+#[tokio::test]
+async fn test_requests_share_one_worker_that_restarts_after_a_crash() {
+    let mut registry = Registry::new();
+    registry.register(stub_entry("inhouse", "stub-model"));
+    let state = Arc::new(AppState::new(
+        Box::new(InferenceEngineAdapter::new()),
+        registry,
+    ));
+    let complete = |prompt: &str| {
+        let state = Arc::clone(&state);
+        let req = serde_json::json!({"model": "inhouse", "prompt": prompt});
+        async move {
+            let req = serde_json::from_value(req).unwrap();
+            let response = openai_compat::completions(State(state), Json(req))
+                .await
+                .into_response();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            (status, body)
+        }
+    };
+    let pid = |body: &serde_json::Value| body["choices"][0]["text"].as_str().unwrap().to_string();
+
+    let (_, first) = complete("pid?").await;
+    let (_, again) = complete("pid?").await;
+    assert_eq!(pid(&first), pid(&again));
+
+    let restarts = exec::total_restarts();
+    let (status, crashed) = complete("crash").await;
+    assert!(status.is_server_error());
+    let message = crashed["error"]["message"].as_str().unwrap();
+    assert!(
+        message.contains("stub engine crashed on purpose"),
+        "{}",
+        message
+    );
+
+    let (_, restarted) = complete("pid?").await;
+    assert_ne!(pid(&restarted), pid(&first));
+    assert!(exec::total_restarts() > restarts);
+    let (_, after) = complete("pid?").await;
+    assert_eq!(pid(&after), pid(&restarted));
+}
+
+#[tokio::test]
+async fn test_engine_errors_are_reported() {
+    let err = load_stub("missing").await.err().unwrap().to_string();
+    assert!(err.contains("no such model"));
+
+    let mut spec = stub_spec("stub-model");
+    spec.command = None;
+    let err = InferenceEngineAdapter::new()
+        .load(&spec)
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("has no command"));
+
+    spec.backend = Some("mystery".to_string());
+    let err = InferenceEngineAdapter::new()
+        .load(&spec)
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("backend = mystery"));
+}
+
+#[tokio::test]
+async fn test_isolated_models_load_in_worker_processes() {
+    let adapter = InferenceEngineAdapter::new().isolated(SHIMMY);
+    // Exec engines are already separate processes and start as configured
+    let model = adapter.load(&stub_spec("stub-model")).await.unwrap();
+    assert_eq!(model.tokenize("hi", false).unwrap(), [104, 105]);
+
+    let mut spec = stub_spec("stub-model");
+    spec.backend = None;
+    spec.command = None;
+    spec.base_path = "/nonexistent/model.gguf".into();
+    let err = adapter.load(&spec).await.err().unwrap().to_string();
+    assert!(err.contains("isolated worker"), "{}", err);
+    assert!(
+        err.contains("no GGUF or SafeTensors model found"),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn test_capabilities_gate_requests_and_show_in_model_list() {
+    let model = load_stub("stub-model").await.unwrap();
+    let caps = model.capabilities();
+    assert!(caps.generate && caps.tokenize && caps.embeddings);
+    assert!(!caps.logprobs && !caps.grammar);
+    assert_eq!(caps.context_length, 2048);
+
+    let mut registry = Registry::new();
+    registry.register(stub_entry("inhouse", "stub-model"));
+    let state = Arc::new(AppState::new(
+        Box::new(InferenceEngineAdapter::new()),
+        registry,
+    ));
+    let chat = |extra: serde_json::Value| {
+        let mut req = serde_json::json!({
+            "model": "inhouse",
+            "messages": [{"role": "user", "content": "hi"}],
+            "stream": false,
+        });
+        req.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        let state = Arc::clone(&state);
+        async move {
+            let req = serde_json::from_value(req).unwrap();
+            openai_compat::chat_completions(State(state), Json(req))
+                .await
+                .into_response()
+        }
+    };
+
+    let listed = |state: Arc<AppState>| async move {
+        let response = openai_compat::models(State(state)).await.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()["data"][0].clone()
+    };
+    assert!(listed(Arc::clone(&state)).await["capabilities"].is_null());
+
+    let rejected = chat(serde_json::json!({"logprobs": true})).await;
+    assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+    let body = axum::body::to_bytes(rejected.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("does not support logprobs"));
+
+    let too_long = chat(serde_json::json!({"max_tokens": 4096})).await;
+    assert_eq!(too_long.status(), StatusCode::BAD_REQUEST);
+    let json_mode = chat(serde_json::json!({"response_format": {"type": "json_object"}})).await;
+    assert_eq!(json_mode.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(chat(serde_json::json!({})).await.status(), StatusCode::OK);
+
+    let entry = listed(Arc::clone(&state)).await;
+    assert_eq!(entry["id"], "inhouse");
+    assert_eq!(entry["capabilities"]["embeddings"], true);
+    assert_eq!(entry["capabilities"]["context_length"], 2048);
+}
+
+#[tokio::test]
+async fn test_chat_usage_and_finish_reason_come_from_the_engine() {
+    let mut registry = Registry::new();
+    registry.register(stub_entry("inhouse", "stub-model"));
+    let state = Arc::new(AppState::new(
+        Box::new(InferenceEngineAdapter::new()),
+        registry,
+    ));
+    let req = serde_json::from_value(serde_json::json!({
+        "model": "inhouse",
+        "messages": [{"role": "user", "content": "hello"}],
+        "max_tokens": 2,
+        "stream": false,
+    }))
+    .unwrap();
+    let response = openai_compat::chat_completions(State(state), Json(req))
+        .await
+        .into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["choices"][0]["finish_reason"], "length");
+    let usage = &body["usage"];
+    assert_eq!(usage["completion_tokens"], 2);
+    // The stub counts one token per prompt byte, plus BOS
+    assert!(usage["prompt_tokens"].as_u64().unwrap() > "hello".len() as u64);
+    assert_eq!(
+        usage["total_tokens"].as_u64(),
+        Some(usage["prompt_tokens"].as_u64().unwrap() + 2)
+    );
+}
+
+#[tokio::test]
+async fn test_legacy_completions_stop_echo_and_stream() {
+    let mut registry = Registry::new();
+    registry.register(stub_entry("inhouse", "stub-model"));
+    let state = Arc::new(AppState::new(
+        Box::new(InferenceEngineAdapter::new()),
+        registry,
+    ));
+    let complete = |req: serde_json::Value| {
+        let state = Arc::clone(&state);
+        async move {
+            let req = serde_json::from_value(req).unwrap();
+            let response = openai_compat::completions(State(state), Json(req))
+                .await
+                .into_response();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+
+    let body = complete(
+        serde_json::json!({"model": "inhouse", "prompt": "hello world",
+        "stop": ["O W", "zzz"], "echo": true, "max_tokens": 20}),
+    )
+    .await;
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["choices"][0]["text"], "hello worldHELL");
+    assert_eq!(body["choices"][0]["finish_reason"], "stop");
+
+    let body = complete(
+        serde_json::json!({"model": "inhouse", "prompt": ["ab", "cd"],
+        "n": 2, "max_tokens": 1}),
+    )
+    .await;
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let texts: Vec<_> = body["choices"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| (c["index"].as_u64().unwrap(), c["text"].as_str().unwrap()))
+        .collect();
+    assert_eq!(texts, [(0, "A"), (1, "A"), (2, "C"), (3, "C")]);
+    assert_eq!(body["choices"][3]["finish_reason"], "length");
+    assert_eq!(body["usage"]["completion_tokens"], 4);
+
+    let body = complete(serde_json::json!({"model": "inhouse", "prompt": "hello",
+        "stop": "LL", "stream": true}))
+    .await;
+    let data: Vec<&str> = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .collect();
+    assert_eq!(data.last(), Some(&"[DONE]"));
+    let chunks: Vec<serde_json::Value> = data[..data.len() - 1]
+        .iter()
+        .map(|d| serde_json::from_str(d).unwrap())
+        .collect();
+    assert!(chunks.iter().all(|c| c["object"] == "text_completion"));
+    let text: String = chunks
+        .iter()
+        .map(|c| c["choices"][0]["text"].as_str().unwrap())
+        .collect();
+    assert_eq!(text, "HE");
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+}
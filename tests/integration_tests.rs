@@ -12,7 +12,7 @@ async fn create_test_server() -> (String, tokio::task::JoinHandle<()>) {
     let registry = Registry::default();
     let engine = Box::new(shimmy::engine::llama::LlamaEngine::new());
 
-    let state = Arc::new(AppState { engine, registry });
+    let state = Arc::new(AppState::new(engine, registry));
 
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
@@ -98,7 +98,7 @@ fn test_cli_parsing() {
     let args = vec!["shimmy", "serve", "--bind", "0.0.0.0:8080"];
     let cli = Cli::try_parse_from(args).unwrap();
     match cli.cmd {
-        Command::Serve { bind } => assert_eq!(bind, "0.0.0.0:8080"),
+        Command::Serve { bind, .. } => assert_eq!(bind, "0.0.0.0:8080"),
         _ => panic!("Expected Serve command"),
     }
 
@@ -25,6 +25,8 @@ mod regression_tests {
             template: Some("chatml".to_string()),
             ctx_len: Some(2048),
             n_threads: None,
+            backend: None,
+            command: None,
         };
 
         registry.register(test_model.clone());
@@ -118,6 +120,7 @@ mod regression_tests {
                 message: crate::api::ChatMessage {
                     role: "assistant".to_string(),
                     content: "Hello!".to_string(),
+                    ..Default::default()
                 },
                 finish_reason: Some("stop".to_string()),
             }],
@@ -126,6 +129,7 @@ mod regression_tests {
                 completion_tokens: 2,
                 total_tokens: 7,
             },
+            tool_trace: None,
         };
 
         let json = serde_json::to_string(&response).unwrap();
@@ -203,12 +207,14 @@ mod regression_tests {
                     object: "model".to_string(),
                     created: 0,
                     owned_by: "shimmy".to_string(),
+                    capabilities: None,
                 },
                 Model {
                     id: "llama-7b".to_string(),
                     object: "model".to_string(),
                     created: 0,
                     owned_by: "shimmy".to_string(),
+                    capabilities: None,
                 },
             ],
         };